@@ -0,0 +1,56 @@
+//! Compare processing a big synthetic workload (see `rust_coding_test::workload`) with the stores
+//! growing as they fill up, and with room allocated upfront, be it given exactly or estimated from
+//! the size of the input (see `Options::expect_clients` and `Options::expect_transactions`). Each
+//! case is run a few times and the best throughput is kept.
+//!
+//! ```shell
+//! $ cargo bench --bench capacity
+//! ```
+
+use rust_coding_test::workload::Workload;
+use rust_coding_test::{estimate_transactions, Engine, Options};
+use std::time::Instant;
+
+const ROWS: usize = 5_000_000;
+const RUNS: usize = 3;
+
+fn main() {
+    let clients = u16::MAX;
+    let input = Workload {
+        rows: ROWS,
+        clients,
+        dispute_ratio: 0.0,
+        seed: 42,
+    }
+    .generate();
+    println!("{} rows, {} clients", ROWS, clients);
+    let estimated = estimate_transactions(input.len() as u64);
+    for (name, expect_clients, expect_transactions) in [
+        ("no hints", None, None),
+        ("exact hints", Some(usize::from(clients)), Some(ROWS)),
+        ("estimated", None, Some(estimated)),
+    ] {
+        let mut best = f64::MAX;
+        let mut stats = None;
+        for _ in 0..RUNS {
+            let mut engine = Engine::new(Options {
+                expect_clients,
+                expect_transactions,
+                ..Options::default()
+            });
+            let start = Instant::now();
+            engine.process_from_str(&input).unwrap();
+            best = best.min(start.elapsed().as_secs_f64());
+            stats = Some(engine.stats());
+        }
+        let stats = stats.unwrap();
+        println!(
+            "  {:<12} {:>9.0} rows/s ({:.3}s), {} of {} history inserts without reallocating",
+            name,
+            ROWS as f64 / best,
+            best,
+            stats.unreallocated_inserts,
+            stats.history_inserts
+        );
+    }
+}
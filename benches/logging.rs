@@ -0,0 +1,32 @@
+//! Check that logging, when disabled, doesn't slow down the hot path: the same input is processed
+//! with logging off, and with the default `warn` level (no event of which is emitted on this
+//! input), the throughputs should be on par.
+//!
+//! ```shell
+//! $ cargo bench --bench logging
+//! ```
+
+use rust_coding_test::log::{self, Level};
+use rust_coding_test::Engine;
+use std::time::Instant;
+
+const ROWS: u32 = 1_000_000;
+
+fn main() {
+    let mut input = String::from("type,client,tx,amount\n");
+    for tx in 1..=ROWS {
+        input.push_str(&format!("deposit,{},{},1.0\n", tx % 1000, tx));
+    }
+    for level in [Level::Off, Level::Warn, Level::Off, Level::Warn] {
+        log::set_max_level(level);
+        let start = Instant::now();
+        Engine::default().process_from_str(&input).unwrap();
+        let elapsed = start.elapsed().as_secs_f64();
+        println!(
+            "log level {:<5} {:>8.0} rows/s ({:.3}s)",
+            level.to_string(),
+            f64::from(ROWS) / elapsed,
+            elapsed
+        );
+    }
+}
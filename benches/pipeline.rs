@@ -0,0 +1,52 @@
+//! Compare single-threaded and pipelined processing (see `Engine::process_pipelined`) of a large
+//! generated file, with a few channel capacities. Each mode is run a few times and the best
+//! throughput is kept.
+//!
+//! ```shell
+//! $ cargo bench --bench pipeline
+//! ```
+
+use rust_coding_test::workload::Workload;
+use rust_coding_test::Engine;
+use std::fs::File;
+use std::time::Instant;
+
+const ROWS: usize = 10_000_000;
+const RUNS: usize = 3;
+
+fn main() {
+    let path = std::env::temp_dir().join("rust-coding-test-bench-pipeline.csv");
+    let input = Workload {
+        rows: ROWS,
+        clients: u16::MAX,
+        dispute_ratio: 0.01,
+        seed: 42,
+    }
+    .generate();
+    std::fs::write(&path, input).unwrap();
+    println!("{} rows, 0.01 disputes", ROWS);
+    for capacity in [None, Some(64), Some(1024), Some(16384)] {
+        let mut best = f64::MAX;
+        for _ in 0..RUNS {
+            let mut engine = Engine::default();
+            let file = File::open(&path).unwrap();
+            let start = Instant::now();
+            match capacity {
+                Some(capacity) => engine.process_pipelined(file, capacity).unwrap(),
+                None => engine.process_from_reader(file).unwrap(),
+            }
+            best = best.min(start.elapsed().as_secs_f64());
+        }
+        let mode = match capacity {
+            Some(capacity) => format!("pipeline {}", capacity),
+            None => "single-threaded".to_owned(),
+        };
+        println!(
+            "  {:<16} {:>9.0} rows/s ({:.3}s)",
+            mode,
+            ROWS as f64 / best,
+            best
+        );
+    }
+    std::fs::remove_file(&path).unwrap();
+}
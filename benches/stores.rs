@@ -0,0 +1,59 @@
+//! Compare the data-structures that can back client accounts and the transaction history, on a few
+//! synthetic workloads (see `rust_coding_test::workload`) varying the number of clients and the
+//! share of disputes. Each combination is run a few times and the best throughput is kept.
+//!
+//! ```shell
+//! $ cargo bench --bench stores
+//! ```
+
+use rust_coding_test::store::StoreKind;
+use rust_coding_test::workload::Workload;
+use rust_coding_test::{Engine, Options};
+use std::time::Instant;
+
+const ROWS: usize = 1_000_000;
+const RUNS: usize = 3;
+const KINDS: [StoreKind; 4] = [
+    StoreKind::HashMap,
+    StoreKind::FxHashMap,
+    StoreKind::BTreeMap,
+    StoreKind::Vec,
+];
+
+fn main() {
+    for (clients, dispute_ratio) in [(100, 0.0), (100, 0.1), (u16::MAX, 0.0), (u16::MAX, 0.1)] {
+        let input = Workload {
+            rows: ROWS,
+            clients,
+            dispute_ratio,
+            seed: 42,
+        }
+        .generate();
+        println!(
+            "{} rows, {} clients, {} disputes",
+            ROWS, clients, dispute_ratio
+        );
+        for accounts_store in KINDS {
+            for history_store in KINDS {
+                let mut best = f64::MAX;
+                for _ in 0..RUNS {
+                    let mut engine = Engine::new(Options {
+                        accounts_store,
+                        history_store,
+                        ..Options::default()
+                    });
+                    let start = Instant::now();
+                    engine.process_from_str(&input).unwrap();
+                    best = best.min(start.elapsed().as_secs_f64());
+                }
+                println!(
+                    "  accounts {:<10} history {:<10} {:>9.0} rows/s ({:.3}s)",
+                    format!("{:?}", accounts_store),
+                    format!("{:?}", history_store),
+                    ROWS as f64 / best,
+                    best
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Compare single-pass and two-pass processing (see `Engine::process_two_pass`) on synthetic
+//! workloads with a varying share of disputes: the size of the history kept in memory, and the
+//! cost of reading the input twice. Each mode is run a few times and the best throughput is kept.
+//!
+//! ```shell
+//! $ cargo bench --bench two_pass
+//! ```
+
+use rust_coding_test::workload::Workload;
+use rust_coding_test::Engine;
+use std::io::Cursor;
+use std::time::Instant;
+
+const ROWS: usize = 1_000_000;
+const RUNS: usize = 3;
+
+fn main() {
+    for dispute_ratio in [0.0, 0.001, 0.01, 0.1] {
+        let input = Workload {
+            rows: ROWS,
+            clients: u16::MAX,
+            dispute_ratio,
+            seed: 42,
+        }
+        .generate();
+        println!("{} rows, {} disputes", ROWS, dispute_ratio);
+        for two_pass in [false, true] {
+            let mut best = f64::MAX;
+            let mut history = 0;
+            for _ in 0..RUNS {
+                let mut engine = Engine::default();
+                let start = Instant::now();
+                if two_pass {
+                    engine.process_two_pass(Cursor::new(&input)).unwrap();
+                } else {
+                    engine.process_from_str(&input).unwrap();
+                }
+                best = best.min(start.elapsed().as_secs_f64());
+                history = engine.transaction_count();
+            }
+            println!(
+                "  {:<11} {:>9} transactions in history {:>9.0} rows/s ({:.3}s)",
+                if two_pass { "two-pass" } else { "single-pass" },
+                history,
+                ROWS as f64 / best,
+                best
+            );
+        }
+    }
+}
@@ -0,0 +1,1260 @@
+//! # Command line interface
+//!
+//! Arguments are parsed by hand, in the spirit of `clap`: every flag has an entry in the `--help`
+//! text, can be given as `--flag value` or `--flag=value`, and is validated upfront (bad values,
+//! repeated or conflicting flags), so a deployed binary is configured at runtime rather than with
+//! cargo features. The contract of the automated scoring is kept unchanged:
+//!
+//! ```shell
+//! $ cargo run -- transactions.csv > accounts.csv
+//! ```
+
+use crate::config::Config;
+use crate::follow::Follow;
+use crate::signal;
+use rust_coding_test::events::EventSink;
+#[cfg(feature = "http")]
+use rust_coding_test::http;
+use rust_coding_test::json;
+use rust_coding_test::metrics::{self, Metrics};
+use rust_coding_test::store::HistoryOverflow;
+use rust_coding_test::timestamp::OrderCheck;
+use rust_coding_test::{
+    Amount, AmountFormat, AmountValidation, EngineError, ErrorSink, InputFormat, LenientAmounts,
+    Options, OutputFormat, Tx, WithdrawalDisputePolicy,
+};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+const HELP: &str = "\
+Toy payments engine: reads transactions as CSV, writes the resulting client accounts as CSV
+(or JSON)
+
+Usage: rust-coding-test [OPTIONS] [INPUT]
+       rust-coding-test validate [OPTIONS] [INPUT]
+
+Commands:
+  validate  Only check every row of the input (known type, client and transaction IDs,
+            amount given for deposits and withdrawals only, positive and with at most
+            --input-precision places past the decimal, disputes referencing an earlier
+            transaction), print the violations found along with their line, and exit
+
+Arguments:
+  [INPUT]  Input CSV file, transactions are read from stdin if missing or `-`, or streamed
+           from an http:// URL (with the `http` feature), gzipped or not (https:// URLs
+           are refused)
+
+Options:
+      --config <PATH>            Read flags from a TOML file, `key = value` lines named after
+                                 them (e.g. `on_business_error = \"error\"`), which flags of
+                                 the command line override
+      --print-config             Print the configuration resulting from --config and flags as
+                                 TOML, and exit
+  -o, --output <PATH>            Write accounts to PATH rather than to stdout
+      --input-format <FORMAT>    Read transactions as `csv` (default), or as `ndjson` (a
+                                 JSON object per line, with the same fields)
+      --format <FORMAT>          Write accounts as `csv` (default), or as a `json` array
+                                 (amounts being strings)
+      --on-interrupt-output <PATH>
+                                 On SIGINT or SIGTERM, write the accounts processed so far to
+                                 PATH (default: the output with a `.partial` suffix, or stdout)
+      --open-disputes <PATH>     Write the open disputes of every account to PATH
+      --stats <PATH>             Write counts and totals of the transactions applied to
+                                 every client to PATH as CSV
+      --summary <PATH>           Write totals over every account (accounts, available and
+                                 held funds, locked accounts, disputes) to PATH as CSV
+      --dispute-trace <PATH>     Write the dispute events of every disputed transaction
+                                 (tx, client, amount, and events as `dispute:LINE`,
+                                 `resolve:LINE`, ...) to PATH as CSV
+      --dump-history <PATH>      Write every transaction still known at the end (tx, client,
+                                 type, amount, and state: recorded, disputed or
+                                 charged_back) to PATH as CSV, sorted by tx
+      --stats-json <PATH>        Write counters of the run (rows by type, skipped rows by
+                                 reason, accounts, ...) to PATH as JSON
+      --emit-events <PATH|->     Write an NDJSON event for every transaction applied (its
+                                 deltas of available and held funds, and the resulting
+                                 balances) to PATH as it's processed, or to stdout with `-`
+                                 (the accounts then going to --output)
+      --rejected <PATH>          Write every row skipped (rejected, or malformed with
+                                 `--on-parse-error skip`) to PATH as an NDJSON object of
+                                 the shape of --json-errors, e.g. with --dry-run
+      --delimiter <CHAR>         Input field separator: `,` (default), `;`, `|` or `\\t`
+      --no-header                Read CSV inputs without a header line: columns are then
+                                 type, client, tx, amount and currency, in this order,
+                                 extra trailing fields being ignored
+      --output-delimiter <CHAR>  Output field separator: `,` (default), `;`, `|` or `\\t`
+      --on-business-error <POLICY>
+                                 On a rejected transaction (e.g. insufficient funds):
+                                 `skip` it (default) or `error` out
+      --http-timeout <SECS>      Timeout of the connection to, and of every read from, an
+                                 HTTP input
+      --follow                   Keep reading the input file as it grows, until SIGINT or
+                                 SIGTERM, and then write the accounts
+      --clients <IDS>            Comma-separated IDs of the only clients whose accounts are
+                                 computed and written (transactions of others are still
+                                 recorded in history, to reject the reuse of their IDs)
+      --disputable <KINDS>       Comma-separated kinds of transactions a dispute may target:
+                                 `deposit` (default) and/or `withdrawal`
+      --withdrawal-dispute-policy <POLICY>
+                                 How disputing a withdrawal moves funds: `hold` its amount
+                                 out of available funds (default, as for a deposit),
+                                 `reject` the dispute, `credit-held` it back to held funds,
+                                 or `reverse` it into available funds right away
+      --two-pass                 Read the input file twice, to only keep in history the
+                                 transactions that get disputed (stdin is read once)
+      --pipeline                 Read and parse rows on a separate thread, overlapping with
+                                 their processing (for large inputs, rows being handed
+                                 over by batches)
+      --pipeline-capacity <ROWS> Rows parsed ahead of processing with --pipeline (default
+                                 1024)
+      --strict                   Same as `--on-business-error error`
+      --on-parse-error <POLICY>  On a malformed row: `abort` (default) or `skip` it
+      --partial-failure          Same as `--on-parse-error skip`
+      --listen <ADDR>            Receive transactions over TCP at ADDR (e.g.
+                                 `127.0.0.1:7878`) rather than from an input, a CSV per
+                                 connection, until SIGINT or SIGTERM: open connections are
+                                 then drained and the accounts written (see server.rs),
+                                 not with --max-history or --dispute-expiry-after
+      --drain-timeout <SECS>     Time open connections get to finish at shutdown with
+                                 --listen (default 30)
+      --metrics <ADDR>           Serve Prometheus metrics on http://ADDR/metrics while
+                                 processing (e.g. `127.0.0.1:9898`)
+      --check-ordering           Flag transactions referenced before they appear
+      --check-order <POLICY>     On a row whose `ts` column is before the one of the previous
+                                 row: `off` (default), `warn`, or `error` out
+      --dedupe-tx                Skip deposits and withdrawals repeated with the very same
+                                 contents (e.g. overlapping exports), rather than
+                                 rejecting them as duplicates
+      --idempotent               Skip rows that already took effect (deposits and
+                                 withdrawals by ID, disputes of disputed transactions,
+                                 chargebacks of charged back ones), e.g. a replayed input
+      --auto-lock-after-chargebacks <N>
+                                 Lock an account for good (resolving a dispute won't
+                                 unlock it) once N chargebacks were applied to it
+      --auto-lock-chargeback-amount <AMOUNT>
+                                 Same, once its chargebacks total more than AMOUNT
+      --dispute-expiry-after <N> Resolve a dispute still pending once N more transactions
+                                 were applied, releasing its held funds
+      --max-history <N>          Keep at most N transactions in history (least recently
+                                 used ones are evicted, and can't be disputed anymore),
+                                 also accepted as --max-tx-history
+      --max-clients <N>          Reject deposits and withdrawals of new clients once N
+                                 accounts are open
+      --max-rows <N>             Stop reading after N rows (rejected and skipped ones
+                                 included), and write the accounts as usual
+      --expect-clients <N>       Allocate room for N accounts upfront
+      --expect-transactions <N>  Allocate room for N transactions in history upfront
+                                 (estimated from the size of an input file by default)
+      --history-overflow <POLICY>
+                                 Once history holds --max-history transactions: `error`
+                                 out, `evict-oldest` (default), or `spill-disk` to a
+                                 temporary file
+      --include-empty-accounts <BOOL>
+                                 Write accounts holding nothing and unlocked, e.g. opened
+                                 by rejected transactions only: `true` (default) or `false`
+      --sorted                   Sort output rows by client ID, and then currency
+      --multi-currency           Keep one account per client and currency
+      --input-precision <N>      Round input amounts to N places past the decimal (default 4)
+      --precision <N>            Write amounts with N places past the decimal (default 4),
+                                 whatever the places they're stored with
+      --amount-format <FORMAT>   Write amounts with every place past the decimal: `fixed4`
+                                 (default), or without trailing zeros: `trim`
+      --lenient-amounts          Read amounts formatted for humans, e.g. `$1,000.50`: without
+                                 it, they're rejected as malformed. With a `,` thousands
+                                 separator, use another --delimiter than `,`.
+      --currency-symbol <SYM>    Currency symbol of --lenient-amounts (default `$`)
+      --thousands-separator <CHAR>
+                                 Thousands separator of --lenient-amounts: `,` (default),
+                                 or e.g. `.` for `1.000,50`, the decimal separator being a
+                                 comma then
+      --validate-amounts <MODE>  Read amounts as floats: `lenient` (default), or reject the
+                                 ones not of the form `digits[.digits]` with up to 4 places
+                                 past the decimal (e.g. `1e3`, `+5` or `.5`): `strict`
+      --warn-unknown-columns     Log a warning naming the columns of the CSV header that are
+                                 ignored, i.e. other than type, client, tx, amount,
+                                 currency and ts
+      --progress                 Report progress on stderr
+      --json-errors              Report rejected rows and errors on stderr as JSON objects,
+                                 even if --quiet
+      --audit-negatives          Report on stderr the accounts whose available funds went
+                                 below zero at some point (e.g. by disputing a deposit
+                                 already withdrawn), even if --quiet
+      --dry-run                  Process the input as usual (rejected rows being reported
+                                 on stderr), but write no accounts nor any other output,
+                                 only the stats of the run as JSON to stdout (see
+                                 --stats-json): a preflight check, exiting with 0 if no row
+                                 would be skipped, and 1 otherwise (see --rejected)
+  -q, --quiet                    Don't log rejected transactions on stderr
+  -h, --help                     Print help
+  -V, --version                  Print version
+
+Exit status:
+  0  Every row was applied (including when there was none, which is warned about)
+  1  Fatal error, e.g. invalid command line usage, input that couldn't be read or
+     parsed, or a transaction rejected in strict mode (or the input has violations,
+     with `validate`)
+  2  Completed, but some rows were skipped (rejected, or malformed with
+     `--on-parse-error skip` or `--partial-failure`)
+  6  Interrupted by a signal
+";
+
+/// Time open connections get to finish once a server is shutting down, see `--drain-timeout`
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pairs of flags that can't be used together
+const CONFLICTS: &[(&str, &str)] = &[
+    ("--quiet", "--progress"),
+    ("--strict", "--on-business-error"),
+    ("--partial-failure", "--on-parse-error"),
+    ("--two-pass", "--follow"),
+    ("--two-pass", "--max-history"),
+    ("--two-pass", "--pipeline"),
+    ("--pipeline", "--follow"),
+    ("--listen", "--follow"),
+    ("--listen", "--two-pass"),
+    ("--listen", "--pipeline"),
+    ("--listen", "--max-history"),
+    ("--listen", "--dispute-expiry-after"),
+    ("--dedupe-tx", "--idempotent"),
+    ("--dry-run", "--output"),
+    ("--dry-run", "--on-interrupt-output"),
+    ("--dry-run", "--open-disputes"),
+    ("--dry-run", "--stats"),
+    ("--dry-run", "--summary"),
+    ("--dry-run", "--dispute-trace"),
+    ("--dry-run", "--dump-history"),
+    ("--dry-run", "--stats-json"),
+    ("--dry-run", "--emit-events"),
+    ("--dry-run", "--follow"),
+    ("--dry-run", "--listen"),
+];
+
+/// Everything that can go wrong when running the binary
+#[derive(Debug)]
+pub enum CliError {
+    /// Invalid command line arguments
+    Usage(String),
+    /// The input file couldn't be opened
+    Open(String, std::io::Error),
+    /// The output file couldn't be created
+    Create(String, std::io::Error),
+    /// Failure while processing transactions or writing accounts
+    Engine(EngineError),
+    /// Processing was interrupted by a signal, and partial accounts were written to the given
+    /// destination
+    Interrupted { rows: u64, partial: String },
+}
+
+impl CliError {
+    /// Any error is fatal, and exits with `1` (`0` and `2` being left to a completed run, see
+    /// `Outcome`), except for an interruption, which exits with `6` so that a scheduler can tell
+    /// it from a failure. The error printed tells the others apart.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Interrupted { .. } | CliError::Engine(EngineError::Interrupted(_)) => 6,
+            _ => 1,
+        }
+    }
+}
+
+impl CliError {
+    /// The error as a JSON object, of the same shape as `EngineError::to_json`
+    pub fn to_json(&self) -> String {
+        let kind = match self {
+            CliError::Engine(e) => return e.to_json(None, None),
+            CliError::Usage(_) => "Usage",
+            CliError::Open(..) => "Open",
+            CliError::Create(..) => "Create",
+            CliError::Interrupted { .. } => "Interrupted",
+        };
+        json::Object::new()
+            .value::<u64>("line", None)
+            .value::<u32>("tx_id", None)
+            .value::<u16>("client_id", None)
+            .string("error", Some(kind))
+            .string("detail", Some(&self.to_string()))
+            .finish()
+    }
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{} (see --help)", message),
+            CliError::Open(path, e) => write!(f, "can't open `{}`: {}", path, e),
+            CliError::Create(path, e) => write!(f, "can't create `{}`: {}", path, e),
+            CliError::Engine(e) => write!(f, "{}", e),
+            CliError::Interrupted { rows, partial } => write!(
+                f,
+                "interrupted after {} rows, partial accounts written to {}",
+                rows, partial
+            ),
+        }
+    }
+}
+
+impl From<EngineError> for CliError {
+    fn from(e: EngineError) -> Self {
+        CliError::Engine(e)
+    }
+}
+
+/// How a run that went through completed
+#[derive(Debug)]
+pub enum Outcome {
+    /// Every row was applied
+    Applied,
+    /// Processing completed, but some rows were skipped (rejected transactions, or malformed rows
+    /// when skipping parse errors)
+    Skipped,
+    /// Validation completed, but found violations, or a dry run would skip some rows
+    Invalid,
+}
+
+impl Outcome {
+    /// - `0`: every row was applied
+    /// - `2`: some rows were skipped
+    /// - `1`: some rows are invalid, as if processing them failed
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Outcome::Applied => 0,
+            Outcome::Skipped => 2,
+            Outcome::Invalid => 1,
+        }
+    }
+}
+
+/// What the command line asks for
+#[derive(Debug)]
+pub enum Action {
+    Run(Box<Args>),
+    /// Print the configuration of the run, see `config::effective`
+    PrintConfig(Box<Args>),
+    /// Only check the input, see `InputValidator`
+    Validate(Box<Args>),
+    Help,
+    Version,
+}
+
+/// Command line arguments of a processing run
+#[derive(Debug)]
+pub struct Args {
+    /// Path of the input CSV file, transactions are read from stdin if it's missing or `-`
+    pub input: Option<String>,
+    /// Path of the output CSV file, accounts are written to stdout if it's missing
+    pub output: Option<String>,
+    /// Path of the partial output written when interrupted, see `create_partial_output`
+    pub on_interrupt_output: Option<String>,
+    /// Path of the CSV report of open disputes, if any
+    pub open_disputes: Option<String>,
+    /// Path of the CSV stats of every client, if any
+    pub stats: Option<String>,
+    /// Path of the CSV summary of every account, if any
+    pub summary: Option<String>,
+    /// Path of the CSV trace of disputed transactions, if any
+    pub dispute_trace: Option<String>,
+    /// Path of the CSV dump of the transaction history, if any
+    pub dump_history: Option<String>,
+    /// Path of the JSON stats of the run, if any
+    pub stats_json: Option<String>,
+    /// Path of the NDJSON events of transactions applied (`-` for stdout), if any
+    pub emit_events: Option<String>,
+    /// Path of the NDJSON list of rows skipped, if any
+    pub rejected: Option<String>,
+    /// Timeout of an HTTP input
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    pub http_timeout: Option<Duration>,
+    /// Keep reading the input file as it grows, see `follow.rs`
+    pub follow: bool,
+    /// Read the input twice to keep less history, see `Engine::process_two_pass`
+    pub two_pass: bool,
+    /// Capacity of the channel between reading and processing threads, when pipelined (see
+    /// `Engine::process_pipelined`)
+    pub pipeline: Option<usize>,
+    /// Address to receive transactions on, rather than reading an input, see `server.rs`
+    pub listen: Option<SocketAddr>,
+    /// Time open connections get to finish once the server is shutting down
+    pub drain_timeout: Duration,
+    /// Address to serve metrics on, if any
+    pub metrics: Option<SocketAddr>,
+    /// Report accounts whose available funds went below zero, see `Engine::negative_balances`
+    pub audit_negatives: bool,
+    /// Only report the stats of the run, rather than writing accounts
+    pub dry_run: bool,
+    /// Don't log rejected transactions
+    pub quiet: bool,
+    pub options: Options,
+}
+
+/// Input of a run, which can be read twice when it's a regular file
+pub enum Input {
+    File(std::fs::File),
+    Stream(Box<dyn Read + Send>),
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Input::File(file) => file.read(buf),
+            Input::Stream(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Args {
+    /// Open the input file, or stdin, so the tool composes in shell pipelines
+    pub fn open_input(&self) -> Result<Input, CliError> {
+        match self.input.as_deref() {
+            None | Some("-") => Ok(Input::Stream(Box::new(std::io::stdin()))),
+            #[cfg(feature = "http")]
+            Some(url) if http::is_url(url) => match http::get(url, self.http_timeout) {
+                Ok(body) => Ok(Input::Stream(Box::new(body))),
+                Err(e) => Err(CliError::Open(url.to_owned(), e)),
+            },
+            Some(path) if self.follow => match Follow::open(path, &signal::INTERRUPTED) {
+                Ok(follow) => Ok(Input::Stream(Box::new(follow))),
+                Err(e) => Err(CliError::Open(path.to_owned(), e)),
+            },
+            Some(path) => match std::fs::File::open(path) {
+                // e.g. a named pipe can't be read twice
+                Ok(file) if file.metadata().is_ok_and(|m| m.is_file()) => Ok(Input::File(file)),
+                Ok(file) => Ok(Input::Stream(Box::new(file))),
+                Err(e) => Err(CliError::Open(path.to_owned(), e)),
+            },
+        }
+    }
+
+    /// Create the output file, or fallback on stdout
+    pub fn create_output(&self) -> Result<Box<dyn Write>, CliError> {
+        match self.output.as_deref() {
+            None => Ok(Box::new(std::io::stdout())),
+            Some(path) => Ok(Box::new(create(path)?)),
+        }
+    }
+
+    /// Create the output of partial results, when interrupted: the given path, or else the output
+    /// path with a `.partial` suffix, or else stdout (along with a description of it)
+    pub fn create_partial_output(&self) -> Result<(Box<dyn Write>, String), CliError> {
+        let path = match (&self.on_interrupt_output, &self.output) {
+            (Some(path), _) => path.clone(),
+            (None, Some(output)) => format!("{}.partial", output),
+            (None, None) => return Ok((Box::new(std::io::stdout()), "stdout".to_owned())),
+        };
+        Ok((Box::new(create(&path)?), format!("`{}`", path)))
+    }
+
+    /// Start serving metrics in the background, if asked for
+    pub fn serve_metrics(&self) -> Result<Option<Arc<Metrics>>, CliError> {
+        let Some(addr) = self.metrics else {
+            return Ok(None);
+        };
+        let metrics = Arc::new(Metrics::default());
+        match metrics::serve(addr, metrics.clone()) {
+            Ok(_) => Ok(Some(metrics)),
+            Err(e) => Err(CliError::Open(addr.to_string(), e)),
+        }
+    }
+
+    /// Create the file of the open disputes report, if asked for
+    pub fn create_open_disputes(&self) -> Result<Option<std::fs::File>, CliError> {
+        self.open_disputes.as_deref().map(create).transpose()
+    }
+
+    /// Create the CSV file of the stats of every client, if asked for
+    pub fn create_stats(&self) -> Result<Option<std::fs::File>, CliError> {
+        self.stats.as_deref().map(create).transpose()
+    }
+
+    /// Create the CSV file of the summary of every account, if asked for
+    pub fn create_summary(&self) -> Result<Option<std::fs::File>, CliError> {
+        self.summary.as_deref().map(create).transpose()
+    }
+
+    /// Create the CSV file of the trace of disputed transactions, if asked for
+    pub fn create_dispute_trace(&self) -> Result<Option<std::fs::File>, CliError> {
+        self.dispute_trace.as_deref().map(create).transpose()
+    }
+
+    /// Create the CSV file of the transaction history, if asked for
+    pub fn create_dump_history(&self) -> Result<Option<std::fs::File>, CliError> {
+        self.dump_history.as_deref().map(create).transpose()
+    }
+
+    /// Create the file of the stats of the run, if asked for
+    pub fn create_stats_json(&self) -> Result<Option<std::fs::File>, CliError> {
+        self.stats_json.as_deref().map(create).transpose()
+    }
+
+    /// Create the sink of the events of transactions applied, if asked for
+    pub fn create_events(&self) -> Result<Option<EventSink>, CliError> {
+        Ok(match self.emit_events.as_deref() {
+            None => None,
+            Some("-") => Some(EventSink::new(std::io::stdout())),
+            Some(path) => Some(EventSink::new(create(path)?)),
+        })
+    }
+
+    /// Create the sink of the rows skipped, if asked for
+    pub fn create_rejected(&self) -> Result<Option<ErrorSink>, CliError> {
+        let file = self.rejected.as_deref().map(create).transpose()?;
+        Ok(file.map(ErrorSink::new))
+    }
+}
+
+fn create(path: &str) -> Result<std::fs::File, CliError> {
+    std::fs::File::create(path).map_err(|e| CliError::Create(path.to_owned(), e))
+}
+
+pub fn help() -> &'static str {
+    HELP
+}
+
+pub fn version() -> String {
+    format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+/// Parse the command line arguments (without the program name)
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Action, CliError> {
+    let mut args = args.into_iter().collect::<Vec<_>>();
+    // The only subcommand, which takes the same flags (e.g. `./validate` is a file named so)
+    let validate = args.first().is_some_and(|arg| arg == "validate");
+    if validate {
+        args.remove(0);
+    }
+    // Flags of the configuration file come first, for the command line to override them
+    let config = match config_path(&args) {
+        Some(path) => load_config(&path)?.flags,
+        None => Vec::new(),
+    };
+    let mut from_config = config.len();
+    let mut args = config.into_iter().chain(args);
+    let mut print_config = false;
+    let mut input = None;
+    let mut output = None;
+    let mut on_interrupt_output = None;
+    let mut open_disputes = None;
+    let mut stats = None;
+    let mut summary = None;
+    let mut dispute_trace = None;
+    let mut dump_history = None;
+    let mut stats_json = None;
+    let mut emit_events = None;
+    let mut rejected = None;
+    let mut http_timeout = None;
+    let mut listen = None;
+    let mut drain_timeout = DRAIN_TIMEOUT;
+    let mut follow = false;
+    let mut two_pass = false;
+    let mut pipeline = false;
+    let mut pipeline_capacity = 1024;
+    let mut metrics = None;
+    let mut audit_negatives = false;
+    let mut dry_run = false;
+    let mut lenient_amounts = false;
+    let mut currency_symbol = None;
+    let mut thousands_separator = None;
+    let mut quiet = false;
+    let mut options = Options::default();
+    let mut seen = Vec::new();
+    let mut config_seen = Vec::new();
+    while let Some(arg) = args.next() {
+        // Every flag of the configuration file is a single `--flag=value` argument
+        let seen = match from_config.checked_sub(1) {
+            Some(left) => {
+                from_config = left;
+                &mut config_seen
+            }
+            None => &mut seen,
+        };
+        // Both `--flag value` and `--flag=value` are accepted
+        let (flag, mut value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_owned(), Some(value.to_owned()))
+            }
+            _ => (arg, None),
+        };
+        let mut value = |name: &str| {
+            value
+                .take()
+                .or_else(|| args.next())
+                .ok_or_else(|| CliError::Usage(format!("missing value for {}", name)))
+        };
+        let name = match flag.as_str() {
+            "-h" | "--help" => return Ok(Action::Help),
+            "-V" | "--version" => return Ok(Action::Version),
+            "--config" => {
+                // Already read, see `config_path`
+                value("--config")?;
+                "--config"
+            }
+            "--print-config" => {
+                print_config = true;
+                "--print-config"
+            }
+            "-o" | "--output" => {
+                output = Some(value("--output")?);
+                "--output"
+            }
+            "--input-format" => {
+                options.input_format = match value("--input-format")?.as_str() {
+                    "csv" => InputFormat::Csv,
+                    "ndjson" => InputFormat::Ndjson,
+                    other => return Err(invalid_policy("--input-format", other, "csv", "ndjson")),
+                };
+                "--input-format"
+            }
+            "--format" => {
+                options.output_format = match value("--format")?.as_str() {
+                    "csv" => OutputFormat::Csv,
+                    "json" => OutputFormat::Json,
+                    other => return Err(invalid_policy("--format", other, "csv", "json")),
+                };
+                "--format"
+            }
+            "--on-interrupt-output" => {
+                on_interrupt_output = Some(value("--on-interrupt-output")?);
+                "--on-interrupt-output"
+            }
+            "--open-disputes" => {
+                open_disputes = Some(value("--open-disputes")?);
+                "--open-disputes"
+            }
+            "--stats" => {
+                stats = Some(value("--stats")?);
+                "--stats"
+            }
+            "--summary" => {
+                summary = Some(value("--summary")?);
+                "--summary"
+            }
+            "--dispute-trace" => {
+                dispute_trace = Some(value("--dispute-trace")?);
+                "--dispute-trace"
+            }
+            "--dump-history" => {
+                dump_history = Some(value("--dump-history")?);
+                "--dump-history"
+            }
+            "--stats-json" => {
+                stats_json = Some(value("--stats-json")?);
+                "--stats-json"
+            }
+            "--emit-events" => {
+                emit_events = Some(value("--emit-events")?);
+                "--emit-events"
+            }
+            "--rejected" => {
+                rejected = Some(value("--rejected")?);
+                "--rejected"
+            }
+            "--delimiter" => {
+                options.delimiter = parse_delimiter("--delimiter", &value("--delimiter")?)?;
+                "--delimiter"
+            }
+            "--no-header" => {
+                options.has_header = false;
+                "--no-header"
+            }
+            "--output-delimiter" => {
+                let delimiter = value("--output-delimiter")?;
+                options.output_delimiter = parse_delimiter("--output-delimiter", &delimiter)?;
+                "--output-delimiter"
+            }
+            "--http-timeout" => {
+                let timeout = value("--http-timeout")?;
+                match timeout.parse() {
+                    Ok(secs) if secs > 0.0 => http_timeout = Some(Duration::from_secs_f64(secs)),
+                    _ => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --http-timeout `{}` (expected a positive number of seconds)",
+                            timeout
+                        )))
+                    }
+                }
+                "--http-timeout"
+            }
+            "--follow" => {
+                follow = true;
+                "--follow"
+            }
+            "--two-pass" => {
+                two_pass = true;
+                "--two-pass"
+            }
+            "--pipeline" => {
+                pipeline = true;
+                "--pipeline"
+            }
+            "--pipeline-capacity" => {
+                let capacity = value("--pipeline-capacity")?;
+                match capacity.parse() {
+                    Ok(capacity) if capacity > 0 => pipeline_capacity = capacity,
+                    _ => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --pipeline-capacity `{}` (expected a positive integer)",
+                            capacity
+                        )))
+                    }
+                }
+                "--pipeline-capacity"
+            }
+            "--clients" => {
+                let clients = value("--clients")?;
+                let clients = clients
+                    .split(',')
+                    .map(|client| {
+                        client.trim().parse().map_err(|_| {
+                            CliError::Usage(format!(
+                                "invalid --clients `{}` (expected client IDs, between 0 and {})",
+                                client,
+                                u16::MAX
+                            ))
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
+                options.clients = Some(clients);
+                "--clients"
+            }
+            "--disputable" => {
+                let kinds = value("--disputable")?;
+                options.disputable = kinds
+                    .split(',')
+                    .map(|kind| match kind.trim() {
+                        "deposit" => Ok(Tx::Deposit),
+                        "withdrawal" => Ok(Tx::Withdrawal),
+                        other => Err(CliError::Usage(format!(
+                            "invalid --disputable `{}` (expected `deposit` and/or `withdrawal`)",
+                            other
+                        ))),
+                    })
+                    .collect::<Result<_, _>>()?;
+                "--disputable"
+            }
+            "--withdrawal-dispute-policy" => {
+                let policy = value("--withdrawal-dispute-policy")?;
+                options.withdrawal_dispute_policy = match policy.as_str() {
+                    "hold" => WithdrawalDisputePolicy::Hold,
+                    "reject" => WithdrawalDisputePolicy::Reject,
+                    "credit-held" => WithdrawalDisputePolicy::CreditHeld,
+                    "reverse" => WithdrawalDisputePolicy::Reverse,
+                    other => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --withdrawal-dispute-policy `{}` (expected `hold`, `reject`, `credit-held` or `reverse`)",
+                            other
+                        )))
+                    }
+                };
+                "--withdrawal-dispute-policy"
+            }
+            "--strict" => {
+                options.strict = true;
+                "--strict"
+            }
+            "--on-business-error" => {
+                options.strict = match value("--on-business-error")?.as_str() {
+                    "skip" => false,
+                    "error" => true,
+                    other => {
+                        return Err(invalid_policy(
+                            "--on-business-error",
+                            other,
+                            "skip",
+                            "error",
+                        ))
+                    }
+                };
+                "--on-business-error"
+            }
+            "--partial-failure" => {
+                options.skip_parse_errors = true;
+                "--partial-failure"
+            }
+            "--on-parse-error" => {
+                options.skip_parse_errors = match value("--on-parse-error")?.as_str() {
+                    "abort" => false,
+                    "skip" => true,
+                    other => {
+                        return Err(invalid_policy("--on-parse-error", other, "abort", "skip"))
+                    }
+                };
+                "--on-parse-error"
+            }
+            "--listen" => {
+                let addr = value("--listen")?;
+                match addr.parse() {
+                    Ok(addr) => listen = Some(addr),
+                    Err(_) => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --listen `{}` (expected an address like 127.0.0.1:7878)",
+                            addr
+                        )))
+                    }
+                }
+                "--listen"
+            }
+            "--drain-timeout" => {
+                let timeout = value("--drain-timeout")?;
+                match timeout.parse() {
+                    Ok(secs) if secs >= 0.0 => drain_timeout = Duration::from_secs_f64(secs),
+                    _ => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --drain-timeout `{}` (expected a number of seconds)",
+                            timeout
+                        )))
+                    }
+                }
+                "--drain-timeout"
+            }
+            "--metrics" => {
+                let addr = value("--metrics")?;
+                match addr.parse() {
+                    Ok(addr) => metrics = Some(addr),
+                    Err(_) => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --metrics `{}` (expected an address like 127.0.0.1:9898)",
+                            addr
+                        )))
+                    }
+                }
+                "--metrics"
+            }
+            "--json-errors" => {
+                options.json_errors = Some(ErrorSink::new(std::io::stderr()));
+                "--json-errors"
+            }
+            "--check-ordering" => {
+                options.check_ordering = true;
+                "--check-ordering"
+            }
+            "--check-order" => {
+                options.check_order = match value("--check-order")?.as_str() {
+                    "off" => OrderCheck::Off,
+                    "warn" => OrderCheck::Warn,
+                    "error" => OrderCheck::Error,
+                    other => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --check-order `{}` (expected `off`, `warn` or `error`)",
+                            other
+                        )))
+                    }
+                };
+                "--check-order"
+            }
+            "--dedupe-tx" => {
+                options.dedupe_tx = true;
+                "--dedupe-tx"
+            }
+            "--idempotent" => {
+                options.idempotent = true;
+                "--idempotent"
+            }
+            "--auto-lock-after-chargebacks" => {
+                let max = value("--auto-lock-after-chargebacks")?;
+                match max.parse() {
+                    Ok(max) if max > 0 => options.auto_lock_after_chargebacks = Some(max),
+                    _ => {
+                        return Err(CliError::Usage(format!(
+                        "invalid --auto-lock-after-chargebacks `{}` (expected a positive integer)",
+                        max
+                    )))
+                    }
+                }
+                "--auto-lock-after-chargebacks"
+            }
+            "--auto-lock-chargeback-amount" => {
+                let max = value("--auto-lock-chargeback-amount")?;
+                match max.parse::<f64>() {
+                    Ok(max) if max.is_finite() && max >= 0.0 => {
+                        options.auto_lock_chargeback_amount = Some(Amount(max))
+                    }
+                    _ => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --auto-lock-chargeback-amount `{}` (expected a non-negative amount)",
+                            max
+                        )))
+                    }
+                }
+                "--auto-lock-chargeback-amount"
+            }
+            // Also named to pair with `--max-clients`
+            "--max-history" | "--max-tx-history" => {
+                let max = value("--max-history")?;
+                match max.parse() {
+                    Ok(max) if max > 0 => options.max_history = Some(max),
+                    _ => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --max-history `{}` (expected a positive integer)",
+                            max
+                        )))
+                    }
+                }
+                "--max-history"
+            }
+            "--dispute-expiry-after" => {
+                let expiry = value("--dispute-expiry-after")?;
+                match expiry.parse() {
+                    Ok(expiry) if expiry > 0 => options.dispute_expiry_after = Some(expiry),
+                    _ => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --dispute-expiry-after `{}` (expected a positive integer)",
+                            expiry
+                        )))
+                    }
+                }
+                "--dispute-expiry-after"
+            }
+            "--max-clients" => {
+                let max = value("--max-clients")?;
+                match max.parse() {
+                    Ok(max) if max > 0 => options.max_clients = Some(max),
+                    _ => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --max-clients `{}` (expected a positive integer)",
+                            max
+                        )))
+                    }
+                }
+                "--max-clients"
+            }
+            "--max-rows" => {
+                let max = value("--max-rows")?;
+                match max.parse() {
+                    Ok(max) if max > 0 => options.max_rows = Some(max),
+                    _ => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --max-rows `{}` (expected a positive integer)",
+                            max
+                        )))
+                    }
+                }
+                "--max-rows"
+            }
+            "--expect-clients" => {
+                let expected = value("--expect-clients")?;
+                match expected.parse() {
+                    Ok(expected) => options.expect_clients = Some(expected),
+                    Err(_) => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --expect-clients `{}` (expected an integer)",
+                            expected
+                        )))
+                    }
+                }
+                "--expect-clients"
+            }
+            "--expect-transactions" => {
+                let expected = value("--expect-transactions")?;
+                match expected.parse() {
+                    Ok(expected) => options.expect_transactions = Some(expected),
+                    Err(_) => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --expect-transactions `{}` (expected an integer)",
+                            expected
+                        )))
+                    }
+                }
+                "--expect-transactions"
+            }
+            "--include-empty-accounts" => {
+                options.include_empty_accounts = match value("--include-empty-accounts")?.as_str() {
+                    "true" => true,
+                    "false" => false,
+                    other => {
+                        return Err(invalid_policy(
+                            "--include-empty-accounts",
+                            other,
+                            "true",
+                            "false",
+                        ))
+                    }
+                };
+                "--include-empty-accounts"
+            }
+            "--history-overflow" => {
+                options.history_overflow = match value("--history-overflow")?.as_str() {
+                    "error" => HistoryOverflow::Error,
+                    "evict-oldest" => HistoryOverflow::EvictOldest,
+                    "spill-disk" => HistoryOverflow::SpillDisk,
+                    other => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --history-overflow `{}` (expected `error`, `evict-oldest` or `spill-disk`)",
+                            other
+                        )))
+                    }
+                };
+                "--history-overflow"
+            }
+            "--sorted" => {
+                options.sorted = true;
+                "--sorted"
+            }
+            "--input-precision" => {
+                let precision = value("--input-precision")?;
+                options.input_precision = parse_precision("--input-precision", &precision)?;
+                "--input-precision"
+            }
+            "--precision" => {
+                let precision = value("--precision")?;
+                options.output_precision = Some(parse_precision("--precision", &precision)?);
+                "--precision"
+            }
+            "--amount-format" => {
+                options.amount_format = match value("--amount-format")?.as_str() {
+                    "fixed4" => AmountFormat::Fixed4,
+                    "trim" => AmountFormat::Trim,
+                    other => {
+                        return Err(invalid_policy("--amount-format", other, "fixed4", "trim"))
+                    }
+                };
+                "--amount-format"
+            }
+            "--validate-amounts" => {
+                options.validate_amounts = match value("--validate-amounts")?.as_str() {
+                    "lenient" => AmountValidation::Lenient,
+                    "strict" => AmountValidation::Strict,
+                    other => {
+                        return Err(invalid_policy(
+                            "--validate-amounts",
+                            other,
+                            "lenient",
+                            "strict",
+                        ))
+                    }
+                };
+                "--validate-amounts"
+            }
+            "--lenient-amounts" => {
+                lenient_amounts = true;
+                "--lenient-amounts"
+            }
+            "--warn-unknown-columns" => {
+                options.warn_unknown_columns = true;
+                "--warn-unknown-columns"
+            }
+            "--currency-symbol" => {
+                currency_symbol = Some(value("--currency-symbol")?);
+                "--currency-symbol"
+            }
+            "--thousands-separator" => {
+                let separator = value("--thousands-separator")?;
+                let mut chars = separator.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if !c.is_ascii_digit() && c != '-' => {
+                        thousands_separator = Some(c)
+                    }
+                    _ => {
+                        return Err(CliError::Usage(format!(
+                            "invalid --thousands-separator `{}` (expected a character other than a digit or `-`)",
+                            separator
+                        )))
+                    }
+                }
+                "--thousands-separator"
+            }
+            "--multi-currency" => {
+                options.multi_currency = true;
+                "--multi-currency"
+            }
+            "--progress" => {
+                options.progress = true;
+                "--progress"
+            }
+            "--audit-negatives" => {
+                audit_negatives = true;
+                "--audit-negatives"
+            }
+            "--dry-run" => {
+                dry_run = true;
+                "--dry-run"
+            }
+            "-q" | "--quiet" => {
+                quiet = true;
+                "--quiet"
+            }
+            "-" => {
+                input = set_input(input, flag)?;
+                continue;
+            }
+            unknown if unknown.starts_with('-') => {
+                return Err(CliError::Usage(format!("unknown flag `{}`", unknown)))
+            }
+            _ => {
+                input = set_input(input, flag)?;
+                continue;
+            }
+        };
+        if seen.contains(&name) {
+            return Err(CliError::Usage(format!("{} given more than once", name)));
+        }
+        seen.push(name);
+    }
+    // A flag of the command line overrides a conflicting one of the configuration file
+    for seen in [&seen, &config_seen] {
+        for (a, b) in CONFLICTS {
+            if seen.contains(a) && seen.contains(b) {
+                return Err(CliError::Usage(format!("{} can't be used with {}", a, b)));
+            }
+        }
+    }
+    seen.extend(config_seen);
+    // Holding and rejecting are as good as the default when withdrawals aren't disputable
+    if matches!(
+        options.withdrawal_dispute_policy,
+        WithdrawalDisputePolicy::CreditHeld | WithdrawalDisputePolicy::Reverse
+    ) && !options.disputable.contains(&Tx::Withdrawal)
+    {
+        return Err(CliError::Usage(
+            "--withdrawal-dispute-policy credit-held or reverse needs --disputable withdrawal"
+                .to_owned(),
+        ));
+    }
+    if seen.contains(&"--history-overflow") && options.max_history.is_none() {
+        return Err(CliError::Usage(
+            "--history-overflow needs --max-history".to_owned(),
+        ));
+    }
+    if seen.contains(&"--pipeline-capacity") && !pipeline {
+        return Err(CliError::Usage(
+            "--pipeline-capacity needs --pipeline".to_owned(),
+        ));
+    }
+    if seen.contains(&"--output-delimiter") && options.output_format != OutputFormat::Csv {
+        return Err(CliError::Usage(
+            "--output-delimiter needs --format csv".to_owned(),
+        ));
+    }
+    // Events and accounts mustn't interleave
+    if emit_events.as_deref() == Some("-") && output.is_none() {
+        return Err(CliError::Usage("--emit-events - needs --output".to_owned()));
+    }
+    if seen.contains(&"--drain-timeout") && listen.is_none() {
+        return Err(CliError::Usage("--drain-timeout needs --listen".to_owned()));
+    }
+    if listen.is_some() && input.is_some() {
+        return Err(CliError::Usage(
+            "--listen can't be used with an input".to_owned(),
+        ));
+    }
+    // Splitting fields, reading the input twice or on another thread, and receiving connections
+    // are up to the CSV reader
+    for flag in ["--delimiter", "--two-pass", "--pipeline", "--listen"] {
+        if seen.contains(&flag) && options.input_format != InputFormat::Csv {
+            return Err(CliError::Usage(format!(
+                "{} needs --input-format csv",
+                flag
+            )));
+        }
+    }
+    for flag in ["--currency-symbol", "--thousands-separator"] {
+        if seen.contains(&flag) && !lenient_amounts {
+            return Err(CliError::Usage(format!("{} needs --lenient-amounts", flag)));
+        }
+    }
+    // Amounts formatted for humans are anything but canonical
+    if lenient_amounts && options.validate_amounts == AmountValidation::Strict {
+        return Err(CliError::Usage(
+            "--lenient-amounts can't be used with --validate-amounts strict".to_owned(),
+        ));
+    }
+    if lenient_amounts {
+        let default = LenientAmounts::default();
+        options.lenient_amounts = Some(LenientAmounts {
+            symbol: currency_symbol.unwrap_or(default.symbol),
+            thousands_separator: thousands_separator.unwrap_or(default.thousands_separator),
+        });
+    }
+    if follow && matches!(input.as_deref(), None | Some("-")) {
+        return Err(CliError::Usage("--follow needs an input file".to_owned()));
+    }
+    let args = Box::new(Args {
+        input,
+        output,
+        on_interrupt_output,
+        open_disputes,
+        stats,
+        summary,
+        dispute_trace,
+        dump_history,
+        stats_json,
+        emit_events,
+        rejected,
+        http_timeout,
+        follow,
+        two_pass,
+        pipeline: pipeline.then_some(pipeline_capacity),
+        listen,
+        drain_timeout,
+        metrics,
+        audit_negatives,
+        dry_run,
+        quiet,
+        options,
+    });
+    Ok(match (print_config, validate) {
+        (true, _) => Action::PrintConfig(args),
+        (false, true) => Action::Validate(args),
+        (false, false) => Action::Run(args),
+    })
+}
+
+/// The configuration file, read ahead of the flags it's overridden by
+fn config_path(args: &[String]) -> Option<String> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().cloned();
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_owned());
+        }
+    }
+    None
+}
+
+fn load_config(path: &str) -> Result<Config, CliError> {
+    let toml = std::fs::read_to_string(path).map_err(|e| CliError::Open(path.to_owned(), e))?;
+    Config::parse(&toml).map_err(|e| CliError::Usage(format!("invalid config `{}`: {}", path, e)))
+}
+
+/// There is a single positional argument, the input file
+fn set_input(input: Option<String>, arg: String) -> Result<Option<String>, CliError> {
+    match input {
+        None => Ok(Some(arg)),
+        Some(_) => Err(CliError::Usage(format!("unexpected argument `{}`", arg))),
+    }
+}
+
+/// Error policies are one of two values
+fn invalid_policy(flag: &str, value: &str, a: &str, b: &str) -> CliError {
+    CliError::Usage(format!(
+        "invalid {} `{}` (expected `{}` or `{}`)",
+        flag, value, a, b
+    ))
+}
+
+/// Beyond 8 places past the decimal, rounding gets meaningless with `f64` amounts
+fn parse_precision(flag: &str, value: &str) -> Result<u8, CliError> {
+    match value.parse() {
+        Ok(places @ 0..=8) => Ok(places),
+        _ => Err(CliError::Usage(format!(
+            "invalid {} `{}` (expected an integer between 0 and 8)",
+            flag, value
+        ))),
+    }
+}
+
+/// Only accept the usual CSV field separators, `\t` standing for a tab
+fn parse_delimiter(flag: &str, value: &str) -> Result<u8, CliError> {
+    match value {
+        "," => Ok(b','),
+        ";" => Ok(b';'),
+        "|" => Ok(b'|'),
+        "\\t" | "\t" => Ok(b'\t'),
+        other => Err(CliError::Usage(format!(
+            "invalid {} `{}` (expected `,`, `;`, `|` or `\\t`)",
+            flag, other
+        ))),
+    }
+}
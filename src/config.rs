@@ -0,0 +1,342 @@
+//! # Configuration file
+//!
+//! Scheduled jobs keep their policies in a file given with `--config engine.toml`, rather than
+//! repeating them as flags on every invocation. Every key is the name of a flag (with `_` rather
+//! than `-`) and takes the same values, e.g.:
+//!
+//! ```toml
+//! on_business_error = "error"
+//! disputable = ["deposit", "withdrawal"]
+//! auto_lock_after_chargebacks = 3
+//! sorted = true
+//! ```
+//!
+//! Flags of the command line override the file, which overrides defaults, and `--print-config`
+//! prints the resulting configuration in the same format. An unknown key is an error, so that a
+//! typo doesn't silently leave a policy to its default. Neither `toml` nor a serde format for it
+//! being at hand, the flat subset of TOML this takes is parsed by hand: a `key = value` per line,
+//! values being strings, numbers, booleans or arrays of those, and `#` starting a comment.
+
+use crate::cli::Args;
+use rust_coding_test::store::HistoryOverflow;
+use rust_coding_test::timestamp::OrderCheck;
+use rust_coding_test::{
+    AmountFormat, AmountValidation, InputFormat, OutputFormat, WithdrawalDisputePolicy,
+};
+
+/// Keys of the flags taking a value
+const VALUE_KEYS: &[&str] = &[
+    "delimiter",
+    "output_delimiter",
+    "input_format",
+    "format",
+    "on_business_error",
+    "on_parse_error",
+    "disputable",
+    "withdrawal_dispute_policy",
+    "clients",
+    "check_order",
+    "auto_lock_after_chargebacks",
+    "auto_lock_chargeback_amount",
+    "dispute_expiry_after",
+    "max_history",
+    "max_clients",
+    "max_rows",
+    "expect_clients",
+    "expect_transactions",
+    "history_overflow",
+    "include_empty_accounts",
+    "input_precision",
+    "precision",
+    "amount_format",
+    "currency_symbol",
+    "thousands_separator",
+    "validate_amounts",
+];
+
+/// Keys of the flags without a value, given with `true`
+const SWITCH_KEYS: &[&str] = &[
+    "check_ordering",
+    "dedupe_tx",
+    "idempotent",
+    "sorted",
+    "multi_currency",
+    "lenient_amounts",
+    "warn_unknown_columns",
+    "json_errors",
+    "no_header",
+];
+
+/// A configuration file, as the command line flags it stands for
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    /// Flags in the `--flag=value` form, so that each one is a single argument
+    pub flags: Vec<String>,
+}
+
+/// A value of the file: strings and numbers are kept as written, to be validated as flag values
+enum Value {
+    Bool(bool),
+    Scalar(String),
+    Array(Vec<String>),
+}
+
+impl Config {
+    pub fn parse(toml: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut keys = Vec::new();
+        for (i, line) in toml.lines().enumerate() {
+            let at_line = |message: String| format!("line {}: {}", i + 1, message);
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(at_line(format!("expected `key = value`, got `{}`", line)));
+            };
+            let key = key.trim();
+            if keys.contains(&key) {
+                return Err(at_line(format!("`{}` given more than once", key)));
+            }
+            keys.push(key);
+            let flag = format!("--{}", key.replace('_', "-"));
+            match (parse_value(value.trim()).map_err(at_line)?, key) {
+                (Value::Bool(true), key) if SWITCH_KEYS.contains(&key) => config.flags.push(flag),
+                (Value::Bool(false), key) if SWITCH_KEYS.contains(&key) => {}
+                (_, key) if SWITCH_KEYS.contains(&key) => {
+                    return Err(at_line(format!("`{}` expects `true` or `false`", key)))
+                }
+                (value, key) if VALUE_KEYS.contains(&key) => {
+                    let value = match value {
+                        Value::Bool(value) => value.to_string(),
+                        Value::Scalar(value) => value,
+                        Value::Array(values) => values.join(","),
+                    };
+                    config.flags.push(format!("{}={}", flag, value));
+                }
+                (_, key) => return Err(at_line(format!("unknown key `{}`", key))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// The line up to a `#` that isn't part of a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(value: &str) -> Result<Value, String> {
+    match value {
+        "true" => Ok(Value::Bool(true)),
+        "false" => Ok(Value::Bool(false)),
+        _ if value.starts_with('[') => {
+            let Some(items) = value[1..].strip_suffix(']') else {
+                return Err(format!("unterminated array `{}`", value));
+            };
+            let items = items.trim().trim_end_matches(',');
+            if items.trim().is_empty() {
+                return Ok(Value::Array(Vec::new()));
+            }
+            items
+                .split(',')
+                .map(|item| scalar(item.trim()))
+                .collect::<Result<_, _>>()
+                .map(Value::Array)
+        }
+        _ => scalar(value).map(Value::Scalar),
+    }
+}
+
+/// A string (unquoted and unescaped) or a number
+fn scalar(value: &str) -> Result<String, String> {
+    if let Some(quoted) = value.strip_prefix('"') {
+        let Some(quoted) = quoted.strip_suffix('"').filter(|_| value.len() > 1) else {
+            return Err(format!("unterminated string `{}`", value));
+        };
+        let mut string = String::with_capacity(quoted.len());
+        let mut chars = quoted.chars();
+        while let Some(c) = chars.next() {
+            string.push(match (c, c == '\\') {
+                (_, false) => c,
+                (_, true) => match chars.next() {
+                    Some('t') => '\t',
+                    Some('n') => '\n',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    _ => return Err(format!("unsupported escape in `{}`", value)),
+                },
+            });
+        }
+        return Ok(string);
+    }
+    match value.replace('_', "").parse::<f64>() {
+        Ok(_) => Ok(value.replace('_', "")),
+        Err(_) => Err(format!("`{}` is neither a string nor a number", value)),
+    }
+}
+
+/// The configuration of a run, in the format of the file, every key being listed (the ones
+/// without a value as comments)
+pub fn effective(args: &Args) -> String {
+    let options = &args.options;
+    let string = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('\t', "\\t"));
+    let delimiter = |delimiter: u8| string(&char::from(delimiter).to_string());
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    let mut clients = options.clients.iter().flatten().collect::<Vec<_>>();
+    clients.sort();
+    let values = [
+        delimiter(options.delimiter),
+        delimiter(options.output_delimiter),
+        string(match options.input_format {
+            InputFormat::Csv => "csv",
+            InputFormat::Ndjson => "ndjson",
+        }),
+        string(match options.output_format {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Json => "json",
+        }),
+        string(if options.strict { "error" } else { "skip" }),
+        string(if options.skip_parse_errors {
+            "skip"
+        } else {
+            "abort"
+        }),
+        format!(
+            "[{}]",
+            options
+                .disputable
+                .iter()
+                .map(|kind| string(&kind.to_string()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        string(match options.withdrawal_dispute_policy {
+            WithdrawalDisputePolicy::Hold => "hold",
+            WithdrawalDisputePolicy::Reject => "reject",
+            WithdrawalDisputePolicy::CreditHeld => "credit-held",
+            WithdrawalDisputePolicy::Reverse => "reverse",
+        }),
+        optional(options.clients.as_ref().map(|_| {
+            let clients = clients.iter().map(|client| client.to_string());
+            format!("[{}]", clients.collect::<Vec<_>>().join(", "))
+        })),
+        string(match options.check_order {
+            OrderCheck::Off => "off",
+            OrderCheck::Warn => "warn",
+            OrderCheck::Error => "error",
+        }),
+        optional(options.auto_lock_after_chargebacks.map(|n| n.to_string())),
+        optional(options.auto_lock_chargeback_amount.map(|x| x.to_string())),
+        optional(options.dispute_expiry_after.map(|n| n.to_string())),
+        optional(options.max_history.map(|n| n.to_string())),
+        optional(options.max_clients.map(|n| n.to_string())),
+        optional(options.max_rows.map(|n| n.to_string())),
+        optional(options.expect_clients.map(|n| n.to_string())),
+        optional(options.expect_transactions.map(|n| n.to_string())),
+        string(match options.history_overflow {
+            HistoryOverflow::Error => "error",
+            HistoryOverflow::EvictOldest => "evict-oldest",
+            HistoryOverflow::SpillDisk => "spill-disk",
+        }),
+        options.include_empty_accounts.to_string(),
+        options.input_precision.to_string(),
+        optional(options.output_precision.map(|n| n.to_string())),
+        string(match options.amount_format {
+            AmountFormat::Fixed4 => "fixed4",
+            AmountFormat::Trim => "trim",
+        }),
+        optional(options.lenient_amounts.as_ref().map(|l| string(&l.symbol))),
+        optional(
+            options
+                .lenient_amounts
+                .as_ref()
+                .map(|l| string(&l.thousands_separator.to_string())),
+        ),
+        string(match options.validate_amounts {
+            AmountValidation::Lenient => "lenient",
+            AmountValidation::Strict => "strict",
+        }),
+    ];
+    let switches = [
+        options.check_ordering,
+        options.dedupe_tx,
+        options.idempotent,
+        options.sorted,
+        options.multi_currency,
+        options.lenient_amounts.is_some(),
+        options.warn_unknown_columns,
+        options.json_errors.is_some(),
+        !options.has_header,
+    ];
+    let mut toml = String::new();
+    for (key, value) in VALUE_KEYS.iter().zip(values) {
+        match value.as_str() {
+            "" => toml += &format!("# {} is unset\n", key),
+            value => toml += &format!("{} = {}\n", key, value),
+        }
+    }
+    for (key, value) in SWITCH_KEYS.iter().zip(switches) {
+        toml += &format!("{} = {}\n", key, value);
+    }
+    toml
+}
+
+#[test]
+fn parse() {
+    let config = Config::parse(
+        r#"
+# Policies of the nightly job
+on_business_error = "error" # rather than skipping
+disputable = ["deposit", "withdrawal",]
+max_history = 1_000_000
+delimiter = "\t"
+sorted = true
+multi_currency = false
+"#,
+    );
+    let flags = [
+        "--on-business-error=error",
+        "--disputable=deposit,withdrawal",
+        "--max-history=1000000",
+        "--delimiter=\t",
+        "--sorted",
+    ];
+    assert_eq!(config.unwrap().flags, flags);
+    let error = |toml| Config::parse(toml).unwrap_err();
+    assert_eq!(
+        error("sorted = true\nsroted = true"),
+        "line 2: unknown key `sroted`"
+    );
+    assert_eq!(
+        error("sorted = 1"),
+        "line 1: `sorted` expects `true` or `false`"
+    );
+    assert_eq!(
+        error("precision = 4\nprecision = 6"),
+        "line 2: `precision` given more than once"
+    );
+    assert_eq!(
+        error("format = json"),
+        "line 1: `json` is neither a string nor a number"
+    );
+    assert_eq!(
+        error("format = \"json"),
+        "line 1: unterminated string `\"json`"
+    );
+    assert_eq!(
+        error("[engine]"),
+        "line 1: expected `key = value`, got `[engine]`"
+    );
+}
@@ -0,0 +1,427 @@
+//! # Errors
+//!
+//! Every way processing can fail, typed so that a caller (e.g. a script driving the binary, or a
+//! service embedding the library) can tell an unreadable input from a malformed row or from a
+//! business rule violated in strict mode.
+
+use crate::store::HistoryError;
+use crate::timestamp::Timestamp;
+use crate::{json, Amount, ClientID, Tx, TxID};
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+/// Reasons why a transaction can't be applied to an account. By default such a transaction is
+/// silently ignored, as an error on our partner's side, but it's a hard error in strict mode
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    /// No transaction is allowed once an account is locked by a chargeback
+    AccountLocked(ClientID),
+    /// A withdrawal of more than the available funds
+    InsufficientFunds {
+        client: ClientID,
+        requested: Amount,
+        available: Amount,
+    },
+    /// A client ID that is a number, but too big to be a `ClientID`
+    ClientOutOfRange(String),
+    /// The text of an amount that isn't of the canonical form, see `Options::validate_amounts`,
+    /// or of an infinite or NaN amount, which is never applied
+    BadAmount(String),
+    /// A deposit or a withdrawal that doesn't specify an amount
+    MissingAmount(TxID),
+    /// A dispute, resolve or chargeback that specifies an amount, whereas it refers to the amount
+    /// of the disputed transaction
+    UnexpectedAmount(TxID, Amount),
+    /// A deposit or a withdrawal reusing the ID of a transaction already in history
+    DuplicateTx(TxID),
+    /// A dispute, resolve or chargeback referencing a transaction not found in the history of that
+    /// client (a client can't dispute someone else's transaction)
+    UnknownTx(TxID),
+    /// A dispute of a transaction already under dispute
+    AlreadyDisputed(TxID),
+    /// A resolve or chargeback referencing a transaction that isn't under dispute
+    NotDisputed(TxID),
+    /// Transactions of that kind can't be disputed, see `Options::disputable`
+    NotDisputable(TxID, Tx),
+    /// A dispute, resolve or chargeback referencing a transaction already charged back, which is
+    /// final
+    ChargedBack(TxID),
+    /// No transaction is allowed once an account is locked for good by too many chargebacks, see
+    /// `Options::auto_lock_after_chargebacks`
+    AutoLocked(ClientID),
+    /// A deposit or a withdrawal of a new client, whereas `Options::max_clients` accounts are
+    /// already open
+    TooManyClients {
+        client: ClientID,
+        max_clients: usize,
+    },
+    /// A transaction that would take the available or held funds of an account below zero (or
+    /// further below), e.g. a deposit of a negative amount: only a dispute may take available
+    /// funds below zero, see `Ledger::min_available_seen`
+    NegativeBalance {
+        client: ClientID,
+        available: Amount,
+        held: Amount,
+    },
+}
+
+impl Rejection {
+    /// Short names of the reasons of rejection, e.g. to label metrics
+    pub const REASONS: [&'static str; 15] = [
+        "account_locked",
+        "insufficient_funds",
+        "client_out_of_range",
+        "missing_amount",
+        "unexpected_amount",
+        "duplicate_tx",
+        "unknown_tx",
+        "already_disputed",
+        "not_disputed",
+        "not_disputable",
+        "charged_back",
+        "auto_locked",
+        "too_many_clients",
+        "negative_balance",
+        "bad_amount",
+    ];
+
+    /// Position of the reason of the rejection in `REASONS`
+    pub fn index(&self) -> usize {
+        let reason = self.reason();
+        Self::REASONS
+            .iter()
+            .position(|r| *r == reason)
+            .expect("every reason is listed")
+    }
+
+    /// Short name of the reason of the rejection, one of `REASONS`
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Rejection::AccountLocked(_) => "account_locked",
+            Rejection::InsufficientFunds { .. } => "insufficient_funds",
+            Rejection::ClientOutOfRange(_) => "client_out_of_range",
+            Rejection::MissingAmount(_) => "missing_amount",
+            Rejection::UnexpectedAmount(..) => "unexpected_amount",
+            Rejection::DuplicateTx(_) => "duplicate_tx",
+            Rejection::UnknownTx(_) => "unknown_tx",
+            Rejection::AlreadyDisputed(_) => "already_disputed",
+            Rejection::NotDisputed(_) => "not_disputed",
+            Rejection::NotDisputable(..) => "not_disputable",
+            Rejection::ChargedBack(_) => "charged_back",
+            Rejection::AutoLocked(_) => "auto_locked",
+            Rejection::TooManyClients { .. } => "too_many_clients",
+            Rejection::NegativeBalance { .. } => "negative_balance",
+            Rejection::BadAmount(_) => "bad_amount",
+        }
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::AccountLocked(client) => write!(f, "account of client {} is locked", client),
+            Rejection::InsufficientFunds {
+                client,
+                requested,
+                available,
+            } => write!(
+                f,
+                "client {} can't withdraw {} (only {} available)",
+                client, requested, available
+            ),
+            Rejection::ClientOutOfRange(client) => write!(f, "client {} is out of range", client),
+            Rejection::BadAmount(amount) => write!(
+                f,
+                "amount `{}` isn't of the form `digits[.digits]`, with up to 4 places past the decimal",
+                amount
+            ),
+            Rejection::MissingAmount(tx) => write!(f, "transaction {} is missing an amount", tx),
+            Rejection::UnexpectedAmount(tx, amount) => {
+                write!(f, "transaction {} has an unexpected amount {}", tx, amount)
+            }
+            Rejection::DuplicateTx(tx) => write!(f, "transaction {} already exists", tx),
+            Rejection::UnknownTx(tx) => write!(f, "transaction {} not found", tx),
+            Rejection::AlreadyDisputed(tx) => write!(f, "transaction {} is already disputed", tx),
+            Rejection::NotDisputed(tx) => write!(f, "transaction {} is not under dispute", tx),
+            Rejection::NotDisputable(tx, kind) => {
+                write!(f, "transaction {} can't be disputed (a {})", tx, kind)
+            }
+            Rejection::ChargedBack(tx) => write!(f, "transaction {} was charged back", tx),
+            Rejection::AutoLocked(client) => write!(
+                f,
+                "account of client {} is locked for too many chargebacks",
+                client
+            ),
+            Rejection::TooManyClients {
+                client,
+                max_clients,
+            } => write!(
+                f,
+                "client {} can't open an account, {} are open already",
+                client, max_clients
+            ),
+            Rejection::NegativeBalance {
+                client,
+                available,
+                held,
+            } => write!(
+                f,
+                "account of client {} would end up with negative funds ({} available, {} held)",
+                client, available, held
+            ),
+        }
+    }
+}
+
+/// Errors aborting the processing of an input
+#[derive(Debug)]
+pub enum EngineError {
+    /// Input couldn't be read, or output couldn't be written
+    Io(std::io::Error),
+    /// A row of the input couldn't be parsed as a transaction
+    Parse {
+        /// Line number of the row in the input (the header being line 1)
+        line: u64,
+        /// Column holding the offending field, if known
+        field: Option<&'static str>,
+        message: String,
+    },
+    /// A transaction was rejected, and the engine runs in strict mode
+    Rejected { line: u64, rejection: Rejection },
+    /// Processing was interrupted (see `Options::interrupt`), after the given number of rows
+    Interrupted(u64),
+    /// A transaction appears after being referenced by a dispute, resolve or chargeback, which is
+    /// an error when checking ordering in strict mode
+    OutOfOrder {
+        line: u64,
+        tx: TxID,
+        /// Line of the first reference to the transaction
+        referenced_at: u64,
+    },
+    /// A row is timestamped before the previous one, see `Options::check_order`
+    OutOfOrderTimestamp {
+        line: u64,
+        timestamp: Timestamp,
+        /// Line and timestamp of the previous timestamped row
+        previous_line: u64,
+        previous: Timestamp,
+    },
+    /// A bounded history is full, and its overflow policy is to fail, see `Options::max_history`
+    HistoryOverflow { line: u64, max_history: usize },
+    /// Engines being merged both hold an account of the given client, see `Engine::merge`
+    MergeConflict(ClientID),
+    /// The transaction to undo isn't in history, see `Engine::undo_transaction`
+    TransactionNotFound(TxID),
+    /// The transaction to undo is under dispute, see `Engine::undo_transaction`
+    TransactionUnderDispute(TxID),
+}
+
+impl From<HistoryError> for Rejection {
+    fn from(e: HistoryError) -> Self {
+        match e {
+            HistoryError::Duplicate(tx) => Rejection::DuplicateTx(tx),
+        }
+    }
+}
+
+/// Where the errors that don't stop processing are written as JSON objects, see
+/// `Options::json_errors`: the binary gives stderr, whereas a service embedding the library may
+/// collect them elsewhere. Unlike events, they aren't buffered, so that they interleave with
+/// other diagnostics in order.
+#[derive(Clone)]
+pub struct ErrorSink(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl ErrorSink {
+    pub fn new(wtr: impl Write + Send + 'static) -> ErrorSink {
+        ErrorSink(Arc::new(Mutex::new(Box::new(wtr))))
+    }
+
+    /// Write an error, i.e. a JSON object, as a line
+    pub(crate) fn emit(&self, error: &str) -> io::Result<()> {
+        let mut wtr = self.0.lock().expect("no panic while writing errors");
+        wtr.write_all(format!("{}\n", error).as_bytes())
+    }
+}
+
+/// Writers can't tell what they are
+impl fmt::Debug for ErrorSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ErrorSink")
+    }
+}
+
+/// Names of the input columns, in order
+const FIELDS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+impl From<std::io::Error> for EngineError {
+    fn from(e: std::io::Error) -> Self {
+        EngineError::Io(e)
+    }
+}
+
+impl From<csv::Error> for EngineError {
+    fn from(e: csv::Error) -> Self {
+        let line = e.position().map_or(0, |p| p.line());
+        let (field, message) = match e.into_kind() {
+            csv::ErrorKind::Io(e) => return EngineError::Io(e),
+            csv::ErrorKind::Deserialize { err, .. } => (
+                err.field().and_then(|i| FIELDS.get(i as usize).copied()),
+                err.kind().to_string(),
+            ),
+            csv::ErrorKind::Utf8 { err, .. } => (None, err.to_string()),
+            csv::ErrorKind::Serialize(message) => (None, message),
+            other => (None, format!("{:?}", other)),
+        };
+        EngineError::Parse {
+            line,
+            field,
+            message,
+        }
+    }
+}
+
+impl EngineError {
+    /// Line of the input the error is about, if any
+    pub fn line(&self) -> Option<u64> {
+        match self {
+            EngineError::Parse { line, .. }
+            | EngineError::Rejected { line, .. }
+            | EngineError::OutOfOrder { line, .. }
+            | EngineError::OutOfOrderTimestamp { line, .. }
+            | EngineError::HistoryOverflow { line, .. } => Some(*line),
+            EngineError::Io(_)
+            | EngineError::Interrupted(_)
+            | EngineError::MergeConflict(_)
+            | EngineError::TransactionNotFound(_)
+            | EngineError::TransactionUnderDispute(_) => None,
+        }
+    }
+
+    /// Name of the kind of error, e.g. `InsufficientFunds` for a withdrawal rejected as such
+    pub fn kind(&self) -> &'static str {
+        match self {
+            EngineError::Io(_) => "Io",
+            EngineError::Parse { .. } => "Parse",
+            EngineError::Rejected { rejection, .. } => match rejection {
+                Rejection::AccountLocked(_) => "AccountLocked",
+                Rejection::InsufficientFunds { .. } => "InsufficientFunds",
+                Rejection::ClientOutOfRange(_) => "ClientOutOfRange",
+                Rejection::MissingAmount(_) => "MissingAmount",
+                Rejection::UnexpectedAmount(..) => "UnexpectedAmount",
+                Rejection::DuplicateTx(_) => "DuplicateTx",
+                Rejection::UnknownTx(_) => "UnknownTx",
+                Rejection::AlreadyDisputed(_) => "AlreadyDisputed",
+                Rejection::NotDisputed(_) => "NotDisputed",
+                Rejection::NotDisputable(..) => "NotDisputable",
+                Rejection::ChargedBack(_) => "ChargedBack",
+                Rejection::AutoLocked(_) => "AutoLocked",
+                Rejection::TooManyClients { .. } => "TooManyClients",
+                Rejection::NegativeBalance { .. } => "NegativeBalance",
+                Rejection::BadAmount(_) => "BadAmount",
+            },
+            EngineError::Interrupted(_) => "Interrupted",
+            EngineError::OutOfOrder { .. } => "OutOfOrder",
+            EngineError::OutOfOrderTimestamp { .. } => "OutOfOrderTimestamp",
+            EngineError::HistoryOverflow { .. } => "HistoryOverflow",
+            EngineError::MergeConflict(_) => "MergeConflict",
+            EngineError::TransactionNotFound(_) => "TransactionNotFound",
+            EngineError::TransactionUnderDispute(_) => "TransactionUnderDispute",
+        }
+    }
+
+    /// What went wrong, without the line
+    pub fn detail(&self) -> String {
+        match self {
+            EngineError::Io(e) => format!("I/O error: {}", e),
+            EngineError::Parse {
+                field: Some(field),
+                message,
+                ..
+            } => format!("invalid {}: {}", field, message),
+            EngineError::Parse { message, .. } => message.clone(),
+            EngineError::Rejected { rejection, .. } => rejection.to_string(),
+            EngineError::Interrupted(rows) => format!("interrupted after {} rows", rows),
+            EngineError::OutOfOrder {
+                tx, referenced_at, ..
+            } => format!(
+                "transaction {} appears after being referenced at line {}",
+                tx, referenced_at
+            ),
+            EngineError::OutOfOrderTimestamp {
+                timestamp,
+                previous_line,
+                previous,
+                ..
+            } => format!(
+                "timestamp {} is before {} at line {}",
+                timestamp, previous, previous_line
+            ),
+            EngineError::HistoryOverflow { max_history, .. } => format!(
+                "transaction history is full (more than {} transactions)",
+                max_history
+            ),
+            EngineError::MergeConflict(client) => format!(
+                "client {} has an account in both engines being merged",
+                client
+            ),
+            EngineError::TransactionNotFound(tx) => format!("unknown transaction {}", tx),
+            EngineError::TransactionUnderDispute(tx) => {
+                format!("transaction {} is under dispute", tx)
+            }
+        }
+    }
+
+    /// The error as a single-line JSON object, for log aggregators, e.g. `{"line":3,"tx_id":2,
+    /// "client_id":1,"error":"InsufficientFunds","detail":"..."}`, given the client and the
+    /// transaction of the row when they are known (otherwise, they're taken from the error)
+    pub fn to_json(&self, client: Option<ClientID>, tx: Option<TxID>) -> String {
+        let (client, tx) = match self {
+            EngineError::OutOfOrder { tx, .. }
+            | EngineError::TransactionNotFound(tx)
+            | EngineError::TransactionUnderDispute(tx) => (client, Some(*tx)),
+            EngineError::Rejected { rejection, .. } => match rejection {
+                Rejection::AccountLocked(id)
+                | Rejection::AutoLocked(id)
+                | Rejection::InsufficientFunds { client: id, .. }
+                | Rejection::TooManyClients { client: id, .. }
+                | Rejection::NegativeBalance { client: id, .. } => (client.or(Some(*id)), tx),
+                Rejection::MissingAmount(id)
+                | Rejection::UnexpectedAmount(id, _)
+                | Rejection::DuplicateTx(id)
+                | Rejection::UnknownTx(id)
+                | Rejection::AlreadyDisputed(id)
+                | Rejection::NotDisputed(id)
+                | Rejection::NotDisputable(id, _)
+                | Rejection::ChargedBack(id) => (client, tx.or(Some(*id))),
+                Rejection::ClientOutOfRange(_) | Rejection::BadAmount(_) => (client, tx),
+            },
+            _ => (client, tx),
+        };
+        json::Object::new()
+            .value("line", self.line())
+            .value("tx_id", tx)
+            .value("client_id", client)
+            .string("error", Some(self.kind()))
+            .string("detail", Some(&self.detail()))
+            .finish()
+    }
+}
+
+impl std::fmt::Display for EngineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line() {
+            Some(line) => write!(f, "line {}: {}", line, self.detail()),
+            None => write!(f, "{}", self.detail()),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EngineError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
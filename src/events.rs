@@ -0,0 +1,73 @@
+//! # Events
+//!
+//! A downstream system reacting to balance changes as they happen (e.g. real-time risk scoring)
+//! shouldn't have to diff snapshots: with `Options::events`, every transaction applied to an
+//! account is emitted as an NDJSON line, along with how it moved the funds of the account and
+//! where it left them:
+//!
+//! ```text
+//! {"tx":1,"type":"deposit","client":1,"delta_available":"1.0000","delta_held":"0.0000","available":"1.0000","held":"0.0000","locked":false}
+//! ```
+//!
+//! Rejected rows change no account, so they emit nothing. Lines are buffered, and flushed every
+//! `FLUSH_INTERVAL` by a background thread, so that a consumer tailing the stream sees them
+//! promptly even when rows stop arriving (e.g. following a file).
+
+use std::fmt;
+use std::io::{self, BufWriter, Write};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// How long an event may wait in the buffer before being flushed
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+type Buffer = Mutex<BufWriter<Box<dyn Write + Send>>>;
+
+/// Where events are written, shared by the engines processing rows (e.g. the shards of a
+/// `SharedEngine`) and the thread flushing them
+#[derive(Clone)]
+pub struct EventSink(Arc<Buffer>);
+
+impl EventSink {
+    /// A sink writing to `wtr`, flushed until it's dropped
+    pub fn new(wtr: impl Write + Send + 'static) -> EventSink {
+        let buffer: Arc<Buffer> = Arc::new(Mutex::new(BufWriter::new(Box::new(wtr))));
+        let flushed = Arc::downgrade(&buffer);
+        thread::spawn(move || flush_periodically(flushed));
+        EventSink(buffer)
+    }
+
+    /// Write an event, i.e. a JSON object, as a line
+    pub(crate) fn emit(&self, event: &str) -> io::Result<()> {
+        let mut buffer = self.0.lock().expect("no panic while writing events");
+        writeln!(buffer, "{}", event)
+    }
+
+    /// Write the events still buffered, e.g. once processing is over
+    pub fn flush(&self) -> io::Result<()> {
+        self.0
+            .lock()
+            .expect("no panic while writing events")
+            .flush()
+    }
+}
+
+/// Writers can't tell what they are
+impl fmt::Debug for EventSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventSink")
+    }
+}
+
+/// Flush the buffer every `FLUSH_INTERVAL`, as long as some sink still writes to it. A failing
+/// flush is left to the next `EventSink::flush` to report.
+fn flush_periodically(buffer: Weak<Buffer>) {
+    loop {
+        thread::sleep(FLUSH_INTERVAL);
+        let Some(buffer) = buffer.upgrade() else {
+            return;
+        };
+        let _ = buffer.lock().map(|mut buffer| buffer.flush());
+    }
+}
@@ -0,0 +1,74 @@
+//! # Follow mode
+//!
+//! Like `tail -f`: an input file our upstream keeps appending transactions to is read to its end,
+//! and then polled for new bytes, rather than being processed again from scratch every hour. A
+//! partially written last row isn't an issue, the CSV reader just waits for the rest of it.
+
+use std::fs::File;
+use std::io::{Error, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// How often a file is polled once its end is reached
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A reader of a growing file, that only reaches its end once `interrupt` is raised
+pub struct Follow {
+    file: File,
+    path: String,
+    /// Number of bytes read so far
+    position: u64,
+    interrupt: &'static AtomicBool,
+}
+
+impl Follow {
+    pub fn open(path: &str, interrupt: &'static AtomicBool) -> std::io::Result<Follow> {
+        Ok(Follow {
+            file: File::open(path)?,
+            path: path.to_owned(),
+            position: 0,
+            interrupt,
+        })
+    }
+
+    /// A rotated file (truncated, or replaced by another one) can't be followed any further,
+    /// reading on would silently mix up unrelated transactions
+    fn check_rotation(&self) -> std::io::Result<()> {
+        let rotated = |what| {
+            let message = format!("`{}` was {} while being followed", self.path, what);
+            Err(Error::other(message))
+        };
+        let current = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(_) => return rotated("removed"),
+        };
+        if current.len() < self.position {
+            return rotated("truncated");
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            if current.ino() != self.file.metadata()?.ino() {
+                return rotated("replaced");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for Follow {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.file.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                self.position += n as u64;
+                return Ok(n);
+            }
+            if self.interrupt.load(Ordering::SeqCst) {
+                return Ok(0);
+            }
+            self.check_rotation()?;
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
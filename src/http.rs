@@ -0,0 +1,324 @@
+//! # HTTP input
+//!
+//! Transaction dumps can be streamed straight from an HTTP server (e.g. object storage), rather
+//! than being downloaded to disk first: the body of the response is fed to the CSV reader as it
+//! arrives. This is a minimal HTTP/1.1 client over `std::net`, handling what such servers send
+//! (`Content-Length` or chunked bodies, gzipped or not, see `gzip.rs`), and reporting a connection
+//! dropped mid-stream along with the number of bytes received so far. It only speaks plain
+//! `http://`: `https://` URLs are refused, as they would take TLS.
+
+use crate::gzip::GzipDecoder;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Whether `input` is to be fetched over HTTP rather than opened as a file
+pub fn is_url(input: &str) -> bool {
+    input.starts_with("http://") || input.starts_with("https://")
+}
+
+/// Split an `http://host[:port][/path]` URL into its host, port and path
+fn parse_url(url: &str) -> std::io::Result<(&str, u16, &str)> {
+    let invalid = |message: &str| Error::new(ErrorKind::InvalidInput, message.to_owned());
+    if url.starts_with("https://") {
+        return Err(invalid("https:// URLs aren't supported, only http:// ones"));
+    }
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| invalid("not an http:// URL"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| invalid("invalid port"))?),
+        None => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err(invalid("missing host"));
+    }
+    Ok((host, port, path))
+}
+
+/// Send a GET request for `url`, and return a reader of the body of the response, which must be
+/// successful (2xx). `timeout` bounds the connection, and then every read.
+pub fn get(url: &str, timeout: Option<Duration>) -> std::io::Result<Body> {
+    let (host, port, path) = parse_url(url)?;
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("can't resolve {}", host)))?;
+    let mut stream = match timeout {
+        Some(timeout) => TcpStream::connect_timeout(&addr, timeout)?,
+        None => TcpStream::connect(addr)?,
+    };
+    stream.set_read_timeout(timeout)?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    stream.write_all(request.as_bytes())?;
+    let mut stream = BufReader::new(stream);
+    let status = read_line(&mut stream)?;
+    let mut parts = status.splitn(3, ' ');
+    let code = match (parts.next(), parts.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/1.") => code.to_owned(),
+        _ => return Err(invalid_data(format!("invalid status line `{}`", status))),
+    };
+    if !code.starts_with('2') {
+        return Err(Error::other(format!("HTTP server responded `{}`", status)));
+    }
+    let mut framing = Framing::UntilClose;
+    let mut gzip = false;
+    loop {
+        let header = read_line(&mut stream)?;
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "transfer-encoding" if value.eq_ignore_ascii_case("chunked") => {
+                framing = Framing::Chunked(0);
+            }
+            "content-length" if !matches!(framing, Framing::Chunked(_)) => {
+                let length = value
+                    .parse()
+                    .map_err(|_| invalid_data("invalid Content-Length"))?;
+                framing = Framing::Length(length);
+            }
+            "content-encoding" => match value.to_ascii_lowercase().as_str() {
+                "identity" => gzip = false,
+                "gzip" | "x-gzip" => gzip = true,
+                _ => {
+                    return Err(invalid_data(format!(
+                        "unsupported Content-Encoding `{}`",
+                        value
+                    )))
+                }
+            },
+            _ => {}
+        }
+    }
+    let framed = Framed {
+        stream,
+        framing,
+        received: 0,
+    };
+    Ok(Body(match gzip {
+        true => Encoding::Gzip(GzipDecoder::new(framed)),
+        false => Encoding::Identity(framed),
+    }))
+}
+
+fn invalid_data(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+/// A line of the response head, without its `\r\n`
+fn read_line<R: BufRead>(stream: &mut R) -> std::io::Result<String> {
+    let mut line = String::new();
+    if stream.read_line(&mut line)? == 0 {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// How the end of a body is told
+#[derive(Debug)]
+enum Framing {
+    /// Bytes left to read
+    Length(u64),
+    /// Bytes left to read in the current chunk
+    Chunked(u64),
+    /// The last chunk was read
+    Done,
+    /// The body ends with the connection
+    UntilClose,
+}
+
+/// Streamed body of a response, decompressed as it's read if it was sent gzipped
+#[derive(Debug)]
+pub struct Body(Encoding);
+
+#[derive(Debug)]
+enum Encoding {
+    Identity(Framed),
+    Gzip(GzipDecoder<Framed>),
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.0 {
+            Encoding::Identity(framed) => framed.read(buf),
+            Encoding::Gzip(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+/// Body of a response as sent, up to its end
+#[derive(Debug)]
+struct Framed {
+    stream: BufReader<TcpStream>,
+    framing: Framing,
+    /// Number of bytes of the body received so far
+    received: u64,
+}
+
+impl Framed {
+    fn dropped(&self) -> Error {
+        let message = format!("connection dropped after {} bytes of body", self.received);
+        Error::new(ErrorKind::UnexpectedEof, message)
+    }
+}
+
+impl Read for Framed {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let left = match self.framing {
+            Framing::Length(0) | Framing::Done => return Ok(0),
+            Framing::Length(left) => left,
+            Framing::Chunked(0) => {
+                // Chunks after the first one are preceded by the `\r\n` ending the previous one
+                let mut size = read_line(&mut self.stream).map_err(|_| self.dropped())?;
+                if size.is_empty() && self.received > 0 {
+                    size = read_line(&mut self.stream).map_err(|_| self.dropped())?;
+                }
+                let size = size.split(';').next().unwrap_or("").trim();
+                let size = u64::from_str_radix(size, 16)
+                    .map_err(|_| invalid_data(format!("invalid chunk size `{}`", size)))?;
+                if size == 0 {
+                    self.framing = Framing::Done;
+                    return Ok(0);
+                }
+                self.framing = Framing::Chunked(size);
+                size
+            }
+            Framing::Chunked(left) => left,
+            Framing::UntilClose => u64::MAX,
+        };
+        let max = buf.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+        let n = self.stream.read(&mut buf[..max])?;
+        if n == 0 && !buf.is_empty() {
+            return match self.framing {
+                Framing::UntilClose => Ok(0),
+                _ => Err(self.dropped()),
+            };
+        }
+        self.received += n as u64;
+        match &mut self.framing {
+            Framing::Length(left) | Framing::Chunked(left) => *left -= n as u64,
+            _ => {}
+        }
+        Ok(n)
+    }
+}
+
+/// Serve `responses` (raw HTTP responses), one per connection, and return the base URL
+#[cfg(test)]
+fn serve(responses: Vec<Vec<u8>>) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for response in responses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = BufReader::new(stream);
+            while !read_line(&mut stream).unwrap().is_empty() {}
+            let _ = stream.get_mut().write_all(&response);
+        }
+    });
+    url
+}
+
+#[test]
+fn http_input() {
+    const INPUT: &str = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\n";
+    let chunked = format!(
+        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+        22,
+        &INPUT[..22],
+        INPUT.len() - 22,
+        &INPUT[22..]
+    );
+    let length = format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+        INPUT.len(),
+        INPUT
+    );
+    let url = serve(vec![chunked.into_bytes(), length.into_bytes()]);
+    let expected = crate::wasm::process_csv(INPUT);
+    for _ in 0..2 {
+        let mut engine = crate::Engine::new(crate::Options {
+            sorted: true,
+            ..crate::Options::default()
+        });
+        let body = get(&format!("{}/dump.csv", url), Some(Duration::from_secs(5))).unwrap();
+        engine.process_from_reader(body).unwrap();
+        let mut output = Vec::new();
+        engine.write_accounts_csv(&mut output).unwrap();
+        let mut lines = expected.lines().collect::<Vec<_>>();
+        lines[1..].sort();
+        assert_eq!(
+            String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .collect::<Vec<_>>(),
+            lines
+        );
+    }
+}
+
+#[test]
+fn http_errors() {
+    let url = serve(vec![
+        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\ntype,client,tx,amount\n".to_vec(),
+    ]);
+    let e = get(&url, None).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "HTTP server responded `HTTP/1.1 404 Not Found`"
+    );
+    let mut body = get(&url, None).unwrap();
+    let e = std::io::copy(&mut body, &mut std::io::sink()).unwrap_err();
+    assert_eq!(e.to_string(), "connection dropped after 22 bytes of body");
+    let e = get("https://example.com/", None).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "https:// URLs aren't supported, only http:// ones"
+    );
+}
+
+#[test]
+fn http_gzip() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/");
+    let gzip = std::fs::read(format!("{}large.in.csv.gz", dir)).unwrap();
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+        gzip.len()
+    );
+    let response = [head.as_bytes(), &gzip].concat();
+    let url = serve(vec![
+        response.clone(),
+        response[..head.len() + 1000].to_vec(),
+    ]);
+    let accounts = |reader: &mut dyn Read| {
+        let mut engine = crate::Engine::new(crate::Options {
+            sorted: true,
+            strict: false,
+            ..crate::Options::default()
+        });
+        engine.process_from_reader(reader).unwrap();
+        let mut output = Vec::new();
+        engine.write_accounts_csv(&mut output).unwrap();
+        output
+    };
+    let file = std::fs::File::open(format!("{}large.in.csv", dir)).unwrap();
+    let mut body = get(&url, Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(accounts(&mut body), accounts(&mut { file }));
+    // The count is of the bytes received, i.e. compressed
+    let mut body = get(&url, None).unwrap();
+    let e = std::io::copy(&mut body, &mut std::io::sink()).unwrap_err();
+    assert_eq!(e.to_string(), "connection dropped after 1000 bytes of body");
+}
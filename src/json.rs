@@ -0,0 +1,418 @@
+//! # JSON
+//!
+//! A tiny stand-in for `serde_json`, enough to write the flat objects the engine reports (e.g.
+//! errors on stderr): values are formatted as they are written, with strings escaped. It also
+//! reads documents back, e.g. transactions of an NDJSON input (see `source::NdjsonSource`), and
+//! parsed values deserialize into anything deriving `Deserialize` (see
+//! `Engine::load_snapshot_json`).
+
+use serde::de::{self, IntoDeserializer, Visitor};
+use std::fmt::{Display, Write};
+
+/// A JSON object being written, field by field
+#[derive(Debug)]
+pub struct Object(String);
+
+impl Object {
+    pub fn new() -> Object {
+        Object(String::from("{"))
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        self.0 += &string(key);
+        self.0.push(':');
+    }
+
+    /// A number or boolean field, `null` when missing
+    pub fn value<T: Display>(mut self, key: &str, value: Option<T>) -> Object {
+        self.key(key);
+        match value {
+            Some(value) => write!(self.0, "{}", value).expect("writing to a String"),
+            None => self.0 += "null",
+        }
+        self
+    }
+
+    /// A string field, `null` when missing
+    pub fn string(mut self, key: &str, value: Option<&str>) -> Object {
+        self.key(key);
+        match value {
+            Some(value) => self.0 += &string(value),
+            None => self.0 += "null",
+        }
+        self
+    }
+
+    /// A nested object field
+    pub fn object(mut self, key: &str, value: Object) -> Object {
+        self.key(key);
+        self.0 += &value.finish();
+        self
+    }
+
+    pub fn finish(mut self) -> String {
+        self.0.push('}');
+        self.0
+    }
+}
+
+impl Default for Object {
+    fn default() -> Self {
+        Object::new()
+    }
+}
+
+/// `s` as a quoted JSON string, escaping what has to be
+pub fn string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted += "\\\"",
+            '\\' => quoted += "\\\\",
+            '\n' => quoted += "\\n",
+            '\r' => quoted += "\\r",
+            '\t' => quoted += "\\t",
+            c if c.is_control() => {
+                write!(quoted, "\\u{:04x}", c as u32).expect("writing to a String")
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A parsed JSON value, numbers being kept as written so that they're parsed as needed
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+/// The fields of a flat JSON object, in order: nested objects and arrays aren't supported
+pub fn parse_object(s: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut parser = Parser::new(s);
+    let fields = match parser.document().map_err(|(_, message)| message)? {
+        Value::Object(fields) => fields,
+        _ => return Err("expected a JSON object".to_owned()),
+    };
+    match fields
+        .iter()
+        .find(|(_, value)| matches!(value, Value::Array(_) | Value::Object(_)))
+    {
+        Some((key, _)) => Err(format!("`{}` isn't a flat value", key)),
+        None => Ok(fields),
+    }
+}
+
+/// The items of a JSON array, along with the line each one starts on (e.g. to report which one
+/// is invalid), or why it isn't one along with the line it's on
+pub fn parse_array(s: &str) -> Result<Vec<(u64, Value)>, (u64, String)> {
+    let mut parser = Parser::new(s);
+    let mut items = Vec::new();
+    parser.skip_whitespace();
+    if parser.next() != Some('[') {
+        return Err((parser.line, "expected a JSON array".to_owned()));
+    }
+    parser.skip_whitespace();
+    if parser.chars.next_if_eq(&']').is_none() {
+        loop {
+            parser.skip_whitespace();
+            let line = parser.line;
+            let item = parser.value().map_err(|message| (parser.line, message))?;
+            items.push((line, item));
+            parser.skip_whitespace();
+            match parser.next() {
+                Some(',') => continue,
+                Some(']') => break,
+                _ => return Err((parser.line, "expected `,` or `]`".to_owned())),
+            }
+        }
+    }
+    parser.skip_whitespace();
+    match parser.next() {
+        None => Ok(items),
+        Some(_) => Err((
+            parser.line,
+            "unexpected characters after the array".to_owned(),
+        )),
+    }
+}
+
+/// A recursive descent over the characters of a document, counting lines
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: u64,
+}
+
+impl<'a> Parser<'a> {
+    fn new(s: &'a str) -> Self {
+        Parser {
+            chars: s.chars().peekable(),
+            line: 1,
+        }
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .chars
+            .next_if(|c| *c != '\n' && c.is_ascii_whitespace())
+            .is_some()
+            || self
+                .chars
+                .next_if_eq(&'\n')
+                .map(|_| self.line += 1)
+                .is_some()
+        {}
+    }
+
+    /// A single value, with nothing but whitespace around
+    fn document(&mut self) -> Result<Value, (u64, String)> {
+        self.skip_whitespace();
+        let value = self.value().map_err(|message| (self.line, message))?;
+        self.skip_whitespace();
+        match self.next() {
+            None => Ok(value),
+            Some(_) => Err((
+                self.line,
+                "unexpected characters after the value".to_owned(),
+            )),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.next() {
+            Some('"') => self.string().map(Value::String),
+            Some(c @ ('-' | '0'..='9')) => {
+                let mut number = String::from(c);
+                while let Some(c) = self
+                    .chars
+                    .next_if(|c| matches!(c, '0'..='9' | '.' | 'e' | 'E' | '+' | '-'))
+                {
+                    number.push(c);
+                }
+                Ok(Value::Number(number))
+            }
+            Some(c @ ('n' | 't' | 'f')) => {
+                let mut word = String::from(c);
+                while let Some(c) = self.chars.next_if(char::is_ascii_alphabetic) {
+                    word.push(c);
+                }
+                match word.as_str() {
+                    "null" => Ok(Value::Null),
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => Err(format!("unexpected `{}`", word)),
+                }
+            }
+            Some('[') => {
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.chars.next_if_eq(&']').is_some() {
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    self.skip_whitespace();
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.next() {
+                        Some(',') => continue,
+                        Some(']') => return Ok(Value::Array(items)),
+                        _ => return Err("expected `,` or `]`".to_owned()),
+                    }
+                }
+            }
+            Some('{') => {
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.chars.next_if_eq(&'}').is_some() {
+                    return Ok(Value::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    if self.next() != Some('"') {
+                        return Err("expected a field name".to_owned());
+                    }
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    if self.next() != Some(':') {
+                        return Err(format!("expected `:` after `{}`", key));
+                    }
+                    self.skip_whitespace();
+                    let value = self.value()?;
+                    fields.push((key, value));
+                    self.skip_whitespace();
+                    match self.next() {
+                        Some(',') => continue,
+                        Some('}') => return Ok(Value::Object(fields)),
+                        _ => return Err("expected `,` or `}`".to_owned()),
+                    }
+                }
+            }
+            _ => Err("expected a value".to_owned()),
+        }
+    }
+
+    /// The rest of a string whose opening quote was read, unescaped
+    fn string(&mut self) -> Result<String, String> {
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                None => return Err("unterminated string".to_owned()),
+                Some('"') => return Ok(string),
+                Some('\\') => string.push(match self.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let hex = (0..4).filter_map(|_| self.next()).collect::<String>();
+                        // Surrogate pairs would only matter to characters IDs and amounts don't use
+                        u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("unsupported escape `\\u{}`", hex))?
+                    }
+                    _ => return Err("invalid escape".to_owned()),
+                }),
+                Some(c) => string.push(c),
+            }
+        }
+    }
+}
+
+/// Values deserialize into anything deriving `Deserialize`, e.g. `Value::String("Locked")` into
+/// `LedgerStatus::Locked`
+impl<'de> serde::Deserializer<'de> for Value {
+    type Error = serde::de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            Value::Null => visitor.visit_unit(),
+            Value::Bool(value) => visitor.visit_bool(value),
+            Value::Number(number) => match (number.parse::<u64>(), number.parse::<i64>()) {
+                (Ok(number), _) => visitor.visit_u64(number),
+                (_, Ok(number)) => visitor.visit_i64(number),
+                _ => match number.parse::<f64>() {
+                    Ok(number) => visitor.visit_f64(number),
+                    Err(_) => Err(de::Error::custom(format!("invalid number `{}`", number))),
+                },
+            },
+            Value::String(string) => visitor.visit_string(string),
+            Value::Array(items) => {
+                let mut items = de::value::SeqDeserializer::new(items.into_iter());
+                let value = visitor.visit_seq(&mut items)?;
+                items.end()?;
+                Ok(value)
+            }
+            Value::Object(fields) => {
+                let mut fields = de::value::MapDeserializer::new(fields.into_iter());
+                let value = visitor.visit_map(&mut fields)?;
+                fields.end()?;
+                Ok(value)
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            Value::Null => visitor.visit_none(),
+            value => visitor.visit_some(value),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Only unit variants are supported, as strings
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self {
+            Value::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+            value => value.deserialize_any(visitor),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de> for Value {
+    type Deserializer = Value;
+
+    fn into_deserializer(self) -> Value {
+        self
+    }
+}
+
+#[test]
+fn parse() {
+    let object = parse_object(
+        r#" {"type": "deposit", "client":1, "tx" :2,"amount":"1.5",
+        "note": "a \"quoted\" \u00e9", "ok": true, "currency": null} "#,
+    );
+    assert_eq!(
+        object.unwrap(),
+        [
+            ("type".to_owned(), Value::String("deposit".to_owned())),
+            ("client".to_owned(), Value::Number("1".to_owned())),
+            ("tx".to_owned(), Value::Number("2".to_owned())),
+            ("amount".to_owned(), Value::String("1.5".to_owned())),
+            (
+                "note".to_owned(),
+                Value::String("a \"quoted\" é".to_owned())
+            ),
+            ("ok".to_owned(), Value::Bool(true)),
+            ("currency".to_owned(), Value::Null),
+        ]
+    );
+    assert_eq!(parse_object("{}"), Ok(Vec::new()));
+    assert!(parse_object(r#"{"client": {"id": 1}}"#).is_err());
+    assert!(parse_object(r#"{"client": 1"#).is_err());
+    assert!(parse_object(r#"{"client": 1} 2"#).is_err());
+    assert!(parse_object("deposit,1,1,1.0").is_err());
+    let array = parse_array("[\n  {\"client\": 1, \"held\": []},\n  [null]\n]");
+    let client = Value::Object(vec![
+        ("client".to_owned(), Value::Number("1".to_owned())),
+        ("held".to_owned(), Value::Array(Vec::new())),
+    ]);
+    assert_eq!(
+        array.unwrap(),
+        [(2, client), (3, Value::Array(vec![Value::Null]))]
+    );
+    assert_eq!(
+        parse_array("[\n1,\n}"),
+        Err((3, "expected a value".to_owned()))
+    );
+}
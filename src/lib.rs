@@ -0,0 +1,5413 @@
+//! # Toy payments engine
+//!
+//! The business logic of the payments engine, decoupled from any particular source of CSV input
+//! or sink of CSV output: the binary in `main.rs` wires it to stdin and stdout, but it could as
+//! well be fed from a file, a TCP stream, or a string coming from a browser (see the `wasm`
+//! feature).
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[macro_use]
+pub mod log;
+pub mod error;
+pub mod events;
+#[cfg(any(feature = "http", test))]
+pub mod gzip;
+#[cfg(any(feature = "http", test))]
+pub mod http;
+pub mod json;
+pub mod metrics;
+pub mod progress;
+pub mod shared;
+pub mod snapshot;
+pub mod source;
+pub mod store;
+pub mod timestamp;
+pub mod validate;
+#[cfg(any(feature = "wasm", test))]
+pub mod wasm;
+pub mod workload;
+
+pub use error::{EngineError, ErrorSink, Rejection};
+use events::EventSink;
+use metrics::Metrics;
+use progress::Progress;
+pub use snapshot::{AccountState, AccountsSnapshot};
+use source::CsvSource;
+pub use source::{AmountValidation, InputFormat, LenientAmounts, NdjsonSource, TransactionSource};
+use store::{AccountStore, DiskTxStore, HistoryOverflow, Lru, StoreKind, TxHistory};
+use timestamp::{OrderCheck, Timestamp};
+pub use validate::{InputValidator, ValidationError};
+
+// ### Input
+//
+// The input will be a CSV file with the columns type, client, tx, and amount. You can assume the
+// type is a string, the client column is a valid u16 client ID, the tx is a valid u32 transaction
+// ID, and the amount is a decimal value with a precision of up to four places past the decimal.
+//
+// For example:
+//
+// ```csv
+// type,  client, tx, amount
+// deposit,    1,  1,    1.0
+// deposit,    2,  2,    2.0
+// deposit,    1,  3,    2.0
+// withdrawal, 1,  4,    1.5
+// withdrawal, 2,  5,    3.0
+// ```
+//
+// The client ID will be unique per client though are not guaranteed to be ordered. Transactions to
+// the client account 2 could occur before transactions to the client account 1. Likewise,
+// transaction IDs (tx) are globally unique, though are also not guaranteed to be ordered. You can
+// assume the transactions occur chronologically in the file, so if transaction b appears after a
+// in the input file then you can assume b occurred chronologically after a. Whitespaces and
+// decimal precisions (up to four places past the decimal) must be accepted by your program.
+
+/// Client IDs are stored on 16-bits unsigned integers
+pub type ClientID = u16;
+/// Transaction IDs are stored on 32-bits unsigned integers
+pub type TxID = u32;
+/// Currencies are free-form codes, e.g. `EUR` or `BTC`
+pub type Currency = String;
+
+/// Using a tuple-struct for `Transaction`, since `type` is a reserved keyword and couldn't be used
+/// as a field name...
+#[derive(Clone, Debug, Deserialize)]
+pub struct Transaction(
+    /// Transaction type
+    pub Tx,
+    /// Client ID
+    pub ClientID,
+    /// Transaction ID
+    pub TxID,
+    /// Transactions of type Dispute, Resolve or Chargeback does not specify an Amount
+    pub Option<Amount>,
+    /// Optional currency column, only taken into account in multi-currency mode (disputes don't
+    /// need it, they refer to the currency of the disputed transaction)
+    #[serde(default)]
+    pub Option<Currency>,
+    /// Optional timestamp, read from the column named `ts` (wherever it is) when checking order,
+    /// see `Options::check_order`
+    #[serde(skip)]
+    pub Option<Timestamp>,
+);
+
+// ### Output
+//
+// The output should be a list of client IDs (client), available amounts (available), held amounts
+// (held), total amounts (total), and whether the account is locked (locked). Columns are defined
+// as:
+//
+// - available:
+//
+//     The total funds that are available for trading, staking, withdrawal, etc. This
+//     should be equal to the total - held amounts
+//
+// - held:
+//
+//     The total funds that are held for dispute. This should be equal to total -
+//     available amounts
+//
+// - total:
+//
+//     The total funds that are available or held. This should be equal to available +
+//     held
+//
+// - locked:
+//
+//     Whether the account is locked. An account is locked if a charge back occurs
+//
+//
+// For example:
+//
+// ```csv
+// client, available, held, total, locked
+//      1,       1.5,  0.0,   1.5,  false
+//      2,       2.0,  0.0,   2.0,  false
+// ```
+//
+// Spacing and displaying decimals for round values do not matter. Row ordering also does not
+// matter. The above output will be considered the exact same as the following:
+//
+// ```csv
+// client,available,held,total,locked
+// 2,2,0,2,false
+// 1,1.5,0,1.5,false
+// ```
+
+/// A wrapper (using `newtype` construct) around `f64` primitive type
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Amount(pub f64);
+
+/// Amounts are totally ordered, so they can be sorted, or used as keys of a `BTreeMap`: `NaN`
+/// (which the engine never stores, but a raw `f64` may hold) is less than any other amount and
+/// equal to itself. Unlike `f64::total_cmp`, `-0.0` and `0.0` stay equal, e.g. a held amount
+/// brought back to zero by a resolve is still a zero.
+impl Ord for Amount {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.0.is_nan(), other.0.is_nan()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            // Only comparisons involving `NaN` are unordered
+            (false, false) => self.0.partial_cmp(&other.0).unwrap_or(Ordering::Equal),
+        }
+    }
+}
+
+impl PartialOrd for Amount {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Amount {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Amount {}
+
+/// ### Precision
+///
+/// You can assume a precision of four places past the decimal and should output values with the
+/// same level of precision (unless another precision is asked for, e.g. with `{:.2}`).
+impl std::fmt::Display for Amount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.*}", f.precision().unwrap_or(4), self.0)
+    }
+}
+
+/// How amounts are written to every output, so that downstream parsers get a stable shape rather
+/// than whatever `f64` makes of a value (`2`, `1.5` or `1.5000000000000002`)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AmountFormat {
+    /// Always four places past the decimal (or `Options::output_precision`), e.g. `2.0000`, as in
+    /// the precision of the specification
+    #[default]
+    Fixed4,
+    /// As few places as needed, e.g. `2` or `1.5`, never more than `Fixed4` has
+    Trim,
+}
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0.0);
+
+    /// The amount without its sign, e.g. to compare the size of amounts regardless of direction
+    pub fn abs(self) -> Amount {
+        Amount(self.0.abs())
+    }
+
+    /// The amount as written to outputs, with (up to, when trimmed) `places` past the decimal,
+    /// and never in scientific notation. Rounding gets rid of floating-point drift, and an amount
+    /// rounded to zero is written without a sign.
+    pub fn format(self, format: AmountFormat, places: u8) -> String {
+        let mut string = format!("{:.*}", usize::from(places), self.0);
+        if format == AmountFormat::Trim && string.contains('.') {
+            let trimmed = string.trim_end_matches('0').trim_end_matches('.').len();
+            string.truncate(trimmed);
+        }
+        match string.strip_prefix('-') {
+            Some(zero) if zero.bytes().all(|b| b == b'0' || b == b'.') => zero.to_owned(),
+            _ => string,
+        }
+    }
+
+    /// Round to the given number of `places` past the decimal, so that e.g. a deposit of
+    /// `1.23456789` is stored as `1.2346` rather than with full floating-point precision
+    pub fn round(self, places: u8) -> Amount {
+        let factor = 10f64.powi(i32::from(places));
+        Amount((self.0 * factor).round() / factor)
+    }
+
+    /// Round to the four places past the decimal of the specification, e.g. a balance drifting to
+    /// `1.50000000001` after `f64` arithmetic back to `1.5`, so that its `{:.4}` output is
+    /// lossless. Balances are rounded after every update to the places amounts are read with (see
+    /// `Ledger::set_funds`), which is this unless `Options::input_precision` says otherwise.
+    pub fn normalize(self) -> Amount {
+        self.round(4)
+    }
+}
+
+/// Tolerance of the comparison of a withdrawal with the available funds, given the `places`
+/// amounts are rounded to: half of the smallest amount at that precision, e.g. `0.00005` at the
+/// four places of the specification. Summing `f64` amounts drifts (e.g. `0.7 + 0.1` is
+/// `0.7999999999999999`), which mustn't make withdrawing the whole balance fail, whereas an
+/// overdraft of the smallest amount still does. It only makes up for `f64`: with fixed-point
+/// amounts, sums are exact, and so are comparisons (see the `withdrawal_boundary` test, meant to
+/// hold either way).
+pub fn withdrawal_tolerance(places: u8) -> f64 {
+    0.5 / 10f64.powi(i32::from(places))
+}
+
+/// Average length of an input row in bytes, to estimate the number of transactions of an input
+/// from its size, see `estimate_transactions`
+pub const AVERAGE_ROW_LEN: u64 = 24;
+
+/// Columns of the lines given to `Engine::process_line`, the same as the ones of CSV inputs
+const LINE_COLUMNS: [&str; 6] = source::COLUMNS;
+
+/// Size of the buffer of CSV readers: bigger than the default 8 KiB, to read huge inputs in fewer
+/// system calls
+const READ_BUFFER: usize = 64 * 1024;
+
+/// Rough number of transactions of an input of `len` bytes, e.g. of a file whose size is known
+/// before reading it, to pre-allocate the history (see `Options::expect_transactions`)
+pub fn estimate_transactions(len: u64) -> usize {
+    usize::try_from(len / AVERAGE_ROW_LEN).unwrap_or(usize::MAX)
+}
+
+/// Explicitly authorizing `+` binary operation on `Amount` (to allow further rounding behavior?)
+impl std::ops::Add for Amount {
+    type Output = Amount;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Amount(self.0 + rhs.0)
+    }
+}
+
+/// Explicitly authorizing `-` binary operation on `Amount` (to allow further rounding behavior?)
+impl std::ops::Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Amount(self.0 - rhs.0)
+    }
+}
+
+/// ### Types of Transactions
+///
+/// Types are named in lowercase in inputs, e.g. `deposit`, and so are they in outputs and messages
+/// (see the `Display` implementation).
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Tx {
+    /// #### Deposit
+    ///
+    /// A deposit is a credit to the client's asset account, meaning it should increase the
+    /// available and total funds of the client account.
+    ///
+    /// A deposit looks like:
+    ///
+    /// ```csv
+    /// type,    client, tx, amount
+    /// deposit,      1,  1,    1.0
+    Deposit,
+
+    /// #### Withdrawal
+    ///
+    /// A withdraw is a debit to the client's asset account, meaning it should decrease the
+    /// available and total funds of the client account.
+    ///
+    /// A withdrawal looks like:
+    ///
+    /// ```csv
+    /// type,  client, tx, amount
+    /// withdrawal, 2,  2,    1.0
+    /// ```
+    ///
+    /// If a client does not have sufficient available funds the withdrawal should fail and the
+    /// total amount of funds should not change.
+    Withdrawal,
+
+    /// #### Dispute
+    ///
+    /// A dispute represents a client's claim that a transaction was erroneous and should be
+    /// reversed. The transaction shouldn't be reversed yet but the associated funds should be held.
+    /// This means that the clients available funds should decrease by the amount disputed, their
+    /// held funds should increase by the amount disputed, while their total funds should remain the
+    /// same.
+    ///
+    /// A dispute looks like:
+    ///
+    /// ```csv
+    /// type, client, tx, amount
+    /// dispute,   1,  1,
+    /// ```
+    ///
+    /// Notice that a dispute does not state the amount disputed. Instead a dispute references the
+    /// transaction that is disputed by ID. If the tx specified by the dispute doesn't exist you can
+    /// ignore it and assume this is an error on our partners side.
+    Dispute,
+
+    /// #### Resolve
+    ///
+    /// A resolve represents a resolution to a dispute, releasing the associated held funds. Funds
+    /// that were previously disputed are no longer disputed. This means that the clients held funds
+    /// should decrease by the amount no longer disputed, their available funds should increase by
+    /// the amount no longer disputed, and their total funds should remain the same.
+    ///
+    /// A resolve looks like:
+    ///
+    /// ```csv
+    /// type, client, tx, amount
+    /// resolve,   1,  1,
+    /// ```
+    ///
+    /// Like disputes, resolves do not specify an amount. Instead they refer to a transaction that
+    /// was  under dispute by ID. If the tx specified doesn't exist, or the tx isn't under dispute,
+    /// you can ignore the resolve and assume this is an error on our partner's side.
+    Resolve,
+
+    /// #### Chargeback
+    ///
+    /// A chargeback is the final state of a dispute and represents the client reversing a
+    /// transaction. Funds that were held have now been withdrawn. This means that the clients held
+    /// funds and total funds should decrease by the amount previously disputed. If a chargeback
+    /// occurs the client's account should be immediately frozen.
+    ///
+    /// A chargeback looks like:
+    ///
+    /// ```csv
+    /// type,  client, tx, amount
+    /// chargeback, 1,  1,
+    /// ```
+    ///
+    /// Like a dispute and a resolve a chargeback refers to the transaction by ID (tx) and does not
+    /// specify an amount. Like a resolve, if the tx specified doesn't exist, or the tx isn't under
+    /// dispute, you can ignore chargeback and assume this is an error on our partner's side.
+    Chargeback,
+}
+
+impl std::fmt::Display for Tx {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Tx::Deposit => "deposit",
+            Tx::Withdrawal => "withdrawal",
+            Tx::Dispute => "dispute",
+            Tx::Resolve => "resolve",
+            Tx::Chargeback => "chargeback",
+        })
+    }
+}
+
+/// Here is a simple dumb algorithm that loop over the input values, mutating a collection of
+/// ledgers. This stateful approach is required and forbid us for doing a lot of naive optimization,
+/// e.g. using rayon parallel iterator, since transaction shouldn't be evaluated out of order...
+///
+/// Ledgers are (de)serializable as is, e.g. in a JSON snapshot (see `Engine::load_snapshot_json`),
+/// missing fields taking their default value.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Ledger {
+    pub available: Amount,
+    pub held: Amount,
+    pub status: LedgerStatus,
+    /// Number of transactions applied to the account, of any kind
+    pub tx_count: u32,
+    /// Number of disputes applied to the account
+    pub dispute_count: u32,
+    /// Number of chargebacks applied to the account (usually one at most, since a chargeback locks
+    /// it until another pending dispute is resolved)
+    pub chargeback_count: u32,
+    /// Sum of the amounts charged back
+    pub chargeback_amount: Amount,
+    /// Whether the account got locked for good by too many chargebacks, see
+    /// `Options::auto_lock_after_chargebacks`
+    pub auto_locked: bool,
+    /// Lowest available funds the account ever had: disputing a deposit whose funds were
+    /// withdrawn already takes them below zero, which final balances may not show anymore
+    pub min_available_seen: Amount,
+    /// Sequence number of the last dispute raised on the account, counting the transactions
+    /// applied by the engine, see `Options::dispute_expiry_after`
+    pub dispute_tx_sequence: Option<u64>,
+}
+
+/// Disputes are tracked per transaction (see `store::TxEntry`), so a ledger is either usable or
+/// locked by a chargeback. Only resolving a dispute still pending on a locked ledger unlocks it
+/// (unless it's locked for good, see `Ledger::auto_locked`), every other transaction being
+/// rejected. It's (de)serialized as a string, i.e. `"Default"` or `"Locked"`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum LedgerStatus {
+    Default,
+    Locked,
+}
+
+impl Ledger {
+    /// Total funds, available or held
+    pub fn total(&self) -> Amount {
+        self.available + self.held
+    }
+
+    /// Set the funds of the ledger of `client`, unless that takes the available or held ones
+    /// below zero, or further below (beyond the drift tolerated by `withdrawal_tolerance`). A
+    /// dispute is the one way to take available funds below zero (disputing funds already
+    /// withdrawn, or resolving the dispute of a withdrawal credited back and spent meanwhile, see
+    /// `WithdrawalDisputePolicy::Reverse`), so it tells to let them.
+    ///
+    /// Funds are rounded to the `places` amounts are read with (see `Options::input_precision`,
+    /// four places being `Amount::normalize`): sums of `f64` amounts drift (e.g. ten deposits of
+    /// `0.1` add up to `0.9999999999999999`), which would otherwise pile up over the transactions
+    /// of an account, until it surfaces in outputs or comparisons.
+    fn set_funds(
+        &mut self,
+        client: ClientID,
+        (available, held): (Amount, Amount),
+        places: u8,
+        dispute: bool,
+    ) -> Result<(), Rejection> {
+        let (available, held) = (available.round(places), held.round(places));
+        let tolerance = withdrawal_tolerance(places);
+        let below_zero = |new: Amount, old: Amount| new.0 < -tolerance && new < old;
+        if (!dispute && below_zero(available, self.available)) || below_zero(held, self.held) {
+            return Err(Rejection::NegativeBalance {
+                client,
+                available,
+                held,
+            });
+        }
+        self.available = available;
+        self.held = held;
+        Ok(())
+    }
+
+    /// Apply a transaction of type `kind` to the ledger of `client`, `amount` being the one of the
+    /// transaction, or the one of the disputed transaction for a dispute, resolve or chargeback.
+    /// Amounts are rounded to `places`, see `set_funds`.
+    pub fn apply(
+        &mut self,
+        client: ClientID,
+        kind: Tx,
+        amount: Amount,
+        places: u8,
+    ) -> Result<(), Rejection> {
+        match kind {
+            Tx::Deposit => self.deposit(client, amount, places),
+            Tx::Withdrawal => self.withdraw(client, amount, places),
+            Tx::Dispute => self.dispute(client, amount, places),
+            Tx::Resolve => self.resolve(client, amount, places),
+            Tx::Chargeback => self.chargeback(client, amount, places),
+        }
+    }
+
+    /// Credit `amount` to the available funds
+    pub fn deposit(
+        &mut self,
+        client: ClientID,
+        amount: Amount,
+        places: u8,
+    ) -> Result<(), Rejection> {
+        self.set_funds(client, (self.available + amount, self.held), places, false)
+    }
+
+    /// Debit `amount` from the available funds, if they cover it
+    pub fn withdraw(
+        &mut self,
+        client: ClientID,
+        amount: Amount,
+        places: u8,
+    ) -> Result<(), Rejection> {
+        let tolerance = withdrawal_tolerance(places);
+        if amount.0 > self.available.0 + tolerance {
+            return Err(Rejection::InsufficientFunds {
+                client,
+                requested: amount,
+                available: self.available,
+            });
+        }
+        // Withdrawing the whole balance leaves nothing, rather than the drift
+        let available = match (self.available - amount).0 {
+            rest if rest.abs() <= tolerance => Amount::ZERO,
+            rest => Amount(rest),
+        };
+        self.set_funds(client, (available, self.held), places, false)
+    }
+
+    /// Hold the `amount` of a disputed transaction, even if it takes available funds below zero
+    /// (disputing funds already withdrawn)
+    pub fn dispute(
+        &mut self,
+        client: ClientID,
+        amount: Amount,
+        places: u8,
+    ) -> Result<(), Rejection> {
+        let funds = (self.available - amount, self.held + amount);
+        self.settle(client, Tx::Dispute, amount, funds, places)
+    }
+
+    /// Release the held `amount` of a transaction no longer disputed
+    pub fn resolve(
+        &mut self,
+        client: ClientID,
+        amount: Amount,
+        places: u8,
+    ) -> Result<(), Rejection> {
+        let funds = (self.available + amount, self.held - amount);
+        self.settle(client, Tx::Resolve, amount, funds, places)
+    }
+
+    /// Withdraw the held `amount` of a disputed transaction for good, locking the account
+    pub fn chargeback(
+        &mut self,
+        client: ClientID,
+        amount: Amount,
+        places: u8,
+    ) -> Result<(), Rejection> {
+        let funds = (self.available, self.held - amount);
+        self.settle(client, Tx::Chargeback, amount, funds, places)
+    }
+
+    /// Dispute, resolve or charge back (as `kind` tells) a withdrawal of `amount`, moving funds as
+    /// `policy` tells (`Hold` and `Reject` moving them as for a deposit, a rejection being up to
+    /// the engine)
+    pub fn settle_withdrawal(
+        &mut self,
+        client: ClientID,
+        kind: Tx,
+        amount: Amount,
+        places: u8,
+        policy: WithdrawalDisputePolicy,
+    ) -> Result<(), Rejection> {
+        match policy.funds(self, kind, amount) {
+            Some(funds) => self.settle(client, kind, amount, funds, places),
+            None => self.apply(client, kind, amount, places),
+        }
+    }
+
+    /// Set the `funds` a dispute, resolve or chargeback (as `kind` tells) of a transaction of
+    /// `amount` results in, and count it: a chargeback locks the account too
+    fn settle(
+        &mut self,
+        client: ClientID,
+        kind: Tx,
+        amount: Amount,
+        funds: (Amount, Amount),
+        places: u8,
+    ) -> Result<(), Rejection> {
+        self.set_funds(
+            client,
+            funds,
+            places,
+            matches!(kind, Tx::Dispute | Tx::Resolve),
+        )?;
+        match kind {
+            Tx::Dispute => self.dispute_count = self.dispute_count.saturating_add(1),
+            Tx::Chargeback => {
+                self.status = LedgerStatus::Locked;
+                self.chargeback_count = self.chargeback_count.saturating_add(1);
+                self.chargeback_amount = self.chargeback_amount + amount;
+            }
+            Tx::Deposit | Tx::Withdrawal | Tx::Resolve => {}
+        }
+        Ok(())
+    }
+}
+
+/// How the dispute of a withdrawal moves funds, once withdrawals are disputable (see
+/// `Options::disputable`). Unlike a deposit, a withdrawal took its amount out of the account, so
+/// policies differ in where it goes back while disputed. Whatever the policy, `total` is
+/// `available + held`: what differs is whether the dispute changes it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WithdrawalDisputePolicy {
+    /// As for a deposit: the amount moves from available funds (below zero if they were spent) to
+    /// held ones, total funds being unchanged. A resolve releases it back to available funds, a
+    /// chargeback takes it away.
+    #[default]
+    Hold,
+    /// Disputes of withdrawals are rejected with `Rejection::NotDisputable`
+    Reject,
+    /// The amount is credited back to held funds, available ones being untouched, so total funds
+    /// rise by it. A resolve takes it away again (the withdrawal stands), a chargeback releases it
+    /// to available funds (the withdrawal is reversed for good).
+    CreditHeld,
+    /// The amount is credited back to available funds right away, so total funds rise by it. A
+    /// resolve debits it again (the withdrawal stands, even if that takes available funds below
+    /// zero), a chargeback leaves funds as they are (the withdrawal is reversed for good).
+    Reverse,
+}
+
+impl WithdrawalDisputePolicy {
+    /// Available and held funds of `ledger` once a withdrawal of `amount` is disputed, resolved
+    /// or charged back (as `kind` tells), or nothing if they move as for a deposit
+    fn funds(self, ledger: &Ledger, kind: Tx, amount: Amount) -> Option<(Amount, Amount)> {
+        let (available, held) = (ledger.available, ledger.held);
+        match (self, kind) {
+            (WithdrawalDisputePolicy::CreditHeld, Tx::Dispute) => Some((available, held + amount)),
+            (WithdrawalDisputePolicy::CreditHeld, Tx::Resolve) => Some((available, held - amount)),
+            (WithdrawalDisputePolicy::CreditHeld, Tx::Chargeback) => {
+                Some((available + amount, held - amount))
+            }
+            (WithdrawalDisputePolicy::Reverse, Tx::Dispute) => Some((available + amount, held)),
+            (WithdrawalDisputePolicy::Reverse, Tx::Resolve) => Some((available - amount, held)),
+            (WithdrawalDisputePolicy::Reverse, Tx::Chargeback) => Some((available, held)),
+            _ => None,
+        }
+    }
+}
+
+/// Lifecycle of a disputed transaction, see `Engine::write_dispute_trace_csv`
+#[derive(Clone, Debug)]
+struct DisputeTrace {
+    client: ClientID,
+    /// Amount of the transaction, as held by its first dispute
+    amount: Amount,
+    /// Dispute events applied, in order: `dispute`, `resolve`, `chargeback` or `expired`, along
+    /// with the line of the row applied (the one after which the dispute expired for `expired`)
+    events: Vec<(&'static str, u64)>,
+}
+
+/// Chargebacks and transactions of a client, summed over its accounts
+#[derive(Clone, Copy, Default)]
+struct ChargebackCounts {
+    chargebacks: u64,
+    transactions: u64,
+}
+
+impl ChargebackCounts {
+    /// An account opened by rejected transactions only has a rate of zero
+    fn rate(self) -> f64 {
+        match self.transactions {
+            0 => 0.0,
+            transactions => self.chargebacks as f64 / transactions as f64,
+        }
+    }
+}
+
+/// By default every client get an empty of fund unlocked account
+impl Default for Ledger {
+    fn default() -> Self {
+        Ledger {
+            available: Amount(0.0),
+            held: Amount(0.0),
+            status: LedgerStatus::Default,
+            tx_count: 0,
+            dispute_count: 0,
+            chargeback_count: 0,
+            chargeback_amount: Amount(0.0),
+            auto_locked: false,
+            min_available_seen: Amount(0.0),
+            dispute_tx_sequence: None,
+        }
+    }
+}
+
+/// An entry of a JSON snapshot, see `Engine::load_snapshot_json`
+#[derive(Deserialize)]
+struct LedgerEntry {
+    client: ClientID,
+    #[serde(default)]
+    currency: Option<Currency>,
+    ledger: Ledger,
+}
+
+/// How an account changed between two states of an engine, see `Engine::diff`
+#[derive(Debug, PartialEq)]
+pub struct AccountDiff {
+    pub client: ClientID,
+    pub available_delta: Amount,
+    pub held_delta: Amount,
+    /// Status before and after, if it changed
+    pub status_changed: Option<(LedgerStatus, LedgerStatus)>,
+}
+
+/// Transactions of an account still under dispute, see `Engine::open_disputes`
+#[derive(Debug, PartialEq)]
+pub struct OpenDisputes {
+    pub client: ClientID,
+    /// Currency of the account in multi-currency mode, `None` for the default one
+    pub currency: Option<Currency>,
+    pub count: usize,
+    /// Sum of the disputed amounts, which is what the account holds
+    pub held: Amount,
+}
+
+/// Totals over every account, for batch operators, see `Engine::summarize`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EngineSummary {
+    pub account_count: usize,
+    /// Available funds of every account (summed over currencies in multi-currency mode)
+    pub total_available: Amount,
+    pub total_held: Amount,
+    pub locked_accounts: usize,
+    /// Transactions currently under dispute
+    pub active_disputes: usize,
+    /// Rows skipped, rejected or malformed (see `Options::skip_parse_errors`)
+    pub skipped_rows: u64,
+}
+
+/// Transactions applied to the accounts of a client (summed over currencies in multi-currency
+/// mode), see `Engine::per_client_stats`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClientStats {
+    pub deposits: u32,
+    pub deposit_total: Amount,
+    pub withdrawals: u32,
+    pub withdrawal_total: Amount,
+    pub disputes_raised: u32,
+    pub disputes_resolved: u32,
+    pub chargebacks: u32,
+}
+
+impl Default for ClientStats {
+    fn default() -> Self {
+        ClientStats {
+            deposits: 0,
+            deposit_total: Amount(0.0),
+            withdrawals: 0,
+            withdrawal_total: Amount(0.0),
+            disputes_raised: 0,
+            disputes_resolved: 0,
+            chargebacks: 0,
+        }
+    }
+}
+
+/// What an engine went through so far, e.g. for monitoring, see `Engine::stats`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// Rows read, including skipped ones
+    pub rows: u64,
+    /// Rows of each type of transaction, in the order of `Tx` variants
+    pub per_type: [u64; 5],
+    /// Rows skipped, rejected or malformed
+    pub skipped: u64,
+    /// Rows rejected and skipped, by reason (in the order of `Rejection::REASONS`)
+    pub rejected: [u64; Rejection::REASONS.len()],
+    /// Malformed rows skipped
+    pub malformed: u64,
+    /// Rows skipped as replays, see `Options::dedupe_tx` and `Options::idempotent`
+    pub replayed: u64,
+    /// Entries removed from history once charged back
+    pub reclaimed: u64,
+    pub accounts: usize,
+    pub locked_accounts: usize,
+    /// Accounts locked for good by too many chargebacks, see `Options::auto_lock_after_chargebacks`
+    pub auto_locked: usize,
+    /// Transactions under dispute
+    pub open_disputes: usize,
+    /// Accounts and transactions the stores can hold without reallocating, to tune
+    /// `Options::expect_clients` and `Options::expect_transactions`
+    pub accounts_capacity: usize,
+    pub history_capacity: usize,
+    /// Entries added to history, and how many of them didn't have it reallocated
+    pub history_inserts: u64,
+    pub unreallocated_inserts: u64,
+}
+
+/// What a run of `process` went through
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunReport {
+    pub stats: Stats,
+    /// Time the run took, from reading the input to writing the output
+    pub elapsed: Duration,
+}
+
+impl RunReport {
+    /// The stats of the run as a JSON object, along with the time it took
+    pub fn to_json(&self) -> String {
+        self.stats.to_json(self.elapsed)
+    }
+}
+
+impl Stats {
+    /// The stats as a JSON object, along with the time the run took
+    pub fn to_json(&self, elapsed: std::time::Duration) -> String {
+        let per_type = ["deposit", "withdrawal", "dispute", "resolve", "chargeback"]
+            .iter()
+            .zip(self.per_type)
+            .fold(json::Object::new(), |object, (kind, count)| {
+                object.value(kind, Some(count))
+            });
+        let rejected = Rejection::REASONS
+            .iter()
+            .zip(self.rejected)
+            .fold(json::Object::new(), |object, (reason, count)| {
+                object.value(reason, Some(count))
+            })
+            .value("malformed", Some(self.malformed));
+        json::Object::new()
+            .value("rows", Some(self.rows))
+            .object("per_type", per_type)
+            .value("skipped", Some(self.skipped))
+            .object("skipped_by_reason", rejected)
+            .value("replayed", Some(self.replayed))
+            .value("reclaimed", Some(self.reclaimed))
+            .value("accounts", Some(self.accounts))
+            .value("locked_accounts", Some(self.locked_accounts))
+            .value("auto_locked", Some(self.auto_locked))
+            .value("open_disputes", Some(self.open_disputes))
+            .value("accounts_capacity", Some(self.accounts_capacity))
+            .value("history_capacity", Some(self.history_capacity))
+            .value("history_inserts", Some(self.history_inserts))
+            .value("unreallocated_inserts", Some(self.unreallocated_inserts))
+            .value("elapsed_ms", Some(elapsed.as_millis()))
+            .finish()
+    }
+}
+
+/// Format accounts are written in
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// See `Engine::write_accounts_json`
+    Json,
+}
+
+/// Knobs tweaking how the engine reads its input and writes its output
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Field separator of the input CSV, partners may send e.g. semicolon-delimited files (common
+    /// in European locales) or tab-delimited ones
+    pub delimiter: u8,
+    /// Whether the first line of a CSV input is a header naming its columns: without one, columns
+    /// are read by position (see `CsvSource::new`)
+    pub has_header: bool,
+    /// Format transactions are read in, see `Engine::process_input`
+    pub input_format: InputFormat,
+    /// Format accounts are written in, see `Engine::write_accounts`
+    pub output_format: OutputFormat,
+    /// Field separator of the output CSV
+    pub output_delimiter: u8,
+    /// Periodically report the number of processed rows to stderr
+    pub progress: bool,
+    /// Report rejected and skipped rows as JSON objects (see `EngineError::to_json`) to the given
+    /// sink (e.g. stderr), rather than as plain warnings, for log aggregators
+    pub json_errors: Option<ErrorSink>,
+    /// Write every row skipped (rejected, or malformed when skipping parse errors) to the given
+    /// sink, as a JSON object of the same shape, e.g. to review them after a dry run
+    pub rejected: Option<ErrorSink>,
+    /// Kinds of transactions a dispute may target (deposits only by default), a dispute of
+    /// another kind being rejected with `Rejection::NotDisputable`
+    pub disputable: Vec<Tx>,
+    /// How disputes of withdrawals move funds, once they're disputable
+    pub withdrawal_dispute_policy: WithdrawalDisputePolicy,
+    /// Only compute the accounts of these clients, e.g. to investigate a few of them out of a
+    /// huge input: transactions of other clients don't open nor change any account, and aren't
+    /// output. Their deposits and withdrawals are still recorded in history though, so that the
+    /// reuse of their IDs is rejected as in a complete run.
+    pub clients: Option<HashSet<ClientID>>,
+    /// Fail on the first rejected transaction (e.g. a withdrawal of insufficient funds), rather
+    /// than silently ignoring it
+    pub strict: bool,
+    /// Skip rows that can't be parsed as a transaction (e.g. a non-numeric client ID), rather than
+    /// aborting, independently of `strict`
+    pub skip_parse_errors: bool,
+    /// Flag disputes, resolves and chargebacks referencing a transaction that only appears later
+    /// in the input, transactions being expected in chronological order
+    pub check_ordering: bool,
+    /// Compare the timestamps of successive rows (from an optional `ts` column), to warn about or
+    /// fail on rows that aren't in chronological order. Accounts don't depend on timestamps.
+    pub check_order: OrderCheck,
+    /// Silently skip a deposit or withdrawal seen before with the very same type, client, amount
+    /// (and currency), e.g. when inputs overlap, rather than rejecting it as a duplicate (one with
+    /// different contents still is). Disputes, resolves and chargebacks have no ID of their own,
+    /// and an exact repeat may be legitimate (e.g. a second dispute after a resolve), so they
+    /// aren't deduplicated: replaying the ones already applied leaves accounts as they were
+    /// anyway, each just sets the disputed state its transaction already is in.
+    pub dedupe_tx: bool,
+    /// Silently skip rows that already took effect, so that delivering an input again (e.g. at
+    /// least once, to an engine restored with `with_transaction_history` and
+    /// `with_charged_back_transactions`) leaves accounts as delivering it once:
+    /// a deposit or withdrawal whose ID is already in history, whatever its contents, a dispute of
+    /// a transaction under dispute, and a chargeback of one charged back. Unlike a duplicate being
+    /// rejected, nothing is reported. Rows rejected the first time aren't in history, so they're
+    /// tried again, as is a dispute of a resolved transaction (and then its resolve).
+    pub idempotent: bool,
+    /// Lock an account for good once this many chargebacks were applied to it, to freeze serial
+    /// abusers: a chargeback locks the account anyway, but resolving a dispute still pending
+    /// unlocks it, which this prevents. Further rows are rejected with `Rejection::AutoLocked`.
+    pub auto_lock_after_chargebacks: Option<u32>,
+    /// Same, once the chargebacks applied to an account total more than this amount
+    pub auto_lock_chargeback_amount: Option<Amount>,
+    /// Resolve a dispute that's neither resolved nor charged back once this many more
+    /// transactions were applied (by any client), so that an abandoned dispute doesn't hold
+    /// funds forever. The account is left locked if it is.
+    pub dispute_expiry_after: Option<u64>,
+    /// Keep a separate account per client and currency, rather than a single asset account per
+    /// client, when transactions specify a currency
+    pub multi_currency: bool,
+    /// Write accounts that hold nothing and aren't locked, e.g. the ones of clients whose every
+    /// transaction was rejected
+    pub include_empty_accounts: bool,
+    /// Sort output rows by client ID, and then currency (row ordering doesn't matter, but it makes
+    /// output stable)
+    pub sorted: bool,
+    /// Number of places past the decimal every input amount is rounded to
+    pub input_precision: u8,
+    /// Number of places past the decimal output amounts are formatted with, regardless of their
+    /// stored precision (4 by default)
+    pub output_precision: Option<u8>,
+    /// Whether output amounts keep trailing zeros
+    pub amount_format: AmountFormat,
+    /// Read amounts formatted for humans, e.g. `$1.50` or `1,000.50`, rather than rejecting them
+    /// as malformed. A thousands separator that is also the field separator splits the amount
+    /// over two fields though (`1,000.50` is then a `1` amount with an extra `000.50` field), so
+    /// such amounts need another delimiter, e.g. `;` with `1.000,50` or `1,000.50`.
+    pub lenient_amounts: Option<LenientAmounts>,
+    /// How strictly the text of amounts is checked: `Strict` rejects amounts such as `1e3`,
+    /// `+5`, `1_000`, `.5` or `1,5` (as `Rejection::BadAmount`), which are otherwise read as floats
+    /// or rejected as malformed rows
+    pub validate_amounts: AmountValidation,
+    /// Log a warning naming the columns of a CSV header that are ignored, i.e. the ones other than
+    /// `type`, `client`, `tx`, `amount`, `currency` and `ts`, e.g. to catch a misspelled one
+    pub warn_unknown_columns: bool,
+    /// Data-structure backing client accounts
+    pub accounts_store: StoreKind,
+    /// Data-structure backing the transaction history
+    pub history_store: StoreKind,
+    /// Number of accounts to allocate room for upfront, so that huge inputs don't go through
+    /// dozens of rehashes (copying every account each time) as accounts are opened
+    pub expect_clients: Option<usize>,
+    /// Same for the transaction history, see `estimate_transactions` to guess it
+    pub expect_transactions: Option<usize>,
+    /// Flag checked between rows, processing stops with `EngineError::Interrupted` once it's
+    /// raised (e.g. by a signal handler)
+    pub interrupt: Option<&'static AtomicBool>,
+    /// Cap on the number of transactions kept in history, the least recently used (deposited,
+    /// withdrawn or disputed) being evicted first. This trades dispute coverage for bounded
+    /// memory: a dispute of an evicted transaction is rejected as unknown, a replay of one isn't
+    /// detected anymore, and funds held by an evicted dispute can't be released.
+    pub max_history: Option<usize>,
+    /// Cap on the number of accounts (one per client, and per currency in multi-currency mode),
+    /// e.g. for a long-running server: a deposit or withdrawal that would open one more is
+    /// rejected with `Rejection::TooManyClients`, existing accounts being unaffected
+    pub max_clients: Option<usize>,
+    /// Stop reading once this many rows were read (over every input, rejected and skipped ones
+    /// included), e.g. to sanity-check the first rows of a huge file: the accounts are those
+    /// resulting from these rows
+    pub max_rows: Option<u64>,
+    /// What to do once the history holds `max_history` transactions: evict the least recently
+    /// used one (as described above), fail, or spill it to disk. Spilled transactions are brought
+    /// back when referenced again, so disputes keep working at the cost of disk I/O, but they
+    /// aren't part of `open_disputes` while on disk.
+    pub history_overflow: HistoryOverflow,
+    /// Counters and gauges updated as rows are processed, e.g. to be served by `metrics::serve`
+    pub metrics: Option<Arc<Metrics>>,
+    /// Where to emit an event for every transaction applied, see `events.rs`
+    pub events: Option<EventSink>,
+    /// Keep every transaction read, in order (rejected ones included, as they may still open an
+    /// account), for `Engine::export_history` to give them, e.g. to `Engine::replay` them later
+    pub keep_log: bool,
+}
+
+impl Options {
+    /// A reader of the CSV of `rdr`, as configured
+    pub(crate) fn csv_reader<R: Read>(&self, rdr: R) -> csv::Reader<R> {
+        // The following code is heavily inspired by CSV crate usage example
+        // from https://docs.rs/csv/latest/csv/#example-with-serde
+        csv::ReaderBuilder::new()
+            // Because it's not explicitly specified of we should handle the absence of amount
+            // field... https://docs.rs/csv/latest/csv/struct.ReaderBuilder.html#method.flexible
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .delimiter(self.delimiter)
+            .has_headers(self.has_header)
+            .buffer_capacity(READ_BUFFER)
+            .from_reader(rdr)
+    }
+
+    /// Rows of the CSV read from `rdr`, once its header is read and its unknown columns reported
+    pub(crate) fn csv_source<R: Read>(&self, rdr: R) -> Result<CsvSource<R>, EngineError> {
+        let source = CsvSource::new(self.csv_reader(rdr), self.check_order)?
+            .with_lenient_amounts(self.lenient_amounts.clone())
+            .with_amount_validation(self.validate_amounts);
+        let unknown = source.unknown_columns();
+        if self.warn_unknown_columns && !unknown.is_empty() {
+            warn!("ignoring unknown columns: {}", unknown.join(", "));
+        }
+        if source.header_is_row() {
+            warn!(
+                "the header line looks like a transaction, which isn't applied (see --no-header)"
+            );
+        }
+        Ok(source)
+    }
+}
+
+/// By default, input and output are both plain comma-separated values
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            delimiter: b',',
+            has_header: true,
+            output_delimiter: b',',
+            input_format: InputFormat::default(),
+            output_format: OutputFormat::default(),
+            progress: false,
+            json_errors: None,
+            rejected: None,
+            disputable: vec![Tx::Deposit],
+            withdrawal_dispute_policy: WithdrawalDisputePolicy::default(),
+            strict: cfg!(feature = "strict_mode"),
+            skip_parse_errors: false,
+            check_ordering: false,
+            check_order: OrderCheck::default(),
+            dedupe_tx: false,
+            idempotent: false,
+            clients: None,
+            auto_lock_after_chargebacks: None,
+            auto_lock_chargeback_amount: None,
+            dispute_expiry_after: None,
+            multi_currency: false,
+            include_empty_accounts: true,
+            sorted: cfg!(feature = "sorted"),
+            input_precision: 4,
+            output_precision: None,
+            amount_format: AmountFormat::default(),
+            lenient_amounts: None,
+            validate_amounts: AmountValidation::default(),
+            warn_unknown_columns: false,
+            accounts_store: StoreKind::default(),
+            history_store: StoreKind::default(),
+            expect_clients: None,
+            expect_transactions: None,
+            max_history: None,
+            max_clients: None,
+            max_rows: None,
+            history_overflow: HistoryOverflow::default(),
+            interrupt: None,
+            metrics: None,
+            events: None,
+            keep_log: false,
+        }
+    }
+}
+
+/// The state of the payments engine: client accounts and the history of transactions needed to
+/// handle disputes. Owning it (rather than relying on a global) lets several engines live side by
+/// side, e.g. one per concurrent TCP stream.
+#[derive(Debug, Default)]
+pub struct Engine {
+    // This `accounts` data-structure could be in the future an abstraction around a cold-storage
+    // database (using e.g. CBOR or SLED)
+    accounts: AccountStore,
+    /// History of deposit and withdrawal amounts, referenced by disputes
+    history: TxHistory,
+    /// Transactions charged back, by client: they can't be referenced anymore, so their entries
+    /// are removed from history, and only this much is kept to tell references to them apart
+    /// from references to unknown transactions
+    charged_back: HashMap<TxID, ClientID>,
+    /// Number of history entries removed once charged back
+    reclaimed_count: u64,
+    /// Number of entries added to history, and how many of them fit without reallocating it
+    history_inserts: u64,
+    unreallocated_inserts: u64,
+    /// Number of rows of clients left out, see `Options::clients`
+    filtered_count: u64,
+    /// Number of transactions applied so far, numbering them for dispute expiry
+    sequence: u64,
+    /// With dispute expiry, sequence numbers of the disputes still pending by transaction, and
+    /// the same in order of expiry (including disputes resolved since, which are skipped then)
+    disputed_at: HashMap<TxID, u64>,
+    dispute_expiries: VecDeque<(u64, TxID)>,
+    /// IDs of the deposits and withdrawals applied to every client, in order, see
+    /// `per_client_history`
+    client_history: HashMap<ClientID, Vec<TxID>>,
+    /// Transactions applied to every client, see `per_client_stats`
+    client_stats: HashMap<ClientID, ClientStats>,
+    /// In multi-currency mode, accounts of the clients in currencies other than the default one
+    wallets: HashMap<(ClientID, Currency), Ledger>,
+    /// In multi-currency mode, currency of the transactions of history that specify one
+    currencies: HashMap<TxID, Currency>,
+    /// Number of rows attempted so far, including rejected or unparsable ones
+    processed_count: u64,
+    /// Number of rows rejected or unparsable, and skipped rather than aborting processing
+    skipped_count: u64,
+    /// Number of duplicate rows skipped as replays, see `Options::dedupe_tx` and
+    /// `Options::idempotent`
+    replayed_count: u64,
+    /// Number of rows of each type of transaction, see `Stats::per_type`
+    type_counts: [u64; 5],
+    /// Number of rows rejected and skipped, by reason, see `Stats::rejected`
+    rejected_counts: [u64; Rejection::REASONS.len()],
+    /// Number of malformed rows skipped
+    malformed_count: u64,
+    /// When deduplicating, rejected deposits and withdrawals, so that a replay of one isn't
+    /// applied after all (e.g. a withdrawal that only the funds of later deposits would cover)
+    rejected: HashMap<TxID, (store::TxEntry, Option<Currency>)>,
+    /// With a bounded history, recency of use of the transactions in history
+    lru: Lru,
+    /// Number of transactions evicted from a bounded history
+    evicted_count: u64,
+    /// Transactions spilled out of a bounded history (several stores once engines are merged)
+    spilled: Vec<DiskTxStore>,
+    /// When checking ordering, transactions referenced before being seen, with the line of their
+    /// first reference
+    unseen: HashMap<TxID, u64>,
+    /// When checking order, line and timestamp of the last timestamped row
+    last_timestamp: Option<(u64, Timestamp)>,
+    /// Position of every column of `LINE_COLUMNS` in the lines given to `process_line`, once
+    /// known, and the number of lines given so far
+    line_columns: Option<[Option<usize>; 6]>,
+    line_count: u64,
+    /// In a two-pass run, the only transactions worth keeping in history, see `process_two_pass`
+    retained: Option<HashSet<TxID>>,
+    /// Every transaction read, with `Options::keep_log`
+    log: Vec<Transaction>,
+    /// Lifecycle of every transaction disputed so far
+    dispute_traces: HashMap<TxID, DisputeTrace>,
+    options: Options,
+}
+
+impl Engine {
+    /// An engine with no accounts yet, reading and writing CSV as configured by `options`
+    pub fn new(options: Options) -> Engine {
+        Engine {
+            accounts: AccountStore::with_capacity(
+                options.accounts_store,
+                options.expect_clients.unwrap_or(0),
+            ),
+            history: TxHistory::with_capacity(
+                options.history_store,
+                options.expect_transactions.unwrap_or(0),
+            ),
+            options,
+            ..Engine::default()
+        }
+    }
+
+    /// An engine with room for `clients` accounts and `transactions` history entries before
+    /// having to reallocate, for large batches whose size is known in advance (transaction IDs
+    /// spanning the whole `u32` range, rehashing a growing history gets expensive)
+    pub fn with_capacity(clients: usize, transactions: usize) -> Engine {
+        Engine {
+            accounts: AccountStore::with_capacity(StoreKind::HashMap, clients),
+            history: TxHistory::with_capacity(StoreKind::HashMap, transactions),
+            ..Engine::default()
+        }
+    }
+
+    /// An engine restored from the accounts of a snapshot (see `Engine::snapshot`), e.g. saved by
+    /// a previous run. Transactions from before the snapshot can't be disputed until their
+    /// history is restored too, see `with_transaction_history`.
+    pub fn load_snapshot(options: Options, snapshot: &AccountsSnapshot) -> Engine {
+        let mut engine = Engine::new(options);
+        for account in snapshot.iter() {
+            let ledger = Ledger {
+                available: account.available,
+                held: account.held,
+                status: match account.locked {
+                    true => LedgerStatus::Locked,
+                    false => LedgerStatus::Default,
+                },
+                // Snapshots don't keep track of counts, they start over
+                ..Ledger::default()
+            };
+            match &account.currency {
+                Some(currency) => {
+                    engine
+                        .wallets
+                        .insert((account.client, currency.clone()), ledger);
+                }
+                None => *engine.accounts.get_or_default(account.client) = ledger,
+            }
+        }
+        engine
+    }
+
+    /// An engine restored from a JSON snapshot (see `write_snapshot_json`), i.e. an array of
+    /// `{"client": 1, "ledger": {"available": 1.5, "held": 0, "status": "Default", ...}}`
+    /// objects (along with a `currency` for multi-currency wallets). Unlike `load_snapshot`,
+    /// ledgers are restored whole, counts included, so that policies depending on them (e.g.
+    /// `Options::auto_lock_after_chargebacks`) carry on where the previous run left off.
+    pub fn load_snapshot_json<R: Read>(
+        options: Options,
+        mut rdr: R,
+    ) -> Result<Engine, EngineError> {
+        let mut json = String::new();
+        rdr.read_to_string(&mut json)?;
+        let error = |line, message| EngineError::Parse {
+            line,
+            field: None,
+            message,
+        };
+        let entries = json::parse_array(&json).map_err(|(line, message)| error(line, message))?;
+        let mut engine = Engine::new(options);
+        for (line, entry) in entries {
+            let entry = LedgerEntry::deserialize(entry).map_err(|e| error(line, e.to_string()))?;
+            match entry.currency {
+                Some(currency) => {
+                    engine
+                        .wallets
+                        .insert((entry.client, currency), entry.ledger);
+                }
+                None => *engine.accounts.get_or_default(entry.client) = entry.ledger,
+            }
+        }
+        Ok(engine)
+    }
+
+    /// Restore the history of transactions (see `transaction_history`) along with the accounts,
+    /// so that disputes, resolves and chargebacks arriving after a snapshot still find the
+    /// transactions they reference. Entries keep their client, kind and dispute state, which is
+    /// what it takes to settle them; in multi-currency mode, they are settled in the default
+    /// currency.
+    pub fn with_transaction_history(mut self, history: HashMap<TxID, store::TxEntry>) -> Engine {
+        for (id, entry) in history {
+            self.history.insert(id, entry);
+            if self.options.max_history.is_some() {
+                self.lru.touch(id);
+            }
+        }
+        self
+    }
+
+    /// A copy of the history of transactions, to be saved along with a snapshot of the accounts
+    /// (transactions charged back being no longer part of it, see `charged_back_transactions`)
+    pub fn transaction_history(&self) -> HashMap<TxID, store::TxEntry> {
+        self.history
+            .iter()
+            .map(|(id, entry)| (id, *entry))
+            .collect()
+    }
+
+    /// Restore the transactions charged back (see `charged_back_transactions`) along with the
+    /// history, so that they are still told apart from unknown ones, and can't be reused
+    pub fn with_charged_back_transactions(
+        mut self,
+        charged_back: HashMap<TxID, ClientID>,
+    ) -> Engine {
+        self.charged_back.extend(charged_back);
+        self
+    }
+
+    /// The transactions charged back, by client, which are no longer in history: to be saved along
+    /// with it
+    pub fn charged_back_transactions(&self) -> HashMap<TxID, ClientID> {
+        self.charged_back.clone()
+    }
+
+    /// IDs of the deposits and withdrawals applied to the account(s) of `client`, in the order
+    /// they were applied, e.g. for an audit trail. Unlike history, this keeps transactions that
+    /// can't be disputed anymore (evicted, charged back, or not retained by a two-pass run), and
+    /// leaves out rejected ones.
+    pub fn per_client_history(&self, client: ClientID) -> Vec<TxID> {
+        self.client_history
+            .get(&client)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Counts and totals of the transactions applied to the accounts of `client` (rejected ones
+    /// being left out), or nothing if none was
+    pub fn per_client_stats(&self, client: ClientID) -> Option<ClientStats> {
+        self.client_stats.get(&client).copied()
+    }
+
+    /// Number of client accounts (one per client and currency in multi-currency mode)
+    pub fn account_count(&self) -> usize {
+        self.accounts.len() + self.wallets.len()
+    }
+
+    /// Number of deposit and withdrawal transactions recorded in history
+    pub fn transaction_count(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Whether the deposit or withdrawal `tx_id` is in history, e.g. to check that a dispute can
+    /// reference it before raising one. Transactions rejected, charged back or evicted aren't, and
+    /// neither are those spilled to disk (which are only read back once referenced).
+    pub fn transaction_exists(&self, tx_id: TxID) -> bool {
+        self.history.contains(tx_id)
+    }
+
+    /// The amount of the deposit or withdrawal `tx_id`, if it is in history (see
+    /// `transaction_exists`)
+    pub fn transaction_amount(&self, tx_id: TxID) -> Option<Amount> {
+        self.history.get(tx_id).map(|entry| entry.amount)
+    }
+
+    /// Every transaction read so far, in order, with `Options::keep_log` (nothing otherwise): the
+    /// log of events the state of the engine results from, see `replay`
+    pub fn export_history(&self) -> &[Transaction] {
+        &self.log
+    }
+
+    /// An engine (with the default options) that processed `transactions` in order, e.g. the
+    /// ones exported by another engine, so as to rebuild its state from scratch. Errors refer to
+    /// transactions by their position, from 1.
+    pub fn replay(transactions: &[Transaction]) -> Result<Engine, EngineError> {
+        let mut engine = Engine::new(Options::default());
+        let mut transactions = transactions.iter().cloned().map(Ok);
+        let mut number = 0;
+        engine.process_source(&mut || {
+            number += 1;
+            Some((number, transactions.next()?))
+        })?;
+        Ok(engine)
+    }
+
+    /// Total number of rows seen, whether they were applied, rejected or couldn't be parsed
+    pub fn processed_count(&self) -> u64 {
+        self.processed_count
+    }
+
+    /// Number of transactions evicted so far from a bounded history, see `Options::max_history`
+    pub fn evicted_count(&self) -> u64 {
+        self.evicted_count
+    }
+
+    /// Number of rows skipped so far, because they were rejected or couldn't be parsed
+    pub fn skipped_count(&self) -> u64 {
+        self.skipped_count
+    }
+
+    /// Number of duplicate rows skipped as replays of earlier ones, see `Options::dedupe_tx` and
+    /// `Options::idempotent`
+    pub fn replayed_count(&self) -> u64 {
+        self.replayed_count
+    }
+
+    /// Counters of everything processed so far
+    pub fn stats(&self) -> Stats {
+        let ledgers = self.accounts.iter().map(|(_, ledger)| ledger);
+        let ledgers = ledgers.chain(self.wallets.values());
+        let (mut locked, mut auto_locked) = (0, 0);
+        for ledger in ledgers {
+            locked += usize::from(ledger.status == LedgerStatus::Locked);
+            auto_locked += usize::from(ledger.auto_locked);
+        }
+        Stats {
+            rows: self.processed_count,
+            per_type: self.type_counts,
+            skipped: self.skipped_count,
+            rejected: self.rejected_counts,
+            malformed: self.malformed_count,
+            replayed: self.replayed_count,
+            reclaimed: self.reclaimed_count,
+            accounts: self.account_count(),
+            locked_accounts: locked,
+            auto_locked,
+            open_disputes: self.history.iter().filter(|(_, e)| e.disputed).count(),
+            accounts_capacity: self.accounts.capacity(),
+            history_capacity: self.history.capacity(),
+            history_inserts: self.history_inserts,
+            unreallocated_inserts: self.unreallocated_inserts,
+        }
+    }
+
+    /// Client accounts (in the default currency), for read-only access
+    pub fn accounts(&self) -> &AccountStore {
+        &self.accounts
+    }
+
+    /// Take the client accounts (in the default currency) out of the engine, e.g. to hand them over
+    /// to another system without cloning them
+    pub fn into_accounts(self) -> HashMap<ClientID, Ledger> {
+        self.accounts.into_map()
+    }
+
+    /// Replace the client accounts (in the default currency) with `accounts`, e.g. taken out of a
+    /// previous run by `into_accounts`, to carry on from there. Ledgers are kept whole, counts
+    /// included; transactions from before need their history restored as well to be disputed,
+    /// see `with_transaction_history`.
+    pub fn import_accounts(&mut self, accounts: HashMap<ClientID, Ledger>) {
+        self.accounts = AccountStore::with_capacity(self.options.accounts_store, accounts.len());
+        for (client, ledger) in accounts {
+            *self.accounts.get_or_default(client) = ledger;
+        }
+    }
+
+    /// Combine the states of engines that processed inputs of disjoint sets of clients, e.g. on
+    /// separate threads. The transactions of a client only make sense in order, so a client with
+    /// an account in both engines is a conflict, rather than something to reconcile.
+    ///
+    /// The result is the engine that would have processed the input of `self` and then the one of
+    /// `other`: the transactions of `other` are numbered after the ones of `self`, so that disputes
+    /// expire as they would have (see `Options::dispute_expiry_after`), and the merged history is
+    /// bounded again (see `Options::max_history`).
+    pub fn merge(mut self, other: Engine) -> Result<Engine, EngineError> {
+        let clients = |engine: &Engine| {
+            let accounts = engine.accounts.iter().map(|(client, _)| client);
+            let wallets = engine.wallets.keys().map(|(client, _)| *client);
+            accounts.chain(wallets).collect::<HashSet<_>>()
+        };
+        if let Some(client) = clients(&self).intersection(&clients(&other)).min() {
+            return Err(EngineError::MergeConflict(*client));
+        }
+        let offset = self.sequence;
+        let rebase = |mut ledger: Ledger| {
+            ledger.dispute_tx_sequence = ledger.dispute_tx_sequence.map(|n| n + offset);
+            ledger
+        };
+        for (client, ledger) in other.accounts.into_map() {
+            *self.accounts.get_or_default(client) = rebase(ledger);
+        }
+        let wallets = other.wallets.into_iter();
+        self.wallets
+            .extend(wallets.map(|(wallet, ledger)| (wallet, rebase(ledger))));
+        self.sequence += other.sequence;
+        // Disputes of `self` were all raised before the ones of `other`, so expiries stay in order
+        let disputed_at = other.disputed_at.into_iter();
+        self.disputed_at
+            .extend(disputed_at.map(|(tx, raised)| (tx, raised + offset)));
+        let expiries = other.dispute_expiries.into_iter();
+        self.dispute_expiries
+            .extend(expiries.map(|(raised, tx)| (raised + offset, tx)));
+        // Transaction IDs being globally unique, histories are disjoint as well
+        for (id, entry) in other.history.iter() {
+            self.history.insert(id, *entry);
+            if self.options.max_history.is_some() {
+                self.lru.touch(id);
+            }
+        }
+        self.currencies.extend(other.currencies);
+        self.charged_back.extend(other.charged_back);
+        self.client_history.extend(other.client_history);
+        self.client_stats.extend(other.client_stats);
+        self.log.extend(other.log);
+        self.dispute_traces.extend(other.dispute_traces);
+        self.filtered_count += other.filtered_count;
+        self.reclaimed_count += other.reclaimed_count;
+        self.history_inserts += other.history_inserts;
+        self.unreallocated_inserts += other.unreallocated_inserts;
+        for (id, line) in other.unseen {
+            self.unseen.entry(id).or_insert(line);
+        }
+        self.processed_count += other.processed_count;
+        self.skipped_count += other.skipped_count;
+        self.replayed_count += other.replayed_count;
+        for (count, other) in self.type_counts.iter_mut().zip(other.type_counts) {
+            *count += other;
+        }
+        for (count, other) in self.rejected_counts.iter_mut().zip(other.rejected_counts) {
+            *count += other;
+        }
+        self.malformed_count += other.malformed_count;
+        self.rejected.extend(other.rejected);
+        self.evicted_count += other.evicted_count;
+        self.spilled.extend(other.spilled);
+        // No row is being applied, hence no line, as when undoing a transaction
+        self.expire_disputes(0);
+        self.bound_history(0)?;
+        Ok(self)
+    }
+
+    /// Undo a deposit or withdrawal, e.g. to correct one applied by mistake: its amount is taken
+    /// back from (or given back to) the account, and the transaction is forgotten, as if it never
+    /// happened (its ID can be used again). A transaction under dispute must be resolved first,
+    /// and a deposit whose funds were withdrawn since can't be undone either, as that would take
+    /// available funds below zero.
+    pub fn undo_transaction(&mut self, tx_id: TxID) -> Result<(), EngineError> {
+        self.recall(tx_id)?;
+        let entry = self
+            .history
+            .get(tx_id)
+            .ok_or(EngineError::TransactionNotFound(tx_id))?;
+        if entry.disputed {
+            return Err(EngineError::TransactionUnderDispute(tx_id));
+        }
+        let (kind, client, amount) = (entry.kind, entry.client, entry.amount);
+        // Transactions of a filtered out client are only kept in history, see `Options::clients`
+        if let Some(clients) = &self.options.clients {
+            if !clients.contains(&client) {
+                self.forget(tx_id);
+                return Ok(());
+            }
+        }
+        let ledger = match self.currencies.get(&tx_id) {
+            Some(currency) => self.wallets.entry((client, currency.clone())).or_default(),
+            None => self.accounts.get_or_default(client),
+        };
+        let available = match kind {
+            Tx::Withdrawal => ledger.available + amount,
+            _ => ledger.available - amount,
+        };
+        let places = self.options.input_precision;
+        ledger
+            .set_funds(client, (available, ledger.held), places, false)
+            .map_err(|rejection| EngineError::Rejected { line: 0, rejection })?;
+        ledger.tx_count = ledger.tx_count.saturating_sub(1);
+        let stats = self.client_stats.entry(client).or_default();
+        match kind {
+            Tx::Withdrawal => {
+                stats.withdrawals = stats.withdrawals.saturating_sub(1);
+                stats.withdrawal_total = stats.withdrawal_total - amount;
+            }
+            _ => {
+                stats.deposits = stats.deposits.saturating_sub(1);
+                stats.deposit_total = stats.deposit_total - amount;
+            }
+        }
+        if let Some(history) = self.client_history.get_mut(&client) {
+            history.retain(|id| *id != tx_id);
+        }
+        self.forget(tx_id);
+        Ok(())
+    }
+
+    /// Remove a transaction from history, and everything kept about it alongside
+    fn forget(&mut self, tx_id: TxID) {
+        self.history.remove(tx_id);
+        self.currencies.remove(&tx_id);
+        self.lru.forget(tx_id);
+    }
+
+    /// A copy of the engine, e.g. as a baseline to `diff` against later. History spilled to disk
+    /// is copied to new files, as both engines then evolve separately, which may fail.
+    pub fn try_clone(&self) -> Result<Engine, EngineError> {
+        let spilled = self
+            .spilled
+            .iter()
+            .map(DiskTxStore::try_clone)
+            .collect::<std::io::Result<_>>()?;
+        Ok(Engine {
+            accounts: self.accounts.clone(),
+            history: self.history.clone(),
+            charged_back: self.charged_back.clone(),
+            reclaimed_count: self.reclaimed_count,
+            history_inserts: self.history_inserts,
+            unreallocated_inserts: self.unreallocated_inserts,
+            filtered_count: self.filtered_count,
+            sequence: self.sequence,
+            disputed_at: self.disputed_at.clone(),
+            dispute_expiries: self.dispute_expiries.clone(),
+            client_history: self.client_history.clone(),
+            client_stats: self.client_stats.clone(),
+            wallets: self.wallets.clone(),
+            currencies: self.currencies.clone(),
+            processed_count: self.processed_count,
+            skipped_count: self.skipped_count,
+            replayed_count: self.replayed_count,
+            type_counts: self.type_counts,
+            rejected_counts: self.rejected_counts,
+            malformed_count: self.malformed_count,
+            rejected: self.rejected.clone(),
+            lru: self.lru.clone(),
+            evicted_count: self.evicted_count,
+            spilled,
+            unseen: self.unseen.clone(),
+            last_timestamp: self.last_timestamp,
+            line_columns: self.line_columns,
+            line_count: self.line_count,
+            retained: self.retained.clone(),
+            log: self.log.clone(),
+            dispute_traces: self.dispute_traces.clone(),
+            options: self.options.clone(),
+        })
+    }
+
+    /// Accounts that changed since `baseline` (e.g. a copy of the engine taken with `try_clone`
+    /// before a batch run), by client ID, and by how much. Accounts missing from `baseline` start from zero
+    /// balances. Like `top_n_by_balance`, multi-currency wallets are left out.
+    pub fn diff(&self, baseline: &Engine) -> Vec<AccountDiff> {
+        let empty = Ledger::default();
+        let mut diffs = self
+            .accounts
+            .iter()
+            .filter_map(|(client, ledger)| {
+                let before = baseline.accounts.get(client).unwrap_or(&empty);
+                let diff = AccountDiff {
+                    client,
+                    available_delta: ledger.available - before.available,
+                    held_delta: ledger.held - before.held,
+                    status_changed: (ledger.status != before.status)
+                        .then_some((before.status, ledger.status)),
+                };
+                let unchanged = diff.available_delta == Amount(0.0)
+                    && diff.held_delta == Amount(0.0)
+                    && diff.status_changed.is_none();
+                (!unchanged).then_some(diff)
+            })
+            .collect::<Vec<_>>();
+        diffs.sort_by_key(|diff| diff.client);
+        diffs
+    }
+
+    /// Share of the transactions of a client that were charged back, a key signal of fraud, or
+    /// nothing if the client has no account. In multi-currency mode, every account of the client
+    /// counts.
+    pub fn chargeback_rate(&self, client: ClientID) -> Option<f64> {
+        self.chargeback_counts(Some(client))
+            .remove(&client)
+            .map(ChargebackCounts::rate)
+    }
+
+    /// Clients whose chargeback rate (see `chargeback_rate`) is above `threshold`, by client ID
+    pub fn high_risk_accounts(&self, threshold: f64) -> Vec<ClientID> {
+        let counts = self.chargeback_counts(None);
+        let high_risk = counts
+            .into_iter()
+            .filter(|(_, counts)| counts.rate() > threshold);
+        high_risk.map(|(client, _)| client).collect()
+    }
+
+    /// Counts of every account of the given client, or of every client
+    fn chargeback_counts(&self, client: Option<ClientID>) -> BTreeMap<ClientID, ChargebackCounts> {
+        let accounts = match client {
+            Some(client) => Box::new(self.accounts.get(client).map(|l| (client, l)).into_iter()),
+            None => self.accounts.iter(),
+        };
+        let wallets = self.wallets.iter().map(|((id, _), ledger)| (*id, ledger));
+        let mut counts = BTreeMap::<_, ChargebackCounts>::new();
+        for (id, ledger) in accounts.chain(wallets) {
+            if client.is_none_or(|client| client == id) {
+                let counts = counts.entry(id).or_default();
+                counts.chargebacks += u64::from(ledger.chargeback_count);
+                counts.transactions += u64::from(ledger.tx_count);
+            }
+        }
+        counts
+    }
+
+    /// The (at most) `n` accounts with the highest total funds, richest first (ties are broken by
+    /// client ID). Only the `n` first accounts get sorted, the rest is just partitioned away, which
+    /// is way cheaper than a full sort when `n` is small. Multi-currency wallets aren't ranked,
+    /// since their totals can't be compared with each other.
+    pub fn top_n_by_balance(&self, n: usize) -> Vec<(ClientID, &Ledger)> {
+        let richest_first = |a: &(ClientID, &Ledger), b: &(ClientID, &Ledger)| {
+            b.1.total().cmp(&a.1.total()).then(a.0.cmp(&b.0))
+        };
+        let mut accounts = self.accounts.iter().collect::<Vec<_>>();
+        if n < accounts.len() {
+            accounts.select_nth_unstable_by(n, richest_first);
+            accounts.truncate(n);
+        }
+        accounts.sort_unstable_by(richest_first);
+        accounts
+    }
+
+    /// Apply every transaction of a seekable input (e.g. a file), reading it twice to save memory:
+    /// the first pass only collects the transactions that disputes, resolves and chargebacks
+    /// reference (along with the ones appearing more than once, to keep detecting replays), and
+    /// the second one processes the input as `process_from_reader` does, but only keeps those in
+    /// history. Accounts end up the same, with a much smaller history when disputes are rare.
+    ///
+    /// Evictions from a bounded history (see `Options::max_history`) depend on the history
+    /// itself, so they may differ from a single pass.
+    pub fn process_two_pass<R: Read + Seek>(&mut self, mut rdr: R) -> Result<(), EngineError> {
+        let start = rdr.stream_position()?;
+        let mut retained = HashSet::new();
+        let mut seen = HashSet::new();
+        // Columns are found as by the second pass, which reports unknown ones
+        let mut csv = CsvSource::new(self.options.csv_reader(&mut rdr), OrderCheck::Off)?;
+        let (kind, id) = (csv.column(0), csv.column(2));
+        let mut record = source::record();
+        info!("collecting referenced transactions");
+        while csv.read_record(&mut record)? {
+            let field = |column: Option<usize>| column.and_then(|i| record.get(i));
+            // Malformed rows are left to the second pass to report
+            let Some(tx) = field(id).and_then(|tx| tx.parse::<TxID>().ok()) else {
+                continue;
+            };
+            match field(kind).unwrap_or("") {
+                "deposit" | "withdrawal" if seen.insert(tx) => {}
+                _ => {
+                    retained.insert(tx);
+                }
+            }
+        }
+        drop(seen);
+        info!("{} transactions to keep in history", retained.len());
+        rdr.seek(SeekFrom::Start(start))?;
+        self.retained = Some(retained);
+        let result = self.process_from_reader(rdr);
+        // Transactions of a later input are unknown to the first pass
+        self.retained = None;
+        result
+    }
+
+    /// Apply, in order, every transaction read as CSV from `rdr` to the engine state
+    pub fn process_from_reader<R: Read>(&mut self, rdr: R) -> Result<(), EngineError> {
+        let mut source = self.options.csv_source(rdr)?;
+        self.process_source(&mut source)
+    }
+
+    /// Apply every transaction read as CSV from `rdr`, as `process_from_reader` does, but with
+    /// reading and parsing rows done by a separate thread: most of the time goes to parsing, the
+    /// ledger updates being trivial, so both overlap. Rows are sent over a channel holding up to
+    /// `capacity` rows, which blocks the reading thread whenever processing lags behind. There is
+    /// a single processing thread, so transactions are still applied in order.
+    ///
+    /// Rows are sent by batches of `PIPELINE_BATCH`, as sending them one by one costs about as
+    /// much as parsing them: this suits large inputs, not ones trickling in (e.g. a followed file).
+    pub fn process_pipelined<R: Read + Send + 'static>(
+        &mut self,
+        rdr: R,
+        capacity: usize,
+    ) -> Result<(), EngineError> {
+        let mut source = self.options.csv_source(rdr)?;
+        let (sender, receiver) = mpsc::sync_channel(capacity.div_ceil(PIPELINE_BATCH));
+        let mut reader = Some(thread::spawn(move || {
+            let mut batch = Vec::with_capacity(PIPELINE_BATCH);
+            while let Some(row) = source.next_row() {
+                // There is likely nothing more to read after an I/O error
+                let io_error = matches!(&row.1, Err((EngineError::Io(_), _)));
+                batch.push(row);
+                if batch.len() == PIPELINE_BATCH || io_error {
+                    let full = std::mem::replace(&mut batch, Vec::with_capacity(PIPELINE_BATCH));
+                    // The processing thread hangs up on the first error it doesn't skip
+                    if sender.send(full).is_err() || io_error {
+                        return;
+                    }
+                }
+            }
+            let _ = sender.send(batch);
+        }));
+        let interrupt = self.options.interrupt;
+        let mut rows = Vec::new().into_iter();
+        self.process_source(&mut || loop {
+            if let Some(row) = rows.next() {
+                return Some(row);
+            }
+            // A signal doesn't wake up a thread waiting on a channel, so the flag is polled
+            match receiver.recv_timeout(Duration::from_millis(100)) {
+                Ok(batch) => rows = batch.into_iter(),
+                Err(RecvTimeoutError::Timeout) => {
+                    if interrupt.is_some_and(|interrupt| interrupt.load(atomic::Ordering::SeqCst)) {
+                        let interrupted = std::io::Error::from(std::io::ErrorKind::Interrupted);
+                        let record = csv::StringRecord::new();
+                        return Some((0, Err((EngineError::Io(interrupted), record))));
+                    }
+                }
+                // Everything was read, unless the reading thread panicked
+                Err(RecvTimeoutError::Disconnected) => {
+                    if let Err(panic) = reader.take()?.join() {
+                        std::panic::resume_unwind(panic);
+                    }
+                    return None;
+                }
+            }
+        })
+    }
+
+    /// Apply, in order, every row of `source`, whatever its format (see `source.rs`)
+    pub fn process_source(
+        &mut self,
+        source: &mut dyn TransactionSource,
+    ) -> Result<(), EngineError> {
+        let progress = self.options.progress.then(Progress::new);
+        let metrics = self.options.metrics.clone();
+        let events = self.options.events.clone();
+        let _connection = metrics.as_ref().map(Metrics::connect);
+        info!("reading transactions");
+        let interrupt = self.options.interrupt;
+        let interrupted =
+            || interrupt.is_some_and(|interrupt| interrupt.load(atomic::Ordering::SeqCst));
+        loop {
+            if interrupted() {
+                warn!("interrupted after {} rows", self.processed_count);
+                return Err(EngineError::Interrupted(self.processed_count));
+            }
+            if let Some(max_rows) = self.options.max_rows {
+                if self.processed_count >= max_rows {
+                    info!("stopped after {} rows", max_rows);
+                    break;
+                }
+            }
+            let Some((line, parsed)) = source.next_row() else {
+                break;
+            };
+            let mut tx = match parsed {
+                Ok(tx) => tx,
+                // A read interrupted by the signal that raised the flag
+                Err(_) if interrupted() => continue,
+                Err((e, record)) => match (self.rejected_row(&e, &record), e) {
+                    (Some(rejection), _) => {
+                        self.processed_count += 1;
+                        if let Some(metrics) = &metrics {
+                            metrics.received();
+                            metrics.rejected(&rejection);
+                        }
+                        if self.options.strict {
+                            return Err(EngineError::Rejected { line, rejection });
+                        }
+                        self.skip(Some(&rejection));
+                        let tx = record.get(2).and_then(|tx| tx.parse().ok());
+                        let error = EngineError::Rejected { line, rejection };
+                        self.report(&error, None, tx, || {
+                            format!("line {}: rejected row: {}", line, error.detail())
+                        })?;
+                        continue;
+                    }
+                    // An I/O error can't be skipped, there is likely nothing more to read
+                    (None, e @ EngineError::Parse { .. }) if self.options.skip_parse_errors => {
+                        self.processed_count += 1;
+                        if let Some(metrics) = &metrics {
+                            metrics.received();
+                        }
+                        self.skip(None);
+                        self.report(&e, None, None, || format!("skipped {}", e))?;
+                        continue;
+                    }
+                    (None, e) => return Err(e),
+                },
+            };
+            self.processed_count += 1;
+            if let Some(progress) = &progress {
+                progress.tick(self.processed_count);
+            }
+            let start = metrics.as_ref().map(|metrics| {
+                metrics.received();
+                Instant::now()
+            });
+            tx.3 =
+                tx.3.map(|amount| amount.round(self.options.input_precision));
+            if self.options.keep_log {
+                self.log.push(tx.clone());
+            }
+            let (kind, client, id) = (tx.0, tx.1, tx.2);
+            self.type_counts[kind as usize] += 1;
+            if let Some(timestamp) = tx.5 {
+                if let Some((previous_line, previous)) = self.last_timestamp {
+                    if timestamp < previous {
+                        let error = EngineError::OutOfOrderTimestamp {
+                            line,
+                            timestamp,
+                            previous_line,
+                            previous,
+                        };
+                        if self.options.check_order == OrderCheck::Error {
+                            return Err(error);
+                        }
+                        self.report(&error, Some(client), Some(id), || error.to_string())?;
+                    }
+                }
+                self.last_timestamp = Some((line, timestamp));
+            }
+            if self.options.check_ordering && matches!(kind, Tx::Deposit | Tx::Withdrawal) {
+                if let Some(referenced_at) = self.unseen.remove(&id) {
+                    let error = EngineError::OutOfOrder {
+                        line,
+                        tx: id,
+                        referenced_at,
+                    };
+                    if self.options.strict {
+                        return Err(error);
+                    }
+                    self.report(&error, Some(client), None, || error.to_string())?;
+                }
+            }
+            if !self.spilled.is_empty() && !self.history.contains(id) {
+                self.recall(id)?;
+            }
+            if (self.options.dedupe_tx && self.is_replay(&tx))
+                || (self.options.idempotent && self.took_effect(&tx))
+            {
+                self.replayed_count += 1;
+                debug!("line {}: skipped replayed {} {}", line, kind, id);
+                continue;
+            }
+            let entry = store::TxEntry::new(kind, client, tx.3.unwrap_or(Amount(0.0)));
+            let currency = tx.4.clone().filter(|_| self.options.multi_currency);
+            let fresh = matches!(kind, Tx::Deposit | Tx::Withdrawal) && !self.history.contains(id);
+            let capacity = self.history.capacity();
+            // Where the funds of the account were, for the event to tell how the row moved them
+            let before = events.as_ref().map(|_| {
+                let currency = match kind {
+                    Tx::Deposit | Tx::Withdrawal => currency.clone(),
+                    _ => self.currencies.get(&id).cloned(),
+                };
+                let currency = currency.filter(|_| self.options.multi_currency);
+                let balances = self.balances(client, currency.as_ref());
+                (currency, balances)
+            });
+            let result = self.process_transaction(tx);
+            if fresh && self.history.contains(id) {
+                self.history_inserts += 1;
+                if self.history.capacity() == capacity {
+                    self.unreallocated_inserts += 1;
+                }
+            }
+            if result.is_err()
+                && self.options.dedupe_tx
+                && matches!(kind, Tx::Deposit | Tx::Withdrawal)
+                && !self.history.contains(id)
+            {
+                self.rejected.entry(id).or_insert((entry, currency));
+            }
+            self.bound_history(line)?;
+            if let (Some(metrics), Some(start)) = (&metrics, start) {
+                match &result {
+                    Ok(()) => metrics.applied(),
+                    Err(rejection) => metrics.rejected(rejection),
+                }
+                metrics.observe_latency(start.elapsed());
+            }
+            match result {
+                Ok(()) => {
+                    debug!(
+                        "line {}: applied {} {} of client {}",
+                        line, kind, id, client
+                    );
+                    let filtered = self.options.clients.as_ref();
+                    if let (Some(events), Some((currency, before))) = (&events, &before) {
+                        if filtered.is_none_or(|clients| clients.contains(&client)) {
+                            let after = self.balances(client, currency.as_ref());
+                            let currency = currency.as_deref();
+                            events.emit(&self.event(id, kind, client, currency, *before, after))?;
+                        }
+                    }
+                    let event = match kind {
+                        Tx::Dispute => Some("dispute"),
+                        Tx::Resolve => Some("resolve"),
+                        Tx::Chargeback => Some("chargeback"),
+                        Tx::Deposit | Tx::Withdrawal => None,
+                    };
+                    if let Some(event) = event {
+                        self.trace_dispute(id, client, event, line);
+                    }
+                    self.sequence += 1;
+                    self.expire_disputes(line);
+                }
+                // Whether the transaction appears later or never is only known at the end
+                Err(Rejection::UnknownTx(id))
+                    if self.options.check_ordering && !self.history.contains(id) =>
+                {
+                    self.unseen.entry(id).or_insert(line);
+                    let rejection = Rejection::UnknownTx(id);
+                    self.skip(Some(&rejection));
+                    let error = EngineError::Rejected { line, rejection };
+                    self.report(&error, Some(client), Some(id), || {
+                        format!("line {}: rejected {} {}: not seen yet", line, kind, id)
+                    })?;
+                }
+                Err(rejection) if self.options.strict => {
+                    return Err(EngineError::Rejected { line, rejection })
+                }
+                Err(rejection) => {
+                    self.skip(Some(&rejection));
+                    let error = EngineError::Rejected { line, rejection };
+                    self.report(&error, Some(client), Some(id), || {
+                        format!(
+                            "line {}: rejected {} {}: {}",
+                            line,
+                            kind,
+                            id,
+                            error.detail()
+                        )
+                    })?;
+                }
+            }
+        }
+        // What's still unseen at this point never exists at all
+        if self.options.strict {
+            if let Some((&id, &line)) = self.unseen.iter().min_by_key(|(_, line)| **line) {
+                let rejection = Rejection::UnknownTx(id);
+                return Err(EngineError::Rejected { line, rejection });
+            }
+        }
+        if let Some(progress) = &progress {
+            progress.finish(self.processed_count);
+        }
+        if let Some(events) = &events {
+            events.flush()?;
+        }
+        info!("{} rows processed", self.processed_count);
+        if self.replayed_count > 0 {
+            info!("{} replayed rows skipped", self.replayed_count);
+        }
+        if self.filtered_count > 0 {
+            info!("{} rows of other clients left out", self.filtered_count);
+        }
+        if self.evicted_count > 0 {
+            warn!(
+                "{} transactions evicted from history, disputes of them were rejected as unknown",
+                self.evicted_count
+            );
+        }
+        Ok(())
+    }
+
+    /// Apply, in order, every transaction read from `rdr` in the format of `Options::input_format`
+    pub fn process_input<R: Read>(&mut self, rdr: R) -> Result<(), EngineError> {
+        match self.options.input_format {
+            InputFormat::Csv => self.process_from_reader(rdr),
+            InputFormat::Ndjson => {
+                let check_order = self.options.check_order;
+                let mut source = NdjsonSource::new(BufReader::new(rdr), check_order)
+                    .with_lenient_amounts(self.options.lenient_amounts.clone())
+                    .with_amount_validation(self.options.validate_amounts);
+                self.process_source(&mut source)
+            }
+        }
+    }
+
+    /// Check every row of `rdr`, read as `process_input` does, with `validator` rather than
+    /// applying them: nothing changes, and every problem of the input is reported at once
+    pub fn validate_input<R: Read>(
+        &self,
+        validator: &InputValidator,
+        rdr: R,
+    ) -> Result<Vec<(u64, ValidationError)>, EngineError> {
+        Ok(match self.options.input_format {
+            InputFormat::Csv => validator.validate_source(&mut self.options.csv_source(rdr)?),
+            InputFormat::Ndjson => {
+                let mut source = NdjsonSource::new(BufReader::new(rdr), self.options.check_order)
+                    .with_lenient_amounts(self.options.lenient_amounts.clone())
+                    .with_amount_validation(self.options.validate_amounts);
+                validator.validate_source(&mut source)
+            }
+        })
+    }
+
+    /// Apply, in order, every transaction of the `csv` string, e.g. the body of an API request
+    pub fn process_from_str(&mut self, csv: &str) -> Result<(), EngineError> {
+        self.process_from_reader(csv.as_bytes())
+    }
+
+    /// Apply, in order, every transaction of the CSV file at `path`, failing with
+    /// `EngineError::Io` if it can't be opened
+    pub fn process_from_file(&mut self, path: &Path) -> Result<(), EngineError> {
+        self.process_from_reader(File::open(path)?)
+    }
+
+    /// Set the order of the columns of the lines given to `process_line`, from a header line
+    /// (e.g. `type,client,tx,amount`): it must name at least the first four columns of a row, in
+    /// any order, and unknown ones are ignored
+    pub fn set_headers(&mut self, header_line: &str) -> Result<(), EngineError> {
+        let line = self.line_count;
+        let headers = self.parse_line(header_line, line)?.unwrap_or_default();
+        let mut columns = [None; 6];
+        for (column, name) in columns.iter_mut().zip(LINE_COLUMNS) {
+            *column = headers.iter().position(|header| header == name);
+        }
+        if let Some(missing) = LINE_COLUMNS
+            .iter()
+            .zip(columns)
+            .take(4)
+            .find(|(_, c)| c.is_none())
+        {
+            return Err(EngineError::Parse {
+                line,
+                field: None,
+                message: format!("missing column `{}`", missing.0),
+            });
+        }
+        self.line_columns = Some(columns);
+        Ok(())
+    }
+
+    /// Apply the transaction of a single CSV row (without a header), e.g. received over a network
+    /// connection, as soon as it arrives. A header line given first (i.e. with a `type` column)
+    /// sets the order of the columns of the next ones, as `set_headers` does: otherwise it's the
+    /// one of the example, `type,client,tx,amount` (and then `currency`). Blank lines are
+    /// ignored. Errors are numbered after the lines given so far.
+    pub fn process_line(&mut self, line: &str) -> Result<(), EngineError> {
+        self.line_count += 1;
+        let number = self.line_count;
+        let Some(record) = self.parse_line(line, number)? else {
+            return Ok(());
+        };
+        let columns = match self.line_columns {
+            Some(columns) => columns,
+            None if record.iter().any(|field| field == LINE_COLUMNS[0]) => {
+                return self.set_headers(line)
+            }
+            None => [Some(0), Some(1), Some(2), Some(3), Some(4), None],
+        };
+        let field = |i: usize| columns[i].and_then(|c| record.get(c)).unwrap_or("");
+        let amount = match &self.options.lenient_amounts {
+            Some(lenient) if !field(3).is_empty() => lenient.normalize(field(3)),
+            _ => field(3).to_owned(),
+        };
+        let mut row =
+            csv::StringRecord::from_iter([field(0), field(1), field(2), &amount, field(4)]);
+        let mut position = csv::Position::new();
+        position.set_line(number);
+        row.set_position(Some(position));
+        let field_error = |field, message| EngineError::Parse {
+            line: number,
+            field: Some(field),
+            message,
+        };
+        let parsed = match row.deserialize::<Transaction>(None) {
+            Ok(mut tx) => match (self.options.validate_amounts.check(&amount), field(5)) {
+                (Err(message), _) => Err((field_error("amount", message), row)),
+                (_, ts) if ts.is_empty() || self.options.check_order == OrderCheck::Off => Ok(tx),
+                (_, ts) => match ts.parse() {
+                    Ok(timestamp) => {
+                        tx.5 = Some(timestamp);
+                        Ok(tx)
+                    }
+                    Err(message) => Err((field_error("ts", message), row)),
+                },
+            },
+            Err(e) => Err((e.into(), row)),
+        };
+        let mut parsed = Some((number, parsed));
+        self.process_source(&mut || parsed.take())
+    }
+
+    /// The fields of a single CSV line, or nothing if it's blank
+    fn parse_line(
+        &self,
+        line: &str,
+        number: u64,
+    ) -> Result<Option<csv::StringRecord>, EngineError> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .delimiter(self.options.delimiter)
+            .from_reader(line.as_bytes());
+        let mut record = csv::StringRecord::new();
+        match rdr.read_record(&mut record) {
+            Ok(true) if record.iter().any(|field| !field.is_empty()) => Ok(Some(record)),
+            Ok(_) => Ok(None),
+            Err(e) => Err(match EngineError::from(e) {
+                EngineError::Parse { field, message, .. } => EngineError::Parse {
+                    line: number,
+                    field,
+                    message,
+                },
+                e => e,
+            }),
+        }
+    }
+
+    /// The rejection of a row that couldn't be parsed because of an error on our partner's side
+    /// (rather than a malformed row), if so
+    fn rejected_row(&self, e: &EngineError, record: &csv::StringRecord) -> Option<Rejection> {
+        let field = match e {
+            EngineError::Parse { field, .. } => *field,
+            _ => return None,
+        };
+        let text = |i| record.get(i).unwrap_or("").trim();
+        match field {
+            // A well-formed client ID that just doesn't fit in a `ClientID`
+            Some("client")
+                if !text(1).is_empty() && text(1).bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                Some(Rejection::ClientOutOfRange(text(1).to_owned()))
+            }
+            // Any amount, when only canonical ones are accepted
+            Some("amount") if self.options.validate_amounts == AmountValidation::Strict => {
+                Some(Rejection::BadAmount(text(3).to_owned()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Count a skipped row, rejected or else malformed
+    fn skip(&mut self, rejection: Option<&Rejection>) {
+        self.skipped_count += 1;
+        match rejection {
+            Some(rejection) => self.rejected_counts[rejection.index()] += 1,
+            None => self.malformed_count += 1,
+        }
+    }
+
+    /// Whether `tx` already took effect, see `Options::idempotent`
+    fn took_effect(&self, tx: &Transaction) -> bool {
+        let charged_back = self.charged_back.get(&tx.2) == Some(&tx.1);
+        match tx.0 {
+            Tx::Deposit | Tx::Withdrawal => {
+                self.history.contains(tx.2) || self.charged_back.contains_key(&tx.2)
+            }
+            Tx::Dispute => {
+                let disputed = self.history.get(tx.2);
+                charged_back || disputed.is_some_and(|e| e.client == tx.1 && e.disputed)
+            }
+            Tx::Chargeback => charged_back,
+            Tx::Resolve => false,
+        }
+    }
+
+    /// Whether `tx` is a deposit or a withdrawal seen before, applied or rejected, with the very
+    /// same contents
+    fn is_replay(&self, tx: &Transaction) -> bool {
+        let (Tx::Deposit | Tx::Withdrawal) = tx.0 else {
+            return false;
+        };
+        let (entry, currency) = match self.history.get(tx.2) {
+            Some(entry) => (entry, self.currencies.get(&tx.2)),
+            None => match self.rejected.get(&tx.2) {
+                Some((entry, currency)) => (entry, currency.as_ref()),
+                // Only the client of a charged back transaction is left to compare
+                None => return self.charged_back.get(&tx.2) == Some(&tx.1),
+            },
+        };
+        let tx_currency = tx.4.as_ref().filter(|_| self.options.multi_currency);
+        (entry.kind, entry.client, Some(entry.amount), currency) == (tx.0, tx.1, tx.3, tx_currency)
+    }
+
+    /// Apply a single transaction to the engine state, or tell why it's rejected
+    fn process_transaction(&mut self, tx: Transaction) -> Result<(), Rejection> {
+        let places = self.options.input_precision;
+        let currency = match tx.0 {
+            _ if !self.options.multi_currency => None,
+            Tx::Deposit | Tx::Withdrawal => tx.4,
+            _ => self.currencies.get(&tx.2).cloned(),
+        };
+        if let Some(clients) = &self.options.clients {
+            if !clients.contains(&tx.1) {
+                self.filtered_count += 1;
+                if let (Tx::Deposit | Tx::Withdrawal, Some(amount)) = (tx.0, tx.3) {
+                    let retained = self.retained.as_ref();
+                    let entry = store::TxEntry::new(tx.0, tx.1, amount);
+                    // Recorded last, as it fails if history already holds the ID
+                    if !self.charged_back.contains_key(&tx.2)
+                        && retained.is_none_or(|retained| retained.contains(&tx.2))
+                        && self.history.record(tx.2, entry).is_ok()
+                    {
+                        if let Some(currency) = currency {
+                            self.currencies.insert(tx.2, currency);
+                        }
+                        if self.options.max_history.is_some() {
+                            self.lru.touch(tx.2);
+                        }
+                    }
+                }
+                return Ok(());
+            }
+        }
+        let metrics = self.options.metrics.as_deref();
+        let opened = match &currency {
+            Some(currency) => self.wallets.contains_key(&(tx.1, currency.clone())),
+            None => self.accounts.get(tx.1).is_some(),
+        };
+        if !opened {
+            // Only deposits and withdrawals open an account: a client without one has no
+            // transaction to dispute, and a stray dispute mustn't show up as an empty account
+            if !matches!(tx.0, Tx::Deposit | Tx::Withdrawal) {
+                return Err(Rejection::UnknownTx(tx.2));
+            }
+            if let Some(max_clients) = self.options.max_clients {
+                if self.account_count() >= max_clients {
+                    return Err(Rejection::TooManyClients {
+                        client: tx.1,
+                        max_clients,
+                    });
+                }
+            }
+            if let Some(metrics) = metrics {
+                metrics.clients.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+        let ledger = match &currency {
+            Some(currency) => self.wallets.entry((tx.1, currency.clone())).or_default(),
+            None => self.accounts.get_or_default(tx.1),
+        };
+        // A resolve may still unlock an account, see below
+        if ledger.auto_locked {
+            return Err(Rejection::AutoLocked(tx.1));
+        }
+        if ledger.status == LedgerStatus::Locked && tx.0 != Tx::Resolve {
+            return Err(Rejection::AccountLocked(tx.1));
+        }
+        // Silently ignoring the amount of a dispute would hide a malformed row
+        if let (Tx::Dispute | Tx::Resolve | Tx::Chargeback, Some(amount)) = (tx.0, tx.3) {
+            return Err(Rejection::UnexpectedAmount(tx.2, amount));
+        }
+        // An infinite or NaN amount (`inf` or `NaN` read as an `f64`) would poison every balance
+        // it's added to
+        if let Some(amount) = tx.3.filter(|amount| !amount.0.is_finite()) {
+            return Err(Rejection::BadAmount(amount.0.to_string()));
+        }
+        // Transaction IDs are globally unique, a replayed one must not be applied twice
+        if matches!(tx.0, Tx::Deposit | Tx::Withdrawal)
+            && (self.history.contains(tx.2) || self.charged_back.contains_key(&tx.2))
+        {
+            return Err(Rejection::DuplicateTx(tx.2));
+        }
+        // A chargeback is final, whereas an unknown transaction may be a partner's mistake
+        if self.charged_back.get(&tx.2) == Some(&tx.1) {
+            return Err(Rejection::ChargedBack(tx.2));
+        }
+        match tx.0 {
+            // Store deposit or withdrawal transaction amount to history
+            Tx::Deposit => {
+                let amount = tx.3.ok_or(Rejection::MissingAmount(tx.2))?;
+                ledger.deposit(tx.1, amount, places)?;
+                let stats = self.client_stats.entry(tx.1).or_default();
+                stats.deposits = stats.deposits.saturating_add(1);
+                stats.deposit_total = stats.deposit_total + amount;
+                if self
+                    .retained
+                    .as_ref()
+                    .is_none_or(|retained| retained.contains(&tx.2))
+                {
+                    // Duplicates are rejected upfront, before any funds move
+                    self.history
+                        .record(tx.2, store::TxEntry::new(tx.0, tx.1, amount))?;
+                    if let Some(currency) = currency {
+                        self.currencies.insert(tx.2, currency);
+                    }
+                }
+            }
+            Tx::Withdrawal => {
+                let amount = tx.3.ok_or(Rejection::MissingAmount(tx.2))?;
+                ledger.withdraw(tx.1, amount, places)?;
+                let stats = self.client_stats.entry(tx.1).or_default();
+                stats.withdrawals = stats.withdrawals.saturating_add(1);
+                stats.withdrawal_total = stats.withdrawal_total + amount;
+                if self
+                    .retained
+                    .as_ref()
+                    .is_none_or(|retained| retained.contains(&tx.2))
+                {
+                    // Duplicates are rejected upfront, before any funds move
+                    self.history
+                        .record(tx.2, store::TxEntry::new(tx.0, tx.1, amount))?;
+                    if let Some(currency) = currency {
+                        self.currencies.insert(tx.2, currency);
+                    }
+                }
+            }
+            // Retrieve deposit or withdrawal transaction amount from history
+            Tx::Dispute => {
+                // Someone else's transaction is as unknown to the client as a missing one
+                let entry = self
+                    .history
+                    .get_mut(tx.2)
+                    .filter(|entry| entry.client == tx.1)
+                    .ok_or(Rejection::UnknownTx(tx.2))?;
+                let policy = self.options.withdrawal_dispute_policy;
+                if !self.options.disputable.contains(&entry.kind)
+                    || (entry.kind == Tx::Withdrawal && policy == WithdrawalDisputePolicy::Reject)
+                {
+                    return Err(Rejection::NotDisputable(tx.2, entry.kind));
+                }
+                if entry.disputed {
+                    return Err(Rejection::AlreadyDisputed(tx.2));
+                }
+                let held = ledger.held;
+                match entry.kind {
+                    Tx::Withdrawal => {
+                        ledger.settle_withdrawal(tx.1, tx.0, entry.amount, places, policy)?
+                    }
+                    _ => ledger.dispute(tx.1, entry.amount, places)?,
+                }
+                entry.disputed = true;
+                let stats = self.client_stats.entry(tx.1).or_default();
+                stats.disputes_raised = stats.disputes_raised.saturating_add(1);
+                // This dispute's own number, once applied
+                let sequence = self.sequence + 1;
+                ledger.dispute_tx_sequence = Some(sequence);
+                if self.options.dispute_expiry_after.is_some() {
+                    self.disputed_at.insert(tx.2, sequence);
+                    self.dispute_expiries.push_back((sequence, tx.2));
+                }
+                if let Some(metrics) = metrics {
+                    metrics.add_held_funds((ledger.held - held).0);
+                }
+            }
+            Tx::Resolve => {
+                let entry = self
+                    .history
+                    .get_mut(tx.2)
+                    .filter(|entry| entry.client == tx.1)
+                    .ok_or(Rejection::UnknownTx(tx.2))?;
+                if !entry.disputed {
+                    return Err(Rejection::NotDisputed(tx.2));
+                }
+                let (policy, held) = (self.options.withdrawal_dispute_policy, ledger.held);
+                match entry.kind {
+                    Tx::Withdrawal => {
+                        ledger.settle_withdrawal(tx.1, tx.0, entry.amount, places, policy)?
+                    }
+                    _ => ledger.resolve(tx.1, entry.amount, places)?,
+                }
+                entry.disputed = false;
+                self.disputed_at.remove(&tx.2);
+                let stats = self.client_stats.entry(tx.1).or_default();
+                stats.disputes_resolved = stats.disputes_resolved.saturating_add(1);
+                if let Some(metrics) = metrics {
+                    metrics.add_held_funds((ledger.held - held).0);
+                }
+                // An account locked by the chargeback of another transaction while this one was
+                // under dispute is cleared by the partner invalidating the dispute
+                if ledger.status == LedgerStatus::Locked {
+                    ledger.status = LedgerStatus::Default;
+                    if let Some(metrics) = metrics {
+                        metrics
+                            .locked_accounts
+                            .fetch_sub(1, atomic::Ordering::Relaxed);
+                    }
+                }
+            }
+            Tx::Chargeback => {
+                let entry = self
+                    .history
+                    .get_mut(tx.2)
+                    .filter(|entry| entry.client == tx.1)
+                    .ok_or(Rejection::UnknownTx(tx.2))?;
+                if !entry.disputed {
+                    return Err(Rejection::NotDisputed(tx.2));
+                }
+                let (policy, held) = (self.options.withdrawal_dispute_policy, ledger.held);
+                match entry.kind {
+                    Tx::Withdrawal => {
+                        ledger.settle_withdrawal(tx.1, tx.0, entry.amount, places, policy)?
+                    }
+                    _ => ledger.chargeback(tx.1, entry.amount, places)?,
+                }
+                entry.disputed = false;
+                self.disputed_at.remove(&tx.2);
+                let stats = self.client_stats.entry(tx.1).or_default();
+                stats.chargebacks = stats.chargebacks.saturating_add(1);
+                let options = &self.options;
+                if options
+                    .auto_lock_after_chargebacks
+                    .is_some_and(|max| ledger.chargeback_count >= max)
+                    || options
+                        .auto_lock_chargeback_amount
+                        .is_some_and(|max| ledger.chargeback_amount > max)
+                {
+                    ledger.auto_locked = true;
+                    debug!(
+                        "client {} locked for good after {} chargebacks of {}",
+                        tx.1, ledger.chargeback_count, ledger.chargeback_amount
+                    );
+                }
+                if let Some(metrics) = metrics {
+                    metrics
+                        .locked_accounts
+                        .fetch_add(1, atomic::Ordering::Relaxed);
+                    metrics.add_held_funds((ledger.held - held).0);
+                }
+                ledger.tx_count = ledger.tx_count.saturating_add(1);
+                // Nothing can happen to the transaction anymore, its entry is dead weight
+                self.forget(tx.2);
+                self.charged_back.insert(tx.2, tx.1);
+                self.reclaimed_count += 1;
+                return Ok(());
+            }
+        }
+        ledger.min_available_seen = ledger.min_available_seen.min(ledger.available);
+        ledger.tx_count = ledger.tx_count.saturating_add(1);
+        if let Tx::Deposit | Tx::Withdrawal = tx.0 {
+            self.client_history.entry(tx.1).or_default().push(tx.2);
+        }
+        if self.options.max_history.is_some() {
+            self.lru.touch(tx.2);
+        }
+        Ok(())
+    }
+
+    /// Report an error that doesn't stop processing, as a JSON object with `json_errors` (whatever
+    /// the log level, as they were asked for), or else as the given warning (formatted only if
+    /// warnings are enabled), and to `rejected` if its row is skipped
+    fn report(
+        &self,
+        error: &EngineError,
+        client: Option<ClientID>,
+        tx: Option<TxID>,
+        warning: impl FnOnce() -> String,
+    ) -> Result<(), EngineError> {
+        // Other errors are about rows applied nonetheless
+        let skipped = matches!(
+            error,
+            EngineError::Rejected { .. } | EngineError::Parse { .. }
+        );
+        if let Some(rejected) = self.options.rejected.as_ref().filter(|_| skipped) {
+            rejected.emit(&error.to_json(client, tx))?;
+        }
+        match &self.options.json_errors {
+            Some(errors) => errors.emit(&error.to_json(client, tx))?,
+            None => warn!("{}", warning()),
+        }
+        Ok(())
+    }
+
+    /// Available and held funds of an account, and whether it's locked (nothing, for an account
+    /// not opened yet)
+    fn balances(&self, client: ClientID, currency: Option<&Currency>) -> (Amount, Amount, bool) {
+        let ledger = match currency {
+            Some(currency) => self.wallets.get(&(client, currency.clone())),
+            None => self.accounts.get(client),
+        };
+        ledger.map_or((Amount::ZERO, Amount::ZERO, false), |ledger| {
+            let locked = ledger.status == LedgerStatus::Locked;
+            (ledger.available, ledger.held, locked)
+        })
+    }
+
+    /// The event of a transaction applied, moving the funds of its account from `before` to
+    /// `after` (see `balances`), as a JSON object
+    fn event(
+        &self,
+        id: TxID,
+        kind: Tx,
+        client: ClientID,
+        currency: Option<&str>,
+        before: (Amount, Amount, bool),
+        after: (Amount, Amount, bool),
+    ) -> String {
+        let amount = |amount| self.output_amount(amount);
+        let mut event = json::Object::new()
+            .value("tx", Some(id))
+            .string("type", Some(&kind.to_string()))
+            .value("client", Some(client));
+        if self.options.multi_currency {
+            event = event.string("currency", currency);
+        }
+        event
+            .string("delta_available", Some(&amount(after.0 - before.0)))
+            .string("delta_held", Some(&amount(after.1 - before.1)))
+            .string("available", Some(&amount(after.0)))
+            .string("held", Some(&amount(after.1)))
+            .value("locked", Some(after.2))
+            .finish()
+    }
+
+    /// Add an event to the lifecycle of a disputed transaction, see `write_dispute_trace_csv`
+    fn trace_dispute(&mut self, id: TxID, client: ClientID, event: &'static str, line: u64) {
+        let amount = self
+            .history
+            .get(id)
+            .map_or(Amount::ZERO, |entry| entry.amount);
+        let trace = self.dispute_traces.entry(id).or_insert(DisputeTrace {
+            client,
+            amount,
+            events: Vec::new(),
+        });
+        trace.events.push((event, line));
+    }
+
+    /// Resolve the disputes raised `Options::dispute_expiry_after` transactions ago, once the row
+    /// at `line` was applied
+    fn expire_disputes(&mut self, line: u64) {
+        let Some(expiry) = self.options.dispute_expiry_after else {
+            return;
+        };
+        while let Some(&(raised, tx)) = self.dispute_expiries.front() {
+            if raised + expiry > self.sequence {
+                break;
+            }
+            self.dispute_expiries.pop_front();
+            // Resolved, charged back, or disputed again since
+            if self.disputed_at.get(&tx) != Some(&raised) {
+                continue;
+            }
+            self.disputed_at.remove(&tx);
+            // e.g. evicted from a bounded history
+            let Some(entry) = self.history.get_mut(tx).filter(|entry| entry.disputed) else {
+                continue;
+            };
+            entry.disputed = false;
+            let (client, kind, amount) = (entry.client, entry.kind, entry.amount);
+            self.trace_dispute(tx, client, "expired", line);
+            let stats = self.client_stats.entry(client).or_default();
+            stats.disputes_resolved = stats.disputes_resolved.saturating_add(1);
+            let ledger = match self.currencies.get(&tx) {
+                Some(currency) => self.wallets.entry((client, currency.clone())).or_default(),
+                None => self.accounts.get_or_default(client),
+            };
+            // As `Ledger::set_funds` does, releasing held funds can't take them below zero
+            let places = self.options.input_precision;
+            let policy = self.options.withdrawal_dispute_policy;
+            let (available, held) = match kind {
+                Tx::Withdrawal => policy.funds(ledger, Tx::Resolve, amount),
+                _ => None,
+            }
+            .unwrap_or((ledger.available + amount, ledger.held - amount));
+            if let Some(metrics) = &self.options.metrics {
+                metrics.add_held_funds((held - ledger.held).0);
+            }
+            ledger.held = held.round(places);
+            ledger.available = available.round(places);
+            warn!(
+                "dispute of transaction {} of client {} expired after {} transactions, resolved",
+                tx, client, expiry
+            );
+        }
+    }
+
+    /// Bring a spilled transaction back into history, ahead of its use
+    fn recall(&mut self, tx: TxID) -> Result<(), EngineError> {
+        for spilled in &mut self.spilled {
+            if let Some(entry) = spilled.remove(tx)? {
+                self.history.insert(tx, entry);
+                self.lru.touch(tx);
+                debug!("transaction {} recalled from disk", tx);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Make room in a bounded history, as told by `Options::history_overflow`
+    fn bound_history(&mut self, line: u64) -> Result<(), EngineError> {
+        let Some(max_history) = self.options.max_history else {
+            return Ok(());
+        };
+        while self.lru.len() > max_history {
+            if self.options.history_overflow == HistoryOverflow::Error {
+                return Err(EngineError::HistoryOverflow { line, max_history });
+            }
+            let Some(evicted) = self.lru.evict() else {
+                break;
+            };
+            let Some(entry) = self.history.remove(evicted) else {
+                continue;
+            };
+            if self.options.history_overflow == HistoryOverflow::SpillDisk {
+                if self.spilled.is_empty() {
+                    self.spilled.push(DiskTxStore::create()?);
+                }
+                self.spilled[0].insert(evicted, entry)?;
+                debug!("transaction {} spilled to disk", evicted);
+            } else {
+                self.currencies.remove(&evicted);
+                self.evicted_count += 1;
+                debug!("transaction {} evicted from history", evicted);
+            }
+        }
+        Ok(())
+    }
+
+    /// The state of every client account (and wallet, in multi-currency mode), in no particular
+    /// order
+    pub fn snapshot(&self) -> AccountsSnapshot {
+        let state = |client, currency, ledger: &Ledger| AccountState {
+            client,
+            currency,
+            available: ledger.available,
+            held: ledger.held,
+            total: ledger.total(),
+            locked: ledger.status == LedgerStatus::Locked,
+        };
+        let accounts = self
+            .accounts
+            .iter()
+            .map(|(client, ledger)| state(client, None, ledger));
+        let wallets = self
+            .wallets
+            .iter()
+            .map(|((client, currency), ledger)| state(*client, Some(currency.clone()), ledger));
+        AccountsSnapshot(accounts.chain(wallets).collect())
+    }
+
+    /// Transactions still under dispute, counted and summed up per account (sorted by client ID
+    /// and then currency)
+    pub fn open_disputes(&self) -> Vec<OpenDisputes> {
+        let mut disputes = HashMap::<(ClientID, Option<&Currency>), OpenDisputes>::new();
+        for (id, entry) in self.history.iter().filter(|(_, entry)| entry.disputed) {
+            let currency = self.currencies.get(&id);
+            let open = disputes
+                .entry((entry.client, currency))
+                .or_insert_with(|| OpenDisputes {
+                    client: entry.client,
+                    currency: currency.cloned(),
+                    count: 0,
+                    held: Amount(0.0),
+                });
+            open.count += 1;
+            open.held = open.held + entry.amount;
+        }
+        let mut disputes = disputes.into_values().collect::<Vec<_>>();
+        disputes.sort_by(|a, b| (a.client, &a.currency).cmp(&(b.client, &b.currency)));
+        disputes
+    }
+
+    /// Accounts whose available funds went below zero at some point, along with their lowest
+    /// balance (sorted by client ID and then currency), see `Ledger::min_available_seen`
+    pub fn negative_balances(&self) -> Vec<(ClientID, Option<Currency>, Amount)> {
+        let accounts = self
+            .accounts
+            .iter()
+            .map(|(client, ledger)| (client, None, ledger));
+        let wallets = self
+            .wallets
+            .iter()
+            .map(|((client, currency), ledger)| (*client, Some(currency.clone()), ledger));
+        let mut negatives = accounts
+            .chain(wallets)
+            .filter(|(_, _, ledger)| ledger.min_available_seen < Amount(0.0))
+            .map(|(client, currency, ledger)| (client, currency, ledger.min_available_seen))
+            .collect::<Vec<_>>();
+        negatives.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        negatives
+    }
+
+    /// Write the open disputes of every account as CSV to `wtr`, with a `currency` column in
+    /// multi-currency mode
+    pub fn write_open_disputes_csv<W: Write>(&self, wtr: W) -> Result<(), EngineError> {
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(self.options.output_delimiter)
+            .from_writer(wtr);
+        if self.options.multi_currency {
+            wtr.write_record(["client", "currency", "open_disputes", "held"])?;
+        } else {
+            wtr.write_record(["client", "open_disputes", "held"])?;
+        }
+        for open in self.open_disputes() {
+            let held = self.output_amount(open.held);
+            if self.options.multi_currency {
+                let currency = open.currency.as_deref().unwrap_or("");
+                wtr.serialize((open.client, currency, open.count, held))?;
+            } else {
+                wtr.serialize((open.client, open.count, held))?;
+            }
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Write every transaction the engine still knows of as CSV, sorted by ID, e.g. to check what
+    /// became of one a partner disputed: the ones in history along with their state, `recorded`
+    /// or `disputed`, and the `charged_back` ones, whose type and amount aren't kept. Transactions
+    /// spilled to disk are read as they're written, rather than loaded at once.
+    pub fn write_history_csv<W: Write>(&self, wtr: W) -> Result<(), EngineError> {
+        type HistoryRow = (TxID, ClientID, Option<(Tx, Amount)>, &'static str);
+        let state = |entry: &store::TxEntry| match entry.disputed {
+            true => "disputed",
+            false => "recorded",
+        };
+        let row = |(id, entry): (TxID, &store::TxEntry)| -> HistoryRow {
+            (
+                id,
+                entry.client,
+                Some((entry.kind, entry.amount)),
+                state(entry),
+            )
+        };
+        let charged_back = self.charged_back.iter();
+        let charged_back = charged_back.map(|(id, client)| (*id, *client, None, "charged_back"));
+        let mut in_memory: Vec<HistoryRow> =
+            self.history.iter().map(row).chain(charged_back).collect();
+        in_memory.sort_unstable_by_key(|row| row.0);
+        // Every source is sorted, and a transaction is in a single one
+        let mut sources: Vec<Box<dyn Iterator<Item = std::io::Result<HistoryRow>>>> =
+            vec![Box::new(in_memory.into_iter().map(Ok))];
+        for spilled in &self.spilled {
+            let entries = spilled.entries()?;
+            let rows = entries.map(move |entry| entry.map(|(id, entry)| row((id, &entry))));
+            sources.push(Box::new(rows));
+        }
+        let mut sources: Vec<_> = sources.into_iter().map(Iterator::peekable).collect();
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(self.options.output_delimiter)
+            .from_writer(wtr);
+        wtr.write_record(["tx", "client", "type", "amount", "state"])?;
+        loop {
+            // Errors come first, to be returned right away
+            let next = sources.iter_mut().enumerate().filter_map(|(i, source)| {
+                Some((source.peek()?.as_ref().map_or(0, |row| row.0), i))
+            });
+            let Some((_, i)) = next.min() else {
+                break;
+            };
+            let (id, client, transaction, state) = sources[i].next().expect("peeked")?;
+            let kind = transaction.map(|(kind, _)| kind.to_string());
+            let amount = transaction.map(|(_, amount)| self.output_amount(amount));
+            wtr.serialize((id, client, kind, amount, state))?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Write the lifecycle of every transaction disputed so far as CSV, sorted by ID: its client,
+    /// its amount, and the dispute events applied to it in order, as `event:line` separated by
+    /// spaces (e.g. `dispute:4 resolve:9 dispute:12 chargeback:15`)
+    pub fn write_dispute_trace_csv<W: Write>(&self, wtr: W) -> Result<(), EngineError> {
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(self.options.output_delimiter)
+            .from_writer(wtr);
+        wtr.write_record(["tx", "client", "amount", "events"])?;
+        let mut traces: Vec<_> = self.dispute_traces.iter().collect();
+        traces.sort_unstable_by_key(|(id, _)| **id);
+        for (id, trace) in traces {
+            let events = trace
+                .events
+                .iter()
+                .map(|(event, line)| format!("{}:{}", event, line));
+            let events = events.collect::<Vec<_>>().join(" ");
+            let amount = self.output_amount(trace.amount);
+            wtr.serialize((id, trace.client, amount, events))?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Totals over every account, e.g. to reconcile a batch with the funds it moved
+    pub fn summarize(&self) -> EngineSummary {
+        let ledgers = self.accounts.iter().map(|(_, ledger)| ledger);
+        let ledgers = ledgers.chain(self.wallets.values());
+        let mut summary = EngineSummary {
+            account_count: 0,
+            total_available: Amount::ZERO,
+            total_held: Amount::ZERO,
+            locked_accounts: 0,
+            active_disputes: self.history.iter().filter(|(_, e)| e.disputed).count(),
+            skipped_rows: self.skipped_count,
+        };
+        for ledger in ledgers {
+            summary.account_count += 1;
+            summary.total_available = summary.total_available + ledger.available;
+            summary.total_held = summary.total_held + ledger.held;
+            summary.locked_accounts += usize::from(ledger.status == LedgerStatus::Locked);
+        }
+        summary
+    }
+
+    /// Write the `EngineSummary` of the engine as a single-row CSV to `wtr`
+    pub fn write_summary_csv<W: Write>(&self, wtr: W) -> Result<(), EngineError> {
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(self.options.output_delimiter)
+            .from_writer(wtr);
+        wtr.write_record([
+            "account_count",
+            "total_available",
+            "total_held",
+            "locked_accounts",
+            "active_disputes",
+            "skipped_rows",
+        ])?;
+        let summary = self.summarize();
+        wtr.serialize((
+            summary.account_count,
+            self.output_amount(summary.total_available),
+            self.output_amount(summary.total_held),
+            summary.locked_accounts,
+            summary.active_disputes,
+            summary.skipped_rows,
+        ))?;
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Write the `ClientStats` of every client as CSV to `wtr`, sorted by client ID
+    pub fn write_client_stats_csv<W: Write>(&self, wtr: W) -> Result<(), EngineError> {
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(self.options.output_delimiter)
+            .from_writer(wtr);
+        wtr.write_record([
+            "client",
+            "deposits",
+            "deposit_total",
+            "withdrawals",
+            "withdrawal_total",
+            "disputes_raised",
+            "disputes_resolved",
+            "chargebacks",
+        ])?;
+        let mut clients = self.client_stats.iter().collect::<Vec<_>>();
+        clients.sort_by_key(|(client, _)| **client);
+        for (client, stats) in clients {
+            wtr.serialize((
+                client,
+                stats.deposits,
+                self.output_amount(stats.deposit_total),
+                stats.withdrawals,
+                self.output_amount(stats.withdrawal_total),
+                stats.disputes_raised,
+                stats.disputes_resolved,
+                stats.chargebacks,
+            ))?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Write the state of every client account to `wtr`, in the format of
+    /// `Options::output_format`
+    pub fn write_accounts<W: Write>(&self, wtr: W) -> Result<(), EngineError> {
+        match self.options.output_format {
+            OutputFormat::Csv => self.write_accounts_csv(wtr),
+            OutputFormat::Json => self.write_accounts_json(wtr),
+        }
+    }
+
+    /// Write the state of every client account as CSV to `wtr`, with an extra `currency` column
+    /// in multi-currency mode (empty for the default currency)
+    pub fn write_accounts_csv<W: Write>(&self, wtr: W) -> Result<(), EngineError> {
+        // From https://docs.rs/csv/latest/csv/tutorial/index.html#writing-with-serde
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(self.options.output_delimiter)
+            .from_writer(wtr);
+        // We still need to write headers manually.
+        if self.options.multi_currency {
+            wtr.write_record(["client", "currency", "available", "held", "total", "locked"])?;
+        } else {
+            wtr.write_record(["client", "available", "held", "total", "locked"])?;
+        }
+        // But now we can write records by providing a normal Rust value.
+        let amount = |amount| self.output_amount(amount);
+        for account in self.output_snapshot().iter() {
+            let (available, held) = (amount(account.available), amount(account.held));
+            let (client, total, locked) = (account.client, amount(account.total), account.locked);
+            if self.options.multi_currency {
+                let currency = account.currency.as_deref().unwrap_or("");
+                wtr.serialize((client, currency, available, held, total, locked))?;
+            } else {
+                wtr.serialize((client, available, held, total, locked))?;
+            }
+        }
+        wtr.flush()?;
+        info!("{} accounts written", self.account_count());
+        Ok(())
+    }
+
+    /// Write the state of every client account as a JSON array, e.g. as the body of a REST API
+    /// response: `[{"client":1,"available":"1.5000","held":"0.0000","total":"1.5000",
+    /// "locked":false}]`. Amounts are strings formatted as in CSV, so that parsing them as floats
+    /// is up to the reader.
+    pub fn write_accounts_json<W: Write>(&self, mut wtr: W) -> Result<(), EngineError> {
+        let amount = |amount| self.output_amount(amount);
+        write!(wtr, "[")?;
+        for (i, account) in self.output_snapshot().iter().enumerate() {
+            if i > 0 {
+                write!(wtr, ",")?;
+            }
+            let mut object = json::Object::new().value("client", Some(account.client));
+            if self.options.multi_currency {
+                object = object.string("currency", Some(account.currency.as_deref().unwrap_or("")));
+            }
+            let object = object
+                .string("available", Some(&amount(account.available)))
+                .string("held", Some(&amount(account.held)))
+                .string("total", Some(&amount(account.total)))
+                .value("locked", Some(account.locked));
+            write!(wtr, "{}", object.finish())?;
+        }
+        writeln!(wtr, "]")?;
+        wtr.flush()?;
+        info!("{} accounts written", self.account_count());
+        Ok(())
+    }
+
+    /// Write every ledger as a JSON snapshot to be restored with `load_snapshot_json`, one entry
+    /// per line, sorted by client ID (and then currency). Amounts are written as numbers, at
+    /// full precision, rather than formatted as in other outputs.
+    pub fn write_snapshot_json<W: Write>(&self, mut wtr: W) -> Result<(), EngineError> {
+        let accounts = self
+            .accounts
+            .iter()
+            .map(|(client, ledger)| (client, None, ledger));
+        let wallets = self
+            .wallets
+            .iter()
+            .map(|((client, currency), ledger)| (*client, Some(currency), ledger));
+        let mut ledgers = accounts.chain(wallets).collect::<Vec<_>>();
+        ledgers.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+        write!(wtr, "[")?;
+        for (i, (client, currency, ledger)) in ledgers.into_iter().enumerate() {
+            let status = match ledger.status {
+                LedgerStatus::Default => "Default",
+                LedgerStatus::Locked => "Locked",
+            };
+            let fields = json::Object::new()
+                .value("available", Some(ledger.available.0))
+                .value("held", Some(ledger.held.0))
+                .string("status", Some(status))
+                .value("tx_count", Some(ledger.tx_count))
+                .value("dispute_count", Some(ledger.dispute_count))
+                .value("chargeback_count", Some(ledger.chargeback_count))
+                .value("chargeback_amount", Some(ledger.chargeback_amount.0))
+                .value("auto_locked", Some(ledger.auto_locked))
+                .value("min_available_seen", Some(ledger.min_available_seen.0))
+                .value("dispute_tx_sequence", ledger.dispute_tx_sequence);
+            let mut entry = json::Object::new().value("client", Some(client));
+            if let Some(currency) = currency {
+                entry = entry.string("currency", Some(currency));
+            }
+            let separator = if i > 0 { "," } else { "" };
+            write!(
+                wtr,
+                "{}\n  {}",
+                separator,
+                entry.object("ledger", fields).finish()
+            )?;
+        }
+        writeln!(wtr, "\n]")?;
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// An amount as written to every output, formatted as configured
+    fn output_amount(&self, amount: Amount) -> String {
+        let places = self.options.output_precision.unwrap_or(4);
+        amount.format(self.options.amount_format, places)
+    }
+
+    /// The accounts to write, sorted and filtered as configured
+    fn output_snapshot(&self) -> AccountsSnapshot {
+        let mut snapshot = self.snapshot();
+        if self.options.sorted {
+            snapshot = snapshot.sorted();
+        }
+        if !self.options.include_empty_accounts {
+            snapshot.0.retain(|account| !account.is_empty());
+        }
+        // e.g. accounts restored from a snapshot
+        if let Some(clients) = &self.options.clients {
+            snapshot
+                .0
+                .retain(|account| clients.contains(&account.client));
+        }
+        snapshot
+    }
+}
+
+/// Process every transaction of `input` with a new engine configured by `opts`, and write the
+/// resulting accounts to `output`, e.g. to embed the engine in a service handling in-memory
+/// buffers. Nothing is read from or written to stdio, besides logs (see `log.rs`).
+pub fn process<R: Read, W: Write>(
+    input: R,
+    output: W,
+    opts: &Options,
+) -> Result<RunReport, EngineError> {
+    let start = Instant::now();
+    let mut engine = Engine::new(opts.clone());
+    engine.process_input(input)?;
+    engine.write_accounts(output)?;
+    Ok(RunReport {
+        stats: engine.stats(),
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Number of rows sent at once from the reading thread to the processing one, when pipelined
+pub const PIPELINE_BATCH: usize = 256;
+
+// Relies on rejected transactions being skipped
+#[cfg(not(feature = "strict_mode"))]
+#[test]
+fn example() {
+    const INPUT: &str = "type,  client, tx, amount
+deposit,    1,  1,    1.0
+deposit,    2,  2,    2.0
+deposit,    1,  3,    2.0
+withdrawal, 1,  4,    1.5
+withdrawal, 2,  5,    3.0
+";
+    let mut engine = Engine::default();
+    engine.process_from_str(INPUT).unwrap();
+    let snapshot = engine.snapshot();
+    assert_eq!(snapshot.get(1).unwrap().available, Amount(1.5));
+    assert_eq!(snapshot.get(2).unwrap().available, Amount(2.0));
+}
+
+// Relies on rejected transactions being skipped
+#[cfg(not(feature = "strict_mode"))]
+#[test]
+fn counts() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.0
+withdrawal,1,3,5.0
+dispute,1,9,
+withdrawal,2,4,1.0
+";
+    let mut engine = Engine::default();
+    engine.process_from_str(INPUT).unwrap();
+    assert_eq!(engine.account_count(), 2);
+    // The withdrawal of 5.0 is rejected, and a dispute isn't recorded in history
+    assert_eq!(engine.transaction_count(), 3);
+    assert_eq!(engine.processed_count(), 5);
+    // The withdrawal of 5.0 and the dispute of an unknown transaction
+    assert_eq!(engine.skipped_count(), 2);
+}
+
+#[test]
+fn multi_currency() {
+    const INPUT: &str = "type,client,tx,amount,currency
+deposit,1,1,1.0,EUR
+deposit,1,2,2.0,BTC
+deposit,1,3,3.0
+withdrawal,1,4,0.5,EUR
+dispute,1,2,
+";
+    let mut engine = Engine::new(Options {
+        multi_currency: true,
+        sorted: true,
+        ..Options::default()
+    });
+    engine.process_from_str(INPUT).unwrap();
+    let mut output = Vec::new();
+    engine.write_accounts_csv(&mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(
+        output.lines().collect::<Vec<_>>(),
+        [
+            "client,currency,available,held,total,locked",
+            "1,,3.0000,0.0000,3.0000,false",
+            "1,BTC,0.0000,2.0000,2.0000,false",
+            "1,EUR,0.5000,0.0000,0.5000,false",
+        ]
+    );
+}
+
+#[test]
+fn sorted_currencies() {
+    let input = |clients: &[u16]| {
+        let mut csv = String::from("type,client,tx,amount,currency\n");
+        for (i, client) in clients.iter().enumerate() {
+            for (j, currency) in ["USD", "", "EUR", "BTC"].iter().enumerate() {
+                csv += &format!("deposit,{},{},1.0,{}\n", client, i * 4 + j + 1, currency);
+            }
+        }
+        csv
+    };
+    let output = |csv: &str| {
+        let mut engine = Engine::new(Options {
+            multi_currency: true,
+            sorted: true,
+            ..Options::default()
+        });
+        engine.process_from_str(csv).unwrap();
+        let mut output = Vec::new();
+        engine.write_accounts_csv(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+    let expected = output(&input(&[2, 1]));
+    let rows = expected
+        .lines()
+        .map(|line| &line[..line.find(",1.").unwrap_or(line.len())]);
+    assert_eq!(
+        rows.collect::<Vec<_>>(),
+        [
+            "client,currency,available,held,total,locked",
+            "1,",
+            "1,BTC",
+            "1,EUR",
+            "1,USD",
+            "2,",
+            "2,BTC",
+            "2,EUR",
+            "2,USD",
+        ]
+    );
+    // Whatever the order of the input, and of the maps accounts are kept in
+    for _ in 0..10 {
+        assert_eq!(output(&input(&[1, 2])), expected);
+    }
+}
+
+#[test]
+fn with_capacity() {
+    let mut engine = Engine::with_capacity(10, 1000);
+    engine
+        .process_from_str("type,client,tx,amount\ndeposit,1,1,1.0\n")
+        .unwrap();
+    assert!(engine.accounts.capacity() >= 10);
+    assert!(engine.history.capacity() >= 1000);
+    assert_eq!(engine.transaction_count(), 1);
+}
+
+#[test]
+fn expect_capacity() {
+    let mut engine = Engine::new(Options {
+        expect_clients: Some(10),
+        expect_transactions: Some(1000),
+        ..Options::default()
+    });
+    let input = (1..=1000).fold(String::from("type,client,tx,amount\n"), |csv, tx| {
+        csv + &format!("deposit,{},{},1.0\n", tx % 10, tx)
+    });
+    engine.process_from_str(&input).unwrap();
+    let stats = engine.stats();
+    assert!(stats.accounts_capacity >= 10 && stats.history_capacity >= 1000);
+    assert_eq!(
+        (stats.history_inserts, stats.unreallocated_inserts),
+        (1000, 1000)
+    );
+    let mut engine = Engine::default();
+    engine.process_from_str(&input).unwrap();
+    assert!(engine.stats().unreallocated_inserts < 1000);
+    assert_eq!(estimate_transactions(24_000), 1000);
+}
+
+#[test]
+fn lenient_amounts() {
+    let process = |options: Options, csv: &str| {
+        let mut engine = Engine::new(Options {
+            strict: false,
+            sorted: true,
+            ..options
+        });
+        engine.process_from_str(csv).map(|()| {
+            let mut output = Vec::new();
+            engine.write_accounts_csv(&mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        })
+    };
+    let csv =
+        "type,client,tx,amount\ndeposit,1,1,$1.50\ndeposit,2,2, 2.25 $\nwithdrawal,1,3,$0.50\n";
+    process(Options::default(), csv).unwrap_err();
+    let lenient = Options {
+        lenient_amounts: Some(LenientAmounts::default()),
+        ..Options::default()
+    };
+    assert_eq!(
+        process(lenient, csv).unwrap(),
+        "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n2,2.2500,0.0000,2.2500,false\n"
+    );
+    let csv = "type;client;tx;amount\ndeposit;1;1;1.000,50\ndeposit;2;2;€2.000.000\n";
+    let european = Options {
+        delimiter: b';',
+        lenient_amounts: Some(LenientAmounts {
+            symbol: "€".to_owned(),
+            thousands_separator: '.',
+        }),
+        ..Options::default()
+    };
+    assert_eq!(
+        process(european, csv).unwrap(),
+        "client,available,held,total,locked\n1,1000.5000,0.0000,1000.5000,false\n2,2000000.0000,0.0000,2000000.0000,false\n"
+    );
+    let us = LenientAmounts::default();
+    assert_eq!(us.normalize("$1,000.50"), "1000.50");
+    assert_eq!(us.normalize("- $ 3"), "-3");
+    assert_eq!(us.normalize("1.5 USD"), "1.5 USD");
+}
+
+#[test]
+fn negative_balance() {
+    let mut engine = Engine::new(Options {
+        strict: true,
+        ..Options::default()
+    });
+    let error = engine.process_from_str("type,client,tx,amount\ndeposit,1,1,-2.0\n");
+    assert!(matches!(
+        error,
+        Err(EngineError::Rejected {
+            rejection: Rejection::NegativeBalance { client: 1, .. },
+            ..
+        })
+    ));
+    // Disputing a negative deposit would take held funds below zero, nothing changes
+    engine
+        .process_from_str("type,client,tx,amount\ndeposit,1,2,3.0\ndeposit,1,3,-1.0\n")
+        .unwrap();
+    engine
+        .process_from_str("type,client,tx,amount\ndispute,1,3,\n")
+        .unwrap_err();
+    let ledger = engine.accounts().get(1).unwrap();
+    assert_eq!((ledger.available, ledger.held), (Amount(2.0), Amount::ZERO));
+    // Whereas disputing funds already withdrawn still takes available funds below zero
+    engine
+        .process_from_str("type,client,tx,amount\nwithdrawal,1,4,2.0\ndispute,1,2,\n")
+        .unwrap();
+    let ledger = engine.accounts().get(1).unwrap();
+    assert_eq!((ledger.available, ledger.held), (Amount(-3.0), Amount(3.0)));
+    assert_eq!(ledger.available.abs(), Amount(3.0));
+}
+
+#[test]
+fn round() {
+    assert_eq!(Amount(1.23456789).round(4), Amount(1.2346));
+    assert_eq!(Amount(1.23456789).round(2), Amount(1.23));
+    assert_eq!(Amount(1.5).round(0), Amount(2.0));
+    assert_eq!(Amount(2.00005).round(4), Amount(2.0001));
+    assert_eq!(Amount(1.50000000001).normalize(), Amount(1.5));
+    assert_eq!(Amount(0.7 + 0.1).normalize().0, 0.8);
+}
+
+#[test]
+fn format() {
+    let fixed = |amount| Amount(amount).format(AmountFormat::Fixed4, 4);
+    let trim = |amount| Amount(amount).format(AmountFormat::Trim, 4);
+    assert_eq!((fixed(0.0), trim(0.0)), ("0.0000".into(), "0".into()));
+    assert_eq!((fixed(-0.00001), trim(-0.0)), ("0.0000".into(), "0".into()));
+    assert_eq!((fixed(2.0), trim(2.0)), ("2.0000".into(), "2".into()));
+    assert_eq!((fixed(10.0), trim(-10.0)), ("10.0000".into(), "-10".into()));
+    assert_eq!(
+        (fixed(0.0001), trim(0.0001)),
+        ("0.0001".into(), "0.0001".into())
+    );
+    assert_eq!(
+        (fixed(0.7 + 0.1), trim(0.7 + 0.1)),
+        ("0.8000".into(), "0.8".into())
+    );
+    assert_eq!(
+        (fixed(1.5000000000000002), trim(1.5000000000000002)),
+        ("1.5000".into(), "1.5".into())
+    );
+    assert_eq!(
+        (fixed(1e15 + 0.25), trim(1e20)),
+        (
+            "1000000000000000.2500".into(),
+            "100000000000000000000".into()
+        )
+    );
+    assert_eq!(Amount(1.2345).format(AmountFormat::Fixed4, 2), "1.23");
+}
+
+#[test]
+fn round_input_amounts() {
+    let mut engine = Engine::default();
+    engine
+        .process_from_str("type,client,tx,amount\ndeposit,1,1,1.23456789\n")
+        .unwrap();
+    assert_eq!(engine.accounts.get(1).unwrap().available, Amount(1.2346));
+}
+
+// Relies on rejected transactions being skipped
+#[cfg(not(feature = "strict_mode"))]
+#[test]
+fn stores() {
+    let input = workload::Workload {
+        rows: 10_000,
+        clients: 100,
+        dispute_ratio: 0.1,
+        seed: 42,
+    }
+    .generate();
+    let output = |accounts_store, history_store| {
+        let mut engine = Engine::new(Options {
+            sorted: true,
+            accounts_store,
+            history_store,
+            ..Options::default()
+        });
+        engine.process_from_str(&input).unwrap();
+        let mut output = Vec::new();
+        engine.write_accounts_csv(&mut output).unwrap();
+        output
+    };
+    let expected = output(StoreKind::HashMap, StoreKind::HashMap);
+    for kind in [StoreKind::FxHashMap, StoreKind::BTreeMap, StoreKind::Vec] {
+        assert_eq!(output(kind, StoreKind::HashMap), expected);
+        assert_eq!(output(StoreKind::HashMap, kind), expected);
+    }
+}
+
+#[test]
+fn top_n_by_balance() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,5.0
+deposit,3,3,3.0
+deposit,4,4,5.0
+deposit,5,5,2.0
+dispute,2,2,
+withdrawal,3,6,2.5
+";
+    let mut engine = Engine::default();
+    engine.process_from_str(INPUT).unwrap();
+    let top = |n| {
+        engine
+            .top_n_by_balance(n)
+            .into_iter()
+            .map(|(client, ledger)| (client, ledger.total()))
+            .collect::<Vec<_>>()
+    };
+    // Held funds count in the total, and ties are broken by client ID
+    assert_eq!(
+        top(3),
+        [(2, Amount(5.0)), (4, Amount(5.0)), (5, Amount(2.0))]
+    );
+    assert_eq!(top(0), []);
+    assert_eq!(top(10).len(), 5);
+}
+
+// Relies on rejected transactions being skipped
+#[cfg(not(feature = "strict_mode"))]
+#[test]
+fn disputes() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,2.0
+deposit,2,3,4.0
+dispute,1,1,
+dispute,1,1,
+dispute,1,3,
+resolve,1,2,
+deposit,1,1,8.0
+dispute,1,2,
+chargeback,1,2,
+";
+    let mut engine = Engine::default();
+    engine.process_from_str(INPUT).unwrap();
+    // Disputes are tracked per transaction: tx 1 is held once, tx 3 belongs to client 2, tx 2
+    // isn't under dispute when resolved, and the replayed tx 1 is ignored
+    let ledger = engine.accounts.get(1).unwrap();
+    assert_eq!((ledger.available, ledger.held), (Amount(0.0), Amount(1.0)));
+    assert_eq!(ledger.status, LedgerStatus::Locked);
+    assert_eq!(engine.accounts.get(2).unwrap().available, Amount(4.0));
+}
+
+#[test]
+fn check_ordering() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+dispute,1,2,
+deposit,1,2,2.0
+";
+    let options = Options {
+        strict: true,
+        check_ordering: true,
+        ..Options::default()
+    };
+    let mut engine = Engine::new(options.clone());
+    match engine.process_from_str(INPUT) {
+        Err(EngineError::OutOfOrder {
+            line: 4,
+            tx: 2,
+            referenced_at: 3,
+        }) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    // A transaction that never appears is told apart, once the whole input is read
+    let mut engine = Engine::new(options);
+    match engine.process_from_str("type,client,tx,amount\ndispute,1,3,\n") {
+        Err(EngineError::Rejected {
+            line: 2,
+            rejection: Rejection::UnknownTx(3),
+        }) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn diff() {
+    let mut engine = Engine::default();
+    let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\n";
+    engine.process_from_str(input).unwrap();
+    let baseline = engine.try_clone().unwrap();
+    let input = "type,client,tx,amount
+deposit,3,3,3.0
+dispute,2,2,
+chargeback,2,2,
+";
+    engine.process_from_str(input).unwrap();
+    assert_eq!(
+        engine.diff(&baseline),
+        [
+            AccountDiff {
+                client: 2,
+                available_delta: Amount(-2.0),
+                held_delta: Amount(0.0),
+                status_changed: Some((LedgerStatus::Default, LedgerStatus::Locked)),
+            },
+            AccountDiff {
+                client: 3,
+                available_delta: Amount(3.0),
+                held_delta: Amount(0.0),
+                status_changed: None,
+            },
+        ]
+    );
+}
+
+// Relies on rejected transactions being skipped
+#[cfg(not(feature = "strict_mode"))]
+#[test]
+fn max_history() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,2.0
+dispute,1,1,
+resolve,1,1,
+deposit,1,3,4.0
+dispute,1,2,
+dispute,1,1,
+";
+    let mut engine = Engine::new(Options {
+        max_history: Some(2),
+        ..Options::default()
+    });
+    engine.process_from_str(INPUT).unwrap();
+    // Disputing tx 1 made it more recent than tx 2, which got evicted by tx 3
+    assert_eq!(engine.transaction_count(), 2);
+    let ledger = engine.accounts.get(1).unwrap();
+    assert_eq!((ledger.available, ledger.held), (Amount(6.0), Amount(1.0)));
+}
+
+#[test]
+fn error_policies() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,1,2,2.0
+deposit,one,3,3.0
+deposit,1,4,4.0
+";
+    for (strict, skip_parse_errors) in [(false, false), (false, true), (true, false), (true, true)]
+    {
+        let mut engine = Engine::new(Options {
+            strict,
+            skip_parse_errors,
+            ..Options::default()
+        });
+        let result = engine.process_from_str(INPUT);
+        let available = engine.accounts.get(1).unwrap().available;
+        match (strict, skip_parse_errors, result) {
+            // The insufficient funds withdrawal comes first
+            (true, _, Err(EngineError::Rejected { line: 3, .. })) => {}
+            (false, false, Err(EngineError::Parse { line: 4, .. })) => {
+                assert_eq!(available, Amount(1.0))
+            }
+            (false, true, Ok(())) => assert_eq!(available, Amount(5.0)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    // A parse error is skipped even in strict mode
+    let input = "type,client,tx,amount\ndeposit,one,1,1.0\ndeposit,1,2,2.0\n";
+    let mut engine = Engine::new(Options {
+        strict: true,
+        skip_parse_errors: true,
+        ..Options::default()
+    });
+    engine.process_from_str(input).unwrap();
+    assert_eq!(engine.processed_count(), 2);
+    assert_eq!(engine.skipped_count(), 1);
+    assert_eq!(engine.accounts.get(1).unwrap().available, Amount(2.0));
+}
+
+#[test]
+fn snapshot() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,2,1,1.2345
+deposit,1,2,2.0
+dispute,1,2,
+";
+    let mut engine = Engine::new(Options {
+        sorted: true,
+        ..Options::default()
+    });
+    engine.process_from_str(INPUT).unwrap();
+    let snapshot = engine.snapshot().sorted();
+    assert_eq!(snapshot.get(1).unwrap().held, Amount(2.0));
+    assert_eq!(snapshot.get(2).unwrap().total, Amount(1.2345));
+    // The snapshot round-trips through serde without loss
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+    for account in snapshot.iter() {
+        wtr.serialize(account).unwrap();
+    }
+    let csv = wtr.into_inner().unwrap();
+    let accounts = csv::Reader::from_reader(csv.as_slice())
+        .deserialize()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(AccountsSnapshot(accounts), snapshot);
+    // And it's what the CSV writer writes
+    let mut output = Vec::new();
+    engine.write_accounts_csv(&mut output).unwrap();
+    let mut expected = String::from("client,available,held,total,locked\n");
+    for account in snapshot.iter() {
+        let (available, held, total) = (account.available, account.held, account.total);
+        expected += &format!(
+            "{},{},{},{},{}\n",
+            account.client, available, held, total, account.locked
+        );
+    }
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+}
+
+#[test]
+fn open_disputes() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,2.0
+deposit,1,3,4.0
+deposit,2,4,8.0
+dispute,1,1,
+dispute,1,3,
+dispute,2,4,
+resolve,2,4,
+";
+    let mut engine = Engine::default();
+    engine.process_from_str(INPUT).unwrap();
+    let open = engine.open_disputes();
+    assert_eq!(
+        open,
+        [OpenDisputes {
+            client: 1,
+            currency: None,
+            count: 2,
+            held: Amount(5.0),
+        }]
+    );
+    // Open disputes reconcile with held funds
+    assert_eq!(open[0].held, engine.accounts.get(1).unwrap().held);
+    let mut output = Vec::new();
+    engine.write_open_disputes_csv(&mut output).unwrap();
+    assert_eq!(output, b"client,open_disputes,held\n1,2,5.0000\n");
+}
+
+#[test]
+fn interrupt() {
+    static INTERRUPT: AtomicBool = AtomicBool::new(false);
+    let mut engine = Engine::new(Options {
+        interrupt: Some(&INTERRUPT),
+        ..Options::default()
+    });
+    engine
+        .process_from_str("type,client,tx,amount\ndeposit,1,1,1.0\n")
+        .unwrap();
+    INTERRUPT.store(true, atomic::Ordering::SeqCst);
+    match engine.process_from_str("type,client,tx,amount\ndeposit,1,2,1.0\n") {
+        Err(EngineError::Interrupted(1)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    // The state up to the interruption is kept
+    assert_eq!(engine.accounts.get(1).unwrap().available, Amount(1.0));
+}
+
+// Relies on rejected transactions being skipped
+#[cfg(not(feature = "strict_mode"))]
+#[test]
+fn client_out_of_range() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,70000,2,2.0
+deposit,1,3,4.0
+";
+    let mut engine = Engine::default();
+    engine.process_from_str(INPUT).unwrap();
+    assert_eq!(engine.accounts.get(1).unwrap().available, Amount(5.0));
+    assert_eq!(engine.processed_count(), 3);
+    let mut engine = Engine::new(Options {
+        strict: true,
+        ..Options::default()
+    });
+    match engine.process_from_str(INPUT) {
+        Err(EngineError::Rejected {
+            line: 3,
+            rejection: Rejection::ClientOutOfRange(client),
+        }) => assert_eq!(client, "70000"),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+/// With the `strict_mode` feature, the default engine fails on the first rejected transaction
+#[cfg(all(test, feature = "strict_mode"))]
+fn process_strictly(input: &str) {
+    let mut engine = Engine::default();
+    if let Err(e) = engine.process_from_str(input) {
+        panic!("{}", e);
+    }
+}
+
+#[cfg(feature = "strict_mode")]
+#[test]
+#[should_panic(expected = "line 3: client 1 can't withdraw 2.0000 (only 1.0000 available)")]
+fn strict_insufficient_funds() {
+    process_strictly("type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,2.0\n");
+}
+
+#[cfg(feature = "strict_mode")]
+#[test]
+#[should_panic(expected = "line 5: account of client 1 is locked")]
+fn strict_locked_account() {
+    process_strictly(
+        "type,client,tx,amount
+deposit,1,1,1.0
+dispute,1,1,
+chargeback,1,1,
+deposit,1,2,1.0
+",
+    );
+}
+
+#[cfg(feature = "strict_mode")]
+#[test]
+#[should_panic(expected = "line 3: transaction 2 not found")]
+fn strict_dispute_unknown_tx() {
+    process_strictly("type,client,tx,amount\ndeposit,1,1,1.0\ndispute,1,2,\n");
+}
+
+#[cfg(feature = "strict_mode")]
+#[test]
+#[should_panic(expected = "line 3: transaction 1 is not under dispute")]
+fn strict_resolve_not_disputed() {
+    process_strictly("type,client,tx,amount\ndeposit,1,1,1.0\nresolve,1,1,\n");
+}
+
+#[cfg(feature = "strict_mode")]
+#[test]
+#[should_panic(expected = "line 3: transaction 1 is not under dispute")]
+fn strict_chargeback_not_disputed() {
+    process_strictly("type,client,tx,amount\ndeposit,1,1,1.0\nchargeback,1,1,\n");
+}
+
+#[cfg(feature = "strict_mode")]
+#[test]
+#[should_panic(expected = "line 3: transaction 1 has an unexpected amount 5.0000")]
+fn strict_dispute_with_amount() {
+    process_strictly("type,client,tx,amount\ndeposit,1,1,1.0\ndispute,1,1,5.0\n");
+}
+
+#[cfg(feature = "strict_mode")]
+#[test]
+#[should_panic(expected = "line 2: transaction 1 is missing an amount")]
+fn strict_deposit_without_amount() {
+    process_strictly("type,client,tx,amount\ndeposit,1,1,\n");
+}
+
+// Relies on rejected transactions being skipped
+#[cfg(not(feature = "strict_mode"))]
+#[test]
+fn amount_presence() {
+    let mut engine = Engine::default();
+    engine
+        .process_from_str(
+            "type,client,tx,amount
+deposit,1,1,1.0
+dispute,1,1,5.0
+deposit,1,2,
+resolve,1,1,1.0
+dispute,1,1,
+",
+        )
+        .unwrap();
+    assert_eq!(engine.skipped_count(), 3);
+    let ledger = engine.accounts().get(1).unwrap();
+    assert_eq!((ledger.available, ledger.held), (Amount(0.0), Amount(1.0)));
+}
+
+#[test]
+fn into_accounts() {
+    for accounts_store in [
+        StoreKind::HashMap,
+        StoreKind::FxHashMap,
+        StoreKind::BTreeMap,
+        StoreKind::Vec,
+    ] {
+        let mut engine = Engine::new(Options {
+            accounts_store,
+            ..Options::default()
+        });
+        engine
+            .process_from_str("type,client,tx,amount\ndeposit,1,1,1.5\ndeposit,3,2,3.0\n")
+            .unwrap();
+        assert_eq!(engine.accounts().get(3).unwrap().available, Amount(3.0));
+        let accounts = engine.into_accounts();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[&1].available, Amount(1.5));
+    }
+}
+
+#[test]
+fn import_accounts() {
+    const BEFORE: &str = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,4.0
+withdrawal,1,3,2.5
+dispute,2,2,
+";
+    const AFTER: &str = "type,client,tx,amount
+deposit,1,4,1.0
+resolve,2,2,
+dispute,1,1,
+chargeback,1,1,
+deposit,3,5,2.0
+";
+    let options = Options {
+        strict: false,
+        sorted: true,
+        ..Options::default()
+    };
+    let output = |engine: &Engine| {
+        let mut output = Vec::new();
+        engine.write_accounts_csv(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+    let mut sequential = Engine::new(options.clone());
+    sequential.process_from_str(BEFORE).unwrap();
+    sequential.process_from_str(AFTER).unwrap();
+    let mut previous = Engine::new(options.clone());
+    previous.process_from_str(BEFORE).unwrap();
+    let history = previous.transaction_history();
+    let accounts = previous.into_accounts();
+    let mut restored = Engine::new(options).with_transaction_history(history);
+    restored.import_accounts(accounts);
+    restored.process_from_str(AFTER).unwrap();
+    assert_eq!(output(&restored), output(&sequential));
+    assert_eq!(
+        output(&restored),
+        "client,available,held,total,locked\n1,-1.5000,0.0000,-1.5000,true\n2,4.0000,0.0000,4.0000,false\n3,2.0000,0.0000,2.0000,false\n"
+    );
+    // Counts are carried over too
+    assert_eq!(restored.accounts().get(2).unwrap().dispute_count, 1);
+}
+
+#[test]
+// Relies on rejected transactions being skipped
+#[cfg(not(feature = "strict_mode"))]
+fn metrics() {
+    use std::io::{Read, Write};
+    let metrics = Arc::new(Metrics::default());
+    let addr = metrics::serve("127.0.0.1:0", metrics.clone()).unwrap();
+    let mut engine = Engine::new(Options {
+        metrics: Some(metrics.clone()),
+        ..Options::default()
+    });
+    let input = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,2,3,7.0
+dispute,1,1,
+dispute,2,2,
+resolve,2,2,
+dispute,2,9,
+chargeback,1,1,
+deposit,1,4,1.0
+";
+    engine.process_from_str(input).unwrap();
+    let scrape = |path: &str| {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let response = scrape("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    for sample in [
+        "transactions_rows_received_total 9\n",
+        "transactions_rows_applied_total 6\n",
+        "transactions_rows_rejected_total{reason=\"insufficient_funds\"} 1\n",
+        "transactions_rows_rejected_total{reason=\"unknown_tx\"} 1\n",
+        "transactions_rows_rejected_total{reason=\"account_locked\"} 1\n",
+        "transactions_rows_rejected_total{reason=\"duplicate_tx\"} 0\n",
+        "transactions_active_connections 0\n",
+        "transactions_clients 2\n",
+        "transactions_locked_accounts 1\n",
+        "transactions_held_funds 0\n",
+        "transactions_row_latency_seconds_bucket{le=\"+Inf\"} 9\n",
+        "transactions_row_latency_seconds_count 9\n",
+    ] {
+        assert!(
+            response.contains(sample),
+            "{} missing from:\n{}",
+            sample,
+            response
+        );
+    }
+    assert!(scrape("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[test]
+// Relies on rejected transactions being skipped
+#[cfg(not(feature = "strict_mode"))]
+fn include_empty_accounts() {
+    const INPUT: &str = "type,client,tx,amount
+withdrawal,1,1,1.0
+deposit,2,2,1.0
+dispute,2,2,
+chargeback,2,2,
+deposit,3,3,1.0
+";
+    for (include_empty_accounts, expected) in [
+        (
+            true,
+            "client,available,held,total,locked\n1,0.0000,0.0000,0.0000,false\n2,0.0000,0.0000,0.0000,true\n3,1.0000,0.0000,1.0000,false\n",
+        ),
+        (
+            false,
+            "client,available,held,total,locked\n2,0.0000,0.0000,0.0000,true\n3,1.0000,0.0000,1.0000,false\n",
+        ),
+    ] {
+        let mut engine = Engine::new(Options {
+            include_empty_accounts,
+            sorted: true,
+            ..Options::default()
+        });
+        engine.process_from_str(INPUT).unwrap();
+        let mut output = Vec::new();
+        engine.write_accounts_csv(&mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+}
+
+#[test]
+fn merge() {
+    let process = |input: &'static str| {
+        std::thread::spawn(move || {
+            let mut engine = Engine::default();
+            engine.process_from_str(input).unwrap();
+            engine
+        })
+    };
+    let left = process("type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\n");
+    let right = process("type,client,tx,amount\ndeposit,3,3,3.0\ndispute,3,3,\n");
+    let (left, right) = (left.join().unwrap(), right.join().unwrap());
+    let mut merged = left.try_clone().unwrap().merge(right).unwrap();
+    assert_eq!(merged.account_count(), 3);
+    assert_eq!(merged.transaction_count(), 3);
+    assert_eq!(merged.processed_count(), 4);
+    // Disputes opened before the merge can still be settled afterwards
+    merged
+        .process_from_str("type,client,tx,amount\nresolve,3,3,\n")
+        .unwrap();
+    assert_eq!(merged.accounts().get(3).unwrap().available, Amount(3.0));
+
+    let mut other = Engine::default();
+    other
+        .process_from_str("type,client,tx,amount\ndeposit,2,4,1.0\n")
+        .unwrap();
+    assert!(matches!(
+        left.merge(other),
+        Err(EngineError::MergeConflict(2))
+    ));
+}
+
+#[test]
+fn merge_dispute_expiry() {
+    const LEFT: &str = "type,client,tx,amount
+deposit,1,1,10.0
+dispute,1,1,
+deposit,1,2,1.0
+";
+    const RIGHT: &str = "deposit,2,3,5.0
+dispute,2,3,
+deposit,2,4,1.0
+deposit,2,5,1.0
+";
+    let options = Options {
+        strict: true,
+        sorted: true,
+        dispute_expiry_after: Some(3),
+        ..Options::default()
+    };
+    let accounts = |engine: &Engine| {
+        let mut output = Vec::new();
+        engine.write_accounts_csv(&mut output).unwrap();
+        let sequence = engine.accounts().get(2).unwrap().dispute_tx_sequence;
+        (String::from_utf8(output).unwrap(), sequence)
+    };
+    let mut single = Engine::new(options.clone());
+    single.process_from_str(&(LEFT.to_owned() + RIGHT)).unwrap();
+    let mut left = Engine::new(options.clone());
+    left.process_from_str(LEFT).unwrap();
+    let mut right = Engine::new(options);
+    right
+        .process_from_str(&format!("type,client,tx,amount\n{}", RIGHT))
+        .unwrap();
+    let mut merged = left.merge(right).unwrap();
+    // The dispute of the left input expired, as it did in the single run
+    assert_eq!(accounts(&merged), accounts(&single));
+    assert_eq!(merged.accounts().get(1).unwrap().held, Amount::ZERO);
+    // The one of the right input expires as late as in the single run
+    for engine in [&mut merged, &mut single] {
+        engine
+            .process_from_str("type,client,tx,amount\ndeposit,2,6,1.0\n")
+            .unwrap();
+    }
+    assert_eq!(accounts(&merged), accounts(&single));
+    assert_eq!(merged.accounts().get(2).unwrap().held, Amount::ZERO);
+    // The merged history is bounded as well
+    let bounded = |input: &str| {
+        let mut engine = Engine::new(Options {
+            strict: true,
+            max_history: Some(2),
+            history_overflow: HistoryOverflow::EvictOldest,
+            ..Options::default()
+        });
+        engine.process_from_str(input).unwrap();
+        engine
+    };
+    let left = bounded("type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,1.0\n");
+    let right = bounded("type,client,tx,amount\ndeposit,2,3,1.0\n");
+    let merged = left.merge(right).unwrap();
+    assert_eq!((merged.transaction_count(), merged.evicted_count), (2, 1));
+    assert!(!merged.transaction_exists(1));
+}
+
+#[test]
+fn history_overflow() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,2.0
+dispute,1,1,
+deposit,1,1,4.0
+";
+    let process = |history_overflow| {
+        let mut engine = Engine::new(Options {
+            strict: false,
+            max_history: Some(1),
+            history_overflow,
+            ..Options::default()
+        });
+        let result = engine.process_from_str(INPUT);
+        (engine, result)
+    };
+    let (_, result) = process(HistoryOverflow::Error);
+    assert!(matches!(
+        result,
+        Err(EngineError::HistoryOverflow {
+            line: 3,
+            max_history: 1
+        })
+    ));
+    // Tx 1 is evicted by tx 2: its dispute is rejected, and its replay isn't detected
+    let (engine, result) = process(HistoryOverflow::EvictOldest);
+    result.unwrap();
+    assert_eq!(engine.evicted_count(), 2);
+    let ledger = engine.accounts.get(1).unwrap();
+    assert_eq!((ledger.available, ledger.held), (Amount(7.0), Amount(0.0)));
+    // Tx 1 is spilled by tx 2, and recalled by its dispute (spilling tx 2)
+    let (engine, result) = process(HistoryOverflow::SpillDisk);
+    result.unwrap();
+    assert_eq!(engine.evicted_count(), 0);
+    assert_eq!(engine.transaction_count(), 1);
+    assert_eq!(engine.spilled[0].len(), 1);
+    assert_eq!(engine.skipped_count(), 1);
+    let ledger = engine.accounts.get(1).unwrap();
+    assert_eq!((ledger.available, ledger.held), (Amount(2.0), Amount(1.0)));
+}
+
+#[test]
+fn error_to_json() {
+    let error = EngineError::Rejected {
+        line: 3,
+        rejection: Rejection::InsufficientFunds {
+            client: 1,
+            requested: Amount(5.0),
+            available: Amount(3.0),
+        },
+    };
+    assert_eq!(
+        error.to_json(None, Some(2)),
+        r#"{"line":3,"tx_id":2,"client_id":1,"error":"InsufficientFunds","detail":"client 1 can't withdraw 5.0000 (only 3.0000 available)"}"#
+    );
+    let error = EngineError::Parse {
+        line: 4,
+        field: None,
+        message: "unexpected \"quote\"\n".to_owned(),
+    };
+    assert_eq!(
+        error.to_json(None, None),
+        r#"{"line":4,"tx_id":null,"client_id":null,"error":"Parse","detail":"unexpected \"quote\"\n"}"#
+    );
+}
+
+#[test]
+// Relies on rejected transactions being skipped
+#[cfg(not(feature = "strict_mode"))]
+fn disputable() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,4.0
+dispute,1,2,
+";
+    let mut engine = Engine::default();
+    engine.process_from_str(INPUT).unwrap();
+    assert_eq!(engine.skipped_count(), 1);
+    let ledger = engine.accounts.get(1).unwrap();
+    assert_eq!((ledger.available, ledger.held), (Amount(6.0), Amount(0.0)));
+    assert!(matches!(
+        Engine::default().process_transaction(Transaction(Tx::Dispute, 1, 2, None, None, None)),
+        Err(Rejection::UnknownTx(2))
+    ));
+
+    let mut engine = Engine::new(Options {
+        disputable: vec![Tx::Deposit, Tx::Withdrawal],
+        ..Options::default()
+    });
+    engine.process_from_str(INPUT).unwrap();
+    assert_eq!(engine.skipped_count(), 0);
+    let ledger = engine.accounts.get(1).unwrap();
+    assert_eq!((ledger.available, ledger.held), (Amount(2.0), Amount(4.0)));
+}
+
+#[test]
+fn withdrawal_dispute_policy() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,4.0
+dispute,1,2,
+";
+    // Available and held funds once disputed, and then once resolved or charged back
+    let cases = [
+        (
+            WithdrawalDisputePolicy::Hold,
+            (2.0, 4.0),
+            (6.0, 0.0),
+            (2.0, 0.0),
+        ),
+        (
+            WithdrawalDisputePolicy::Reject,
+            (6.0, 0.0),
+            (6.0, 0.0),
+            (6.0, 0.0),
+        ),
+        (
+            WithdrawalDisputePolicy::CreditHeld,
+            (6.0, 4.0),
+            (6.0, 0.0),
+            (10.0, 0.0),
+        ),
+        (
+            WithdrawalDisputePolicy::Reverse,
+            (10.0, 0.0),
+            (6.0, 0.0),
+            (10.0, 0.0),
+        ),
+    ];
+    for (policy, disputed, resolved, charged_back) in cases {
+        let engine = |then: &str| {
+            let mut engine = Engine::new(Options {
+                strict: false,
+                disputable: vec![Tx::Deposit, Tx::Withdrawal],
+                withdrawal_dispute_policy: policy,
+                ..Options::default()
+            });
+            engine
+                .process_from_str(&format!("{}{}", INPUT, then))
+                .unwrap();
+            engine
+        };
+        let funds = |engine: &Engine| {
+            let ledger = engine.accounts().get(1).unwrap();
+            assert_eq!(ledger.total(), ledger.available + ledger.held);
+            (ledger.available.0, ledger.held.0)
+        };
+        let rejected = u64::from(policy == WithdrawalDisputePolicy::Reject);
+        let disputed_engine = engine("");
+        assert_eq!(funds(&disputed_engine), disputed, "{:?}", policy);
+        assert_eq!(disputed_engine.skipped_count(), rejected);
+        assert_eq!(funds(&engine("resolve,1,2,\n")), resolved, "{:?}", policy);
+        let charged_back_engine = engine("chargeback,1,2,\n");
+        assert_eq!(funds(&charged_back_engine), charged_back, "{:?}", policy);
+        let locked = charged_back_engine.accounts().get(1).unwrap().status == LedgerStatus::Locked;
+        assert_eq!(locked, policy != WithdrawalDisputePolicy::Reject);
+    }
+}
+
+#[test]
+fn two_pass() {
+    let input = workload::Workload {
+        rows: 20_000,
+        clients: 50,
+        dispute_ratio: 0.01,
+        seed: 7,
+    }
+    .generate();
+    // Replays of deposits must still be detected
+    let input = input + "deposit,1,1,1000.0\n";
+    let output = |engine: &Engine| {
+        let mut output = Vec::new();
+        engine.write_accounts_csv(&mut output).unwrap();
+        output
+    };
+    let options = Options {
+        strict: false,
+        sorted: true,
+        ..Options::default()
+    };
+    let mut single = Engine::new(options.clone());
+    single.process_from_str(&input).unwrap();
+    let mut two_pass = Engine::new(options);
+    two_pass
+        .process_two_pass(std::io::Cursor::new(&input))
+        .unwrap();
+    assert_eq!(output(&single), output(&two_pass));
+    assert_eq!(single.skipped_count(), two_pass.skipped_count());
+    assert!(two_pass.transaction_count() * 20 < single.transaction_count());
+    // Columns are found by name by both passes
+    const REORDERED: &str =
+        "tx,type,client,amount\n10,deposit,7,5.0\n11,deposit,7,3.0\n10,dispute,7,\n";
+    let mut two_pass = Engine::new(Options {
+        strict: true,
+        ..Options::default()
+    });
+    two_pass
+        .process_two_pass(std::io::Cursor::new(REORDERED))
+        .unwrap();
+    let account = two_pass.accounts().get(7).unwrap();
+    assert_eq!(
+        (account.available, account.held),
+        (Amount(3.0), Amount(5.0))
+    );
+    assert_eq!(two_pass.transaction_count(), 1);
+}
+
+#[test]
+fn no_header() {
+    const INPUT: &str = "deposit,1,1,1.0\ndeposit,1,2,2.0,,memo,7\n";
+    let mut engine = Engine::new(Options {
+        has_header: false,
+        strict: true,
+        ..Options::default()
+    });
+    engine.process_from_str(INPUT).unwrap();
+    assert_eq!(engine.accounts().get(1).unwrap().available, Amount(3.0));
+    // Otherwise, the first row is taken for a header, which is told
+    let header_is_row = |input: &str| {
+        let source = Options::default().csv_source(input.as_bytes()).unwrap();
+        source.header_is_row()
+    };
+    assert!(header_is_row(INPUT));
+    assert!(!header_is_row("type,client,tx,amount\ndeposit,1,1,1.0\n"));
+    assert!(!header_is_row("kind,account,id,value\ndeposit,1,1,1.0\n"));
+}
+
+#[test]
+fn with_transaction_history() {
+    const BEFORE: &str = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+deposit,2,3,4.0
+dispute,1,2,
+";
+    const AFTER: &str = "type,client,tx,amount
+dispute,1,1,
+resolve,1,2,
+dispute,2,3,
+chargeback,2,3,
+";
+    let options = Options {
+        strict: true,
+        sorted: true,
+        ..Options::default()
+    };
+    let output = |engine: &Engine| {
+        let mut output = Vec::new();
+        engine.write_accounts_csv(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+    let mut uninterrupted = Engine::new(options.clone());
+    uninterrupted.process_from_str(BEFORE).unwrap();
+    let (snapshot, history) = (
+        uninterrupted.snapshot(),
+        uninterrupted.transaction_history(),
+    );
+    uninterrupted.process_from_str(AFTER).unwrap();
+    let mut restored =
+        Engine::load_snapshot(options.clone(), &snapshot).with_transaction_history(history);
+    restored.process_from_str(AFTER).unwrap();
+    assert_eq!(output(&restored), output(&uninterrupted));
+    assert_eq!(
+        output(&restored),
+        "client,available,held,total,locked\n1,5.0000,10.0000,15.0000,false\n2,0.0000,0.0000,0.0000,true\n"
+    );
+    // Without history, the first dispute references an unknown transaction
+    let mut accounts_only = Engine::load_snapshot(options, &snapshot);
+    assert!(matches!(
+        accounts_only.process_from_str(AFTER),
+        Err(EngineError::Rejected {
+            line: 2,
+            rejection: Rejection::UnknownTx(1)
+        })
+    ));
+}
+
+#[test]
+fn pipelined() {
+    let input = workload::Workload {
+        rows: 20_000,
+        clients: 50,
+        dispute_ratio: 0.01,
+        seed: 7,
+    }
+    .generate();
+    let output = |engine: &Engine| {
+        let mut output = Vec::new();
+        engine.write_accounts_csv(&mut output).unwrap();
+        output
+    };
+    let options = Options {
+        strict: false,
+        sorted: true,
+        ..Options::default()
+    };
+    let mut single = Engine::new(options.clone());
+    single.process_from_str(&input).unwrap();
+    // A tiny channel, to exercise backpressure
+    let mut pipelined = Engine::new(options.clone());
+    pipelined
+        .process_pipelined(std::io::Cursor::new(input.clone()), 4)
+        .unwrap();
+    assert_eq!(output(&single), output(&pipelined));
+    assert_eq!(single.skipped_count(), pipelined.skipped_count());
+    // Errors of the reading thread are reported at the line of the row
+    let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,x,2,1.0\ndeposit,1,3,1.0\n";
+    let mut engine = Engine::new(options);
+    match engine.process_pipelined(std::io::Cursor::new(input), 1) {
+        Err(EngineError::Parse { line: 3, .. }) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn amount_ordering() {
+    let mut amounts = [
+        Amount(2.5),
+        Amount(f64::INFINITY),
+        Amount(0.0),
+        Amount(f64::NAN),
+        Amount(-1.0),
+        Amount(-0.0),
+        Amount(0.0001),
+        Amount(f64::NEG_INFINITY),
+    ]
+    .to_vec();
+    amounts.sort();
+    let sorted = amounts.iter().map(|amount| amount.0).collect::<Vec<_>>();
+    assert!(sorted[0].is_nan());
+    assert_eq!(sorted[1..3], [f64::NEG_INFINITY, -1.0]);
+    // Both zeros are equal, so they keep their relative order in a stable sort
+    assert_eq!(sorted[3].to_bits(), 0.0f64.to_bits());
+    assert_eq!(sorted[4].to_bits(), (-0.0f64).to_bits());
+    assert_eq!(sorted[5..], [0.0001, 2.5, f64::INFINITY]);
+    assert_eq!(Amount(f64::NAN), Amount(f64::NAN));
+    assert_eq!(Amount(-0.0), Amount(0.0));
+    assert_eq!(amounts.iter().max(), Some(&Amount(f64::INFINITY)));
+    // Amounts can be keys of ordered collections
+    let by_amount = std::collections::BTreeMap::from([(Amount(1.0), 'a'), (Amount(-1.0), 'b')]);
+    assert_eq!(by_amount.values().collect::<String>(), "ba");
+}
+
+// Relies on rejected transactions being skipped
+#[cfg(not(feature = "strict_mode"))]
+#[test]
+fn dedupe_tx() {
+    const DAY1: &str = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,2,3,8.0
+dispute,1,1,
+deposit,2,4,5.0
+resolve,1,1,
+dispute,2,2,
+";
+    // The last hour of day 1 (from the rejected withdrawal on) is exported again with day 2
+    const DAY2: &str = "type,client,tx,amount
+withdrawal,2,3,8.0
+dispute,1,1,
+deposit,2,4,5.0
+resolve,1,1,
+dispute,2,2,
+withdrawal,1,5,3.0
+resolve,2,2,
+";
+    let tail = DAY2.lines().skip(6).collect::<Vec<_>>().join("\n");
+    let output = |inputs: &[&str]| {
+        let mut engine = Engine::new(Options {
+            dedupe_tx: true,
+            sorted: true,
+            ..Options::default()
+        });
+        for input in inputs {
+            engine.process_from_str(input).unwrap();
+        }
+        let mut output = Vec::new();
+        engine.write_accounts_csv(&mut output).unwrap();
+        (String::from_utf8(output).unwrap(), engine.replayed_count())
+    };
+    let (expected, replayed) = output(&[DAY1, &format!("type,client,tx,amount\n{}\n", tail)]);
+    assert_eq!(replayed, 0);
+    assert_eq!(output(&[DAY1, DAY2]), (expected, 2));
+    // A repeated ID with different contents is still a duplicate
+    let mut engine = Engine::new(Options {
+        dedupe_tx: true,
+        ..Options::default()
+    });
+    engine
+        .process_from_str("type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,1,2.0\n")
+        .unwrap();
+    assert_eq!((engine.replayed_count(), engine.skipped_count()), (0, 1));
+}
+
+#[test]
+fn chargeback_rate() {
+    let mut engine = Engine::default();
+    engine
+        .process_from_str(
+            "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,2.0
+deposit,2,3,1.0
+dispute,1,1,
+dispute,2,3,
+chargeback,1,1,
+resolve,2,3,
+",
+        )
+        .unwrap();
+    let ledger = engine.accounts().get(1).unwrap();
+    assert_eq!(
+        (
+            ledger.tx_count,
+            ledger.dispute_count,
+            ledger.chargeback_count
+        ),
+        (4, 1, 1)
+    );
+    assert_eq!(engine.chargeback_rate(1), Some(0.25));
+    assert_eq!(engine.chargeback_rate(2), Some(0.0));
+    assert_eq!(engine.chargeback_rate(3), None);
+    assert_eq!(engine.high_risk_accounts(0.1), [1]);
+    assert_eq!(engine.high_risk_accounts(0.25), []);
+}
+
+#[test]
+fn check_order() {
+    const INPUT: &str = "type,client,tx,amount,ts
+deposit,1,1,1.0,2024-01-01T10:00:00Z
+deposit,1,2,1.0,2024-01-01T12:00:00+01:00
+deposit,1,3,1.0,2024-01-01T09:00:00Z
+withdrawal,1,4,1.0,
+";
+    let process = |input: &str, check_order| {
+        let mut engine = Engine::new(Options {
+            check_order,
+            multi_currency: true,
+            sorted: true,
+            ..Options::default()
+        });
+        let result = engine.process_from_str(input);
+        let mut output = Vec::new();
+        engine.write_accounts_csv(&mut output).unwrap();
+        (result, String::from_utf8(output).unwrap())
+    };
+    const OUTPUT: &str =
+        "client,currency,available,held,total,locked\n1,,2.0000,0.0000,2.0000,false\n";
+    // A timestamp isn't a currency, and doesn't change accounts
+    for check_order in [OrderCheck::Off, OrderCheck::Warn] {
+        let (result, output) = process(INPUT, check_order);
+        assert!(result.is_ok());
+        assert_eq!(output, OUTPUT);
+    }
+    match process(INPUT, OrderCheck::Error).0 {
+        Err(e @ EngineError::OutOfOrderTimestamp { .. }) => assert_eq!(
+            e.to_string(),
+            "line 4: timestamp 2024-01-01T09:00:00Z is before 2024-01-01T11:00:00Z at line 3"
+        ),
+        other => panic!("unexpected {:?}", other),
+    }
+    // Without a `ts` column, nothing is checked
+    let input = INPUT.lines().map(|line| line.rsplit_once(',').unwrap().0);
+    let input = input.map(|line| format!("{}\n", line)).collect::<String>();
+    let (result, output) = process(&input, OrderCheck::Error);
+    assert!(result.is_ok());
+    assert_eq!(output, OUTPUT);
+    match process(&INPUT.replace("09:00:00Z", "9am"), OrderCheck::Error).0 {
+        Err(EngineError::Parse {
+            line: 4,
+            field: Some("ts"),
+            ..
+        }) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+// Relies on rejected transactions being skipped
+#[cfg(not(feature = "strict_mode"))]
+#[test]
+fn resolve_locked_account() {
+    let mut engine = Engine::default();
+    engine
+        .process_from_str(
+            "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+dispute,1,1,
+dispute,1,2,
+chargeback,1,2,
+deposit,1,3,1.0
+resolve,1,2,
+",
+        )
+        .unwrap();
+    // Neither a deposit nor resolving the charged back transaction unlock the account
+    let ledger = engine.accounts().get(1).unwrap();
+    assert_eq!((ledger.available, ledger.held), (Amount(0.0), Amount(10.0)));
+    assert_eq!(ledger.status, LedgerStatus::Locked);
+    assert_eq!(engine.skipped_count(), 2);
+    // Invalidating the dispute still pending does
+    engine
+        .process_from_str("type,client,tx,amount\nresolve,1,1,\ndeposit,1,4,1.0\n")
+        .unwrap();
+    let ledger = engine.accounts().get(1).unwrap();
+    assert_eq!((ledger.available, ledger.held), (Amount(11.0), Amount(0.0)));
+    assert_eq!(ledger.status, LedgerStatus::Default);
+}
+
+#[test]
+fn reclaim_charged_back() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+dispute,1,1,
+dispute,1,2,
+chargeback,1,1,
+resolve,1,2,
+dispute,1,1,
+chargeback,1,1,
+deposit,1,1,10.0
+dispute,2,1,
+";
+    let mut engine = Engine::new(Options {
+        strict: false,
+        ..Options::default()
+    });
+    engine.process_from_str(INPUT).unwrap();
+    // Unlocked by the resolve, the account can't have the same transaction charged back twice
+    let ledger = engine.accounts().get(1).unwrap();
+    assert_eq!((ledger.available, ledger.held), (Amount(5.0), Amount(0.0)));
+    assert_eq!(ledger.status, LedgerStatus::Default);
+    let stats = engine.stats();
+    let rejected = |rejection: Rejection| stats.rejected[rejection.index()];
+    assert_eq!(rejected(Rejection::ChargedBack(1)), 2);
+    assert_eq!(rejected(Rejection::DuplicateTx(1)), 1);
+    // Someone else's transaction is still unknown to a client
+    assert_eq!(rejected(Rejection::UnknownTx(1)), 1);
+    assert_eq!((stats.reclaimed, engine.transaction_count()), (1, 1));
+    let mut engine = Engine::new(Options {
+        strict: true,
+        ..Options::default()
+    });
+    assert!(matches!(
+        engine.process_from_str(INPUT),
+        Err(EngineError::Rejected {
+            line: 8,
+            rejection: Rejection::ChargedBack(1)
+        })
+    ));
+}
+
+#[test]
+fn process_from_file() {
+    let path = std::env::temp_dir().join("rust-coding-test-process_from_file.csv");
+    std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,2.0\n").unwrap();
+    let mut engine = Engine::default();
+    engine.process_from_file(&path).unwrap();
+    assert_eq!(engine.accounts().get(1).unwrap().available, Amount(2.0));
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(
+        engine.process_from_file(&path),
+        Err(EngineError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound
+    ));
+}
+
+#[test]
+fn no_phantom_accounts() {
+    let mut engine = Engine::new(Options {
+        strict: false,
+        sorted: true,
+        ..Options::default()
+    });
+    engine
+        .process_from_str(
+            "type,client,tx,amount
+deposit,1,1,2.0
+dispute,9999,5,
+resolve,9998,1,
+chargeback,9997,1,
+withdrawal,2,2,1.0
+",
+        )
+        .unwrap();
+    // A rejected withdrawal still opens an account, as any deposit or withdrawal does
+    let mut output = Vec::new();
+    engine.write_accounts_csv(&mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n2,0.0000,0.0000,0.0000,false\n"
+    );
+    let stats = engine.stats();
+    assert_eq!(stats.rejected[Rejection::UnknownTx(5).index()], 3);
+}
+
+#[test]
+fn withdrawal_drift() {
+    let mut engine = Engine::new(Options {
+        strict: true,
+        ..Options::default()
+    });
+    // `0.7 + 0.1` is `0.7999999999999999`, but balances are rounded, so it's stored as `0.8`, and
+    // withdrawing that leaves exactly nothing
+    engine
+        .process_from_str(
+            "type,client,tx,amount\ndeposit,1,1,0.7\ndeposit,1,2,0.1\nwithdrawal,1,3,0.8\n",
+        )
+        .unwrap();
+    assert_eq!(engine.accounts().get(1).unwrap().available.0, 0.0);
+    // A ledger updated directly may still hold a drifted balance, which the tolerance of
+    // withdrawals makes up for, whereas an overdraft of a ten-thousandth is still rejected
+    let mut ledger = Ledger {
+        available: Amount(0.7 + 0.1),
+        ..Ledger::default()
+    };
+    assert_ne!(ledger.available.0, 0.8);
+    ledger.withdraw(1, Amount(0.8001), 4).unwrap_err();
+    ledger.withdraw(1, Amount(0.8), 4).unwrap();
+    assert_eq!(ledger.available.0, 0.0);
+}
+
+#[test]
+fn transaction_exists() {
+    let mut engine = Engine::new(Options {
+        strict: false,
+        ..Options::default()
+    });
+    engine
+        .process_from_str(
+            "type,client,tx,amount
+deposit,1,1,1.5
+withdrawal,1,2,0.5
+withdrawal,1,3,5.0
+deposit,2,4,2.0
+dispute,2,4,
+chargeback,2,4,
+",
+        )
+        .unwrap();
+    assert!(engine.transaction_exists(1));
+    assert_eq!(engine.transaction_amount(1), Some(Amount(1.5)));
+    assert_eq!(engine.transaction_amount(2), Some(Amount(0.5)));
+    // Rejected, charged back, and never seen
+    for tx in [3, 4, 5] {
+        assert!(!engine.transaction_exists(tx));
+        assert_eq!(engine.transaction_amount(tx), None);
+    }
+    // Lookups are read-only
+    assert_eq!(engine.processed_count(), 6);
+}
+
+#[test]
+fn withdrawal_boundary() {
+    let mut engine = Engine::new(Options {
+        strict: true,
+        ..Options::default()
+    });
+    // Amounts with no drift, where the tolerance plays no part: the boundary is exact
+    engine
+        .process_from_str("type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,10.0\n")
+        .unwrap();
+    engine
+        .process_from_str("type,client,tx,amount\nwithdrawal,1,3,10.0\n")
+        .unwrap();
+    assert_eq!(engine.accounts().get(1).unwrap().available, Amount::ZERO);
+    assert!(matches!(
+        engine.process_from_str("type,client,tx,amount\nwithdrawal,2,4,10.0001\n"),
+        Err(EngineError::Rejected {
+            rejection: Rejection::InsufficientFunds { .. },
+            ..
+        })
+    ));
+    assert_eq!(engine.accounts().get(2).unwrap().available, Amount(10.0));
+    // A ten-thousandth is left rather than rounded away
+    engine
+        .process_from_str("type,client,tx,amount\nwithdrawal,2,5,9.9999\n")
+        .unwrap();
+    let available = engine.accounts().get(2).unwrap().available;
+    assert_eq!(available.format(AmountFormat::Fixed4, 4), "0.0001");
+    // The tolerance shrinks along with the smallest amount
+    let mut engine = Engine::new(Options {
+        strict: true,
+        input_precision: 8,
+        ..Options::default()
+    });
+    engine
+        .process_from_str("type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,0.00004\n")
+        .unwrap();
+    assert!(matches!(
+        engine.process_from_str("type,client,tx,amount\nwithdrawal,1,3,1.00004\n"),
+        Err(EngineError::Rejected {
+            rejection: Rejection::InsufficientFunds { .. },
+            ..
+        })
+    ));
+    engine
+        .process_from_str("type,client,tx,amount\nwithdrawal,2,4,0.00001\n")
+        .unwrap();
+    assert_eq!(engine.accounts().get(2).unwrap().available, Amount(0.00003));
+}
+
+#[test]
+fn write_accounts_json() {
+    let mut engine = Engine::new(Options {
+        multi_currency: true,
+        output_precision: Some(2),
+        sorted: true,
+        ..Options::default()
+    });
+    engine
+        .process_from_str(
+            "type,client,tx,amount,currency\ndeposit,1,1,1.5,EUR\ndeposit,2,2,2.25,\n",
+        )
+        .unwrap();
+    let mut output = Vec::new();
+    engine.write_accounts_json(&mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        concat!(
+            r#"[{"client":1,"currency":"EUR","available":"1.50","held":"0.00","total":"1.50","#,
+            r#""locked":false},{"client":2,"currency":"","available":"2.25","held":"0.00","#,
+            r#""total":"2.25","locked":false}]"#,
+            "\n"
+        )
+    );
+    let mut output = Vec::new();
+    Engine::default().write_accounts_json(&mut output).unwrap();
+    assert_eq!(output, b"[]\n");
+}
+
+#[test]
+fn load_snapshot_json() {
+    let options = || Options {
+        strict: false,
+        sorted: true,
+        ..Options::default()
+    };
+    let mut engine = Engine::new(options());
+    engine
+        .process_from_str(concat!(
+            "type,client,tx,amount\n",
+            "deposit,1,1,3.0\nwithdrawal,1,2,1.0\n",
+            "deposit,2,3,5.0\ndispute,2,3,\nchargeback,2,3,\n",
+        ))
+        .unwrap();
+    let mut snapshot = Vec::new();
+    engine.write_snapshot_json(&mut snapshot).unwrap();
+    let mut engine = Engine::load_snapshot_json(options(), snapshot.as_slice()).unwrap();
+    let ledger = engine.accounts().get(2).unwrap();
+    assert_eq!(ledger.status, LedgerStatus::Locked);
+    assert_eq!(ledger.chargeback_count, 1);
+    engine
+        .process_from_str(
+            "type,client,tx,amount\ndeposit,1,4,0.5\nwithdrawal,1,5,2.5\ndeposit,2,6,1.0\n",
+        )
+        .unwrap();
+    let mut output = Vec::new();
+    engine.write_accounts_csv(&mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,0.0000,0.0000,0.0000,false\n2,0.0000,0.0000,0.0000,true\n"
+    );
+    assert_eq!(engine.accounts().get(1).unwrap().tx_count, 4);
+    let error = Engine::load_snapshot_json(
+        options(),
+        &b"[\n{\"client\": 1, \"ledger\": {\"status\": \"Frozen\"}}]"[..],
+    );
+    assert!(matches!(error, Err(EngineError::Parse { line: 2, .. })));
+}
+
+#[test]
+fn idempotent() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,4.0
+deposit,2,3,5.0
+dispute,2,3,
+resolve,2,3,
+deposit,3,4,7.0
+dispute,3,4,
+chargeback,3,4,
+deposit,4,5,1.0
+dispute,4,5,
+";
+    let options = Options {
+        idempotent: true,
+        strict: true,
+        sorted: true,
+        ..Options::default()
+    };
+    let output = |engine: &Engine| {
+        let mut output = Vec::new();
+        engine.write_accounts_csv(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+    let mut engine = Engine::new(options.clone());
+    engine.process_from_str(INPUT).unwrap();
+    let expected = output(&engine);
+    // Delivered again, to an engine restored from what the first run persisted
+    let mut restored = Engine::load_snapshot(options, &engine.snapshot())
+        .with_transaction_history(engine.transaction_history())
+        .with_charged_back_transactions(engine.charged_back_transactions());
+    restored.process_from_str(INPUT).unwrap();
+    assert_eq!(output(&restored), expected);
+    // Only the dispute and resolve of the resolved transaction are applied again
+    assert_eq!(
+        (restored.replayed_count(), restored.skipped_count()),
+        (8, 0)
+    );
+}
+
+#[test]
+fn auto_lock_after_chargebacks() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,1.0
+deposit,1,3,1.0
+deposit,1,4,1.0
+deposit,1,5,1.0
+deposit,1,6,1.0
+dispute,1,1,
+dispute,1,2,
+dispute,1,3,
+dispute,1,4,
+dispute,1,5,
+dispute,1,6,
+chargeback,1,1,
+resolve,1,6,
+chargeback,1,2,
+resolve,1,5,
+chargeback,1,3,
+resolve,1,4,
+deposit,1,7,1.0
+";
+    let process = |auto_lock_after_chargebacks| {
+        let mut engine = Engine::new(Options {
+            auto_lock_after_chargebacks,
+            strict: false,
+            ..Options::default()
+        });
+        engine.process_from_str(INPUT).unwrap();
+        engine
+    };
+    // Without a threshold, the last resolve unlocks the account, as the previous ones did
+    let engine = process(None);
+    let ledger = engine.accounts().get(1).unwrap();
+    assert_eq!(
+        (ledger.available, ledger.status),
+        (Amount(4.0), LedgerStatus::Default)
+    );
+    // The third chargeback trips the threshold
+    let engine = process(Some(3));
+    let ledger = engine.accounts().get(1).unwrap();
+    assert_eq!((ledger.available, ledger.held), (Amount(2.0), Amount(1.0)));
+    assert_eq!(ledger.status, LedgerStatus::Locked);
+    let stats = engine.stats();
+    assert_eq!(stats.auto_locked, 1);
+    assert_eq!(stats.rejected[Rejection::AutoLocked(1).index()], 2);
+}
+
+#[test]
+fn per_client_history() {
+    let mut engine = Engine::new(Options {
+        strict: false,
+        ..Options::default()
+    });
+    engine
+        .process_from_str(
+            "type,client,tx,amount
+deposit,1,3,5.0
+deposit,2,1,1.0
+withdrawal,1,2,1.0
+withdrawal,2,4,9.0
+dispute,1,3,
+chargeback,1,3,
+",
+        )
+        .unwrap();
+    assert_eq!(engine.per_client_history(1), [3, 2]);
+    assert_eq!(engine.per_client_history(2), [1]);
+    assert!(engine.per_client_history(3).is_empty());
+}
+
+#[test]
+fn replay() {
+    let mut engine = Engine::new(Options {
+        strict: false,
+        keep_log: true,
+        ..Options::default()
+    });
+    engine
+        .process_from_str(
+            "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,2.5
+dispute,1,1,
+dispute,2,2,
+resolve,2,2,
+chargeback,1,1,
+deposit,3,4,1.23456
+",
+        )
+        .unwrap();
+    let log = engine.export_history();
+    assert_eq!(log.len(), 8);
+    // Amounts are logged as applied, i.e. rounded
+    assert_eq!(log[7].3, Some(Amount(1.2346)));
+    let replayed = Engine::replay(log).unwrap();
+    assert_eq!(replayed.snapshot().sorted(), engine.snapshot().sorted());
+    assert_eq!(replayed.processed_count(), 8);
+    // Nothing is logged by default
+    assert!(replayed.export_history().is_empty());
+}
+
+#[test]
+fn ledger_transitions() {
+    let funds = |ledger: &Ledger| (ledger.available.0, ledger.held.0);
+    let mut ledger = Ledger::default();
+    ledger.deposit(1, Amount(10.0), 4).unwrap();
+    assert_eq!(funds(&ledger), (10.0, 0.0));
+    // A withdrawal is all or nothing
+    assert_eq!(
+        ledger.withdraw(1, Amount(10.5), 4),
+        Err(Rejection::InsufficientFunds {
+            client: 1,
+            requested: Amount(10.5),
+            available: Amount(10.0),
+        })
+    );
+    ledger.withdraw(1, Amount(7.5), 4).unwrap();
+    assert_eq!(funds(&ledger), (2.5, 0.0));
+    // Disputing funds already withdrawn takes available funds below zero
+    ledger.dispute(1, Amount(10.0), 4).unwrap();
+    assert_eq!((funds(&ledger), ledger.dispute_count), ((-7.5, 10.0), 1));
+    // Releasing more than held is an engine bug, not something to apply
+    assert!(matches!(
+        ledger.resolve(1, Amount(20.0), 4),
+        Err(Rejection::NegativeBalance { client: 1, .. })
+    ));
+    ledger.resolve(1, Amount(10.0), 4).unwrap();
+    assert_eq!(funds(&ledger), (2.5, 0.0));
+    assert!(matches!(
+        ledger.chargeback(1, Amount(1.0), 4),
+        Err(Rejection::NegativeBalance { client: 1, .. })
+    ));
+    assert_eq!(ledger.status, LedgerStatus::Default);
+    ledger.apply(1, Tx::Dispute, Amount(2.0), 4).unwrap();
+    ledger.apply(1, Tx::Chargeback, Amount(2.0), 4).unwrap();
+    assert_eq!(funds(&ledger), (0.5, 0.0));
+    assert_eq!(ledger.status, LedgerStatus::Locked);
+    assert_eq!(
+        (ledger.chargeback_count, ledger.chargeback_amount),
+        (1, Amount(2.0))
+    );
+    // Amounts are rounded to the given places
+    ledger.apply(1, Tx::Deposit, Amount(0.123456), 4).unwrap();
+    assert_eq!(funds(&ledger), (0.6235, 0.0));
+}
+
+#[test]
+fn undo_transaction() {
+    let mut engine = Engine::new(Options {
+        strict: false,
+        ..Options::default()
+    });
+    engine
+        .process_from_str(
+            "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+withdrawal,1,3,1.0
+dispute,1,2,
+deposit,2,4,1.0
+withdrawal,2,5,1.0
+",
+        )
+        .unwrap();
+    let funds = |engine: &Engine, client| {
+        let ledger = engine.accounts.get(client).unwrap();
+        (ledger.available, ledger.held)
+    };
+    assert_eq!(funds(&engine, 1), (Amount(4.0), Amount(3.0)));
+    assert!(matches!(
+        engine.undo_transaction(9),
+        Err(EngineError::TransactionNotFound(9))
+    ));
+    assert!(matches!(
+        engine.undo_transaction(2),
+        Err(EngineError::TransactionUnderDispute(2))
+    ));
+    // A withdrawal gives its amount back, a deposit takes it back
+    engine.undo_transaction(3).unwrap();
+    assert_eq!(funds(&engine, 1), (Amount(5.0), Amount(3.0)));
+    engine.undo_transaction(1).unwrap();
+    assert_eq!(funds(&engine, 1), (Amount(0.0), Amount(3.0)));
+    assert_eq!(engine.per_client_history(1), [2]);
+    assert!(matches!(
+        engine.undo_transaction(1),
+        Err(EngineError::TransactionNotFound(1))
+    ));
+    // The ID of an undone transaction is free again
+    engine
+        .process_from_str("type,client,tx,amount\ndeposit,1,1,2.0\n")
+        .unwrap();
+    assert_eq!(funds(&engine, 1), (Amount(2.0), Amount(3.0)));
+    // The funds of a deposit withdrawn since are gone
+    assert!(matches!(
+        engine.undo_transaction(4),
+        Err(EngineError::Rejected {
+            rejection: Rejection::NegativeBalance { client: 2, .. },
+            ..
+        })
+    ));
+    assert_eq!(funds(&engine, 2), (Amount(0.0), Amount(0.0)));
+}
+
+#[test]
+fn clients() {
+    let mut engine = Engine::new(Options {
+        clients: Some(HashSet::from([2])),
+        strict: false,
+        ..Options::default()
+    });
+    engine
+        .process_from_str(
+            "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,1,1.0
+deposit,2,2,2.0
+dispute,1,1,
+withdrawal,3,3,1.0
+",
+        )
+        .unwrap();
+    // The deposit of client 2 reusing the ID of one of client 1 is still a duplicate
+    assert_eq!(engine.account_count(), 1);
+    assert_eq!(engine.accounts().get(2).unwrap().available, Amount(2.0));
+    assert_eq!(
+        engine.stats().rejected[Rejection::DuplicateTx(1).index()],
+        1
+    );
+    assert_eq!(engine.transaction_count(), 3);
+}
+
+#[test]
+fn max_clients() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.0
+withdrawal,3,3,1.0
+deposit,2,4,1.0
+dispute,3,1,
+";
+    let mut engine = Engine::new(Options {
+        max_clients: Some(2),
+        strict: false,
+        ..Options::default()
+    });
+    engine.process_from_str(INPUT).unwrap();
+    assert_eq!(engine.account_count(), 2);
+    assert_eq!(engine.accounts().get(2).unwrap().available, Amount(3.0));
+    let stats = engine.stats();
+    let too_many = Rejection::TooManyClients {
+        client: 3,
+        max_clients: 2,
+    };
+    assert_eq!(stats.rejected[too_many.index()], 1);
+    let mut engine = Engine::new(Options {
+        max_clients: Some(2),
+        strict: true,
+        ..Options::default()
+    });
+    assert!(matches!(
+        engine.process_from_str(INPUT),
+        Err(EngineError::Rejected { line: 4, rejection }) if rejection == too_many
+    ));
+}
+
+#[test]
+fn ndjson() {
+    const CSV: &str = "type,  client, tx, amount
+deposit,    1,  1,    1.0
+deposit,    2,  2,    2.0
+deposit,    1,  3,    2.0
+withdrawal, 1,  4,    1.5
+withdrawal, 2,  5,    3.0
+";
+    const NDJSON: &str = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1.0}
+{"type": "deposit", "client": 2, "tx": 2, "amount": "2.0"}
+
+{"type": "deposit", "client": 1, "tx": 3, "amount": 2.0, "note": "ignored"}
+{"type": "withdrawal", "client": 1, "tx": 4, "amount": 1.5}
+{"type": "withdrawal", "client": 2, "tx": 5, "amount": 3.0}
+"#;
+    let options = || Options {
+        strict: false,
+        sorted: true,
+        ..Options::default()
+    };
+    let output = |engine: Engine| {
+        let mut output = Vec::new();
+        engine.write_accounts_csv(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+    let mut csv = Engine::new(options());
+    csv.process_from_str(CSV).unwrap();
+    let mut ndjson = Engine::new(options());
+    let mut source = NdjsonSource::new(NDJSON.as_bytes(), OrderCheck::Off);
+    ndjson.process_source(&mut source).unwrap();
+    assert_eq!(ndjson.stats().rejected, csv.stats().rejected);
+    assert_eq!(output(ndjson), output(csv));
+    // Lines are numbered from the first one, there being no header
+    let mut engine = Engine::new(options());
+    let input = "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1.0}\n\n{\"type\": \"deposit\", \"client\": 1}\n";
+    let mut source = NdjsonSource::new(input.as_bytes(), OrderCheck::Off);
+    assert!(matches!(
+        engine.process_source(&mut source),
+        Err(EngineError::Parse {
+            line: 3,
+            field: Some("tx"),
+            ..
+        })
+    ));
+}
+
+#[test]
+fn dispute_expiry() {
+    let mut engine = Engine::new(Options {
+        dispute_expiry_after: Some(3),
+        strict: false,
+        ..Options::default()
+    });
+    let held = |engine: &Engine| engine.accounts.get(1).unwrap().held;
+    engine
+        .process_from_str("type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,1,\n")
+        .unwrap();
+    let ledger = engine.accounts.get(1).unwrap();
+    assert_eq!(ledger.dispute_tx_sequence, Some(2));
+    // Rejected rows don't count
+    engine
+        .process_from_str("type,client,tx,amount\ndeposit,2,2,1.0\nwithdrawal,2,3,9.0\ndispute,1,1,\ndeposit,2,4,1.0\n")
+        .unwrap();
+    assert_eq!(held(&engine), Amount(5.0));
+    engine
+        .process_from_str("type,client,tx,amount\ndeposit,2,5,1.0\n")
+        .unwrap();
+    assert_eq!(held(&engine), Amount(0.0));
+    assert_eq!(engine.accounts.get(1).unwrap().available, Amount(5.0));
+    // Disputing it again starts over, and a resolve in time leaves nothing to expire
+    engine
+        .process_from_str("type,client,tx,amount\ndispute,1,1,\ndeposit,2,6,1.0\nresolve,1,1,\ndispute,1,1,\ndeposit,2,7,1.0\ndeposit,2,8,1.0\n")
+        .unwrap();
+    assert_eq!(held(&engine), Amount(5.0));
+    engine
+        .process_from_str("type,client,tx,amount\ndeposit,2,9,1.0\n")
+        .unwrap();
+    assert_eq!(held(&engine), Amount(0.0));
+    assert_eq!(engine.accounts.get(1).unwrap().dispute_tx_sequence, Some(9));
+}
+
+#[test]
+fn process_buffers() {
+    use std::io::Cursor;
+    let input = Cursor::new(b"type,client,tx,amount\ndeposit,1,1,2.0\nwithdrawal,1,2,5.0\ndeposit,2,3,1.0\ndispute,2,3,\n".to_vec());
+    let mut output = Cursor::new(Vec::new());
+    let options = Options {
+        strict: false,
+        sorted: true,
+        ..Options::default()
+    };
+    let report = process(input, &mut output, &options).unwrap();
+    assert_eq!(
+        String::from_utf8(output.into_inner()).unwrap(),
+        "client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n2,0.0000,1.0000,1.0000,false\n"
+    );
+    let insufficient_funds = Rejection::InsufficientFunds {
+        client: 1,
+        requested: Amount(5.0),
+        available: Amount(2.0),
+    };
+    assert_eq!(report.stats.rows, 4);
+    assert_eq!(report.stats.skipped, 1);
+    assert_eq!(report.stats.rejected[insufficient_funds.index()], 1);
+    assert_eq!(report.stats.accounts, 2);
+    assert_eq!(report.stats.open_disputes, 1);
+    // Policies apply as they do to the binary
+    let options = Options {
+        strict: true,
+        ..options
+    };
+    let input = Cursor::new(b"type,client,tx,amount\nwithdrawal,1,1,5.0\n".to_vec());
+    assert!(matches!(
+        process(input, Cursor::new(Vec::new()), &options),
+        Err(EngineError::Rejected { line: 2, .. })
+    ));
+}
+
+#[test]
+fn per_client_stats() {
+    let mut engine = Engine::new(Options {
+        strict: false,
+        ..Options::default()
+    });
+    engine
+        .process_from_str(
+            "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,2.5
+withdrawal,1,3,1.0
+withdrawal,1,4,100.0
+dispute,1,1,
+resolve,1,1,
+dispute,1,2,
+chargeback,1,2,
+dispute,2,9,
+",
+        )
+        .unwrap();
+    assert_eq!(
+        engine.per_client_stats(1),
+        Some(ClientStats {
+            deposits: 2,
+            deposit_total: Amount(7.5),
+            withdrawals: 1,
+            withdrawal_total: Amount(1.0),
+            disputes_raised: 2,
+            disputes_resolved: 1,
+            chargebacks: 1,
+        })
+    );
+    // Nothing was applied to client 2
+    assert_eq!(engine.per_client_stats(2), None);
+    let mut output = Vec::new();
+    engine.write_client_stats_csv(&mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,deposits,deposit_total,withdrawals,withdrawal_total,disputes_raised,disputes_resolved,chargebacks\n1,2,7.5000,1,1.0000,2,1,1\n"
+    );
+}
+
+#[test]
+fn process_line() {
+    let options = Options {
+        strict: true,
+        ..Options::default()
+    };
+    // Columns in the order of the example by default, blank lines ignored
+    let mut engine = Engine::new(options.clone());
+    engine.process_line("deposit, 1, 1, 5.0").unwrap();
+    engine.process_line("").unwrap();
+    engine.process_line("withdrawal,1,2,2.0").unwrap();
+    assert!(matches!(
+        engine.process_line("withdrawal,1,3,9.0"),
+        Err(EngineError::Rejected {
+            line: 4,
+            rejection: Rejection::InsufficientFunds { .. }
+        })
+    ));
+    let expected = engine.snapshot();
+    assert_eq!(expected.0[0].available, Amount(3.0));
+    // Order set by a header line given first, or by `set_headers`
+    let mut engine = Engine::new(options.clone());
+    engine.process_line("client,amount,tx,type").unwrap();
+    engine.process_line("1,5.0,1,deposit").unwrap();
+    engine.process_line("1,2.0,2,withdrawal").unwrap();
+    assert_eq!(engine.snapshot(), expected);
+    let mut engine = Engine::new(options);
+    engine.set_headers("tx,type,client,amount").unwrap();
+    engine.process_line("1,deposit,1,5.0").unwrap();
+    engine.process_line("2,withdrawal,1,2.0").unwrap();
+    assert_eq!(engine.snapshot(), expected);
+    assert!(matches!(
+        engine.process_line("x,deposit,1,5.0"),
+        Err(EngineError::Parse { line: 3, .. })
+    ));
+    assert!(matches!(
+        engine.set_headers("type,client,amount"),
+        Err(EngineError::Parse { .. })
+    ));
+}
+
+#[test]
+fn summary() {
+    let mut engine = Engine::new(Options {
+        strict: false,
+        ..Options::default()
+    });
+    engine
+        .process_from_str(
+            "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,2.5
+deposit,3,3,1.0
+withdrawal,1,4,1.0
+dispute,1,1,
+dispute,2,2,
+chargeback,2,2,
+dispute,3,3,
+",
+        )
+        .unwrap();
+    assert_eq!(
+        engine.summarize(),
+        EngineSummary {
+            account_count: 3,
+            total_available: Amount(-1.0),
+            total_held: Amount(6.0),
+            locked_accounts: 1,
+            active_disputes: 2,
+            skipped_rows: 0,
+        }
+    );
+    let mut output = Vec::new();
+    engine.write_summary_csv(&mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "account_count,total_available,total_held,locked_accounts,active_disputes,skipped_rows\n3,-1.0000,6.0000,1,2,0\n"
+    );
+}
+
+#[test]
+fn validate_amounts() {
+    let cases = [
+        ("1", true),
+        ("1.0", true),
+        ("0.5", true),
+        ("10.1234", true),
+        (" 2.25 ", true),
+        ("1e3", false),
+        ("+5", false),
+        ("1_000", false),
+        (".5", false),
+        ("1,5", false),
+        ("1.", false),
+        ("1.23456", false),
+        ("-1.0", false),
+        ("NaN", false),
+    ];
+    for (amount, canonical) in cases {
+        let strict = AmountValidation::Strict.check(amount);
+        assert_eq!(strict.is_ok(), canonical, "{}", amount);
+        assert_eq!(AmountValidation::Lenient.check(amount), Ok(()));
+        // Rejected with the raw text, whether the amount reads as a float or not
+        let mut engine = Engine::new(Options {
+            strict: true,
+            validate_amounts: AmountValidation::Strict,
+            ..Options::default()
+        });
+        let csv = format!("type,client,tx,amount\ndeposit,1,1,\"{}\"\n", amount);
+        match engine.process_from_str(&csv) {
+            Ok(()) => assert!(canonical, "{}", amount),
+            Err(EngineError::Rejected {
+                line: 2,
+                rejection: Rejection::BadAmount(text),
+            }) => assert_eq!((canonical, text.as_str()), (false, amount.trim())),
+            Err(e) => panic!("{}: {}", amount, e),
+        }
+    }
+    // Disputes have no amount to check
+    let mut engine = Engine::new(Options {
+        strict: true,
+        validate_amounts: AmountValidation::Strict,
+        ..Options::default()
+    });
+    engine
+        .process_from_str("type,client,tx,amount\ndeposit,1,1,1.5\ndispute,1,1,\n")
+        .unwrap();
+}
+
+#[test]
+fn non_finite_amounts() {
+    for amount in ["inf", "-inf", "NaN"] {
+        let mut engine = Engine::new(Options {
+            strict: true,
+            ..Options::default()
+        });
+        let csv = format!(
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,{}\n",
+            amount
+        );
+        match engine.process_from_str(&csv) {
+            Err(EngineError::Rejected {
+                line: 3,
+                rejection: Rejection::BadAmount(text),
+            }) => assert_eq!(text.to_lowercase(), amount.to_lowercase()),
+            result => panic!("{}: {:?}", amount, result),
+        }
+        assert_eq!(engine.accounts().get(1).unwrap().available, Amount(1.0));
+    }
+}
+
+#[test]
+fn normalized_balances() {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for tx in 1..=1000 {
+        csv += &format!("deposit,1,{},0.1\n", tx);
+    }
+    // Moving funds back and forth doesn't drift either
+    for tx in 1..=100 {
+        csv += &format!("dispute,1,{},\nresolve,1,{},\n", tx, tx);
+    }
+    csv += "withdrawal,1,1001,0.3\ndispute,1,1000,\n";
+    let mut engine = Engine::new(Options {
+        strict: true,
+        ..Options::default()
+    });
+    engine.process_from_str(&csv).unwrap();
+    let ledger = engine.accounts.get(1).unwrap();
+    // Exactly the `f64` closest to the four-place decimal, as read from the output
+    assert_eq!((ledger.available.0, ledger.held.0), (99.6, 0.1));
+    for funds in [ledger.available, ledger.held] {
+        assert_eq!(funds.normalize().0, funds.0);
+        assert_eq!(format!("{}", funds).parse::<f64>().unwrap(), funds.0);
+    }
+    // Unlike the plain sum of the same amounts
+    assert_ne!((0..1000).map(|_| 0.1).sum::<f64>(), 100.0);
+}
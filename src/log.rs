@@ -0,0 +1,115 @@
+//! # Logging
+//!
+//! A tiny stand-in for the `log` crate facade: events are written to stderr, filtered by a global
+//! maximum level which the binary reads from the `RUST_LOG` environment variable. The level is a
+//! single atomic, and the logging macros check it before formatting anything, so a silent run
+//! doesn't pay for the events it doesn't print.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Verbosity of an event, or maximum verbosity of the events to print
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Level; 6] = [
+        Level::Off,
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    fn parse(s: &str) -> Option<Level> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Some(Level::Off),
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// As with the `log` crate, nothing is printed until a level is set (e.g. by `init_from_env`)
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Off as u8);
+
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn max_level() -> Level {
+    Level::ALL[MAX_LEVEL.load(Ordering::Relaxed) as usize]
+}
+
+/// Whether events of the given `level` are printed
+#[inline]
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Set the maximum level from the `RUST_LOG` environment variable, defaulting to warnings only.
+/// Directives in the `env_logger` style (`warn`, `rust_coding_test=debug`, `info,other=trace`)
+/// are understood, although there is a single level for the whole crate: the last one wins.
+pub fn init_from_env() {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|directives| {
+            directives
+                .split(',')
+                .rev()
+                .find_map(|directive| Level::parse(directive.rsplit('=').next()?))
+        })
+        .unwrap_or(Level::Warn);
+    set_max_level(level);
+}
+
+/// Log an event at the given level, formatting it only if that level is enabled. Like the macros
+/// below, it's exported for the binary, e.g. `rust_coding_test::warn!("...")`.
+#[macro_export]
+macro_rules! log {
+    ($level: expr, $($arg: tt)+) => {
+        if $crate::log::enabled($level) {
+            eprintln!("[{}] {}", $level, format_args!($($arg)+));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg: tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg: tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg: tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
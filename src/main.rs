@@ -26,43 +26,43 @@
 //!
 //! - **Basics:**
 //!
-//!     Does your application build? Does it read and write data in the way we'd
-//!     like it to? Is it properly formatted?
+//!   Does your application build? Does it read and write data in the way we'd
+//!   like it to? Is it properly formatted?
 //!
 //! - **Completeness:**
 //!
-//!     Do you handle all of the cases, including disputes, resolutions, and
-//!     chargebacks? Maybe you don't handle disputes and resolutions but you
-//!     can tell when a transaction is charged back. Try to cover as much as you
-//!     can.
+//!   Do you handle all of the cases, including disputes, resolutions, and
+//!   chargebacks? Maybe you don't handle disputes and resolutions but you
+//!   can tell when a transaction is charged back. Try to cover as much as you
+//!   can.
 //!
 //! - **Correctness:**
 //!
-//!     For the cases you are handling are you handling them correctly? How do
-//!     you know this? Did you test against sample data? If so, include it in the
-//!     repo. Did you write unit tests for the complicated bits? Or are you using the
-//!     type system to ensure correctness? Tell us about it in the README.
+//!   For the cases you are handling are you handling them correctly? How do
+//!   you know this? Did you test against sample data? If so, include it in the
+//!   repo. Did you write unit tests for the complicated bits? Or are you using the
+//!   type system to ensure correctness? Tell us about it in the README.
 //!
 //! - **Safety and Robustness:**
 //!
-//!     Are you doing something dangerous? Tell us why you chose to do it this
-//!     way. How are you handling errors?
+//!   Are you doing something dangerous? Tell us why you chose to do it this
+//!   way. How are you handling errors?
 //!
 //! - **Efficiency:**
 //!
-//!     Be thoughtful about how you use system resources. Sample data sets may
-//!     be small but you may be evaluated against much larger data sets (hint:
-//!     transaction IDs are valid u32 values). Can you stream values through
-//!     memory as opposed to loading the entire data set upfront? What if your
-//!     code was bundled in a server, and these CSVs came from thousands of
-//!     concurrent TCP streams?
+//!   Be thoughtful about how you use system resources. Sample data sets may
+//!   be small but you may be evaluated against much larger data sets (hint:
+//!   transaction IDs are valid u32 values). Can you stream values through
+//!   memory as opposed to loading the entire data set upfront? What if your
+//!   code was bundled in a server, and these CSVs came from thousands of
+//!   concurrent TCP streams?
 //!
 //! - **Maintainabilit:**
 //!
-//!     In this case clean code is more important than efficient code because
-//!     humans will have to read and review your code without an opportunity for
-//!     you to explain it. Inefficient code can often be improved if it is correct and
-//!     highly maintainable.
+//!   In this case clean code is more important than efficient code because
+//!   humans will have to read and review your code without an opportunity for
+//!   you to explain it. Inefficient code can often be improved if it is correct and
+//!   highly maintainable.
 //!
 //! Your solution will be scored using a combination of automated and manual scoring. Automated
 //! scoring will be used to run your solution against a handful of sample inputs, comparing the
@@ -98,11 +98,11 @@
 //! You're safe to make the following assumptions:
 //!
 //! - The client has a single asset account. All transactions are to and from this single asset
-//! account;
+//!   account;
 //! - There are multiple clients. Transactions reference clients. If a client doesn't exist create a
-//! new record;
+//!   new record;
 //! - Clients are represented by u16 integers. No names, addresses, or complex client profile
-//! info;
+//!   info;
 //!
 //! When in doubt on how to interpret a requirement, try to make assumptions that make sense for
 //! a bank (think an ATM or more elaborate transaction processors), and document them.
@@ -113,384 +113,260 @@
 //! - csv for reading and writing CSVs
 //! - Any other common crate that you deem secure.
 
-use anyhow::Result; // handy construct on top of `Result<T, Box<dyn Error>>`
-use serde::{Deserialize, Serialize};
+mod cli;
+mod config;
+mod follow;
+mod server;
+mod signal;
 
-// ### Input
-//
-// The input will be a CSV file with the columns type, client, tx, and amount. You can assume the
-// type is a string, the client column is a valid u16 client ID, the tx is a valid u32 transaction
-// ID, and the amount is a decimal value with a precision of up to four places past the decimal.
-//
-// For example:
-//
-// ```csv
-// type,  client, tx, amount
-// deposit,    1,  1,    1.0
-// deposit,    2,  2,    2.0
-// deposit,    1,  3,    2.0
-// withdrawal, 1,  4,    1.5
-// withdrawal, 2,  5,    3.0
-// ```
-//
-// The client ID will be unique per client though are not guaranteed to be ordered. Transactions to
-// the client account 2 could occur before transactions to the client account 1. Likewise,
-// transaction IDs (tx) are globally unique, though are also not guaranteed to be ordered. You can
-// assume the transactions occur chronologically in the file, so if transaction b appears after a
-// in the input file then you can assume b occurred chronologically after a. Whitespaces and
-// decimal precisions (up to four places past the decimal) must be accepted by your program.
-
-/// Client IDs are stored on 16-bits unsigned integers
-type ClientID = u16;
-/// Transaction IDs are stored on 32-bits unsigned integers
-type TxID = u32;
-
-/// Using a tuple-struct for `Input`, since `type` is a reserved keyword and couldn't be used as a
-/// field name...
-#[derive(Debug, Deserialize)]
-struct Input(
-    /// Transaction type
-    Tx,
-    /// Client ID
-    ClientID,
-    /// Transaction ID
-    TxID,
-    /// Transactions of type Dispute, Resolve or Chargeback does not specify an Amount
-    Option<Amount>,
-);
-
-// ### Output
-//
-// The output should be a list of client IDs (client), available amounts (available), held amounts
-// (held), total amounts (total), and whether the account is locked (locked). Columns are defined
-// as:
-//
-// - available:
-//
-//     The total funds that are available for trading, staking, withdrawal, etc. This
-//     should be equal to the total - held amounts
-//
-// - held:
-//
-//     The total funds that are held for dispute. This should be equal to total -
-//     available amounts
-//
-// - total:
-//
-//     The total funds that are available or held. This should be equal to available +
-//     held
-//
-// - locked:
-//
-//     Whether the account is locked. An account is locked if a charge back occurs
-//
-//
-// For example:
-//
-// ```csv
-// client, available, held, total, locked
-//      1,       1.5,  0.0,   1.5,  false
-//      2,       2.0,  0.0,   2.0,  false
-// ```
-//
-// Spacing and displaying decimals for round values do not matter. Row ordering also does not
-// matter. The above output will be considered the exact same as the following:
-//
-// ```csv
-// client,available,held,total,locked
-// 2,2,0,2,false
-// 1,1.5,0,1.5,false
-// ```
+use cli::{Action, Args, CliError, Input, Outcome};
+use rust_coding_test::log::{self, Level};
+use rust_coding_test::shared::SharedEngine;
+use rust_coding_test::{
+    estimate_transactions, info, warn, Engine, EngineError, InputValidator, Options, RunReport,
+};
+use server::Shutdown;
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener};
+use std::process::ExitCode;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
 
-/// A wrapper (using `newtype` construct) around `f64` primitive type
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq, PartialOrd, Serialize)]
-struct Amount(f64);
-
-/// ### Precision
+/// I choose to design my code under few principles:
 ///
-/// You can assume a precision of four places past the decimal and should output values with the
-/// same level of precision.
-impl std::fmt::Display for Amount {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:.5}", self)
+/// - Literate programming (bunch of comments surrounding my code) in the way of the Rust `std` was
+///   written to be the most self-explanatory possible (please `cargo doc --no-deps` all this!)
+///
+/// - Binary-oriented approach, since the code will be tested as binary, I write my unit-test using
+///   this approach, enabling or disabling feature using command line flags (see `cli.rs`, the
+///   `sorted` and `strict_mode` cargo features only change their defaults); the business logic
+///   itself lives in the library crate (`lib.rs`) so it could be reused outside of this harness
+///
+/// - By default the program will ignore erroneous transactions, only logging a warning on stderr
+///   (the verbosity being controlled with e.g. `export RUST_LOG=debug`, or `RUST_LOG=off` to fail
+///   silently), but with `--strict` (or `--feature strict_mode`) it will stop on the first invalid
+///   operation; every error is reported as a single line on stderr, and exits with `1` (see
+///   `CliError`), whereas a run completing despite skipped rows exits with `2` (see `Outcome`), so
+///   CI can gate on it
+fn main() -> ExitCode {
+    let args = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(Action::Run(args)) => args,
+        Ok(Action::PrintConfig(args)) => {
+            print!("{}", config::effective(&args));
+            return ExitCode::SUCCESS;
+        }
+        Ok(Action::Validate(args)) => {
+            return match validate(&args) {
+                Ok(outcome) => ExitCode::from(outcome.exit_code()),
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    ExitCode::from(e.exit_code())
+                }
+            };
+        }
+        Ok(Action::Help) => {
+            print!("{}", cli::help());
+            return ExitCode::SUCCESS;
+        }
+        Ok(Action::Version) => {
+            println!("{}", cli::version());
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::from(e.exit_code());
+        }
+    };
+    match run(&args) {
+        Ok(outcome) => ExitCode::from(outcome.exit_code()),
+        Err(e) => {
+            if args.options.json_errors.is_some() {
+                eprintln!("{}", e.to_json());
+            } else {
+                eprintln!("error: {}", e);
+            }
+            ExitCode::from(e.exit_code())
+        }
     }
 }
 
-/// Explicitly authorizing `+` binary operation on `Amount` (to allow further rounding behavior?)
-impl std::ops::Add for Amount {
-    type Output = Amount;
-
-    fn add(self, rhs: Self) -> Self::Output {
-        Amount(self.0 + rhs.0)
+fn run(args: &Args) -> Result<Outcome, CliError> {
+    let start = Instant::now();
+    log::init_from_env();
+    if args.quiet {
+        log::set_max_level(Level::Error);
     }
-}
-
-/// Explicitly authorizing `-` binary operation on `Amount` (to allow further rounding behavior?)
-impl std::ops::Sub for Amount {
-    type Output = Amount;
-
-    fn sub(self, rhs: Self) -> Self::Output {
-        Amount(self.0 - rhs.0)
+    // A server has no input to read
+    let input = match args.listen {
+        Some(_) => None,
+        None => Some(args.open_input()?),
+    };
+    let output = args.create_output()?;
+    let open_disputes = args.create_open_disputes()?;
+    let stats = args.create_stats()?;
+    let summary = args.create_summary()?;
+    let dispute_trace = args.create_dispute_trace()?;
+    let dump_history = args.create_dump_history()?;
+    let stats_json = args.create_stats_json()?;
+    let options = Options {
+        metrics: args.serve_metrics()?,
+        events: args.create_events()?,
+        rejected: args.create_rejected()?,
+        ..args.options.clone()
+    };
+    let engine = match (input, args.listen) {
+        (Some(input), _) => process(args, input, options)?,
+        (None, Some(addr)) => listen(args, addr, options)?,
+        (None, None) => unreachable!("there is an input unless listening"),
+    };
+    // Still a success, but one which shouldn't go unnoticed (e.g. an export cut short upstream)
+    if engine.processed_count() == 0 {
+        warn!("the input has no transactions, only the header is written");
     }
-}
-
-/// ### Types of Transactions
-#[derive(Debug, Deserialize)]
-#[allow(non_camel_case_types)]
-enum Tx {
-    /// #### Deposit
-    ///
-    /// A deposit is a credit to the client's asset account, meaning it should increase the
-    /// available and total funds of the client account.
-    ///
-    /// A deposit looks like:
-    ///
-    /// ```csv
-    /// type,    client, tx, amount
-    /// deposit,      1,  1,    1.0
-    deposit,
-
-    /// #### Withdrawal
-    ///
-    /// A withdraw is a debit to the client's asset account, meaning it should decrease the
-    /// available and total funds of the client account.
-    ///
-    /// A withdrawal looks like:
-    ///
-    /// ```csv
-    /// type,  client, tx, amount
-    /// withdrawal, 2,  2,    1.0
-    /// ```
-    ///
-    /// If a client does not have sufficient available funds the withdrawal should fail and the
-    /// total amount of funds should not change.
-    withdrawal,
-
-    /// #### Dispute
-    ///
-    /// A dispute represents a client's claim that a transaction was erroneous and should be
-    /// reversed. The transaction shouldn't be reversed yet but the associated funds should be held.
-    /// This means that the clients available funds should decrease by the amount disputed, their
-    /// held funds should increase by the amount disputed, while their total funds should remain the
-    /// same.
-    ///
-    /// A dispute looks like:
-    ///
-    /// ```csv
-    /// type, client, tx, amount
-    /// dispute,   1,  1,
-    /// ```
-    ///
-    /// Notice that a dispute does not state the amount disputed. Instead a dispute references the
-    /// transaction that is disputed by ID. If the tx specified by the dispute doesn't exist you can
-    /// ignore it and assume this is an error on our partners side.
-    dispute,
-
-    /// #### Resolve
-    ///
-    /// A resolve represents a resolution to a dispute, releasing the associated held funds. Funds
-    /// that were previously disputed are no longer disputed. This means that the clients held funds
-    /// should decrease by the amount no longer disputed, their available funds should increase by
-    /// the amount no longer disputed, and their total funds should remain the same.
-    ///
-    /// A resolve looks like:
-    ///
-    /// ```csv
-    /// type, client, tx, amount
-    /// resolve,   1,  1,
-    /// ```
-    ///
-    /// Like disputes, resolves do not specify an amount. Instead they refer to a transaction that
-    /// was  under dispute by ID. If the tx specified doesn't exist, or the tx isn't under dispute,
-    /// you can ignore the resolve and assume this is an error on our partner's side.
-    resolve,
-
-    /// #### Chargeback
-    ///
-    /// A chargeback is the final state of a dispute and represents the client reversing a
-    /// transaction. Funds that were held have now been withdrawn. This means that the clients held
-    /// funds and total funds should decrease by the amount previously disputed. If a chargeback
-    /// occurs the client's account should be immediately frozen.
-    ///
-    /// A chargeback looks like:
-    ///
-    /// ```csv
-    /// type,  client, tx, amount
-    /// chargeback, 1,  1,
-    /// ```
-    ///
-    /// Like a dispute and a resolve a chargeback refers to the transaction by ID (tx) and does not
-    /// specify an amount. Like a resolve, if the tx specified doesn't exist, or the tx isn't under
-    /// dispute, you can ignore chargeback and assume this is an error on our partner's side.
-    chargeback,
-}
-
-/// Here is a simple dumb algorithm that loop over the input values, mutating a collection of
-/// ledgers. This stateful approach is required and forbid us for doing a lot of naive optimization,
-/// e.g. using rayon parallel iterator, since transaction shouldn't be evaluated out of order...
-#[derive(Debug)]
-struct Ledger {
-    available: Amount,
-    held: Amount,
-    status: LedgerStatus,
-}
-
-/// A ledger couldn't be not both locked and under dispute
-#[derive(Debug, PartialEq)]
-enum LedgerStatus {
-    Default,
-    Disputed,
-    Locked,
-}
-
-/// By default every client get an empty of fund unlocked account
-impl Default for Ledger {
-    fn default() -> Self {
-        Ledger {
-            available: Amount(0.0),
-            held: Amount(0.0),
-            status: LedgerStatus::Default,
+    let outcome = match engine.skipped_count() {
+        0 => Outcome::Applied,
+        _ => Outcome::Skipped,
+    };
+    // The stats of the run are all a dry run is for, and any row skipped fails it
+    if args.dry_run {
+        let report = RunReport {
+            stats: engine.stats(),
+            elapsed: start.elapsed(),
+        };
+        writeln!(std::io::stdout(), "{}", report.to_json()).map_err(EngineError::Io)?;
+        return Ok(match outcome {
+            Outcome::Applied => Outcome::Applied,
+            _ => Outcome::Invalid,
+        });
+    }
+    engine.write_accounts(output)?;
+    if let Some(open_disputes) = open_disputes {
+        engine.write_open_disputes_csv(open_disputes)?;
+    }
+    if let Some(stats) = stats {
+        engine.write_client_stats_csv(stats)?;
+    }
+    if let Some(summary) = summary {
+        engine.write_summary_csv(summary)?;
+    }
+    if let Some(dispute_trace) = dispute_trace {
+        engine.write_dispute_trace_csv(dispute_trace)?;
+    }
+    if let Some(dump_history) = dump_history {
+        engine.write_history_csv(dump_history)?;
+    }
+    // Asked for, so it isn't silenced by `--quiet`
+    if args.audit_negatives {
+        for (client, currency, min) in engine.negative_balances() {
+            let account = match currency {
+                Some(currency) => format!("client {} in {}", client, currency),
+                None => format!("client {}", client),
+            };
+            eprintln!(
+                "[{}] available funds of {} went down to {}",
+                Level::Warn,
+                account,
+                min
+            );
         }
     }
+    if let Some(mut stats_json) = stats_json {
+        let report = RunReport {
+            stats: engine.stats(),
+            elapsed: start.elapsed(),
+        };
+        writeln!(stats_json, "{}", report.to_json()).map_err(EngineError::Io)?;
+    }
+    Ok(outcome)
 }
 
-#[macro_use]
-extern crate lazy_static;
-use std::collections::HashMap;
-use std::sync::Mutex;
-
-lazy_static! {
-    /// Global history of all transactions (designed to be shared between several threads)
-    static ref HISTORY: Mutex<HashMap<TxID, Amount>> = Mutex::new(HashMap::new());
+/// Receive transactions over TCP at `addr` until a signal, and give the resulting engine once the
+/// server shut down, see `server.rs`
+fn listen(args: &Args, addr: SocketAddr, options: Options) -> Result<Engine, CliError> {
+    let listener = TcpListener::bind(addr).map_err(|e| CliError::Open(addr.to_string(), e))?;
+    let local_addr = listener.local_addr().map_err(EngineError::Io)?;
+    info!("listening on {}", local_addr);
+    signal::GRACEFUL.store(true, Ordering::SeqCst);
+    signal::install();
+    // Rows keep being applied while draining, the engine itself isn't interrupted
+    let engine = Arc::new(SharedEngine::new(options));
+    let shutdown = server::serve(listener, &engine, args.drain_timeout).map_err(EngineError::Io)?;
+    let engine = Arc::try_unwrap(engine)
+        .expect("connections are over")
+        .into_engine()?;
+    match shutdown {
+        Shutdown::Drained => {}
+        Shutdown::TimedOut(cut) => {
+            warn!("{} connections cut at the end of the drain timeout", cut);
+        }
+        // Don't lose what was received so far
+        Shutdown::Forced => {
+            let (partial_output, partial) = args.create_partial_output()?;
+            engine.write_accounts(partial_output)?;
+            let rows = engine.processed_count();
+            return Err(CliError::Interrupted { rows, partial });
+        }
+    }
+    Ok(engine)
 }
 
-/// Simple macro to insert a new transaction in global history
-macro_rules! history_insert {
-    ($tx_id: expr, $amount: expr) => {
-        HISTORY.lock().unwrap().insert($tx_id, $amount);
+/// Apply the transactions of `input`, and give the resulting engine
+fn process(args: &Args, input: Input, options: Options) -> Result<Engine, CliError> {
+    signal::install();
+    // Spare a file's history the rehashes of growing one transaction at a time
+    let expect_transactions = match &input {
+        Input::File(file) if options.expect_transactions.is_none() => file
+            .metadata()
+            .ok()
+            .map(|metadata| estimate_transactions(metadata.len())),
+        _ => options.expect_transactions,
     };
-}
-
-/// Neat trick to make history reading won't fail in non-strict mode if transaction not found
-macro_rules! history_get {
-    ($tx_id: expr) => {
-        match HISTORY.lock().unwrap().get($tx_id) {
-            Some(x) => *x,
-            None => {
-                #[cfg(feature = "strict_mode")]
-                panic!("transaction ID {} not found", $tx_id);
-                continue;
+    let mut engine = Engine::new(Options {
+        interrupt: Some(&signal::INTERRUPTED),
+        expect_transactions,
+        ..options
+    });
+    let result = match (input, args.pipeline) {
+        (Input::File(file), _) if args.two_pass => engine.process_two_pass(file),
+        (input, Some(capacity)) => engine.process_pipelined(input, capacity),
+        (input, None) => {
+            if args.two_pass {
+                warn!("the input can't be read twice, falling back to a single pass");
             }
+            engine.process_input(input)
         }
     };
-}
-
-/// I choose to design my code under few principles:
-///
-/// - Literate programming (bunch of comments surrounding my code) in the way of the Rust `std` was
-///   written to be the most self-explanatory possible (please `cargo doc --no-deps` all this!)
-///
-/// - Binary-oriented approach, since the code will be tested as binary, I write my unit-test using
-///   this approach, one `main` business logic enabling or disabling feature using cargo, rather
-///   than split in functions and types and types exposed in the way library crate would be designed
-///
-/// - By default the program will fail silently on erroneous transactions, but with
-///   `--feature strict_mode` it will panic if such invalid operation occurs, an improvement would
-///   be to have an `export LOG_LEVEL=verbose` mode (using e.g. `log` crate) to warn user without
-///   stopping the program on a non-recovered error!
-fn main() -> Result<()> {
-    // This `accounts` data-structure could be in the future an abstraction around a cold-storage
-    // database (using e.g. CBOR or SLED)
-    let mut accounts: HashMap<ClientID, Ledger> = HashMap::new();
-    // The following code is heavily inspired by CSV crate usage example
-    // from https://docs.rs/csv/latest/csv/#example-with-serde
-    let mut rdr = csv::ReaderBuilder::new()
-        // Because it's not explicitly specified of we should handle the absence of amount field...
-        // https://docs.rs/csv/latest/csv/struct.ReaderBuilder.html#method.flexible
-        .flexible(true)
-        .trim(csv::Trim::All)
-        .from_reader(std::io::stdin());
-    // `continue` block mixed with macro invocation currently mislead `rust-analyser` to false
-    // positive on `unreachable blocks` lint...
-    #[allow(unreachable_code)]
-    for result in rdr.deserialize() {
-        // Notice that we need to provide a type hint for automatic deserialization.
-        let tx: Input = result?;
-        let ledger = accounts.entry(tx.1).or_insert_with(Ledger::default);
-        if ledger.status == LedgerStatus::Locked {
-            #[cfg(feature = "strict_mode")]
-            panic!("account locked, transaction are forbidden");
-            continue;
-        }
-        match tx.0 {
-            // Store deposit or withdrawal transaction amount to history
-            Tx::deposit => {
-                let amount = tx.3.expect("missing amount in deposit transaction");
-                ledger.available = ledger.available + amount;
-                history_insert!(tx.2, amount);
-            }
-            Tx::withdrawal => {
-                let amount = tx.3.expect("missing amount in withdrawal transaction");
-                if amount <= ledger.available {
-                    ledger.available = ledger.available - amount;
-                    history_insert!(tx.2, amount);
-                } else {
-                    #[cfg(feature = "strict_mode")]
-                    panic!("client {} can't withdraw (not enough money)", tx.1);
-                }
-            }
-            // Retrieve deposit or withdrawal transaction amount from history
-            Tx::dispute => {
-                let amount = history_get!(&tx.2);
-                ledger.status = LedgerStatus::Disputed;
-                ledger.available = ledger.available - amount;
-                ledger.held = ledger.held + amount;
-            }
-            Tx::resolve => {
-                if ledger.status == LedgerStatus::Disputed {
-                    let amount = history_get!(&tx.2);
-                    ledger.status = LedgerStatus::Default;
-                    ledger.held = ledger.held - amount;
-                    ledger.available = ledger.available + amount;
-                } else {
-                    #[cfg(feature = "strict_mode")]
-                    panic!("transaction {} should be disputed to be resolved", tx.2);
-                }
-            }
-            Tx::chargeback => {
-                if ledger.status == LedgerStatus::Disputed {
-                    let amount = history_get!(&tx.2);
-                    ledger.status = LedgerStatus::Locked;
-                    ledger.held = ledger.held - amount;
-                } else {
-                    #[cfg(feature = "strict_mode")]
-                    panic!("transaction {} should be disputed to be chargeback", tx.2);
-                }
-            }
+    match result {
+        // A signal is the way out of follow mode
+        Err(EngineError::Interrupted(_)) if args.follow => {}
+        // Don't lose what was processed so far
+        Err(EngineError::Interrupted(rows)) => {
+            let (partial_output, partial) = args.create_partial_output()?;
+            engine.write_accounts(partial_output)?;
+            return Err(CliError::Interrupted { rows, partial });
         }
+        result => result?,
     }
-    // From https://docs.rs/csv/latest/csv/tutorial/index.html#writing-with-serde
-    let mut wtr = csv::Writer::from_writer(std::io::stdout());
-    // We still need to write headers manually.
-    wtr.write_record(&["client", "available", "held", "total", "locked"])?;
-    #[cfg(feature = "sorted")]
-    let accounts = {
-        let mut v = accounts.iter().collect::<Vec<(&ClientID, &Ledger)>>();
-        v.sort_by(|a, b| a.0.cmp(b.0));
-        v
+    Ok(engine)
+}
+
+/// Print every violation of the input on stdout, one per line, rather than processing it
+fn validate(args: &Args) -> Result<Outcome, CliError> {
+    let input = args.open_input()?;
+    let validator = InputValidator {
+        places: args.options.input_precision,
+        ..InputValidator::default()
     };
-    // But now we can write records by providing a normal Rust value.
-    for (client_id, ledger) in accounts {
-        wtr.serialize((
-            client_id,
-            ledger.available,
-            ledger.held,
-            ledger.available + ledger.held,
-            ledger.status == LedgerStatus::Locked,
-        ))?;
+    let engine = Engine::new(args.options.clone());
+    let violations = engine.validate_input(&validator, input)?;
+    for (line, violation) in &violations {
+        println!("line {}: {}", line, violation);
     }
-    wtr.flush()?;
-    Ok(())
+    Ok(match violations.len() {
+        0 => Outcome::Applied,
+        _ => Outcome::Invalid,
+    })
 }
 
 // Unordered list of improvement ideas:
@@ -501,9 +377,21 @@ fn main() -> Result<()> {
 //
 // - check the correctness of the program using fuzzing with `Arbitrary` crate
 //
-// - write more tests, for e.g. with `#[should_fail]` decorator in `strict_mode`
+// - export `wasm::process_csv` to JavaScript with a `#[wasm_bindgen]` wrapper behind the `wasm`
+//   feature, so that a web page can call it
+//
+// - check that the engine builds for the browser, with `cargo build --target wasm32-unknown-unknown
+//   --features wasm --no-default-features` in CI, and run the exported wrapper with
+//   `wasm-bindgen-test` on the five-row example of the specification
+//
+// - add an async entry point behind a `tokio` feature, applying the rows of a
+//   `tokio::io::AsyncBufRead` read through `csv-async`, e.g. for a server built on tokio
+//
+// - stream `https://` inputs too, with a TLS client (e.g. `rustls`) behind the `http` feature
 #[cfg(test)]
 use assert_cmd::Command;
+#[cfg(test)]
+use predicates::prelude::*;
 #[test]
 fn example() {
     // `transactions.csv`
@@ -516,21 +404,1492 @@ withdrawal, 2,  5,    3.0
 "#;
     // `accounts.csv`
     const OUTPUT: &str = r#"client,available,held,total,locked
-1,1.5,0.0,1.5,false
-2,2.0,0.0,2.0,false
+1,1.5000,0.0000,1.5000,false
+2,2.0000,0.0000,2.0000,false
 "#;
     // From https://docs.rs/assert_cmd/latest/assert_cmd/#examples
     let assert = Command::new("cargo")
-        .args(["run", "--features", "sorted"])
+        .args(["run", "--", "--sorted"])
         .write_stdin(INPUT)
         .assert();
     // Improvement: have a test that is robust to CSV formatting (currently I'm cheating requiring
-    // the `sorted` feature in test mode)
-    assert.success().stdout(OUTPUT);
+    // the `--sorted` flag in test mode)
+    // The last withdrawal is skipped for insufficient funds
+    assert.code(2).stdout(OUTPUT);
     // let stdout = String::from_utf8_lossy(&assert.success().get_output().clone().stdout);
     // let result = csv::Reader::from_reader(stdout).records();
     // let expected = csv::Reader::from_reader(OUTPUT).records();
     // TODO: turn `result` and `expected` to `Vec`, sort and compare them!
 }
 
+#[test]
+fn semicolon_delimiter() {
+    const INPUT: &str = "type;  client; tx; amount
+deposit;    1;  1;    1.0
+deposit;    2;  2;    2.0
+withdrawal; 1;  3;    0.5
+";
+    const OUTPUT: &str = "client|available|held|total|locked
+1|0.5000|0.0000|0.5000|false
+2|2.0000|0.0000|2.0000|false
+";
+    Command::new("cargo")
+        .args(["run", "--", "--sorted"])
+        .args(["--delimiter", ";", "--output-delimiter", "|"])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout(OUTPUT);
+}
+
+#[test]
+fn progress() {
+    let mut input = String::from("type,client,tx,amount\n");
+    for tx in 1..=150_000 {
+        input.push_str(&format!("deposit,1,{},1.0\n", tx));
+    }
+    let assert = Command::new("cargo")
+        .args(["run", "--", "--sorted", "--progress"])
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,150000.0000,0.0000,150000.0000,false\n");
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(stderr.contains("100000 rows processed in"), "{}", stderr);
+    assert!(stderr.contains("150000 rows processed in"), "{}", stderr);
+}
+
+#[test]
+fn invalid_delimiter() {
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--delimiter", "::"])
+        .write_stdin("type,client,tx,amount\n")
+        .assert()
+        .failure();
+}
+
+#[test]
+fn exit_code_usage() {
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--teleport"])
+        .write_stdin("type,client,tx,amount\n")
+        .assert()
+        .code(1)
+        .stderr(predicates::str::ends_with(
+            "error: unknown flag `--teleport` (see --help)\n",
+        ));
+}
+
+#[test]
+fn exit_code_skipped() {
+    const INPUT: &str = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+    Command::new("cargo")
+        .args(["run", "--", "--sorted"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(0);
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--on-business-error", "skip"])
+        .write_stdin(format!("{}withdrawal,1,2,2.0\n", INPUT))
+        .assert()
+        .code(2)
+        .stdout("client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n");
+}
+
+#[test]
+fn exit_code_parse() {
+    Command::new("cargo")
+        .args(["run", "--", "--sorted"])
+        .write_stdin("type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,one\n")
+        .assert()
+        .code(1)
+        .stderr(predicates::str::ends_with(
+            "error: line 3: invalid amount: invalid float literal\n",
+        ));
+}
+
+#[test]
+fn exit_code_rejected() {
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--strict"])
+        .write_stdin("type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,2.0\n")
+        .assert()
+        .code(1)
+        .stderr(predicates::str::ends_with(
+            "error: line 3: client 1 can't withdraw 2.0000 (only 1.0000 available)\n",
+        ));
+}
+
+#[test]
+fn exit_code_io() {
+    // Reading a directory fails with an I/O error (rather than a parse error)
+    use assert_cmd::assert::OutputAssertExt;
+    let stdin = std::fs::File::open(env!("CARGO_MANIFEST_DIR")).unwrap();
+    std::process::Command::new("cargo")
+        .args(["run", "--", "--sorted"])
+        .stdin(stdin)
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains("error: I/O error: "));
+}
+
+#[test]
+fn log_rejections() {
+    const INPUT: &str = "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,2.0\n";
+    const WARNING: &str = "[WARN] line 3: rejected withdrawal 2: client 1 can't withdraw";
+    Command::new("cargo")
+        .args(["run", "--", "--sorted"])
+        .env("RUST_LOG", "warn")
+        .write_stdin(INPUT)
+        .assert()
+        .code(2)
+        .stderr(predicates::str::contains(WARNING));
+    Command::new("cargo")
+        .args(["run", "--", "--sorted"])
+        .env("RUST_LOG", "off")
+        .write_stdin(INPUT)
+        .assert()
+        .code(2)
+        .stderr(predicates::str::contains(WARNING).not());
+}
+
+#[test]
+fn input_file_or_stdin() {
+    const INPUT: &str = "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,0.25\n";
+    const OUTPUT: &str = "client,available,held,total,locked\n1,0.7500,0.0000,0.7500,false\n";
+    let path = std::env::temp_dir().join("rust-coding-test-input_file_or_stdin.csv");
+    std::fs::write(&path, INPUT).unwrap();
+    Command::new("cargo")
+        .args(["run", "--", "--sorted"])
+        .arg(&path)
+        .assert()
+        .success()
+        .stdout(OUTPUT);
+    Command::new("cargo")
+        .args(["run", "--", "--sorted"])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout(OUTPUT);
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "-"])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout(OUTPUT);
+}
+
+#[test]
+fn missing_input_file() {
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "does-not-exist.csv"])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(
+            "error: can't open `does-not-exist.csv`",
+        ));
+}
+
+#[test]
+fn help() {
+    Command::new("cargo")
+        .args(["run", "--", "--help"])
+        .assert()
+        .success()
+        .stdout(predicates::str::starts_with("Toy payments engine"))
+        .stdout(predicates::str::contains(
+            "Usage: rust-coding-test [OPTIONS] [INPUT]",
+        ));
+}
+
+#[test]
+fn conflicting_flags() {
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--quiet", "--progress"])
+        .write_stdin("type,client,tx,amount\n")
+        .assert()
+        .code(1)
+        .stderr(predicates::str::ends_with(
+            "error: --quiet can't be used with --progress (see --help)\n",
+        ));
+    // Shards of a server would each bound their own history
+    Command::new("cargo")
+        .args(["run", "--", "--listen", "127.0.0.1:0", "--max-history", "2"])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::ends_with(
+            "error: --listen can't be used with --max-history (see --help)\n",
+        ));
+}
+
+#[test]
+fn output_file() {
+    let path = std::env::temp_dir().join("rust-coding-test-output_file.csv");
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--output"])
+        .arg(&path)
+        .write_stdin("type,client,tx,amount\ndeposit,1,1,1.0\n")
+        .assert()
+        .success()
+        .stdout("");
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n"
+    );
+}
+
+#[test]
+fn input_precision() {
+    const INPUT: &str = "type,client,tx,amount\ndeposit,1,1,1.23456789\n";
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--input-precision", "2"])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,1.2300,0.0000,1.2300,false\n");
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--input-precision", "9"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(1);
+}
+
+#[test]
+fn precision() {
+    const INPUT: &str = "type,client,tx,amount\ndeposit,1,1,1.2345\ndeposit,2,2,2\n";
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--precision", "2"])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n1,1.23,0.00,1.23,false\n2,2.00,0.00,2.00,false\n",
+        );
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--precision", "4"])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,1.2345,0.0000,1.2345,false\n2,2.0000,0.0000,2.0000,false\n");
+}
+
+#[test]
+fn check_ordering() {
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--check-ordering"])
+        .write_stdin("type,client,tx,amount\ndispute,1,1,\ndeposit,1,1,1.0\n")
+        .assert()
+        .code(2)
+        .stdout("client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n")
+        .stderr(predicates::str::contains(
+            "line 3: transaction 1 appears after being referenced at line 2",
+        ));
+}
+
+#[test]
+fn error_policies() {
+    const INPUT: &str = "type,client,tx,amount\nwithdrawal,1,1,1.0\ndeposit,x,2,1.0\n";
+    for (business, parse, code) in [
+        ("skip", "abort", 1),
+        ("skip", "skip", 2),
+        ("error", "abort", 1),
+        ("error", "skip", 1),
+    ] {
+        Command::new("cargo")
+            .args(["run", "--", "--sorted", "--on-business-error", business])
+            .args(["--on-parse-error", parse])
+            .write_stdin(INPUT)
+            .assert()
+            .code(code);
+    }
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--on-parse-error", "ignore"])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(
+            "invalid --on-parse-error `ignore` (expected `abort` or `skip`)",
+        ));
+}
+
+#[test]
+fn metrics() {
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--metrics", "localhost"])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(
+            "invalid --metrics `localhost` (expected an address like 127.0.0.1:9898)",
+        ));
+}
+
+#[test]
+fn include_empty_accounts() {
+    const INPUT: &str = "type,client,tx,amount\nwithdrawal,1,1,1.0\ndeposit,2,2,1.0\n";
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--include-empty-accounts", "false"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(2)
+        .stdout("client,available,held,total,locked\n2,1.0000,0.0000,1.0000,false\n");
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--include-empty-accounts=true"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(2)
+        .stdout("client,available,held,total,locked\n1,0.0000,0.0000,0.0000,false\n2,1.0000,0.0000,1.0000,false\n");
+}
+
+#[test]
+fn history_overflow() {
+    const INPUT: &str = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.0\ndispute,1,1,\n";
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--max-history", "1"])
+        .args(["--history-overflow", "error"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(1)
+        .stderr(predicates::str::ends_with(
+            "error: line 3: transaction history is full (more than 1 transactions)\n",
+        ));
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--max-history", "1"])
+        .args(["--history-overflow", "spill-disk"])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,2.0000,1.0000,3.0000,false\n");
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--history-overflow", "error"])
+        .assert()
+        .code(1);
+}
+
+#[test]
+fn json_errors() {
+    const INPUT: &str = "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,2.0\n";
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--json-errors"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(2)
+        .stdout("client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n")
+        .stderr(predicates::str::contains(
+            r#"{"line":3,"tx_id":2,"client_id":1,"error":"InsufficientFunds","detail":"client 1 can't withdraw 2.0000 (only 1.0000 available)"}"#,
+        ));
+    // Asked for, so they aren't silenced along with the warnings
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--json-errors", "--quiet"])
+        .env("RUST_LOG", "off")
+        .write_stdin(INPUT)
+        .assert()
+        .code(2)
+        .stderr(predicates::str::contains(r#""error":"InsufficientFunds""#));
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--json-errors", "--strict"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(1)
+        .stderr(predicates::str::ends_with(
+            r#"{"line":3,"tx_id":null,"client_id":1,"error":"InsufficientFunds","detail":"client 1 can't withdraw 2.0000 (only 1.0000 available)"}"#.to_owned() + "\n",
+        ));
+}
+
+#[test]
+fn disputable() {
+    const INPUT: &str =
+        "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,4.0\ndispute,1,2,\n";
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--strict"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(1)
+        .stderr(predicates::str::ends_with(
+            "error: line 4: transaction 2 can't be disputed (a withdrawal)\n",
+        ));
+    Command::new("cargo")
+        .args([
+            "run",
+            "--",
+            "--sorted",
+            "--disputable",
+            "deposit,withdrawal",
+        ])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,2.0000,4.0000,6.0000,false\n");
+}
+
+#[test]
+fn withdrawal_dispute_policy() {
+    const INPUT: &str =
+        "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,4.0\ndispute,1,2,\n";
+    for (policy, output) in [
+        ("credit-held", "1,6.0000,4.0000,10.0000,false\n"),
+        ("reverse", "1,10.0000,0.0000,10.0000,false\n"),
+    ] {
+        Command::new("cargo")
+            .args(["run", "--", "--disputable", "deposit,withdrawal"])
+            .args(["--withdrawal-dispute-policy", policy])
+            .write_stdin(INPUT)
+            .assert()
+            .success()
+            .stdout(format!("client,available,held,total,locked\n{}", output));
+    }
+    Command::new("cargo")
+        .args(["run", "--", "--disputable", "deposit,withdrawal"])
+        .args(["--withdrawal-dispute-policy", "reject"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(2)
+        .stdout("client,available,held,total,locked\n1,6.0000,0.0000,6.0000,false\n");
+    // Withdrawals aren't disputable by default
+    Command::new("cargo")
+        .args(["run", "--", "--withdrawal-dispute-policy", "reverse"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(
+            "--withdrawal-dispute-policy credit-held or reverse needs --disputable withdrawal",
+        ));
+}
+
+#[test]
+fn two_pass() {
+    const INPUT: &str =
+        "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.0\ndispute,1,1,\ndeposit,1,2,2.0\n";
+    const OUTPUT: &str = "client,available,held,total,locked\n1,2.0000,1.0000,3.0000,false\n";
+    let path = std::env::temp_dir().join("rust-coding-test-two_pass.csv");
+    std::fs::write(&path, INPUT).unwrap();
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--two-pass"])
+        .arg(&path)
+        .assert()
+        .code(2)
+        .stdout(OUTPUT)
+        .stderr(predicates::str::contains("falling back").not());
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--two-pass"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(2)
+        .stdout(OUTPUT)
+        .stderr(predicates::str::contains(
+            "[WARN] the input can't be read twice, falling back to a single pass",
+        ));
+    // Columns in another order than the usual one
+    std::fs::write(
+        &path,
+        "tx,type,client,amount\n10,deposit,7,5.0\n11,deposit,7,3.0\n10,dispute,7,\n",
+    )
+    .unwrap();
+    Command::new("cargo")
+        .args(["run", "--", "--two-pass"])
+        .arg(&path)
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n7,3.0000,5.0000,8.0000,false\n");
+}
+
+#[test]
+fn pipeline() {
+    Command::new("cargo")
+        .args([
+            "run",
+            "--",
+            "--sorted",
+            "--pipeline",
+            "--pipeline-capacity",
+            "1",
+        ])
+        .write_stdin(
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\nwithdrawal,1,3,1.5\n",
+        )
+        .assert()
+        .code(2)
+        .stdout("client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n2,2.0000,0.0000,2.0000,false\n");
+    Command::new("cargo")
+        .args(["run", "--", "--pipeline"])
+        .write_stdin("type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,1.5,1.0\n")
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(
+            "error: line 3: invalid tx: invalid digit found in string",
+        ));
+    Command::new("cargo")
+        .args(["run", "--", "--pipeline-capacity", "8"])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(
+            "error: --pipeline-capacity needs --pipeline",
+        ));
+}
+
+#[test]
+fn dedupe_tx() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,2.0
+withdrawal,1,2,1.0
+deposit,1,1,2.0
+withdrawal,1,2,1.0
+deposit,1,2,1.0
+";
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--dedupe-tx"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(2)
+        .stdout("client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n")
+        .stderr(predicates::str::contains(
+            "line 6: rejected deposit 2: transaction 2 already exists",
+        ))
+        .stderr(predicates::str::contains("line 4").not());
+}
+
+#[test]
+fn check_order() {
+    const INPUT: &str = "type,client,tx,amount,ts
+deposit,1,1,1.0,2024-01-01T10:00:00Z
+deposit,1,2,1.0,2024-01-01T09:00:00Z
+";
+    const OUTPUT: &str = "client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n";
+    const WARNING: &str =
+        "line 3: timestamp 2024-01-01T09:00:00Z is before 2024-01-01T10:00:00Z at line 2";
+    Command::new("cargo")
+        .args(["run", "--"])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout(OUTPUT)
+        .stderr(predicates::str::contains("timestamp").not());
+    Command::new("cargo")
+        .args(["run", "--", "--check-order", "warn"])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout(OUTPUT)
+        .stderr(predicates::str::contains(WARNING));
+    Command::new("cargo")
+        .args(["run", "--", "--check-order", "error"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(format!("error: {}", WARNING)));
+}
+
+#[test]
+fn dry_run() {
+    let fixture = |name| format!("{}/tests/data/{}.in.csv", env!("CARGO_MANIFEST_DIR"), name);
+    let stats = |assert: assert_cmd::assert::Assert| {
+        String::from_utf8(assert.get_output().stdout.clone()).unwrap()
+    };
+    // No accounts, only the stats of the run
+    let clean = Command::new("cargo")
+        .args(["run", "--", "--dry-run", &fixture("disputes")])
+        .assert()
+        .success();
+    let clean = stats(clean);
+    assert!(clean.starts_with(r#"{"rows":5,"#), "{}", clean);
+    assert!(clean.contains(r#""skipped":0,"#));
+    assert_eq!(clean.lines().count(), 1);
+    // Rejected rows are still reported on stderr, and fail the dry run
+    let rejected = std::env::temp_dir().join(format!("dry-run-{}.ndjson", std::process::id()));
+    let dirty = Command::new("cargo")
+        .args(["run", "--", "--dry-run", &fixture("insufficient_funds")])
+        .arg("--rejected")
+        .arg(&rejected)
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains("rejected withdrawal 2"));
+    let dirty = stats(dirty);
+    assert!(dirty.contains(r#""skipped":2,"#), "{}", dirty);
+    assert!(dirty.contains(r#""insufficient_funds":2,"#));
+    // Along with the list of them
+    let rejected = std::fs::read_to_string(&rejected).unwrap();
+    assert_eq!(rejected.lines().count(), 2);
+    assert!(rejected.starts_with(r#"{"line":"#), "{}", rejected);
+    assert!(rejected.contains(r#""error":"InsufficientFunds""#));
+    // Nothing is written
+    Command::new("cargo")
+        .args(["run", "--", "--dry-run", "--output", "accounts.csv"])
+        .arg(fixture("disputes"))
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(
+            "--dry-run can't be used with --output",
+        ));
+}
+
+#[test]
+fn stats_json() {
+    let path = std::env::temp_dir().join("rust-coding-test-stats.json");
+    Command::new("cargo")
+        .args(["run", "--", "--on-parse-error", "skip", "--stats-json"])
+        .arg(&path)
+        .write_stdin(
+            "type,client,tx,amount
+deposit,1,1,2.0
+deposit,2,2,1.0
+withdrawal,1,3,5.0
+dispute,1,1,
+dispute,2,2,
+chargeback,2,2,
+deposit,x,4,1.0
+resolve,1,9,
+",
+        )
+        .assert()
+        .code(2);
+    let stats = std::fs::read_to_string(&path).unwrap();
+    let expected = concat!(
+        r#"{"rows":8,"#,
+        r#""per_type":{"deposit":2,"withdrawal":1,"dispute":2,"resolve":1,"chargeback":1},"#,
+        r#""skipped":3,"#,
+        r#""skipped_by_reason":{"account_locked":0,"insufficient_funds":1,"#,
+        r#""client_out_of_range":0,"missing_amount":0,"unexpected_amount":0,"duplicate_tx":0,"#,
+        r#""unknown_tx":1,"already_disputed":0,"not_disputed":0,"not_disputable":0,"#,
+        r#""charged_back":0,"auto_locked":0,"too_many_clients":0,"negative_balance":0,"#,
+        r#""bad_amount":0,"malformed":1},"#,
+        r#""replayed":0,"reclaimed":1,"accounts":2,"locked_accounts":1,"auto_locked":0,"#,
+        r#""open_disputes":1,"accounts_capacity":3,"history_capacity":3,"history_inserts":2,"#,
+        r#""unreallocated_inserts":1,"elapsed_ms":"#,
+    );
+    assert!(stats.starts_with(expected), "{}", stats);
+    let elapsed = stats[expected.len()..]
+        .trim_end()
+        .strip_suffix('}')
+        .unwrap();
+    assert!(elapsed.parse::<u64>().is_ok(), "{}", stats);
+}
+
+#[test]
+fn empty_input() {
+    let path = std::env::temp_dir().join("rust-coding-test-empty_input.csv");
+    let stats = std::env::temp_dir().join("rust-coding-test-empty_input.json");
+    // Zero bytes, and then a header alone
+    for input in ["", "type,client,tx,amount\n"] {
+        std::fs::write(&path, input).unwrap();
+        for mode in ["--two-pass", "--pipeline", "--strict"] {
+            Command::new("cargo")
+                .args(["run", "--", mode, "--stats-json"])
+                .arg(&stats)
+                .arg(&path)
+                .assert()
+                .success()
+                .stdout("client,available,held,total,locked\n")
+                .stderr(predicates::str::contains(
+                    "[WARN] the input has no transactions, only the header is written",
+                ));
+            let stats = std::fs::read_to_string(&stats).unwrap();
+            assert!(stats.starts_with(r#"{"rows":0,"#), "{}", stats);
+        }
+        Command::new("cargo")
+            .args(["run", "--", "--quiet"])
+            .write_stdin(input)
+            .assert()
+            .success()
+            .stdout("client,available,held,total,locked\n")
+            .stderr(predicates::str::contains("[WARN]").not());
+    }
+}
+
+#[test]
+fn format_json() {
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--format", "json"])
+        .write_stdin("type,client,tx,amount\ndeposit,2,1,1.5\ndeposit,1,2,2.0\ndispute,1,2,\n")
+        .assert()
+        .success()
+        .stdout(concat!(
+            r#"[{"client":1,"available":"0.0000","held":"2.0000","total":"2.0000","locked":false},"#,
+            r#"{"client":2,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false}]"#,
+            "\n"
+        ));
+    Command::new("cargo")
+        .args(["run", "--", "--format", "json", "--output-delimiter", ";"])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(
+            "error: --output-delimiter needs --format csv",
+        ));
+}
+
+#[test]
+fn auto_lock() {
+    Command::new("cargo")
+        .args([
+            "run",
+            "--",
+            "--sorted",
+            "--auto-lock-chargeback-amount",
+            "2.5",
+        ])
+        .write_stdin(
+            "type,client,tx,amount
+deposit,1,1,3.0
+deposit,1,2,1.0
+dispute,1,1,
+dispute,1,2,
+chargeback,1,1,
+resolve,1,2,
+",
+        )
+        .assert()
+        .code(2)
+        .stdout("client,available,held,total,locked\n1,0.0000,1.0000,1.0000,true\n")
+        .stderr(predicates::str::contains(
+            "rejected resolve 2: account of client 1 is locked for too many chargebacks",
+        ));
+    Command::new("cargo")
+        .args(["run", "--", "--auto-lock-after-chargebacks", "0"])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(
+            "error: invalid --auto-lock-after-chargebacks `0` (expected a positive integer)",
+        ));
+}
+
+#[test]
+fn clients() {
+    Command::new("cargo")
+        .args(["run", "--", "--clients", "2"])
+        .write_stdin(
+            "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.0
+deposit,3,3,3.0
+withdrawal,1,4,1.0
+dispute,2,2,
+",
+        )
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n2,0.0000,2.0000,2.0000,false\n");
+    Command::new("cargo")
+        .args(["run", "--", "--clients", "1,x"])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(
+            "error: invalid --clients `x` (expected client IDs, between 0 and 65535)",
+        ));
+}
+
+#[test]
+fn config() {
+    let path = std::env::temp_dir().join("rust-coding-test-config.toml");
+    std::fs::write(
+        &path,
+        "on_business_error = \"error\"\nsorted = true\nprecision = 2 # cents\n",
+    )
+    .unwrap();
+    const INPUT: &str =
+        "type,client,tx,amount\ndeposit,2,1,1.0\ndeposit,1,2,2.0\nwithdrawal,1,3,5.0\n";
+    Command::new("cargo")
+        .args(["run", "--", "--config"])
+        .arg(&path)
+        .write_stdin(INPUT)
+        .assert()
+        .code(1);
+    // The command line overrides the file
+    Command::new("cargo")
+        .args(["run", "--", "--on-business-error", "skip", "--config"])
+        .arg(&path)
+        .write_stdin(INPUT)
+        .assert()
+        .code(2)
+        .stdout(
+            "client,available,held,total,locked\n1,2.00,0.00,2.00,false\n2,1.00,0.00,1.00,false\n",
+        );
+    Command::new("cargo")
+        .args(["run", "--", "--strict", "--print-config", "--config"])
+        .arg(&path)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("on_business_error = \"error\"\n"))
+        .stdout(predicates::str::contains("# max_history is unset\n"))
+        .stdout(predicates::str::contains("\nprecision = 2\n"))
+        .stdout(predicates::str::contains("sorted = true\n"));
+    std::fs::write(&path, "sorted = true\nsroted = false\n").unwrap();
+    Command::new("cargo")
+        .args(["run", "--", "--config"])
+        .arg(&path)
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains("line 2: unknown key `sroted`"));
+}
+
+#[test]
+fn max_clients() {
+    Command::new("cargo")
+        .args([
+            "run",
+            "--",
+            "--sorted",
+            "--max-clients",
+            "2",
+            "--max-tx-history",
+            "1",
+        ])
+        .write_stdin(
+            "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.0
+deposit,3,3,3.0
+deposit,1,4,1.0
+dispute,2,2,
+",
+        )
+        .assert()
+        .code(2)
+        .stdout("client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n2,2.0000,0.0000,2.0000,false\n")
+        .stderr(predicates::str::contains(
+            "rejected deposit 3: client 3 can't open an account, 2 are open already",
+        ));
+}
+
+#[test]
+fn amount_format() {
+    const INPUT: &str =
+        "type,client,tx,amount\ndeposit,1,1,1.5\ndeposit,2,2,2\ndeposit,2,3,0.0001\n";
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--amount-format", "trim"])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,1.5,0,1.5,false\n2,2.0001,0,2.0001,false\n");
+    Command::new("cargo")
+        .args([
+            "run",
+            "--",
+            "--format",
+            "json",
+            "--sorted",
+            "--amount-format",
+            "trim",
+        ])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout(predicates::str::starts_with(
+            r#"[{"client":1,"available":"1.5","held":"0","total":"1.5","locked":false},"#,
+        ));
+    Command::new("cargo")
+        .args(["run", "--", "--amount-format", "exact"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(
+            "invalid --amount-format `exact` (expected `fixed4` or `trim`)",
+        ));
+}
+
+#[test]
+fn audit_negatives() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,2.0
+withdrawal,1,2,1.5
+dispute,1,1,
+resolve,1,1,
+deposit,2,3,1.0
+dispute,2,3,
+";
+    const OUTPUT: &str = "client,available,held,total,locked
+1,0.5000,0.0000,0.5000,false
+2,0.0000,1.0000,1.0000,false
+";
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--audit-negatives", "--quiet"])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout(OUTPUT)
+        .stderr(predicates::str::contains(
+            "[WARN] available funds of client 1 went down to -1.5000",
+        ))
+        .stderr(predicates::str::contains("client 2").not());
+    Command::new("cargo")
+        .args(["run", "--", "--sorted"])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout(OUTPUT)
+        .stderr(predicates::str::contains("went down").not());
+}
+
+#[test]
+fn input_format() {
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--input-format", "ndjson"])
+        .write_stdin(
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1.0}
+{"type": "deposit", "client": 70000, "tx": 2, "amount": 1.0}
+{"type": "withdrawal", "client": 1, "tx": 3, "amount": "0.25"}
+{"type": "dispute", "client": 1, "tx": 1}
+"#,
+        )
+        .assert()
+        .code(2)
+        .stdout("client,available,held,total,locked\n1,-0.2500,1.0000,0.7500,false\n")
+        .stderr(predicates::str::contains("line 2: rejected row"));
+    Command::new("cargo")
+        .args(["run", "--", "--input-format", "ndjson"])
+        .write_stdin("type,client,tx,amount\ndeposit,1,1,1.0\n")
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains("line 1"));
+    Command::new("cargo")
+        .args(["run", "--", "--input-format", "ndjson", "--delimiter", ";"])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(
+            "--delimiter needs --input-format csv",
+        ));
+}
+
+#[test]
+fn dispute_expiry_after() {
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--dispute-expiry-after", "2"])
+        .write_stdin("type,client,tx,amount\ndeposit,1,1,1.0\ndispute,1,1,\ndeposit,2,2,1.0\ndeposit,2,3,1.0\n")
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n2,2.0000,0.0000,2.0000,false\n")
+        .stderr(predicates::str::contains(
+            "dispute of transaction 1 of client 1 expired after 2 transactions, resolved",
+        ));
+    Command::new("cargo")
+        .args(["run", "--", "--dispute-expiry-after", "0"])
+        .assert()
+        .code(1);
+}
+
+#[test]
+fn stats() {
+    let path = std::env::temp_dir().join(format!("stats-{}.csv", std::process::id()));
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--stats", path.to_str().unwrap()])
+        .write_stdin("type,client,tx,amount\ndeposit,2,1,1.0\ndeposit,1,2,2.0\ndispute,1,2,\n")
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,0.0000,2.0000,2.0000,false\n2,1.0000,0.0000,1.0000,false\n");
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "client,deposits,deposit_total,withdrawals,withdrawal_total,disputes_raised,disputes_resolved,chargebacks
+1,1,2.0000,0,0.0000,1,0,0
+2,1,1.0000,0,0.0000,0,0,0
+"
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn summary() {
+    let path = std::env::temp_dir().join(format!("summary-{}.csv", std::process::id()));
+    Command::new("cargo")
+        .args(["run", "--", "--summary", path.to_str().unwrap()])
+        .write_stdin("type,client,tx,amount\ndeposit,2,1,1.0\ndeposit,1,2,2.0\ndispute,1,2,\n")
+        .assert()
+        .success();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "account_count,total_available,total_held,locked_accounts,active_disputes,skipped_rows\n2,1.0000,2.0000,0,1,0\n"
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn dispute_trace() {
+    let path = std::env::temp_dir().join(format!("dispute-trace-{}.csv", std::process::id()));
+    Command::new("cargo")
+        .args(["run", "--", "--dispute-trace", path.to_str().unwrap()])
+        .write_stdin(
+            "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+dispute,2,2,
+dispute,1,1,
+resolve,2,2,
+chargeback,1,1,
+dispute,2,2,
+",
+        )
+        .assert()
+        .success();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "tx,client,amount,events
+1,1,10.0000,dispute:5 chargeback:7
+2,2,5.0000,dispute:4 resolve:6 dispute:8
+"
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn emit_events() {
+    let path = std::env::temp_dir().join(format!("emit-events-{}.csv", std::process::id()));
+    let assert = Command::new("cargo")
+        .args(["run", "--", "--sorted", "--emit-events", "-", "--output"])
+        .arg(&path)
+        .write_stdin(
+            "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,2.0
+withdrawal,2,3,5.0
+dispute,1,1,
+deposit,1,4,1.5
+resolve,1,1,
+dispute,1,1,
+chargeback,1,1,
+",
+        )
+        .assert()
+        .code(2);
+    let events = String::from_utf8(assert.get_output().stdout.clone()).unwrap();
+    // The rejected withdrawal emits nothing
+    assert_eq!(events.lines().count(), 7);
+    assert_eq!(
+        events.lines().next().unwrap(),
+        r#"{"tx":1,"type":"deposit","client":1,"delta_available":"10.0000","delta_held":"0.0000","available":"10.0000","held":"0.0000","locked":false}"#
+    );
+    /// The raw value of a field of a flat JSON object
+    fn field<'a>(event: &'a str, key: &str) -> &'a str {
+        let value = event.split(&format!("\"{}\":", key)).nth(1).unwrap();
+        value.split([',', '}']).next().unwrap().trim_matches('"')
+    }
+    // Accounts reconstructed from the deltas alone, by client
+    let mut accounts = std::collections::BTreeMap::new();
+    for event in events.lines() {
+        let client: u16 = field(event, "client").parse().unwrap();
+        let (available, held, locked) = accounts.entry(client).or_insert((0.0, 0.0, false));
+        *available += field(event, "delta_available").parse::<f64>().unwrap();
+        *held += field(event, "delta_held").parse::<f64>().unwrap();
+        *locked = field(event, "locked") == "true";
+    }
+    let mut reconstructed = String::from("client,available,held,total,locked\n");
+    for (client, (available, held, locked)) in accounts {
+        reconstructed += &format!(
+            "{},{:.4},{:.4},{:.4},{}\n",
+            client,
+            available,
+            held,
+            available + held,
+            locked
+        );
+    }
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), reconstructed);
+    assert_eq!(
+        reconstructed,
+        "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,true\n2,2.0000,0.0000,2.0000,false\n"
+    );
+    std::fs::remove_file(path).unwrap();
+    // Accounts would interleave with events on stdout
+    Command::new("cargo")
+        .args(["run", "--", "--emit-events", "-"])
+        .write_stdin("type,client,tx,amount\n")
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("--emit-events - needs --output"));
+}
+
+#[test]
+fn dump_history() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+deposit,1,3,2.0
+withdrawal,1,4,1.0
+dispute,1,1,
+dispute,2,2,
+chargeback,2,2,
+deposit,3,5,4.0
+dispute,3,5,
+resolve,3,5,
+dispute,1,3,
+";
+    let path = std::env::temp_dir().join(format!("dump-history-{}.csv", std::process::id()));
+    // Transactions spilled to disk are merged into the dump, in order
+    let spill = ["--max-history", "2", "--history-overflow", "spill-disk"];
+    for args in [&[][..], &spill] {
+        Command::new("cargo")
+            .args(["run", "--", "--dump-history", path.to_str().unwrap()])
+            .args(args)
+            .write_stdin(INPUT)
+            .assert()
+            .success();
+        let dump = std::fs::read_to_string(&path).unwrap();
+        let rows: Vec<_> = dump.lines().collect();
+        assert_eq!(rows.len(), 6, "{}", dump);
+        assert_eq!(rows[0], "tx,client,type,amount,state");
+        assert_eq!(rows[1], "1,1,deposit,10.0000,disputed");
+        assert_eq!(rows[2], "2,2,,,charged_back");
+        assert_eq!(rows[4], "4,1,withdrawal,1.0000,recorded");
+        assert_eq!(rows[5], "5,3,deposit,4.0000,recorded");
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn validate_amounts() {
+    Command::new("cargo")
+        .args(["run", "--", "--validate-amounts", "strict"])
+        .write_stdin("type,client,tx,amount\ndeposit,1,1,1e3\ndeposit,1,2,1.5\n")
+        .assert()
+        .code(2)
+        .stdout("client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n")
+        .stderr(predicates::str::contains("amount `1e3` isn't of the form"));
+    Command::new("cargo")
+        .args([
+            "run",
+            "--",
+            "--validate-amounts",
+            "strict",
+            "--lenient-amounts",
+        ])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(
+            "--lenient-amounts can't be used with --validate-amounts strict",
+        ));
+}
+
+#[test]
+fn partial_failure() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,abc
+refund,1,3,1.0
+deposit,1
+withdrawal,1,4,0.5
+";
+    // Every malformed row is reported along with its line, and the run goes on
+    Command::new("cargo")
+        .args(["run", "--", "--partial-failure"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(2)
+        .stdout("client,available,held,total,locked\n1,0.5000,0.0000,0.5000,false\n")
+        .stderr(predicates::str::contains("skipped line 3: invalid amount"))
+        .stderr(predicates::str::contains(
+            "skipped line 4: unknown variant `refund`",
+        ))
+        .stderr(predicates::str::contains(
+            "skipped line 5: invalid length 2",
+        ));
+    Command::new("cargo")
+        .args(["run", "--"])
+        .write_stdin(INPUT)
+        .assert()
+        .code(1);
+}
+
+#[test]
+fn warn_unknown_columns() {
+    const INPUT: &str = "type,client,memo,tx,amount,batch\ndeposit,1,refund,1,1.0,7\n";
+    const WARNING: &str = "[WARN] ignoring unknown columns: memo, batch";
+    Command::new("cargo")
+        .args(["run", "--", "--warn-unknown-columns"])
+        .env("RUST_LOG", "warn")
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n")
+        .stderr(predicates::str::contains(WARNING));
+    Command::new("cargo")
+        .args(["run", "--"])
+        .env("RUST_LOG", "warn")
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stderr(predicates::str::contains(WARNING).not());
+}
+
+#[test]
+fn no_header() {
+    const INPUT: &str = "deposit,1,1,1.0\ndeposit,1,2,2.0,memo\n";
+    Command::new("cargo")
+        .args(["run", "--", "--no-header"])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,3.0000,0.0000,3.0000,false\n")
+        .stderr(predicates::str::contains("looks like a transaction").not());
+    // Without it, the first row is lost, but not silently
+    Command::new("cargo")
+        .args(["run", "--"])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n")
+        .stderr(predicates::str::contains(
+            "[WARN] the header line looks like a transaction, which isn't applied (see --no-header)",
+        ));
+}
+
+#[test]
+fn max_rows() {
+    const INPUT: &str = "type,  client, tx, amount
+deposit,    1,  1,    1.0
+deposit,    2,  2,    2.0
+deposit,    1,  3,    2.0
+withdrawal, 1,  4,    1.5
+withdrawal, 2,  5,    3.0
+";
+    // Only the first two deposits are applied, so that nothing is skipped either
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--max-rows", "2"])
+        .write_stdin(INPUT)
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n2,2.0000,0.0000,2.0000,false\n");
+    Command::new("cargo")
+        .args(["run", "--", "--max-rows", "0"])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains("invalid --max-rows `0`"));
+}
+
+#[test]
+fn lenient_amounts() {
+    Command::new("cargo")
+        .args(["run", "--", "--delimiter", ";", "--lenient-amounts"])
+        .args(["--thousands-separator", ".", "--currency-symbol", "€"])
+        .write_stdin("type;client;tx;amount\ndeposit;1;1;€1.000,50\n")
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,1000.5000,0.0000,1000.5000,false\n");
+    Command::new("cargo")
+        .args(["run", "--", "--currency-symbol", "€"])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(
+            "error: --currency-symbol needs --lenient-amounts",
+        ));
+}
+
+#[test]
+fn validate_subcommand() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+refund,1,2,1.0
+deposit,70000,3,1.0
+withdrawal,1,4,
+deposit,1,5,1.23456
+dispute,1,1,1.0
+resolve,1,9,
+withdrawal,1,6,0.5
+";
+    let path = std::env::temp_dir().join("rust-coding-test-validate.csv");
+    std::fs::write(&path, INPUT).unwrap();
+    // Every violation is reported, rather than only the first one, and nothing is applied
+    Command::new("cargo")
+        .args(["run", "--", "validate"])
+        .arg(&path)
+        .assert()
+        .code(1)
+        .stdout(
+            "line 3: malformed row: unknown variant `refund`, expected one of `deposit`, `withdrawal`, `dispute`, `resolve`, `chargeback`
+line 4: malformed `client`: number too large to fit in target type
+line 5: missing amount
+line 6: amount 1.23456 has more than 4 places past the decimal
+line 7: unexpected amount 1.0000
+line 8: transaction 9 doesn't appear earlier
+",
+        );
+    // A valid input, which `validate` doesn't confuse with a file named so
+    Command::new("cargo")
+        .args(["run", "--", "validate", "-"])
+        .write_stdin("type,client,tx,amount\ndeposit,1,1,1.2345\n")
+        .assert()
+        .success()
+        .stdout("");
+}
+
+#[test]
+fn open_disputes() {
+    let path = std::env::temp_dir().join("rust-coding-test-open_disputes.csv");
+    Command::new("cargo")
+        .args(["run", "--", "--sorted", "--open-disputes"])
+        .arg(&path)
+        .write_stdin(
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.5\ndispute,1,1,\ndispute,1,2,\n",
+        )
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,0.0000,3.5000,3.5000,false\n");
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "client,open_disputes,held\n1,2,3.5000\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupt() {
+    use std::io::Write;
+    use std::process::Stdio;
+    let path = std::env::temp_dir().join("rust-coding-test-interrupt.csv");
+    let partial = std::env::temp_dir().join("rust-coding-test-interrupt.csv.partial");
+    let _ = std::fs::remove_file(&partial);
+    // Signals must go to the binary itself, not to `cargo run`
+    assert!(Command::new("cargo").arg("build").ok().is_ok());
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("rust-coding-test"))
+        .args(["--sorted", "--output"])
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // A throttled input, that stalls after a few rows
+    let mut stdin = child.stdin.take().unwrap();
+    stdin
+        .write_all(b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\n")
+        .unwrap();
+    stdin.flush().unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(6));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("interrupted after 2 rows"));
+    assert_eq!(
+        std::fs::read_to_string(&partial).unwrap(),
+        "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n2,2.0000,0.0000,2.0000,false\n"
+    );
+    drop(stdin);
+}
+
+#[cfg(unix)]
+#[test]
+fn server_shutdown() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::process::Stdio;
+    let path = std::env::temp_dir().join("rust-coding-test-server.csv");
+    assert!(Command::new("cargo").arg("build").ok().is_ok());
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("rust-coding-test"))
+        .args(["--sorted", "--listen", "127.0.0.1:0", "--output"])
+        .arg(&path)
+        .env("RUST_LOG", "info")
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Port 0 has the system pick a free port
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    let addr = loop {
+        line.clear();
+        assert_ne!(stderr.read_line(&mut line).unwrap(), 0);
+        if let Some(addr) = line.trim_end().split("listening on ").nth(1) {
+            break addr.to_owned();
+        }
+    };
+    let mut connection = std::net::TcpStream::connect(&addr).unwrap();
+    connection
+        .write_all(b"type,client,tx,amount\ndeposit,1,1,1.0\n")
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    // New connections are refused, but the open one is drained
+    assert!(std::net::TcpStream::connect(&addr).is_err());
+    connection.write_all(b"deposit,2,2,2.0\n").unwrap();
+    drop(connection);
+    let status = child.wait().unwrap();
+    assert!(status.success());
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n2,2.0000,0.0000,2.0000,false\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupt_ndjson() {
+    use std::io::Write;
+    use std::process::Stdio;
+    assert!(Command::new("cargo").arg("build").ok().is_ok());
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("rust-coding-test"))
+        .args(["--sorted", "--input-format", "ndjson"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Stalled in the middle of a line
+    let mut stdin = child.stdin.take().unwrap();
+    stdin
+        .write_all(
+            b"{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1.0}\n{\"type\":",
+        )
+        .unwrap();
+    stdin.flush().unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(6));
+    // The partial output goes to stdout, whole
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n"
+    );
+    drop(stdin);
+}
+
+#[cfg(unix)]
+#[test]
+fn follow() {
+    use std::io::Write;
+    use std::time::Duration;
+    let input = std::env::temp_dir().join("rust-coding-test-follow.in.csv");
+    let output = std::env::temp_dir().join("rust-coding-test-follow.out.csv");
+    std::fs::write(&input, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+    let _ = std::fs::remove_file(&output);
+    assert!(Command::new("cargo").arg("build").ok().is_ok());
+    let child = std::process::Command::new(assert_cmd::cargo::cargo_bin("rust-coding-test"))
+        .args(["--sorted", "--follow", "--output"])
+        .arg(&output)
+        .arg(&input)
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Rows are appended while the engine runs, the last one in two writes
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&input)
+        .unwrap();
+    for chunk in ["deposit,2,2,2.0\n", "deposit,1,3,", "4.0\n"] {
+        std::thread::sleep(Duration::from_millis(300));
+        file.write_all(chunk.as_bytes()).unwrap();
+        file.flush().unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(500));
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    let result = child.wait_with_output().unwrap();
+    assert!(result.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&output).unwrap(),
+        "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n2,2.0000,0.0000,2.0000,false\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn follow_rotation() {
+    use std::time::Duration;
+    let input = std::env::temp_dir().join("rust-coding-test-follow_rotation.csv");
+    std::fs::write(&input, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+    assert!(Command::new("cargo").arg("build").ok().is_ok());
+    let child = std::process::Command::new(assert_cmd::cargo::cargo_bin("rust-coding-test"))
+        .args(["--sorted", "--follow"])
+        .arg(&input)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+    std::fs::write(&input, "").unwrap();
+    let result = child.wait_with_output().unwrap();
+    assert_eq!(result.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("was truncated while being followed"));
+}
+
 // Thanks for reading me along the way 🦀! /Yvan <yvan@sraka.xyz>
@@ -0,0 +1,211 @@
+//! # Metrics
+//!
+//! Observability of a long run (e.g. following a growing file): counters and gauges updated by the
+//! engine as it processes rows, and exposed in the Prometheus text format on a `/metrics` HTTP
+//! endpoint. Updates are plain atomic operations, cheap enough for the hot path, and scrapes only
+//! read them, so serving the endpoint never blocks processing.
+
+use crate::Rejection;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bounds of the buckets of the row latency histogram, in nanoseconds
+const LATENCY_BUCKETS: [u64; 8] = [500, 1_000, 2_500, 5_000, 10_000, 50_000, 100_000, 1_000_000];
+
+/// Metrics of an engine, shared with the thread serving them
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Rows read, parsed or not
+    pub rows_received: AtomicU64,
+    /// Transactions applied to an account
+    pub rows_applied: AtomicU64,
+    /// Transactions rejected, by reason (see `Rejection::REASONS`)
+    pub rows_rejected: [AtomicU64; Rejection::REASONS.len()],
+    /// Inputs being processed
+    pub active_connections: AtomicU64,
+    /// Accounts (and multi-currency wallets) opened
+    pub clients: AtomicU64,
+    /// Accounts locked by a chargeback
+    pub locked_accounts: AtomicU64,
+    /// Sum of held funds across accounts, as the bits of an `f64`
+    held_funds: AtomicU64,
+    /// Number of rows in each bucket of `LATENCY_BUCKETS`, the last one being `+Inf`
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    /// Sum of the processing latency of rows, in nanoseconds
+    latency_sum: AtomicU64,
+}
+
+impl Metrics {
+    pub fn received(&self) {
+        self.rows_received.fetch_add(1, Relaxed);
+    }
+
+    pub fn applied(&self) {
+        self.rows_applied.fetch_add(1, Relaxed);
+    }
+
+    pub fn rejected(&self, rejection: &Rejection) {
+        self.rows_rejected[rejection.index()].fetch_add(1, Relaxed);
+    }
+
+    /// Only the engine updates held funds, so there is no concurrent update to race with
+    pub fn add_held_funds(&self, delta: f64) {
+        let held = f64::from_bits(self.held_funds.load(Relaxed)) + delta;
+        self.held_funds.store(held.to_bits(), Relaxed);
+    }
+
+    pub fn held_funds(&self) -> f64 {
+        f64::from_bits(self.held_funds.load(Relaxed))
+    }
+
+    /// Record the processing latency of a row
+    pub fn observe_latency(&self, latency: Duration) {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| nanos <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket].fetch_add(1, Relaxed);
+        self.latency_sum.fetch_add(nanos, Relaxed);
+    }
+
+    /// The metrics, in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            text += &format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind);
+            for (labels, value) in samples {
+                text += &format!("{}{} {}\n", name, labels, value);
+            }
+        };
+        let value = |atomic: &AtomicU64| vec![(String::new(), atomic.load(Relaxed).to_string())];
+        metric(
+            "transactions_rows_received_total",
+            "counter",
+            "Rows read, parsed or not",
+            &value(&self.rows_received),
+        );
+        metric(
+            "transactions_rows_applied_total",
+            "counter",
+            "Transactions applied to an account",
+            &value(&self.rows_applied),
+        );
+        let rejected = Rejection::REASONS
+            .iter()
+            .zip(&self.rows_rejected)
+            .map(|(reason, count)| {
+                let labels = format!("{{reason=\"{}\"}}", reason);
+                (labels, count.load(Relaxed).to_string())
+            })
+            .collect::<Vec<_>>();
+        metric(
+            "transactions_rows_rejected_total",
+            "counter",
+            "Transactions rejected, by reason",
+            &rejected,
+        );
+        metric(
+            "transactions_active_connections",
+            "gauge",
+            "Inputs being processed",
+            &value(&self.active_connections),
+        );
+        metric(
+            "transactions_clients",
+            "gauge",
+            "Client accounts",
+            &value(&self.clients),
+        );
+        metric(
+            "transactions_locked_accounts",
+            "gauge",
+            "Accounts locked by a chargeback",
+            &value(&self.locked_accounts),
+        );
+        metric(
+            "transactions_held_funds",
+            "gauge",
+            "Sum of held funds across accounts",
+            &[(String::new(), self.held_funds().to_string())],
+        );
+        let mut count = 0;
+        let mut latency = Vec::new();
+        for (i, bucket) in self.latency_buckets.iter().enumerate() {
+            count += bucket.load(Relaxed);
+            let bound = match LATENCY_BUCKETS.get(i) {
+                Some(nanos) => (*nanos as f64 / 1e9).to_string(),
+                None => "+Inf".to_owned(),
+            };
+            latency.push((format!("_bucket{{le=\"{}\"}}", bound), count.to_string()));
+        }
+        let sum = self.latency_sum.load(Relaxed) as f64 / 1e9;
+        latency.push(("_sum".to_owned(), sum.to_string()));
+        latency.push(("_count".to_owned(), count.to_string()));
+        metric(
+            "transactions_row_latency_seconds",
+            "histogram",
+            "Processing latency of a row",
+            &latency,
+        );
+        text
+    }
+}
+
+/// Serve `metrics` on `GET /metrics` at `addr`, from a background thread, and return the address
+/// actually listened on (e.g. when asking for port 0)
+pub fn serve<A: ToSocketAddrs>(addr: A, metrics: Arc<Metrics>) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // A scraper that misbehaves only fails its own scrape
+            let _ = respond(stream, &metrics);
+        }
+    });
+    Ok(addr)
+}
+
+fn respond(stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut stream = BufReader::new(stream);
+    let mut request = String::new();
+    stream.read_line(&mut request)?;
+    // Skip the headers of the request
+    let mut header = String::new();
+    while stream.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let (status, body) = match request.split(' ').take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.get_mut().write_all(response.as_bytes())
+}
+
+impl Metrics {
+    /// Count an input as being processed, until the returned guard is dropped
+    pub fn connect(self: &Arc<Self>) -> Connection {
+        self.active_connections.fetch_add(1, Relaxed);
+        Connection(self.clone())
+    }
+}
+
+/// An input being processed, see `Metrics::connect`
+#[derive(Debug)]
+pub struct Connection(Arc<Metrics>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Relaxed);
+    }
+}
@@ -0,0 +1,51 @@
+//! # Progress reporting
+//!
+//! Processing a multi-gigabyte file can take a while, so the engine can report how many rows it
+//! went through so far. Reports are only ever written to stderr, so stdout stays clean CSV.
+
+use std::io::IsTerminal;
+use std::time::Instant;
+
+/// Number of processed rows between two reports
+pub const PROGRESS_INTERVAL: u64 = 100_000;
+
+/// Keeps track of the time elapsed since processing started
+pub(crate) struct Progress {
+    start: Instant,
+    /// An interactive terminal gets a single status line overwritten with `\r`, while a piped
+    /// stderr (e.g. to a log file) gets one line per report
+    tty: bool,
+}
+
+impl Progress {
+    pub(crate) fn new() -> Progress {
+        Progress {
+            start: Instant::now(),
+            tty: std::io::stderr().is_terminal(),
+        }
+    }
+
+    /// Report `rows` processed rows, if it's a multiple of the `PROGRESS_INTERVAL`
+    pub(crate) fn tick(&self, rows: u64) {
+        if rows.is_multiple_of(PROGRESS_INTERVAL) {
+            self.report(rows);
+        }
+    }
+
+    /// Report the final count of processed rows, ending the status line
+    pub(crate) fn finish(&self, rows: u64) {
+        self.report(rows);
+        if self.tty {
+            eprintln!();
+        }
+    }
+
+    fn report(&self, rows: u64) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if self.tty {
+            eprint!("\r{} rows processed in {:.1}s", rows, elapsed);
+        } else {
+            eprintln!("{} rows processed in {:.1}s", rows, elapsed);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! # Server mode
+//!
+//! With `--listen`, transactions are received over TCP rather than read from an input: every
+//! connection streams a CSV (its header line first), whose rows are applied as they arrive to a
+//! `SharedEngine`, concurrently with the ones of other connections. Accounts are written once
+//! serving is over, i.e. on SIGINT or SIGTERM, which shuts the server down gracefully:
+//!
+//! 1. new connections are refused,
+//! 2. open connections get `--drain-timeout` to finish sending their rows, after which they're
+//!    cut (a row is applied as a whole or not at all, so a row cut halfway is lost),
+//! 3. the accounts are written, along with the other reports asked for.
+//!
+//! A second signal cuts the drain short, for the accounts applied so far to be written as a
+//! partial output, as when a file input is interrupted. A third one exits right away.
+
+use crate::signal;
+use rust_coding_test::shared::SharedEngine;
+use rust_coding_test::{debug, info, warn, EngineError};
+use std::io::{self, ErrorKind, Read};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often the listener, and connections waiting for rows, check whether to stop
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How serving ended
+#[derive(Debug, PartialEq)]
+pub enum Shutdown {
+    /// Every connection was over within the drain timeout
+    Drained,
+    /// This many connections were cut at the end of the drain timeout
+    TimedOut(usize),
+    /// The drain was cut short by a second signal
+    Forced,
+}
+
+/// Receive connections on `listener` until a signal is received, and then drain them
+pub fn serve(
+    listener: TcpListener,
+    engine: &Arc<SharedEngine>,
+    drain: Duration,
+) -> io::Result<Shutdown> {
+    listener.set_nonblocking(true)?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut connections = Vec::new();
+    while !signal::INTERRUPTED.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                debug!("connection from {}", peer);
+                let (engine, stop) = (engine.clone(), stop.clone());
+                connections.push(thread::spawn(move || receive(stream, &engine, &stop)));
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {
+                thread::sleep(POLL_INTERVAL)
+            }
+            Err(e) => return Err(e),
+        }
+        connections = finish(connections);
+    }
+    drop(listener);
+    info!("shutting down, draining {} connections", connections.len());
+    let deadline = Instant::now() + drain;
+    let shutdown = loop {
+        connections = finish(connections);
+        if connections.is_empty() {
+            break Shutdown::Drained;
+        }
+        if signal::FORCED.load(Ordering::SeqCst) {
+            break Shutdown::Forced;
+        }
+        if Instant::now() >= deadline {
+            break Shutdown::TimedOut(connections.len());
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    // Connections notice within a poll interval, so that the engine is theirs no more
+    stop.store(true, Ordering::SeqCst);
+    for connection in connections {
+        report(connection.join());
+    }
+    Ok(shutdown)
+}
+
+/// Apply the rows of a connection, until its end or until told to stop
+fn receive(stream: TcpStream, engine: &SharedEngine, stop: &AtomicBool) -> Result<(), EngineError> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    engine.process_from_reader(Connection { stream, stop })
+}
+
+/// The connections still open, the errors of the others being reported
+fn finish(
+    connections: Vec<JoinHandle<Result<(), EngineError>>>,
+) -> Vec<JoinHandle<Result<(), EngineError>>> {
+    let (over, open): (Vec<_>, Vec<_>) = connections.into_iter().partition(|c| c.is_finished());
+    for connection in over {
+        report(connection.join());
+    }
+    open
+}
+
+/// A connection failing (e.g. sending a malformed row) doesn't affect the others
+fn report(result: thread::Result<Result<(), EngineError>>) {
+    if let Ok(Err(e)) = result {
+        warn!("connection closed: {}", e);
+    }
+}
+
+/// The stream of a connection, whose reads wait for more bytes until told to stop
+struct Connection<'a> {
+    stream: TcpStream,
+    stop: &'a AtomicBool,
+}
+
+impl Read for Connection<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.stream.read(buf) {
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                    ) =>
+                {
+                    if self.stop.load(Ordering::SeqCst) {
+                        return Err(io::Error::new(ErrorKind::TimedOut, "cut at shutdown"));
+                    }
+                }
+                read => return read,
+            }
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! # Shared state
+//!
+//! An engine per connection only works when connections don't share clients, and a single lock
+//! around one engine serializes every connection. `SharedEngine` splits the state in shards
+//! instead: accounts, along with the history of their transactions, are kept by the shard of
+//! their client, each behind its own lock, so that independent clients proceed in parallel.
+//!
+//! The rows of a client are all handled by the same shard, so they're applied in the order a
+//! connection sends them, and a dispute finds the deposit sent earlier on the same connection.
+//! Transaction IDs are globally unique though, so the IDs of deposits and withdrawals are also
+//! claimed in a separate set (sharded by ID): a deposit reusing the ID of another client's one is
+//! rejected as a duplicate, as by a single engine. Nothing orders rows of different clients sent
+//! on different connections, which is what makes them independent.
+//!
+//! What depends on every transaction applied so far isn't shared though: each shard counts its
+//! own transactions to expire disputes (see `Options::dispute_expiry_after`), and bounds its own
+//! history (see `Options::max_history`), so that the history of `SHARDS` shards can hold up to
+//! `SHARDS` times the bound. It's only bounded as a whole once they're merged by `into_engine`,
+//! which is why the binary doesn't take `--max-history` or `--dispute-expiry-after` along with
+//! `--listen`.
+
+use crate::source::{Row, TransactionSource};
+use crate::{AccountsSnapshot, ClientID, Engine, EngineError, Options, Transaction, Tx, TxID};
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Mutex, MutexGuard};
+
+/// Number of shards of accounts, and of transaction IDs
+pub const SHARDS: usize = 64;
+
+/// An engine whose state is shared by several threads, e.g. one per connection of a server
+#[derive(Debug)]
+pub struct SharedEngine {
+    /// Engines of the clients whose ID modulo `SHARDS` is their index
+    shards: Vec<Mutex<Engine>>,
+    /// Client of every deposit or withdrawal applied, by transaction ID modulo `SHARDS`
+    claims: Vec<Mutex<HashMap<TxID, ClientID>>>,
+    /// Options of every shard, e.g. to read an input as they do
+    options: Options,
+}
+
+impl SharedEngine {
+    pub fn new(options: Options) -> SharedEngine {
+        SharedEngine {
+            shards: (0..SHARDS)
+                .map(|_| Mutex::new(Engine::new(options.clone())))
+                .collect(),
+            claims: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            options,
+        }
+    }
+
+    /// Apply a transaction, read at `line` of the input of a connection, as `Engine` does: only
+    /// the shard of its client is locked (along with the claims of its ID, for a deposit or a
+    /// withdrawal)
+    pub fn process(&self, line: u64, tx: Transaction) -> Result<(), EngineError> {
+        let index = usize::from(tx.1) % SHARDS;
+        let mut engine = lock(&self.shards[index]);
+        if !matches!(tx.0, Tx::Deposit | Tx::Withdrawal) {
+            return process_row(&mut engine, line, Ok(tx));
+        }
+        // Held while the transaction is applied, so that two shards can't both claim an ID.
+        // Claims are only ever locked after a shard, which rules out deadlocks.
+        let (client, id) = (tx.1, tx.2);
+        let mut claims = lock(&self.claims[id as usize % SHARDS]);
+        let owner = claims
+            .get(&id)
+            .copied()
+            .filter(|owner| usize::from(*owner) % SHARDS != index);
+        // For the time of the row, the shard knows the ID is taken (as by a transaction charged
+        // back, which leaves nothing else behind), so that it's rejected as a duplicate with every
+        // policy of its engine. Duplicates within a shard are rejected by its engine anyway.
+        if let Some(owner) = owner {
+            engine.charged_back.insert(id, owner);
+        }
+        let result = process_row(&mut engine, line, Ok(tx));
+        match owner {
+            Some(_) => {
+                engine.charged_back.remove(&id);
+            }
+            None if engine.history.contains(id) || engine.charged_back.contains_key(&id) => {
+                claims.insert(id, client);
+            }
+            None => {}
+        }
+        result
+    }
+
+    /// Apply every row of `source`, e.g. the stream of a connection. Malformed rows, which can't
+    /// be told apart by client, are all handled by the first shard.
+    pub fn process_source(&self, source: &mut dyn TransactionSource) -> Result<(), EngineError> {
+        while let Some((line, row)) = source.next_row() {
+            match row {
+                Ok(tx) => self.process(line, tx)?,
+                Err(e) => process_row(&mut lock(&self.shards[0]), line, Err(e))?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply every row of the CSV read from `rdr`, e.g. the stream of a connection, as
+    /// `process_source` does. Nothing is locked while waiting for the rows.
+    pub fn process_from_reader<R: Read>(&self, rdr: R) -> Result<(), EngineError> {
+        self.process_source(&mut self.options.csv_source(rdr)?)
+    }
+
+    /// State of every account, as in `Engine::snapshot`
+    pub fn snapshot(&self) -> AccountsSnapshot {
+        let accounts = self
+            .shards
+            .iter()
+            .flat_map(|shard| lock(shard).snapshot().0);
+        AccountsSnapshot(accounts.collect())
+    }
+
+    /// The shards merged into a single engine, e.g. to write its accounts once serving is over.
+    /// This fails as `Engine::merge` does, e.g. if their histories together hold more than
+    /// `Options::max_history` transactions with `HistoryOverflow::Error`.
+    pub fn into_engine(self) -> Result<Engine, EngineError> {
+        let mut shards = self.shards.into_iter().map(|shard| {
+            shard
+                .into_inner()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+        });
+        let first = shards.next().expect("there is at least one shard");
+        shards.try_fold(first, Engine::merge)
+    }
+}
+
+/// A shard, even if another thread panicked while holding it: every row is applied as a whole or
+/// not at all, so its state is still consistent
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Apply a single row to the engine of a shard, with every policy of `Engine::process_source`
+fn process_row(engine: &mut Engine, line: u64, row: Row) -> Result<(), EngineError> {
+    let mut row = Some((line, row));
+    engine.process_source(&mut || row.take())
+}
+
+#[test]
+fn stress() {
+    use crate::source::CsvSource;
+    use crate::timestamp::OrderCheck;
+    use crate::workload::Workload;
+    use crate::Amount;
+    use std::sync::Arc;
+
+    const THREADS: usize = 16;
+    let input = Workload {
+        rows: 50_000,
+        clients: 200,
+        dispute_ratio: 0.2,
+        seed: 7,
+    }
+    .generate();
+    let options = Options {
+        strict: false,
+        ..Options::default()
+    };
+    // Every thread stands for the connection of a few clients, their rows in order
+    let mut connections = (0..THREADS).map(|_| Vec::new()).collect::<Vec<_>>();
+    let rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(input.as_bytes());
+    let mut source = CsvSource::new(rdr, OrderCheck::Off).unwrap();
+    while let Some((line, row)) = source.next_row() {
+        let tx = row.unwrap();
+        connections[usize::from(tx.1) % THREADS].push((line, tx));
+    }
+    let shared = Arc::new(SharedEngine::new(options.clone()));
+    let threads = connections.into_iter().map(|rows| {
+        let shared = shared.clone();
+        std::thread::spawn(move || {
+            for (line, tx) in rows {
+                shared.process(line, tx).unwrap();
+            }
+        })
+    });
+    for thread in threads.collect::<Vec<_>>() {
+        thread.join().unwrap();
+    }
+    let shared = Arc::into_inner(shared).unwrap();
+    let mut sequential = Engine::new(options);
+    sequential.process_from_str(&input).unwrap();
+    assert_eq!(shared.snapshot().sorted(), sequential.snapshot().sorted());
+    let (shared, sequential) = (shared.into_engine().unwrap().stats(), sequential.stats());
+    assert_eq!(
+        (
+            shared.rows,
+            shared.per_type,
+            shared.rejected,
+            shared.open_disputes
+        ),
+        (
+            sequential.rows,
+            sequential.per_type,
+            sequential.rejected,
+            sequential.open_disputes
+        )
+    );
+    // A deposit reusing the ID of another client's one, whichever shard it's in
+    let shared = SharedEngine::new(Options {
+        strict: true,
+        ..Options::default()
+    });
+    let deposit = |client| Transaction(Tx::Deposit, client, 1, Some(Amount(1.0)), None, None);
+    shared.process(1, deposit(1)).unwrap();
+    assert!(matches!(
+        shared.process(2, deposit(2)),
+        Err(EngineError::Rejected {
+            rejection: crate::Rejection::DuplicateTx(1),
+            ..
+        })
+    ));
+    shared.process(3, deposit(1)).unwrap_err();
+}
+
+#[test]
+fn shard_history() {
+    use crate::store::HistoryOverflow;
+    use crate::Amount;
+
+    let shared = |history_overflow| {
+        let shared = SharedEngine::new(Options {
+            strict: false,
+            max_history: Some(2),
+            history_overflow,
+            ..Options::default()
+        });
+        // In three shards, each holding a single transaction, below the bound
+        for client in 1..=3 {
+            let deposit = Transaction(
+                Tx::Deposit,
+                client,
+                client as TxID,
+                Some(Amount(1.0)),
+                None,
+                None,
+            );
+            shared.process(client as u64, deposit).unwrap();
+        }
+        shared
+    };
+    let merged = shared(HistoryOverflow::EvictOldest).into_engine().unwrap();
+    assert_eq!((merged.transaction_count(), merged.evicted_count()), (2, 1));
+    assert!(matches!(
+        shared(HistoryOverflow::Error).into_engine(),
+        Err(EngineError::HistoryOverflow { max_history: 2, .. })
+    ));
+}
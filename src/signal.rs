@@ -0,0 +1,49 @@
+//! # Signals
+//!
+//! A batch scheduler stops a run with SIGTERM (and an operator with Ctrl-C, i.e. SIGINT): rather
+//! than dying with nothing written, the first such signal raises the `INTERRUPTED` flag, which the
+//! engine checks between rows (see `Options::interrupt`) to stop reading and let partial results
+//! be written. A second signal exits immediately, unless shutting down `GRACEFUL`ly (see
+//! `server.rs`): it then raises the `FORCED` flag, for partial results to still be written, and
+//! only a third one exits immediately.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Raised by the first SIGINT or SIGTERM
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a second signal raises `FORCED` rather than exiting
+pub static GRACEFUL: AtomicBool = AtomicBool::new(false);
+
+/// Raised by the second SIGINT or SIGTERM, when `GRACEFUL`
+pub static FORCED: AtomicBool = AtomicBool::new(false);
+
+/// Only async-signal-safe operations are allowed in there: atomic operations, and `_exit`
+#[cfg(unix)]
+extern "C" fn handle(signal: libc::c_int) {
+    if !INTERRUPTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    if GRACEFUL.load(Ordering::SeqCst) && !FORCED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    unsafe { libc::_exit(128 + signal) };
+}
+
+/// Install the handlers of SIGINT and SIGTERM. They are installed without `SA_RESTART`, so that a
+/// blocking read of a stalled input is interrupted too, rather than waiting for the next row.
+#[cfg(unix)]
+pub fn install() {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+/// Elsewhere, signals keep their default behavior
+#[cfg(not(unix))]
+pub fn install() {}
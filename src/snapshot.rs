@@ -0,0 +1,68 @@
+//! # Snapshots
+//!
+//! The final state of the accounts as plain data, for services embedding the engine, and the
+//! single source every output format is written from, so that formats can't drift apart.
+
+use crate::{Amount, ClientID, Currency};
+use serde::{Deserialize, Serialize};
+
+/// State of an account, as output
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AccountState {
+    pub client: ClientID,
+    /// Currency of the account in multi-currency mode, `None` for the default one
+    pub currency: Option<Currency>,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+}
+
+impl AccountState {
+    /// Whether the account holds nothing and isn't locked, e.g. opened by rejected transactions
+    /// only (a locked account with a zero balance still tells something)
+    pub fn is_empty(&self) -> bool {
+        self.available == Amount(0.0) && self.held == Amount(0.0) && !self.locked
+    }
+}
+
+/// State of every account of an engine, see `Engine::snapshot`
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct AccountsSnapshot(pub Vec<AccountState>);
+
+impl AccountsSnapshot {
+    /// Accounts sorted by client ID, and then by currency (the default one first): a total
+    /// order, so the result doesn't depend on the order accounts were in
+    pub fn sorted(mut self) -> AccountsSnapshot {
+        self.0
+            .sort_by(|a, b| (a.client, &a.currency).cmp(&(b.client, &b.currency)));
+        self
+    }
+
+    /// The account of `client` in the default currency
+    pub fn get(&self, client: ClientID) -> Option<&AccountState> {
+        self.0
+            .iter()
+            .find(|account| account.client == client && account.currency.is_none())
+    }
+
+    /// The account of `client` in `currency`, in multi-currency mode
+    pub fn get_wallet(&self, client: ClientID, currency: &str) -> Option<&AccountState> {
+        self.0.iter().find(|account| {
+            account.client == client && account.currency.as_deref() == Some(currency)
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &AccountState> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
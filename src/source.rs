@@ -0,0 +1,497 @@
+//! # Transaction sources
+//!
+//! Not every partner sends CSV: some push NDJSON event streams, one JSON object per line with the
+//! same fields as the CSV columns, e.g. `{"type": "deposit", "client": 1, "tx": 1, "amount":
+//! 1.5}`. The engine consumes rows from a `TransactionSource`, whatever format they're read from,
+//! so its logic doesn't depend on it.
+
+use crate::json::{self, Value};
+use crate::timestamp::OrderCheck;
+use crate::{Amount, ClientID, EngineError, Transaction, Tx, TxID};
+use std::io::{BufRead, Read};
+
+/// A row of the input: a transaction, or why it couldn't be parsed along with its raw fields (e.g.
+/// to tell an out-of-range client ID apart from a malformed row)
+pub type Row = Result<Transaction, (EngineError, csv::StringRecord)>;
+
+/// An empty record with room for a typical row, i.e. six fields (along with a currency and a
+/// timestamp) of 64 bytes overall, so that reading the first rows doesn't grow it
+pub(crate) fn record() -> csv::StringRecord {
+    csv::StringRecord::with_capacity(64, 6)
+}
+
+/// Columns of the input, in the order of the fields of `Transaction`: the first four are
+/// mandatory, and in this order when the header doesn't name them
+pub(crate) const COLUMNS: [&str; 6] = ["type", "client", "tx", "amount", "currency", "ts"];
+
+/// Format transactions are read in
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    /// One JSON object per line, see `NdjsonSource`
+    Ndjson,
+}
+
+/// How amounts formatted for humans (e.g. `$1,000.50`) are read, see `Options::lenient_amounts`
+#[derive(Clone, Debug, PartialEq)]
+pub struct LenientAmounts {
+    /// Currency symbol amounts may start or end with, e.g. `$` (the default) or `€`
+    pub symbol: String,
+    /// Separator of groups of thousands, `,` by default (as in `1,000.50`). When it's `.` (as in
+    /// `1.000,50`), the decimal separator is a comma.
+    pub thousands_separator: char,
+}
+
+impl Default for LenientAmounts {
+    fn default() -> Self {
+        LenientAmounts {
+            symbol: "$".to_owned(),
+            thousands_separator: ',',
+        }
+    }
+}
+
+impl LenientAmounts {
+    /// `amount` without currency symbol nor thousands separators, and with a `.` as decimal
+    /// separator, to be parsed as usual: anything else is left as is (and still rejected)
+    pub fn normalize(&self, amount: &str) -> String {
+        let amount = amount.trim();
+        let (sign, amount) = match amount.strip_prefix('-') {
+            Some(amount) => ("-", amount.trim_start()),
+            None => ("", amount),
+        };
+        let amount = match self.symbol.as_str() {
+            "" => amount,
+            symbol => amount
+                .strip_prefix(symbol)
+                .or_else(|| amount.strip_suffix(symbol))
+                .unwrap_or(amount)
+                .trim(),
+        };
+        let amount = amount.replace(self.thousands_separator, "");
+        match self.thousands_separator {
+            '.' => format!("{}{}", sign, amount.replace(',', ".")),
+            _ => format!("{}{}", sign, amount),
+        }
+    }
+}
+
+/// How strictly the text of amounts is checked, see `Options::validate_amounts`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AmountValidation {
+    /// Anything read as a float, e.g. `1e3`, `+5` or `.5`
+    #[default]
+    Lenient,
+    /// Only `digits[.digits]` with 1 to 4 places past the decimal (e.g. `1`, `0.5` or
+    /// `10.1234`), surrounding whitespace aside
+    Strict,
+}
+
+impl AmountValidation {
+    /// Check the raw text of an amount, an empty one (i.e. a missing amount) being left to the
+    /// engine
+    pub fn check(self, amount: &str) -> Result<(), String> {
+        let amount = amount.trim();
+        if self == AmountValidation::Lenient || amount.is_empty() {
+            return Ok(());
+        }
+        let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        let canonical = match amount.split_once('.') {
+            Some((units, places)) => digits(units) && digits(places) && places.len() <= 4,
+            None => digits(amount),
+        };
+        match canonical {
+            true => Ok(()),
+            false => Err(format!(
+                "`{}` isn't an amount of the form `digits[.digits]`, with up to 4 places past the decimal",
+                amount
+            )),
+        }
+    }
+}
+
+/// Rows of an input, in order
+pub trait TransactionSource {
+    /// The next row along with its line number, or nothing at the end
+    fn next_row(&mut self) -> Option<(u64, Row)>;
+}
+
+/// A closure giving rows is a source too, e.g. of rows received from another thread
+impl<F: FnMut() -> Option<(u64, Row)>> TransactionSource for F {
+    fn next_row(&mut self) -> Option<(u64, Row)> {
+        self()
+    }
+}
+
+/// Rows of a CSV input, see `Engine::process_from_reader`
+pub struct CsvSource<R> {
+    rdr: csv::Reader<R>,
+    /// Reading into a single record, rather than iterating over `rdr.deserialize()`, saves an
+    /// allocation per row and keeps track of the row position for error reporting
+    record: csv::StringRecord,
+    /// Index of the `ts` column, if any
+    ts: Option<usize>,
+    /// Position of every column of `COLUMNS` in the rows, when the header names them in another
+    /// order or along with other columns: rows are then rearranged before being deserialized
+    columns: Option<[Option<usize>; 6]>,
+    /// Columns named by the header that aren't among `COLUMNS`, and are ignored
+    unknown_columns: Vec<String>,
+    /// Whether the header line has the client and transaction IDs of a row where they'd be
+    header_is_row: bool,
+    check_order: OrderCheck,
+    lenient_amounts: Option<LenientAmounts>,
+    validate_amounts: AmountValidation,
+}
+
+impl<R: Read> CsvSource<R> {
+    /// Rows of `rdr` once its header is read, parsing their timestamps when checking order.
+    ///
+    /// Columns are found by name when the header names the mandatory ones, whatever their order,
+    /// other columns (e.g. a partner's `memo`) being ignored. Otherwise, or if `rdr` doesn't read
+    /// a header at all (see `csv::ReaderBuilder::has_headers`), columns are read by position: the
+    /// mandatory ones, and then a currency, extra trailing fields being ignored.
+    ///
+    /// A header line is skipped whatever it holds, so the first row of an input without one would
+    /// be lost: see `header_is_row`.
+    pub fn new(mut rdr: csv::Reader<R>, check_order: OrderCheck) -> Result<Self, EngineError> {
+        if !rdr.has_headers() {
+            return Ok(CsvSource {
+                rdr,
+                record: record(),
+                ts: None,
+                columns: None,
+                unknown_columns: Vec::new(),
+                header_is_row: false,
+                check_order,
+                lenient_amounts: None,
+                validate_amounts: AmountValidation::default(),
+            });
+        }
+        let headers = rdr.headers()?;
+        let header_is_row = headers.len() >= 3
+            && headers
+                .iter()
+                .skip(1)
+                .take(2)
+                .all(|id| id.parse::<u64>().is_ok());
+        let mut ts = headers.iter().position(|column| column == "ts");
+        let named = COLUMNS[..3]
+            .iter()
+            .all(|name| headers.iter().any(|c| c == *name));
+        // Rows are deserialized as they are if their first columns are the expected ones, in
+        // order (a timestamp in the fifth column being handled apart)
+        let in_order = headers
+            .iter()
+            .take(4)
+            .enumerate()
+            .all(|(i, column)| column == COLUMNS[i])
+            && headers
+                .get(4)
+                .is_none_or(|column| column == "currency" || column == "ts");
+        let mut columns = None;
+        let mut unknown_columns = Vec::new();
+        if named {
+            unknown_columns = headers
+                .iter()
+                .filter(|column| !COLUMNS.contains(column))
+                .map(str::to_owned)
+                .collect();
+            if !in_order {
+                columns = Some(COLUMNS.map(|name| headers.iter().position(|c| c == name)));
+                ts = ts.map(|_| 5);
+            }
+        }
+        Ok(CsvSource {
+            rdr,
+            record: record(),
+            ts,
+            columns,
+            unknown_columns,
+            header_is_row,
+            check_order,
+            lenient_amounts: None,
+            validate_amounts: AmountValidation::default(),
+        })
+    }
+
+    /// Read amounts formatted for humans too, see `Options::lenient_amounts`
+    pub fn with_lenient_amounts(mut self, lenient_amounts: Option<LenientAmounts>) -> Self {
+        self.lenient_amounts = lenient_amounts;
+        self
+    }
+
+    /// Check the text of amounts, see `Options::validate_amounts`
+    pub fn with_amount_validation(mut self, validate_amounts: AmountValidation) -> Self {
+        self.validate_amounts = validate_amounts;
+        self
+    }
+
+    /// Columns named by the header that are ignored, see `Options::warn_unknown_columns`
+    pub fn unknown_columns(&self) -> &[String] {
+        &self.unknown_columns
+    }
+
+    /// Whether the header line looks like a row, i.e. has numbers for the client and transaction
+    /// IDs, as when the input doesn't have a header at all
+    pub fn header_is_row(&self) -> bool {
+        self.header_is_row
+    }
+
+    /// Position in the rows of the column of `COLUMNS` at `index`, if they have one
+    pub(crate) fn column(&self, index: usize) -> Option<usize> {
+        self.columns.map_or(Some(index), |columns| columns[index])
+    }
+
+    /// Read the next row into `record` as it is, e.g. to only look at a few columns of every row
+    /// (see `column`), `false` meaning the end of the input
+    pub(crate) fn read_record(&mut self, record: &mut csv::StringRecord) -> csv::Result<bool> {
+        self.rdr.read_record(record)
+    }
+}
+
+impl<R: Read> TransactionSource for CsvSource<R> {
+    fn next_row(&mut self) -> Option<(u64, Row)> {
+        let record = &mut self.record;
+        let lenient_amounts = self.lenient_amounts.as_ref();
+        let columns = self.columns.as_ref();
+        let parsed = loop {
+            match self.rdr.read_record(record) {
+                // The reader skips empty lines, and one of whitespace only is as blank once
+                // trimmed, as a single empty field
+                Ok(true) if record.len() == 1 && record[0].is_empty() => continue,
+                read => break read,
+            }
+        };
+        let parsed = parsed.and_then(|more| {
+            if let (true, Some(columns)) = (more, columns) {
+                let fields = columns.map(|column| column.and_then(|i| record.get(i)));
+                let rearranged = fields.iter().map(|field| field.unwrap_or(""));
+                let position = record.position().cloned();
+                *record = rearranged.collect();
+                record.set_position(position);
+            }
+            if let (true, Some(lenient), Some(amount)) = (more, lenient_amounts, record.get(3)) {
+                let amount = lenient.normalize(amount);
+                let fields = record.iter().enumerate().map(|(i, field)| match i {
+                    3 => amount.as_str(),
+                    _ => field,
+                });
+                let position = record.position().cloned();
+                *record = fields.collect();
+                record.set_position(position);
+            }
+            match more {
+                // Notice that we need to provide a type hint for automatic deserialization.
+                true => record.deserialize::<Transaction>(None).map(Some),
+                false => Ok(None),
+            }
+        });
+        let line = record.position().map_or(0, |p| p.line());
+        let mut tx = match parsed {
+            Ok(Some(tx)) => tx,
+            Ok(None) => return None,
+            Err(e) => return Some((line, Err((e.into(), record.clone())))),
+        };
+        if let Err(message) = self.validate_amounts.check(record.get(3).unwrap_or("")) {
+            let field = Some("amount");
+            let error = EngineError::Parse {
+                line,
+                field,
+                message,
+            };
+            return Some((line, Err((error, record.clone()))));
+        }
+        if let Some(ts) = self.ts {
+            // A timestamp in the fifth column isn't a currency
+            if ts == 4 {
+                tx.4 = None;
+            }
+            if self.check_order == OrderCheck::Off {
+                return Some((line, Ok(tx)));
+            }
+            match record.get(ts).filter(|ts| !ts.is_empty()).map(str::parse) {
+                Some(Ok(timestamp)) => tx.5 = Some(timestamp),
+                Some(Err(message)) => {
+                    let field = Some("ts");
+                    let error = EngineError::Parse {
+                        line,
+                        field,
+                        message,
+                    };
+                    return Some((line, Err((error, record.clone()))));
+                }
+                None => {}
+            }
+        }
+        Some((line, Ok(tx)))
+    }
+}
+
+/// Rows of an NDJSON input, blank lines being skipped. Numbers may also be given as strings
+/// (e.g. amounts, to keep their precision), and other fields are ignored.
+pub struct NdjsonSource<R> {
+    rdr: R,
+    /// Bytes of the line being read, kept when a read is interrupted
+    pending: Vec<u8>,
+    line: u64,
+    check_order: OrderCheck,
+    lenient_amounts: Option<LenientAmounts>,
+    validate_amounts: AmountValidation,
+}
+
+impl<R: BufRead> NdjsonSource<R> {
+    pub fn new(rdr: R, check_order: OrderCheck) -> Self {
+        NdjsonSource {
+            rdr,
+            pending: Vec::new(),
+            line: 0,
+            check_order,
+            lenient_amounts: None,
+            validate_amounts: AmountValidation::default(),
+        }
+    }
+
+    /// Read amounts formatted for humans too, see `Options::lenient_amounts`
+    pub fn with_lenient_amounts(mut self, lenient_amounts: Option<LenientAmounts>) -> Self {
+        self.lenient_amounts = lenient_amounts;
+        self
+    }
+
+    /// Check the text of amounts, see `Options::validate_amounts`
+    pub fn with_amount_validation(mut self, validate_amounts: AmountValidation) -> Self {
+        self.validate_amounts = validate_amounts;
+        self
+    }
+
+    /// Read the next line into `pending`, up to and including its `\n` (there may be none at the
+    /// end). Unlike `BufRead::read_line`, a read interrupted by a signal isn't retried, so that a
+    /// stalled input doesn't hold off `Options::interrupt`.
+    fn read_line(&mut self) -> std::io::Result<()> {
+        loop {
+            let buf = self.rdr.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(());
+            }
+            let (end, complete) = match buf.iter().position(|&b| b == b'\n') {
+                Some(i) => (i + 1, true),
+                None => (buf.len(), false),
+            };
+            self.pending.extend_from_slice(&buf[..end]);
+            self.rdr.consume(end);
+            if complete {
+                return Ok(());
+            }
+        }
+    }
+
+    /// The transaction of a line, or why it isn't one
+    fn parse(&self, text: &str) -> Row {
+        let line = self.line;
+        let error = |field, message| EngineError::Parse {
+            line,
+            field,
+            message,
+        };
+        let object = json::parse_object(text)
+            .map_err(|message| (error(None, message), csv::StringRecord::new()))?;
+        // Raw fields, in the order of the CSV columns
+        let mut fields: [Option<String>; 6] = Default::default();
+        const NAMES: [&str; 6] = ["type", "client", "tx", "amount", "currency", "ts"];
+        for (key, value) in object {
+            if let Some(i) = NAMES.iter().position(|name| *name == key) {
+                fields[i] = match value {
+                    // Nested values are rejected by `parse_object`
+                    Value::Null | Value::Array(_) | Value::Object(_) => None,
+                    Value::Bool(value) => Some(value.to_string()),
+                    Value::Number(value) | Value::String(value) => Some(value),
+                };
+            }
+        }
+        let record = fields.iter().take(4).map(|f| f.as_deref().unwrap_or(""));
+        let record = csv::StringRecord::from_iter(record);
+        let field = |i: usize| match fields[i].as_deref().map(str::trim) {
+            Some("") | None => Err((
+                error(Some(NAMES[i]), format!("missing field `{}`", NAMES[i])),
+                record.clone(),
+            )),
+            Some(field) => Ok(field),
+        };
+        let parse_error =
+            |i: usize, message: String| (error(Some(NAMES[i]), message), record.clone());
+        let kind = match field(0)? {
+            "deposit" => Tx::Deposit,
+            "withdrawal" => Tx::Withdrawal,
+            "dispute" => Tx::Dispute,
+            "resolve" => Tx::Resolve,
+            "chargeback" => Tx::Chargeback,
+            other => {
+                let message = format!(
+                    "unknown variant `{}`, expected one of `deposit`, `withdrawal`, `dispute`, `resolve`, `chargeback`",
+                    other
+                );
+                return Err(parse_error(0, message));
+            }
+        };
+        let client = field(1)?
+            .parse::<ClientID>()
+            .map_err(|e| parse_error(1, e.to_string()))?;
+        let tx = field(2)?
+            .parse::<TxID>()
+            .map_err(|e| parse_error(2, e.to_string()))?;
+        let amount = match field(3) {
+            Ok(amount) => {
+                let lenient = self.lenient_amounts.as_ref();
+                let normalized = lenient.map(|lenient| lenient.normalize(amount));
+                self.validate_amounts
+                    .check(normalized.as_deref().unwrap_or(amount))
+                    .map_err(|message| parse_error(3, message))?;
+                Some(Amount(
+                    normalized
+                        .as_deref()
+                        .unwrap_or(amount)
+                        .parse::<f64>()
+                        .map_err(|e| parse_error(3, e.to_string()))?,
+                ))
+            }
+            Err(_) => None,
+        };
+        let currency = field(4).ok().map(str::to_owned);
+        let timestamp = match field(5) {
+            Ok(ts) if self.check_order != OrderCheck::Off => {
+                Some(ts.parse().map_err(|message| parse_error(5, message))?)
+            }
+            _ => None,
+        };
+        Ok(Transaction(kind, client, tx, amount, currency, timestamp))
+    }
+}
+
+impl<R: BufRead> TransactionSource for NdjsonSource<R> {
+    fn next_row(&mut self) -> Option<(u64, Row)> {
+        loop {
+            if let Err(e) = self.read_line() {
+                let line = self.line + 1;
+                return Some((line, Err((EngineError::Io(e), csv::StringRecord::new()))));
+            }
+            if self.pending.is_empty() {
+                return None;
+            }
+            self.line += 1;
+            let pending = std::mem::take(&mut self.pending);
+            let row = match String::from_utf8(pending) {
+                Ok(text) if text.trim().is_empty() => continue,
+                Ok(text) => self.parse(&text),
+                Err(e) => {
+                    let error = EngineError::Parse {
+                        line: self.line,
+                        field: None,
+                        message: e.utf8_error().to_string(),
+                    };
+                    Err((error, csv::StringRecord::new()))
+                }
+            };
+            return Some((self.line, row));
+        }
+    }
+}
@@ -0,0 +1,597 @@
+//! # Storage
+//!
+//! Where the engine keeps client accounts and the history of transactions. A `HashMap` is a sane
+//! default, but other data-structures may perform better depending on the workload, e.g. a `Vec`
+//! indexed by ID when the ID space is small or dense (see `benches/stores.rs` to compare them).
+//! A history bounded in memory may spill its overflow to disk, see `DiskTxStore`.
+
+use crate::{Amount, ClientID, Ledger, Tx, TxID};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasherDefault, Hasher};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Data-structures a store can be backed by
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StoreKind {
+    #[default]
+    HashMap,
+    /// A `HashMap` with the much faster `FxHasher`, rather than the default SipHash
+    FxHashMap,
+    BTreeMap,
+    /// A `Vec` indexed by ID, with a slot for every ID up to the biggest one seen
+    Vec,
+}
+
+/// The hasher of `rustc` (FxHash): a rotate, a xor and a multiply per word, which is much faster
+/// than the default SipHash on small integer keys like client and transaction IDs. It isn't meant
+/// to resist HashDoS: a partner could craft IDs colliding on purpose, and slow processing down.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FxHasher(u64);
+
+impl FxHasher {
+    const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+    #[inline]
+    fn add(&mut self, word: u64) {
+        self.0 = (self.0.rotate_left(5) ^ word).wrapping_mul(Self::SEED);
+    }
+}
+
+impl Hasher for FxHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.add(u64::from_le_bytes(word));
+        }
+    }
+
+    #[inline]
+    fn write_u16(&mut self, n: u16) {
+        self.add(n.into());
+    }
+
+    #[inline]
+    fn write_u32(&mut self, n: u32) {
+        self.add(n.into());
+    }
+
+    #[inline]
+    fn write_u64(&mut self, n: u64) {
+        self.add(n);
+    }
+
+    #[inline]
+    fn write_usize(&mut self, n: usize) {
+        self.add(n as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+pub type FxBuildHasher = BuildHasherDefault<FxHasher>;
+
+/// What to do once a bounded history (see `Options::max_history`) is full
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HistoryOverflow {
+    /// Stop processing with `EngineError::HistoryOverflow`
+    Error,
+    /// Drop the least recently used transaction, disputes of it being rejected as unknown
+    #[default]
+    EvictOldest,
+    /// Move the least recently used transaction to a `DiskTxStore`, from where it's brought back
+    /// when referenced again
+    SpillDisk,
+}
+
+/// Client accounts, indexed by client ID
+#[derive(Clone, Debug)]
+pub enum AccountStore {
+    HashMap(HashMap<ClientID, Ledger>),
+    FxHashMap(HashMap<ClientID, Ledger, FxBuildHasher>),
+    BTreeMap(BTreeMap<ClientID, Ledger>),
+    Vec(Vec<Option<Ledger>>),
+}
+
+impl Default for AccountStore {
+    fn default() -> Self {
+        AccountStore::new(StoreKind::default())
+    }
+}
+
+impl AccountStore {
+    pub fn new(kind: StoreKind) -> AccountStore {
+        AccountStore::with_capacity(kind, 0)
+    }
+
+    /// A store with room for `capacity` accounts (only meaningful for a `HashMap`, a `BTreeMap`
+    /// doesn't pre-allocate and a `Vec` grows to fit the biggest client ID anyway)
+    pub fn with_capacity(kind: StoreKind, capacity: usize) -> AccountStore {
+        match kind {
+            StoreKind::HashMap => AccountStore::HashMap(HashMap::with_capacity(capacity)),
+            StoreKind::FxHashMap => AccountStore::FxHashMap(HashMap::with_capacity_and_hasher(
+                capacity,
+                FxBuildHasher::default(),
+            )),
+            StoreKind::BTreeMap => AccountStore::BTreeMap(BTreeMap::new()),
+            StoreKind::Vec => AccountStore::Vec(Vec::new()),
+        }
+    }
+
+    pub fn get(&self, client: ClientID) -> Option<&Ledger> {
+        match self {
+            AccountStore::HashMap(map) => map.get(&client),
+            AccountStore::FxHashMap(map) => map.get(&client),
+            AccountStore::BTreeMap(map) => map.get(&client),
+            AccountStore::Vec(vec) => vec.get(usize::from(client))?.as_ref(),
+        }
+    }
+
+    /// The ledger of `client`, created empty if the client doesn't exist yet
+    pub fn get_or_default(&mut self, client: ClientID) -> &mut Ledger {
+        match self {
+            AccountStore::HashMap(map) => map.entry(client).or_default(),
+            AccountStore::FxHashMap(map) => map.entry(client).or_default(),
+            AccountStore::BTreeMap(map) => map.entry(client).or_default(),
+            AccountStore::Vec(vec) => {
+                let index = usize::from(client);
+                if index >= vec.len() {
+                    vec.resize_with(index + 1, || None);
+                }
+                vec[index].get_or_insert_with(Ledger::default)
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            AccountStore::HashMap(map) => map.len(),
+            AccountStore::FxHashMap(map) => map.len(),
+            AccountStore::BTreeMap(map) => map.len(),
+            AccountStore::Vec(vec) => vec.iter().flatten().count(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of accounts the store can hold without reallocating
+    pub fn capacity(&self) -> usize {
+        match self {
+            AccountStore::HashMap(map) => map.capacity(),
+            AccountStore::FxHashMap(map) => map.capacity(),
+            AccountStore::BTreeMap(map) => map.len(),
+            AccountStore::Vec(vec) => vec.capacity(),
+        }
+    }
+
+    /// Turn the store into a `HashMap`, without copying accounts (nor moving them at all if it's
+    /// already backed by one)
+    pub fn into_map(self) -> HashMap<ClientID, Ledger> {
+        match self {
+            AccountStore::HashMap(map) => map,
+            AccountStore::FxHashMap(map) => map.into_iter().collect(),
+            AccountStore::BTreeMap(map) => map.into_iter().collect(),
+            AccountStore::Vec(vec) => vec
+                .into_iter()
+                .enumerate()
+                .filter_map(|(id, ledger)| Some((id as ClientID, ledger?)))
+                .collect(),
+        }
+    }
+
+    /// Iterate over accounts, in no particular order
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (ClientID, &Ledger)> + '_> {
+        match self {
+            AccountStore::HashMap(map) => Box::new(map.iter().map(|(id, ledger)| (*id, ledger))),
+            AccountStore::FxHashMap(map) => Box::new(map.iter().map(|(id, ledger)| (*id, ledger))),
+            AccountStore::BTreeMap(map) => Box::new(map.iter().map(|(id, ledger)| (*id, ledger))),
+            AccountStore::Vec(vec) => {
+                Box::new(vec.iter().enumerate().filter_map(|(id, ledger)| {
+                    // Only indexes that fit in a `ClientID` are ever filled
+                    Some((id as ClientID, ledger.as_ref()?))
+                }))
+            }
+        }
+    }
+}
+
+/// What is remembered of a deposit or a withdrawal, so that it can later be disputed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TxEntry {
+    /// Deposit or withdrawal
+    pub kind: Tx,
+    /// Client the transaction belongs to, the only one allowed to dispute it
+    pub client: ClientID,
+    pub amount: Amount,
+    /// Whether the transaction is currently under dispute
+    pub disputed: bool,
+}
+
+impl TxEntry {
+    pub fn new(kind: Tx, client: ClientID, amount: Amount) -> TxEntry {
+        TxEntry {
+            kind,
+            client,
+            amount,
+            disputed: false,
+        }
+    }
+}
+
+/// Why a transaction can't be recorded in history
+#[derive(Debug, PartialEq)]
+pub enum HistoryError {
+    /// History already holds a transaction with this ID
+    Duplicate(TxID),
+}
+
+impl std::fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HistoryError::Duplicate(tx) => write!(f, "transaction {} is already recorded", tx),
+        }
+    }
+}
+
+/// History of transactions, indexed by transaction ID
+#[derive(Clone, Debug)]
+pub enum TxHistory {
+    HashMap(HashMap<TxID, TxEntry>),
+    FxHashMap(HashMap<TxID, TxEntry, FxBuildHasher>),
+    BTreeMap(BTreeMap<TxID, TxEntry>),
+    Vec(Vec<Option<TxEntry>>),
+}
+
+impl Default for TxHistory {
+    fn default() -> Self {
+        TxHistory::new(StoreKind::default())
+    }
+}
+
+impl TxHistory {
+    pub fn new(kind: StoreKind) -> TxHistory {
+        TxHistory::with_capacity(kind, 0)
+    }
+
+    /// A store with room for `capacity` transactions (not meaningful for a `BTreeMap`)
+    pub fn with_capacity(kind: StoreKind, capacity: usize) -> TxHistory {
+        match kind {
+            StoreKind::HashMap => TxHistory::HashMap(HashMap::with_capacity(capacity)),
+            StoreKind::FxHashMap => TxHistory::FxHashMap(HashMap::with_capacity_and_hasher(
+                capacity,
+                FxBuildHasher::default(),
+            )),
+            StoreKind::BTreeMap => TxHistory::BTreeMap(BTreeMap::new()),
+            StoreKind::Vec => TxHistory::Vec(Vec::with_capacity(capacity)),
+        }
+    }
+
+    /// Record a new transaction, which can't replace one with the same ID (see `insert`)
+    pub fn record(&mut self, tx: TxID, entry: TxEntry) -> Result<(), HistoryError> {
+        if self.contains(tx) {
+            return Err(HistoryError::Duplicate(tx));
+        }
+        self.insert(tx, entry);
+        Ok(())
+    }
+
+    /// Put a transaction in history, replacing any with the same ID, e.g. when restoring it
+    pub fn insert(&mut self, tx: TxID, entry: TxEntry) {
+        match self {
+            TxHistory::HashMap(map) => {
+                map.insert(tx, entry);
+            }
+            TxHistory::FxHashMap(map) => {
+                map.insert(tx, entry);
+            }
+            TxHistory::BTreeMap(map) => {
+                map.insert(tx, entry);
+            }
+            TxHistory::Vec(vec) => {
+                let index = tx as usize;
+                if index >= vec.len() {
+                    vec.resize(index + 1, None);
+                }
+                vec[index] = Some(entry);
+            }
+        }
+    }
+
+    pub fn get(&self, tx: TxID) -> Option<&TxEntry> {
+        match self {
+            TxHistory::HashMap(map) => map.get(&tx),
+            TxHistory::FxHashMap(map) => map.get(&tx),
+            TxHistory::BTreeMap(map) => map.get(&tx),
+            TxHistory::Vec(vec) => vec.get(tx as usize)?.as_ref(),
+        }
+    }
+
+    pub fn get_mut(&mut self, tx: TxID) -> Option<&mut TxEntry> {
+        match self {
+            TxHistory::HashMap(map) => map.get_mut(&tx),
+            TxHistory::FxHashMap(map) => map.get_mut(&tx),
+            TxHistory::BTreeMap(map) => map.get_mut(&tx),
+            TxHistory::Vec(vec) => vec.get_mut(tx as usize)?.as_mut(),
+        }
+    }
+
+    pub fn contains(&self, tx: TxID) -> bool {
+        self.get(tx).is_some()
+    }
+
+    /// Iterate over transactions, in no particular order
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (TxID, &TxEntry)> + '_> {
+        match self {
+            TxHistory::HashMap(map) => Box::new(map.iter().map(|(id, entry)| (*id, entry))),
+            TxHistory::FxHashMap(map) => Box::new(map.iter().map(|(id, entry)| (*id, entry))),
+            TxHistory::BTreeMap(map) => Box::new(map.iter().map(|(id, entry)| (*id, entry))),
+            TxHistory::Vec(vec) => Box::new(vec.iter().enumerate().filter_map(|(id, entry)| {
+                // Only indexes that fit in a `TxID` are ever filled
+                Some((id as TxID, entry.as_ref()?))
+            })),
+        }
+    }
+
+    pub fn remove(&mut self, tx: TxID) -> Option<TxEntry> {
+        match self {
+            TxHistory::HashMap(map) => map.remove(&tx),
+            TxHistory::FxHashMap(map) => map.remove(&tx),
+            TxHistory::BTreeMap(map) => map.remove(&tx),
+            TxHistory::Vec(vec) => vec.get_mut(tx as usize)?.take(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            TxHistory::HashMap(map) => map.len(),
+            TxHistory::FxHashMap(map) => map.len(),
+            TxHistory::BTreeMap(map) => map.len(),
+            TxHistory::Vec(vec) => vec.iter().flatten().count(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of transactions the store can hold without reallocating
+    pub fn capacity(&self) -> usize {
+        match self {
+            TxHistory::HashMap(map) => map.capacity(),
+            TxHistory::FxHashMap(map) => map.capacity(),
+            TxHistory::BTreeMap(map) => map.len(),
+            TxHistory::Vec(vec) => vec.capacity(),
+        }
+    }
+}
+
+/// Recency of use of transactions, to evict the least recently used one from a bounded history.
+/// Every use is queued along with a timestamp; when popping the queue, uses older than the last
+/// one of their transaction are stale and skipped.
+#[derive(Clone, Debug, Default)]
+pub struct Lru {
+    clock: u64,
+    /// Last use of every tracked transaction
+    last_used: HashMap<TxID, u64>,
+    /// Uses, from the oldest to the most recent
+    uses: VecDeque<(TxID, u64)>,
+}
+
+impl Lru {
+    /// Number of tracked transactions
+    pub fn len(&self) -> usize {
+        self.last_used.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.last_used.is_empty()
+    }
+
+    /// Mark `tx` as the most recently used transaction
+    pub fn touch(&mut self, tx: TxID) {
+        self.clock += 1;
+        self.last_used.insert(tx, self.clock);
+        self.uses.push_back((tx, self.clock));
+        // Don't let stale uses pile up, e.g. when the same transactions are disputed over and over
+        if self.uses.len() > 2 * self.last_used.len() + 16 {
+            let last_used = &self.last_used;
+            self.uses.retain(|(tx, time)| last_used[tx] == *time);
+        }
+    }
+
+    /// Stop tracking `tx`, e.g. once it's removed from history (its queued uses become stale)
+    pub fn forget(&mut self, tx: TxID) {
+        self.last_used.remove(&tx);
+    }
+
+    /// Stop tracking the least recently used transaction, and return it
+    pub fn evict(&mut self) -> Option<TxID> {
+        while let Some((tx, time)) = self.uses.pop_front() {
+            if self.last_used.get(&tx) == Some(&time) {
+                self.last_used.remove(&tx);
+                return Some(tx);
+            }
+        }
+        None
+    }
+}
+
+/// Transactions spilled out of a bounded history, in a temporary file holding a fixed-size record
+/// at the offset of every transaction ID. The file is sparse, so the ID space only costs the disk
+/// space of the records actually written, and there is no index to keep in memory.
+#[derive(Debug)]
+pub struct DiskTxStore {
+    path: PathBuf,
+    file: File,
+    len: usize,
+}
+
+impl DiskTxStore {
+    /// Size of a record: presence flag, dispute flag, client ID (2 bytes), kind flag, padding,
+    /// amount
+    const RECORD: u64 = 16;
+
+    /// A store backed by a new file in the temporary directory, removed once dropped
+    pub fn create() -> io::Result<DiskTxStore> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "rust-coding-test-history-{}-{}.bin",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = std::env::temp_dir().join(name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(DiskTxStore { path, file, len: 0 })
+    }
+
+    /// A copy of the store in a new file, since both stores then evolve separately
+    pub fn try_clone(&self) -> io::Result<DiskTxStore> {
+        let mut clone = DiskTxStore::create()?;
+        std::fs::copy(&self.path, &clone.path)?;
+        clone.len = self.len;
+        Ok(clone)
+    }
+
+    fn read(&mut self, tx: TxID) -> io::Result<Option<TxEntry>> {
+        let offset = tx as u64 * Self::RECORD;
+        // Past the end of the file is a hole as well
+        if offset >= self.file.metadata()?.len() {
+            return Ok(None);
+        }
+        let mut record = [0; Self::RECORD as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut record)?;
+        Ok(Self::decode(&record))
+    }
+
+    /// Every transaction of the store, in ID order (the one of the file), read as the iterator
+    /// goes rather than loaded at once
+    pub fn entries(&self) -> io::Result<impl Iterator<Item = io::Result<(TxID, TxEntry)>> + '_> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        let mut rdr = io::BufReader::new(file);
+        let mut ids = 0..=TxID::MAX;
+        Ok(std::iter::from_fn(move || loop {
+            let mut record = [0; Self::RECORD as usize];
+            match rdr.read_exact(&mut record) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+                Err(e) => return Some(Err(e)),
+            }
+            let id = ids.next()?;
+            if let Some(entry) = Self::decode(&record) {
+                return Some(Ok((id, entry)));
+            }
+        }))
+    }
+
+    /// The transaction of a record, or nothing if it's a hole
+    fn decode(record: &[u8; Self::RECORD as usize]) -> Option<TxEntry> {
+        if record[0] == 0 {
+            return None;
+        }
+        let client = ClientID::from_le_bytes([record[2], record[3]]);
+        let amount = f64::from_le_bytes(record[8..].try_into().expect("8 bytes"));
+        Some(TxEntry {
+            kind: match record[4] {
+                0 => Tx::Deposit,
+                _ => Tx::Withdrawal,
+            },
+            client,
+            amount: Amount(amount),
+            disputed: record[1] != 0,
+        })
+    }
+
+    fn write(&mut self, tx: TxID, entry: Option<&TxEntry>) -> io::Result<()> {
+        let mut record = [0; Self::RECORD as usize];
+        if let Some(entry) = entry {
+            record[0] = 1;
+            record[1] = entry.disputed as u8;
+            record[2..4].copy_from_slice(&entry.client.to_le_bytes());
+            record[4] = (entry.kind != Tx::Deposit) as u8;
+            record[8..].copy_from_slice(&entry.amount.0.to_le_bytes());
+        }
+        self.file.seek(SeekFrom::Start(tx as u64 * Self::RECORD))?;
+        self.file.write_all(&record)
+    }
+
+    pub fn insert(&mut self, tx: TxID, entry: TxEntry) -> io::Result<()> {
+        if self.read(tx)?.is_none() {
+            self.len += 1;
+        }
+        self.write(tx, Some(&entry))
+    }
+
+    pub fn remove(&mut self, tx: TxID) -> io::Result<Option<TxEntry>> {
+        let entry = self.read(tx)?;
+        if entry.is_some() {
+            self.write(tx, None)?;
+            self.len -= 1;
+        }
+        Ok(entry)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Drop for DiskTxStore {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[test]
+fn history() {
+    let kinds = [
+        StoreKind::HashMap,
+        StoreKind::FxHashMap,
+        StoreKind::BTreeMap,
+        StoreKind::Vec,
+    ];
+    for kind in kinds {
+        let mut history = TxHistory::new(kind);
+        let deposit = TxEntry::new(Tx::Deposit, 1, Amount(1.0));
+        assert_eq!(history.get(3), None);
+        history.record(3, deposit).unwrap();
+        assert_eq!(history.get(3), Some(&deposit));
+        // A recorded transaction is never replaced, unlike an inserted one
+        let withdrawal = TxEntry::new(Tx::Withdrawal, 2, Amount(2.0));
+        assert_eq!(
+            history.record(3, withdrawal),
+            Err(HistoryError::Duplicate(3))
+        );
+        assert_eq!(history.get(3), Some(&deposit));
+        history.insert(3, withdrawal);
+        assert_eq!(history.get(3), Some(&withdrawal));
+        assert_eq!((history.get(2), history.len()), (None, 1));
+    }
+}
+
+#[test]
+fn disk_store_copy() {
+    let mut store = DiskTxStore::create().unwrap();
+    let deposit = TxEntry::new(Tx::Deposit, 1, Amount(1.0));
+    store.insert(3, deposit).unwrap();
+    let mut copy = store.try_clone().unwrap();
+    assert_eq!(copy.remove(3).unwrap(), Some(deposit));
+    assert_eq!((copy.len(), store.len()), (0, 1));
+    assert_eq!(store.remove(3).unwrap(), Some(deposit));
+}
@@ -0,0 +1,184 @@
+//! # Timestamps
+//!
+//! Some partners add a `ts` column of ISO-8601 timestamps to their exports. Accounts never depend
+//! on it, but it's a way to check the assumption of the specification that rows are in
+//! chronological order (see `Options::check_order`). Neither `chrono` nor `time` being at hand,
+//! the few formats partners use are parsed by hand: `2024-01-31`, `2024-01-31T12:00:00` with
+//! optional fractional seconds, and an optional `Z` or `±HH:MM` offset (UTC being assumed
+//! otherwise).
+
+/// What to do with a row timestamped before the previous one
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OrderCheck {
+    /// Don't even parse timestamps
+    #[default]
+    Off,
+    /// Report the row, and apply it anyway
+    Warn,
+    /// Fail with `EngineError::OutOfOrderTimestamp`
+    Error,
+}
+
+/// An instant, as seconds and nanoseconds since the Unix epoch, in UTC
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    secs: i64,
+    nanos: u32,
+}
+
+impl std::str::FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("`{}` isn't an ISO-8601 timestamp", s);
+        let (date, time) = match s.split_once(['T', 't', ' ']) {
+            Some((date, time)) => (date, Some(time)),
+            None => (s, None),
+        };
+        let mut date = date.splitn(3, '-');
+        let mut next = |digits| number(date.next(), digits).ok_or_else(invalid);
+        let (year, month, day) = (next(4)?, next(2)?, next(2)?);
+        if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+            return Err(invalid());
+        }
+        let mut secs = days_from_civil(year, month, day) * 86_400;
+        let mut nanos = 0;
+        if let Some(time) = time {
+            // The offset is what follows the seconds, if anything
+            let (time, offset) = match time.find(['Z', 'z', '+', '-']) {
+                Some(i) => time.split_at(i),
+                None => (time, ""),
+            };
+            let (time, fraction) = match time.split_once('.') {
+                Some((time, fraction)) => (time, Some(fraction)),
+                None => (time, None),
+            };
+            let mut time = time.splitn(3, ':');
+            let mut next = |digits| number(time.next(), digits).ok_or_else(invalid);
+            let (hours, minutes, seconds) = (next(2)?, next(2)?, next(2)?);
+            if hours > 23 || minutes > 59 || seconds > 60 {
+                return Err(invalid());
+            }
+            secs += hours * 3600 + minutes * 60 + seconds;
+            if let Some(fraction) = fraction {
+                if fraction.is_empty() || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(invalid());
+                }
+                // Beyond nanoseconds, digits are dropped
+                let digits = &fraction[..fraction.len().min(9)];
+                let scale = 10u32.pow(9 - digits.len() as u32);
+                nanos = digits.parse::<u32>().map_err(|_| invalid())? * scale;
+            }
+            secs -= match offset {
+                "" | "Z" | "z" => 0,
+                _ => {
+                    let sign = if offset.starts_with('-') { -1 } else { 1 };
+                    let mut offset = offset[1..].splitn(2, ':');
+                    let mut next = |digits| number(offset.next(), digits).ok_or_else(invalid);
+                    let (hours, minutes) = (next(2)?, next(2)?);
+                    if hours > 23 || minutes > 59 {
+                        return Err(invalid());
+                    }
+                    sign * (hours * 3600 + minutes * 60)
+                }
+            };
+        }
+        Ok(Timestamp { secs, nanos })
+    }
+}
+
+/// Written back in UTC, e.g. `2024-01-31T11:00:00Z`, whatever the offset it was parsed with
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (year, month, day) = civil_from_days(self.secs.div_euclid(86_400));
+        let secs = self.secs.rem_euclid(86_400);
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )?;
+        if self.nanos > 0 {
+            let fraction = format!("{:09}", self.nanos);
+            write!(f, ".{}", fraction.trim_end_matches('0'))?;
+        }
+        write!(f, "Z")
+    }
+}
+
+/// A field of exactly `digits` digits
+fn number(field: Option<&str>, digits: usize) -> Option<i64> {
+    let field = field?;
+    if field.len() != digits || !field.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    field.parse().ok()
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since the Unix epoch of a date of the proleptic Gregorian calendar, after
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The other way around, after
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[test]
+fn parse() {
+    let parse = |s: &str| s.parse::<Timestamp>().map(|ts| ts.to_string());
+    assert_eq!(parse("1970-01-01"), Ok("1970-01-01T00:00:00Z".to_owned()));
+    assert_eq!(
+        parse("2024-02-29T23:59:60"),
+        Ok("2024-03-01T00:00:00Z".to_owned())
+    );
+    assert_eq!(
+        parse("2024-01-31T12:30:00.250+01:30"),
+        Ok("2024-01-31T11:00:00.25Z".to_owned())
+    );
+    assert_eq!(
+        parse("1969-12-31 23:00:00-01:00"),
+        Ok("1970-01-01T00:00:00Z".to_owned())
+    );
+    assert!(parse("2023-02-29").is_err());
+    assert!(parse("2024-1-31").is_err());
+    assert!(parse("2024-01-31T24:00:00").is_err());
+    assert!(parse("2024-01-31T12:00:00+1").is_err());
+    assert!(parse("yesterday").is_err());
+    assert!("2024-01-31T12:00:00Z".parse::<Timestamp>() > "2024-01-31T12:00:00+01:00".parse());
+}
@@ -0,0 +1,226 @@
+//! # Validation
+//!
+//! Semantic checks of a whole input, run before the engine processes anything, so that a partner
+//! gets every problem of a file at once rather than one rejected transaction at a time.
+
+use crate::source::TransactionSource;
+use crate::{Amount, ClientID, EngineError, Transaction, Tx, TxID};
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::ops::RangeInclusive;
+
+/// Ways a transaction can be invalid, regardless of the state of accounts
+#[derive(Debug, PartialEq)]
+pub enum ValidationError {
+    /// A row that couldn't be parsed as a transaction (e.g. of an unknown type), as reported by
+    /// the engine
+    Malformed {
+        field: Option<&'static str>,
+        message: String,
+    },
+    /// A deposit or a withdrawal without an amount
+    MissingAmount,
+    /// A deposit or a withdrawal of a negative or null amount
+    NonPositiveAmount(Amount),
+    /// An infinite or NaN amount, which `f64` reads from `inf` or `NaN`
+    NonFiniteAmount(Amount),
+    /// An amount with more places past the decimal than the given number
+    TooPrecise(Amount, u8),
+    /// A dispute, resolve or chargeback specifying an amount
+    UnexpectedAmount(Amount),
+    ClientOutOfRange(ClientID),
+    TxOutOfRange(TxID),
+    /// A dispute, resolve or chargeback referencing a transaction that doesn't appear earlier
+    UnknownReference(TxID),
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::Malformed {
+                field: Some(field),
+                message,
+            } => write!(f, "malformed `{}`: {}", field, message),
+            ValidationError::Malformed {
+                field: None,
+                message,
+            } => write!(f, "malformed row: {}", message),
+            ValidationError::MissingAmount => write!(f, "missing amount"),
+            ValidationError::NonPositiveAmount(amount) => {
+                write!(f, "amount {} is not positive", amount)
+            }
+            ValidationError::NonFiniteAmount(amount) => {
+                write!(f, "amount {} is not finite", amount.0)
+            }
+            ValidationError::TooPrecise(amount, places) => {
+                // Not rounded by `Amount`'s formatting, which would hide the extra places
+                write!(
+                    f,
+                    "amount {} has more than {} places past the decimal",
+                    amount.0, places
+                )
+            }
+            ValidationError::UnexpectedAmount(amount) => write!(f, "unexpected amount {}", amount),
+            ValidationError::ClientOutOfRange(client) => {
+                write!(f, "client {} is out of range", client)
+            }
+            ValidationError::TxOutOfRange(tx) => write!(f, "transaction {} is out of range", tx),
+            ValidationError::UnknownReference(tx) => {
+                write!(f, "transaction {} doesn't appear earlier", tx)
+            }
+        }
+    }
+}
+
+/// Checks every transaction of an input, in a single pass
+#[derive(Clone, Debug)]
+pub struct InputValidator {
+    /// Valid client IDs, all of them by default
+    pub clients: RangeInclusive<ClientID>,
+    /// Valid transaction IDs, all of them by default
+    pub txs: RangeInclusive<TxID>,
+    /// Places past the decimal amounts may have, 4 by default as in the specification
+    pub places: u8,
+}
+
+impl Default for InputValidator {
+    fn default() -> Self {
+        InputValidator {
+            clients: ClientID::MIN..=ClientID::MAX,
+            txs: TxID::MIN..=TxID::MAX,
+            places: 4,
+        }
+    }
+}
+
+impl InputValidator {
+    /// Every error found in `transactions`, along with the index of the offending transaction (a
+    /// transaction may have several errors)
+    pub fn validate<I>(&self, transactions: I) -> Vec<(usize, ValidationError)>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<Transaction>,
+    {
+        let mut errors = Vec::new();
+        // IDs of the deposits and withdrawals seen so far
+        let mut seen = HashSet::new();
+        for (index, tx) in transactions.into_iter().enumerate() {
+            self.check(tx.borrow(), &mut seen, &mut |error| {
+                errors.push((index, error))
+            });
+        }
+        errors
+    }
+
+    /// Every error found in the rows of `source`, along with their line number, without applying
+    /// anything: rows the engine can't parse are reported as `ValidationError::Malformed`
+    pub fn validate_source(
+        &self,
+        source: &mut dyn TransactionSource,
+    ) -> Vec<(u64, ValidationError)> {
+        let mut errors = Vec::new();
+        let mut seen = HashSet::new();
+        while let Some((line, row)) = source.next_row() {
+            match row {
+                Ok(tx) => self.check(&tx, &mut seen, &mut |error| errors.push((line, error))),
+                Err((EngineError::Parse { field, message, .. }, _)) => {
+                    errors.push((line, ValidationError::Malformed { field, message }))
+                }
+                Err((e, _)) => errors.push((
+                    line,
+                    ValidationError::Malformed {
+                        field: None,
+                        message: e.to_string(),
+                    },
+                )),
+            }
+        }
+        errors
+    }
+
+    /// Report every error of `tx` to `error`, given the IDs of the deposits and withdrawals seen
+    /// before it
+    fn check(
+        &self,
+        tx: &Transaction,
+        seen: &mut HashSet<TxID>,
+        error: &mut dyn FnMut(ValidationError),
+    ) {
+        let Transaction(kind, client, id, amount, ..) = tx;
+        if !self.clients.contains(client) {
+            error(ValidationError::ClientOutOfRange(*client));
+        }
+        if !self.txs.contains(id) {
+            error(ValidationError::TxOutOfRange(*id));
+        }
+        let finite = amount.is_none_or(|amount| amount.0.is_finite());
+        if let Some(amount) = amount.filter(|_| !finite) {
+            error(ValidationError::NonFiniteAmount(amount));
+        }
+        // Rounding an amount with few enough places gives it back exactly, as both are the
+        // closest `f64` to the same decimal
+        let too_precise = |amount: &Amount| amount.round(self.places).0 != amount.0;
+        if let Some(amount) = amount.filter(|amount| finite && too_precise(amount)) {
+            error(ValidationError::TooPrecise(amount, self.places));
+        }
+        match (kind, amount) {
+            (Tx::Deposit | Tx::Withdrawal, None) => error(ValidationError::MissingAmount),
+            // A non-finite amount is reported as such already
+            (Tx::Deposit | Tx::Withdrawal, Some(amount)) if finite && *amount <= Amount(0.0) => {
+                error(ValidationError::NonPositiveAmount(*amount))
+            }
+            (Tx::Deposit | Tx::Withdrawal, Some(_)) => {
+                seen.insert(*id);
+            }
+            (_, Some(amount)) => error(ValidationError::UnexpectedAmount(*amount)),
+            (_, None) if !seen.contains(id) => error(ValidationError::UnknownReference(*id)),
+            (_, None) => {}
+        }
+    }
+}
+
+#[test]
+fn validate() {
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,2,2,
+deposit,1,3,-1.0
+chargeback,1,4,
+deposit,1,4,1.0
+dispute,1,1,1.0
+deposit,0,0,2.0
+deposit,1,5,1.23456
+deposit,1,6,inf
+withdrawal,1,7,NaN
+deposit,1,8,-inf
+";
+    let transactions = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(INPUT.as_bytes())
+        .deserialize()
+        .collect::<Result<Vec<Transaction>, _>>()
+        .unwrap();
+    let validator = InputValidator {
+        clients: 1..=ClientID::MAX,
+        txs: 1..=TxID::MAX,
+        ..InputValidator::default()
+    };
+    assert_eq!(
+        validator.validate(&transactions),
+        [
+            (1, ValidationError::MissingAmount),
+            (2, ValidationError::NonPositiveAmount(Amount(-1.0))),
+            (3, ValidationError::UnknownReference(4)),
+            (5, ValidationError::UnexpectedAmount(Amount(1.0))),
+            (6, ValidationError::ClientOutOfRange(0)),
+            (6, ValidationError::TxOutOfRange(0)),
+            (7, ValidationError::TooPrecise(Amount(1.23456), 4)),
+            (8, ValidationError::NonFiniteAmount(Amount(f64::INFINITY))),
+            (9, ValidationError::NonFiniteAmount(Amount(f64::NAN))),
+            (
+                10,
+                ValidationError::NonFiniteAmount(Amount(f64::NEG_INFINITY))
+            ),
+        ]
+    );
+}
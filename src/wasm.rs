@@ -0,0 +1,64 @@
+//! # In-browser entry point
+//!
+//! A pure `&str -> String` function, with no dependency on stdin, stdout or the filesystem, so the
+//! engine could be compiled to `wasm32-unknown-unknown` and run client-side: sensitive transaction
+//! data never has to leave the browser.
+//!
+//! It's a plain Rust function, which JavaScript can't call as it is: that takes a
+//! `#[wasm_bindgen]` wrapper, which the `wasm` feature doesn't provide.
+//!
+//! Nothing builds the crate for `wasm32-unknown-unknown`, so it's by hand that the path this
+//! function takes through the engine avoids what that target lacks: threads (see
+//! `Engine::process_pipelined`), the filesystem (see `HistoryOverflow::SpillDisk`), or the clock,
+//! since `std::time::Instant::now` panics there (which is why it doesn't go through `process`).
+
+use crate::{json, Engine};
+
+/// Run the engine over `input` CSV transactions, returning the accounts CSV, or a JSON object of
+/// the shape `{"error": "..."}` if the input couldn't be processed
+pub fn process_csv(input: &str) -> String {
+    let mut engine = Engine::default();
+    let mut output = Vec::new();
+    let result = engine
+        .process_from_str(input)
+        .and_then(|_| engine.write_accounts_csv(&mut output));
+    match result {
+        // The CSV writer only ever writes back strings that were valid UTF-8
+        Ok(()) => String::from_utf8_lossy(&output).into_owned(),
+        Err(e) => json::Object::new()
+            .string("error", Some(&e.to_string()))
+            .finish(),
+    }
+}
+
+// Relies on rejected transactions being skipped
+#[cfg(not(feature = "strict_mode"))]
+#[test]
+fn process_csv_example() {
+    const INPUT: &str = "type,  client, tx, amount
+deposit,    1,  1,    1.0
+deposit,    2,  2,    2.0
+deposit,    1,  3,    2.0
+withdrawal, 1,  4,    1.5
+withdrawal, 2,  5,    3.0
+";
+    let output = process_csv(INPUT);
+    // Row ordering does not matter (unless the `sorted` feature is enabled)
+    let mut lines = output.lines().collect::<Vec<_>>();
+    lines[1..].sort_unstable();
+    assert_eq!(
+        lines,
+        [
+            "client,available,held,total,locked",
+            "1,1.5000,0.0000,1.5000,false",
+            "2,2.0000,0.0000,2.0000,false"
+        ]
+    );
+}
+
+#[test]
+fn process_csv_error() {
+    let output = process_csv("type,client,tx,amount\nteleport,1,1,1.0\n");
+    assert!(output.starts_with("{\"error\":\""), "{}", output);
+    assert!(output.ends_with("\"}"), "{}", output);
+}
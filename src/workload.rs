@@ -0,0 +1,89 @@
+//! # Synthetic workloads
+//!
+//! Deterministic generation of large transaction inputs, shared by the benchmarks (see `benches/`)
+//! and the tests, so that e.g. every store can be checked to produce the very same accounts.
+
+use std::fmt::Write;
+
+/// A small, fast and deterministic pseudo-random number generator (SplitMix64), good enough to
+/// generate test data but not meant for anything else
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// `true` with the given `probability`
+    pub fn chance(&mut self, probability: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+/// Parameters of a synthetic workload
+#[derive(Clone, Debug)]
+pub struct Workload {
+    /// Number of rows to generate
+    pub rows: usize,
+    /// Number of distinct clients, from `1` to `clients`
+    pub clients: u16,
+    /// Share of the rows that are disputes, resolves or chargebacks
+    pub dispute_ratio: f64,
+    pub seed: u64,
+}
+
+impl Workload {
+    /// Generate the workload as CSV: deposits and withdrawals (about 3 to 1) of up to 100.0000,
+    /// disputes of past deposits, and then resolves (or chargebacks, 1 time out of 10) of them
+    pub fn generate(&self) -> String {
+        let mut rng = Rng::new(self.seed);
+        let mut csv = String::with_capacity(self.rows * 24 + 32);
+        csv.push_str("type,client,tx,amount\n");
+        let mut deposits = Vec::new();
+        let mut disputes = Vec::new();
+        let mut tx = 0u32;
+        for _ in 0..self.rows {
+            if !deposits.is_empty() && rng.chance(self.dispute_ratio) {
+                if !disputes.is_empty() && rng.chance(0.5) {
+                    let (client, id) =
+                        disputes.swap_remove(rng.below(disputes.len() as u64) as usize);
+                    let kind = if rng.chance(0.1) {
+                        "chargeback"
+                    } else {
+                        "resolve"
+                    };
+                    let _ = writeln!(csv, "{},{},{},", kind, client, id);
+                } else {
+                    let (client, id) = deposits[rng.below(deposits.len() as u64) as usize];
+                    disputes.push((client, id));
+                    let _ = writeln!(csv, "dispute,{},{},", client, id);
+                }
+                continue;
+            }
+            tx += 1;
+            let client = 1 + rng.below(u64::from(self.clients)) as u16;
+            let amount = rng.below(1_000_000) as f64 / 10_000.0;
+            if rng.chance(0.75) {
+                deposits.push((client, tx));
+                let _ = writeln!(csv, "deposit,{},{},{:.4}", client, tx, amount);
+            } else {
+                let _ = writeln!(csv, "withdrawal,{},{},{:.4}", client, tx, amount);
+            }
+        }
+        csv
+    }
+}
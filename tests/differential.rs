@@ -0,0 +1,281 @@
+//! Differential testing of the engine against a naive reference model: random sequences of
+//! transactions (across a handful of clients, with disputes, resolves and chargebacks referencing
+//! both existing and nonexistent transactions) are processed by both, and the final accounts must
+//! be equal. A failing sequence is shrunk, by removing rows one at a time as long as it keeps
+//! failing, before being reported. The invariants of accounts (e.g. held funds are never negative)
+//! are checked after every row of such sequences too.
+//!
+//! The number of cases can be raised with e.g. `DIFFERENTIAL_CASES=100000 cargo test`.
+
+use rust_coding_test::workload::Rng;
+use rust_coding_test::{Amount, Engine, Options, Tx};
+use std::collections::BTreeMap;
+
+const CLIENTS: u64 = 4;
+const MAX_ROWS: u64 = 40;
+
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Row {
+    kind: Kind,
+    client: u16,
+    tx: u32,
+    /// In quarters: these are exactly representable as `f64`, so the engine's floating-point
+    /// arithmetic can be compared exactly with the model's integer one
+    quarters: i64,
+}
+
+fn to_csv(rows: &[Row]) -> String {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for row in rows {
+        let kind = format!("{:?}", row.kind).to_lowercase();
+        let amount = match row.kind {
+            Kind::Deposit | Kind::Withdrawal => (row.quarters as f64 / 4.0).to_string(),
+            _ => String::new(),
+        };
+        csv.push_str(&format!("{},{},{},{}\n", kind, row.client, row.tx, amount));
+    }
+    csv
+}
+
+fn generate(rng: &mut Rng) -> Vec<Row> {
+    let mut rows = Vec::new();
+    let mut next_tx = 1;
+    for _ in 0..rng.below(MAX_ROWS) + 1 {
+        let kind = match rng.below(10) {
+            0..=3 => Kind::Deposit,
+            4..=5 => Kind::Withdrawal,
+            6..=7 => Kind::Dispute,
+            8 => Kind::Resolve,
+            _ => Kind::Chargeback,
+        };
+        let tx = match kind {
+            // Mostly fresh IDs, but sometimes a replayed one
+            Kind::Deposit | Kind::Withdrawal if rng.chance(0.9) => {
+                next_tx += 1;
+                next_tx - 1
+            }
+            // Past transactions, or a few that don't exist
+            _ => 1 + rng.below(u64::from(next_tx) + 2) as u32,
+        };
+        rows.push(Row {
+            kind,
+            client: 1 + rng.below(CLIENTS) as u16,
+            tx,
+            quarters: 1 + rng.below(400) as i64,
+        });
+    }
+    rows
+}
+
+/// Final state of an account, in quarters
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Account {
+    available: i64,
+    held: i64,
+    locked: bool,
+}
+
+/// The obviously-correct model: every transaction is kept in a list with its dispute state, and
+/// held funds are recomputed from scratch as the sum of the ones currently under dispute
+fn model(rows: &[Row]) -> BTreeMap<u16, Account> {
+    #[derive(PartialEq)]
+    enum State {
+        Settled,
+        Disputed,
+        ChargedBack,
+    }
+    let mut accounts = BTreeMap::<u16, Account>::new();
+    let mut history: Vec<(u32, u16, i64, State)> = Vec::new();
+    for row in rows {
+        // Every deposit or withdrawal opens an account, even a rejected one, but a client without
+        // one has nothing to dispute
+        if !matches!(row.kind, Kind::Deposit | Kind::Withdrawal)
+            && !accounts.contains_key(&row.client)
+        {
+            continue;
+        }
+        let account = *accounts.entry(row.client).or_default();
+        // Only resolving a pending dispute gets an account out of being locked
+        if account.locked && !matches!(row.kind, Kind::Resolve) {
+            continue;
+        }
+        let amount = row.quarters;
+        let found = history
+            .iter()
+            .position(|(tx, client, _, _)| *tx == row.tx && *client == row.client);
+        match row.kind {
+            Kind::Deposit | Kind::Withdrawal if history.iter().any(|h| h.0 == row.tx) => {}
+            Kind::Deposit => history.push((row.tx, row.client, amount, State::Settled)),
+            Kind::Withdrawal => {
+                if amount <= balance(&accounts, &history, row.client).available {
+                    history.push((row.tx, row.client, -amount, State::Settled));
+                }
+            }
+            Kind::Dispute => match found {
+                Some(i) if history[i].3 == State::Settled => history[i].3 = State::Disputed,
+                _ => {}
+            },
+            Kind::Resolve | Kind::Chargeback => match found {
+                Some(i) if history[i].3 == State::Disputed => {
+                    if let Kind::Chargeback = row.kind {
+                        history[i].3 = State::ChargedBack;
+                        accounts.get_mut(&row.client).unwrap().locked = true;
+                    } else {
+                        history[i].3 = State::Settled;
+                        accounts.get_mut(&row.client).unwrap().locked = false;
+                    }
+                }
+                _ => {}
+            },
+        }
+    }
+
+    /// Balance of a client out of its transactions: a disputed one is held (withdrawals too, by
+    /// their absolute amount, like the engine does) and a charged back one is gone
+    fn balance(
+        accounts: &BTreeMap<u16, Account>,
+        history: &[(u32, u16, i64, State)],
+        client: u16,
+    ) -> Account {
+        let mut account = Account {
+            locked: accounts[&client].locked,
+            ..Account::default()
+        };
+        for (_, _, amount, state) in history.iter().filter(|h| h.1 == client) {
+            match state {
+                State::Settled => account.available += amount,
+                State::Disputed if *amount < 0 => {
+                    account.available += 2 * amount;
+                    account.held -= amount;
+                }
+                State::Disputed => account.held += amount,
+                State::ChargedBack if *amount < 0 => account.available += 2 * amount,
+                State::ChargedBack => {}
+            }
+        }
+        account
+    }
+
+    let clients = accounts.keys().copied().collect::<Vec<_>>();
+    for client in clients {
+        let account = balance(&accounts, &history, client);
+        accounts.insert(client, account);
+    }
+    accounts
+}
+
+fn engine(rows: &[Row]) -> BTreeMap<u16, Account> {
+    // Rejected transactions are skipped, even with the `strict_mode` feature, and the model lets
+    // withdrawals be disputed too
+    let mut engine = Engine::new(Options {
+        strict: false,
+        disputable: vec![Tx::Deposit, Tx::Withdrawal],
+        ..Options::default()
+    });
+    engine.process_from_str(&to_csv(rows)).unwrap();
+    let mut output = Vec::new();
+    engine.write_accounts_csv(&mut output).unwrap();
+    let mut rdr = csv::Reader::from_reader(output.as_slice());
+    rdr.deserialize::<(u16, f64, f64, f64, bool)>()
+        .map(|record| {
+            let (client, available, held, _, locked) = record.unwrap();
+            let quarters = |amount: f64| (amount * 4.0) as i64;
+            let account = Account {
+                available: quarters(available),
+                held: quarters(held),
+                locked,
+            };
+            (client, account)
+        })
+        .collect()
+}
+
+/// Remove rows, one at a time, as long as the sequence keeps failing
+fn shrink(mut rows: Vec<Row>) -> Vec<Row> {
+    let mut i = 0;
+    while i < rows.len() {
+        let mut smaller = rows.clone();
+        smaller.remove(i);
+        if engine(&smaller) != model(&smaller) {
+            rows = smaller;
+        } else {
+            i += 1;
+        }
+    }
+    rows
+}
+
+/// Whether the funds of every account are as they should be after any sequence of transactions:
+/// held funds are never negative, and neither are available ones unless a dispute (of funds
+/// already withdrawn) or a chargeback took them below zero
+fn check_invariants(engine: &Engine) -> Result<(), String> {
+    let disputed = engine
+        .open_disputes()
+        .iter()
+        .map(|open| open.client)
+        .collect::<Vec<_>>();
+    for (client, ledger) in engine.accounts().iter() {
+        let may_be_negative = disputed.contains(&client) || ledger.chargeback_count > 0;
+        if ledger.held < Amount::ZERO || (ledger.available < Amount::ZERO && !may_be_negative) {
+            return Err(format!("client {}: {:?}", client, ledger));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn invariants() {
+    let cases = std::env::var("DIFFERENTIAL_CASES")
+        .ok()
+        .and_then(|cases| cases.parse().ok())
+        .unwrap_or(500);
+    let mut rng = Rng::new(0x1a7a);
+    for _ in 0..cases {
+        let rows = generate(&mut rng);
+        let mut engine = Engine::new(Options {
+            strict: false,
+            disputable: vec![Tx::Deposit, Tx::Withdrawal],
+            ..Options::default()
+        });
+        for (i, row) in rows.iter().enumerate() {
+            engine.process_from_str(&to_csv(&[*row])).unwrap();
+            if let Err(account) = check_invariants(&engine) {
+                panic!(
+                    "invariants broken by:\n{}\n{}",
+                    to_csv(&rows[..=i]),
+                    account
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn engine_matches_model() {
+    let cases = std::env::var("DIFFERENTIAL_CASES")
+        .ok()
+        .and_then(|cases| cases.parse().ok())
+        .unwrap_or(2_000);
+    let mut rng = Rng::new(0x5eed);
+    for _ in 0..cases {
+        let rows = generate(&mut rng);
+        if engine(&rows) != model(&rows) {
+            let rows = shrink(rows);
+            panic!(
+                "engine and model disagree on:\n{}\nengine: {:?}\nmodel:  {:?}",
+                to_csv(&rows),
+                engine(&rows),
+                model(&rows)
+            );
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Golden-file tests: every `tests/data/NAME.in.csv` is processed by the engine, and the accounts
+//! it outputs must match `tests/data/NAME.expected.csv`. Rows are compared regardless of their
+//! order, and amounts by value (so that e.g. `1.5` matches `1.5000`), so a scenario is added by
+//! just dropping these two files in the directory.
+
+use rust_coding_test::{Engine, Options};
+use std::path::Path;
+
+/// Amounts are `f64`, summing thousands of them can't be expected to be exact
+const TOLERANCE: f64 = 1e-6;
+
+/// Header and rows of a CSV, rows being sorted so that their order doesn't matter
+fn records(csv: &[u8]) -> (csv::StringRecord, Vec<csv::StringRecord>) {
+    let mut rdr = csv::Reader::from_reader(csv);
+    let header = rdr.headers().unwrap().clone();
+    let mut rows = rdr.records().collect::<Result<Vec<_>, _>>().unwrap();
+    rows.sort_by(|a, b| a.iter().cmp(b.iter()));
+    (header, rows)
+}
+
+/// Fields are equal as numbers if they both parse as such, or else as strings
+fn same_field(a: &str, b: &str) -> bool {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => (a - b).abs() <= TOLERANCE,
+        _ => a == b,
+    }
+}
+
+/// Tell how the output of the engine differs from the expected one, if it does
+fn check(input: &Path, expected: &Path) -> Result<(), String> {
+    // Rejected transactions are skipped, even with the `strict_mode` feature
+    let mut engine = Engine::new(Options {
+        strict: false,
+        ..Options::default()
+    });
+    engine.process_from_file(input).map_err(|e| e.to_string())?;
+    let mut output = Vec::new();
+    engine.write_accounts_csv(&mut output).unwrap();
+    let expected = std::fs::read(expected).map_err(|e| e.to_string())?;
+    let (header, rows) = records(&output);
+    let (expected_header, expected_rows) = records(&expected);
+    if header != expected_header {
+        return Err(format!(
+            "header {:?}, expected {:?}",
+            header, expected_header
+        ));
+    }
+    if rows.len() != expected_rows.len() {
+        let (n, expected) = (rows.len(), expected_rows.len());
+        return Err(format!("{} accounts, expected {}", n, expected));
+    }
+    // Rows are sorted by client ID first, so they are paired even if amounts differ slightly
+    for (row, expected) in rows.iter().zip(&expected_rows) {
+        let same =
+            row.len() == expected.len() && row.iter().zip(expected).all(|(a, b)| same_field(a, b));
+        if !same {
+            return Err(format!("account {:?}, expected {:?}", row, expected));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn golden() {
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    let mut cases = std::fs::read_dir(&data)
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().into_string().ok()?;
+            Some(name.strip_suffix(".in.csv")?.to_owned())
+        })
+        .collect::<Vec<_>>();
+    cases.sort();
+    assert!(!cases.is_empty(), "no test case found in {:?}", data);
+    let failures = cases
+        .iter()
+        .filter_map(|name| {
+            let input = data.join(format!("{}.in.csv", name));
+            let expected = data.join(format!("{}.expected.csv", name));
+            let failure = check(&input, &expected).err()?;
+            Some(format!("{}: {}", name, failure))
+        })
+        .collect::<Vec<_>>();
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
@@ -0,0 +1,66 @@
+//! Snapshot tests: every `tests/snapshots/NAME.csv` input is processed by the engine, and its
+//! output (sorted by client ID) must be exactly `tests/snapshots/NAME.snap`. A missing snapshot is
+//! written rather than compared, and `UPDATE_SNAPSHOTS=1 cargo test` rewrites them all, so that a
+//! change of output is reviewed in the diff of the snapshots.
+
+use rust_coding_test::{Engine, Options};
+use std::path::Path;
+
+/// Output of the engine for the given input
+fn run(input: &Path) -> String {
+    let mut engine = Engine::new(Options {
+        sorted: true,
+        // Rejected transactions are skipped, even with the `strict_mode` feature
+        strict: false,
+        ..Options::default()
+    });
+    engine.process_from_file(input).unwrap();
+    let mut output = Vec::new();
+    engine.write_accounts_csv(&mut output).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+/// Lines only in `expected` prefixed with `-`, lines only in `actual` with `+`
+fn diff(expected: &str, actual: &str) -> String {
+    let removed = expected
+        .lines()
+        .filter(|line| !actual.lines().any(|other| other == *line))
+        .map(|line| format!("-{}\n", line));
+    let added = actual
+        .lines()
+        .filter(|line| !expected.lines().any(|other| other == *line))
+        .map(|line| format!("+{}\n", line));
+    removed.chain(added).collect()
+}
+
+#[test]
+fn snapshots() {
+    let update = std::env::var_os("UPDATE_SNAPSHOTS").is_some_and(|update| update == "1");
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots");
+    let mut inputs = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "csv"))
+        .collect::<Vec<_>>();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "no input found in {:?}", dir);
+    let mut failures = Vec::new();
+    for input in inputs {
+        let snap = input.with_extension("snap");
+        let actual = run(&input);
+        match std::fs::read_to_string(&snap) {
+            Ok(expected) if !update => {
+                if expected != actual {
+                    let name = input.file_stem().unwrap().to_string_lossy();
+                    failures.push(format!("{}:\n{}", name, diff(&expected, &actual)));
+                }
+            }
+            _ => std::fs::write(&snap, actual).unwrap(),
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "snapshots differ (run with UPDATE_SNAPSHOTS=1 to accept the changes):\n{}",
+        failures.join("\n")
+    );
+}
@@ -0,0 +1,106 @@
+//! Properties of the dispute lifecycle, checked on random sequences of transactions: held funds
+//! are all released by resolves, and a chargeback takes exactly the disputed amount away (and
+//! locks the account). Amounts are multiples of a quarter, so that `f64` sums are exact.
+
+use rust_coding_test::workload::Rng;
+use rust_coding_test::{Amount, Engine, Options};
+
+const CASES: usize = 500;
+const CLIENTS: u64 = 4;
+
+fn engine() -> Engine {
+    Engine::new(Options {
+        strict: true,
+        ..Options::default()
+    })
+}
+
+fn amount(rng: &mut Rng) -> f64 {
+    (1 + rng.below(400)) as f64 / 4.0
+}
+
+/// Random deposits and withdrawals (within the available funds) of a few clients, along with the
+/// net amount of every client
+fn deposits_and_withdrawals(rng: &mut Rng, csv: &mut String) -> (Vec<(u16, u32, f64)>, [f64; 5]) {
+    let mut deposits = Vec::new();
+    let mut net = [0.0; 5];
+    for tx in 1..=rng.below(30) as u32 + 1 {
+        let client = 1 + rng.below(CLIENTS) as u16;
+        let amount = amount(rng);
+        if rng.chance(0.3) && amount <= net[client as usize] {
+            net[client as usize] -= amount;
+            csv.push_str(&format!("withdrawal,{},{},{}\n", client, tx, amount));
+        } else {
+            net[client as usize] += amount;
+            deposits.push((client, tx, amount));
+            csv.push_str(&format!("deposit,{},{},{}\n", client, tx, amount));
+        }
+    }
+    (deposits, net)
+}
+
+#[test]
+fn resolved_disputes_release_held_funds() {
+    let mut rng = Rng::new(1);
+    for _ in 0..CASES {
+        let mut csv = String::from("type,client,tx,amount\n");
+        let (mut deposits, net) = deposits_and_withdrawals(&mut rng, &mut csv);
+        // Interleaved dispute → resolve pairs: a dispute opens, and a random open one is resolved
+        let mut open = Vec::new();
+        while !deposits.is_empty() || !open.is_empty() {
+            if !deposits.is_empty() && (open.is_empty() || rng.chance(0.5)) {
+                let (client, tx, _) =
+                    deposits.swap_remove(rng.below(deposits.len() as u64) as usize);
+                open.push((client, tx));
+                csv.push_str(&format!("dispute,{},{},\n", client, tx));
+            } else {
+                let (client, tx) = open.swap_remove(rng.below(open.len() as u64) as usize);
+                csv.push_str(&format!("resolve,{},{},\n", client, tx));
+            }
+        }
+        let mut engine = engine();
+        // Disputing a deposit that was partly withdrawn is fine, available funds go negative
+        engine
+            .process_from_str(&csv)
+            .unwrap_or_else(|e| panic!("{}:\n{}", e, csv));
+        for account in engine.snapshot().iter() {
+            assert_eq!(account.held, Amount(0.0), "{}", csv);
+            assert_eq!(
+                account.available,
+                Amount(net[account.client as usize]),
+                "{}",
+                csv
+            );
+        }
+    }
+}
+
+#[test]
+fn chargebacks_take_the_disputed_amount() {
+    let mut rng = Rng::new(2);
+    for _ in 0..CASES {
+        let mut csv = String::from("type,client,tx,amount\n");
+        let (deposits, _) = deposits_and_withdrawals(&mut rng, &mut csv);
+        let mut engine = engine();
+        engine.process_from_str(&csv).unwrap();
+        let before = engine.snapshot();
+        let (client, tx, amount) = deposits[rng.below(deposits.len() as u64) as usize];
+        let chargeback = format!(
+            "type,client,tx,amount\ndispute,{0},{1},\nchargeback,{0},{1},\n",
+            client, tx
+        );
+        engine
+            .process_from_str(&chargeback)
+            .unwrap_or_else(|e| panic!("{}:\n{}{}", e, csv, chargeback));
+        let after = engine.snapshot();
+        let (before, after) = (before.get(client).unwrap(), after.get(client).unwrap());
+        assert_eq!(
+            after.total,
+            before.total - Amount(amount),
+            "{}{}",
+            csv,
+            chargeback
+        );
+        assert!(after.locked && !before.locked, "{}{}", csv, chargeback);
+    }
+}